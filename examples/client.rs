@@ -1,38 +1,36 @@
-extern crate futures;
-extern crate hyper;
-extern crate hyperlocal;
-
 use std::io::{self, Write};
 
-use futures::Future;
-use futures::Stream;
-use hyper::{rt, Client};
+use hyper::body::HttpBody;
+use hyper::Client;
 use hyperlocal::{UnixConnector, Uri};
 
-fn main() {
+#[tokio::main]
+async fn main() {
     let client = Client::builder()
-        .keep_alive(false)
-        .build::<_, ::hyper::Body>(UnixConnector::new());
+        .pool_max_idle_per_host(0)
+        .build::<_, hyper::Body>(UnixConnector::new());
     let url = Uri::new("test.sock", "/").into();
 
-    let work = client
-        .get(url)
-        .and_then(|res| {
-            println!("Response: {}", res.status());
-            println!("Headers: {:#?}", res.headers());
-
-            res.into_body().for_each(|chunk| {
-                io::stdout()
-                    .write_all(&chunk)
-                    .map_err(|e| panic!("example expects stdout is open, error={}", e))
-            })
-        })
-        .map(|_| {
-            println!("\n\nDone.");
-        })
-        .map_err(|err| {
+    let mut res = match client.get(url).await {
+        Ok(res) => res,
+        Err(err) => {
             eprintln!("Error {}", err);
-        });
+            return;
+        }
+    };
+    println!("Response: {}", res.status());
+    println!("Headers: {:#?}", res.headers());
 
-    rt::run(work);
+    while let Some(chunk) = res.body_mut().data().await {
+        match chunk {
+            Ok(chunk) => io::stdout()
+                .write_all(&chunk)
+                .unwrap_or_else(|e| panic!("example expects stdout is open, error={}", e)),
+            Err(err) => {
+                eprintln!("Error {}", err);
+                return;
+            }
+        }
+    }
+    println!("\n\nDone.");
 }
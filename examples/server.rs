@@ -1,24 +1,16 @@
-extern crate futures;
-extern crate hyper;
-extern crate hyperlocal;
-
 use hyper::service::service_fn;
 use hyper::{header, Body, Request, Response};
 use std::{fs, io};
 
-const PHRASE: &'static str = "It's a Unix system. I know this.";
+const PHRASE: &str = "It's a Unix system. I know this.";
 
-fn hello(
-    req: Request<Body>,
-) -> impl futures::Future<Item = Response<Body>, Error = io::Error> + Send {
+async fn hello(req: Request<Body>) -> io::Result<Response<Body>> {
     println!("servicing new request {:?}", req);
-    futures::future::ok(
-        Response::builder()
-            .header(header::CONTENT_TYPE, "text/plain")
-            .header(header::CONTENT_LENGTH, PHRASE.len() as u64)
-            .body(PHRASE.into())
-            .expect("failed to create response"),
-    )
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "text/plain")
+        .header(header::CONTENT_LENGTH, PHRASE.len() as u64)
+        .body(PHRASE.into())
+        .expect("failed to create response"))
 }
 
 fn run() -> io::Result<()> {
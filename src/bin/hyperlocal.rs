@@ -0,0 +1,44 @@
+use std::env;
+use std::io;
+use std::process;
+
+use hyperlocal::server::relay::{self, Log};
+
+const USAGE: &str = "usage: hyperlocal relay [--log | --log-headers] <listen> <upstream>";
+
+fn relay(args: &[String]) -> io::Result<()> {
+    let mut log = Log::Nothing;
+    let mut paths = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--log" => log = Log::Requests,
+            "--log-headers" => log = Log::Headers,
+            flag if flag.starts_with("--") => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("unknown flag {}\n{}", flag, USAGE),
+                ))
+            }
+            path => paths.push(path),
+        }
+    }
+    match paths.as_slice() {
+        [listen, upstream] => {
+            eprintln!("relaying unix://{} to unix://{}", listen, upstream);
+            relay::run(listen, upstream, log)
+        }
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, USAGE)),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.split_first() {
+        Some((command, rest)) if command == "relay" => relay(rest),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, USAGE)),
+    };
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,88 @@
+//! Finding the socket of whichever container runtime is running
+//!
+//! Tools talking to "the container runtime" can't know up front whether the host runs
+//! Docker, rootless Docker, Podman or containerd, each listening at a socket of its own.
+//! `container_runtime` tries the well-known sockets of each in turn, and returns the first
+//! one accepting connections. `discover` does the same for any list of candidates.
+
+use crate::endpoint::Endpoint;
+use crate::paths;
+
+/// The socket of a system-wide Docker daemon.
+pub const DOCKER_SOCKET: &str = "/var/run/docker.sock";
+
+/// The socket of a system-wide Podman service.
+pub const PODMAN_SOCKET: &str = "/run/podman/podman.sock";
+
+/// The socket of containerd.
+pub const CONTAINERD_SOCKET: &str = "/run/containerd/containerd.sock";
+
+/// Return the well-known sockets of container runtimes, in the order `container_runtime`
+/// tries them.
+///
+/// These are Docker's, then those of rootless Docker and of the current user's Podman in
+/// their `paths::runtime_dir`, then the system-wide Podman's and finally containerd's.
+pub fn container_runtimes() -> Vec<Endpoint> {
+    let mut candidates = vec![Endpoint::Path(DOCKER_SOCKET.into())];
+    if let Some(runtime_dir) = paths::runtime_dir() {
+        candidates.push(Endpoint::Path(runtime_dir.join("docker.sock")));
+        candidates.push(Endpoint::Path(runtime_dir.join("podman/podman.sock")));
+    }
+    candidates.push(Endpoint::Path(PODMAN_SOCKET.into()));
+    candidates.push(Endpoint::Path(CONTAINERD_SOCKET.into()));
+    candidates
+}
+
+/// Return the socket of the first container runtime accepting connections, if any are.
+///
+/// # Examples
+///
+/// ```rust
+/// use hyperlocal::client::discover;
+///
+/// match discover::container_runtime() {
+///     Some(endpoint) => println!("found a container runtime at {}", endpoint),
+///     None => println!("no container runtime is running"),
+/// }
+/// ```
+pub fn container_runtime() -> Option<Endpoint> {
+    discover(container_runtimes())
+}
+
+/// Return the first of `candidates` accepting connections, if any do.
+///
+/// Each candidate is connected to, and the connection closed straight away. Connecting to a
+/// unix domain socket completes or fails immediately, so this doesn't block for long.
+pub fn discover<I>(candidates: I) -> Option<Endpoint>
+where
+    I: IntoIterator<Item = Endpoint>,
+{
+    candidates
+        .into_iter()
+        .find(|candidate| candidate.connect().is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempSocket;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn discover_finds_the_first_live_socket() {
+        let stale = TempSocket::new().unwrap();
+        drop(UnixListener::bind(stale.path()).unwrap());
+        let live = TempSocket::new().unwrap();
+        let _listener = UnixListener::bind(live.path()).unwrap();
+        let candidates = vec![
+            Endpoint::Path("/nonexistent/hyperlocal.sock".into()),
+            Endpoint::Path(stale.path().to_owned()),
+            Endpoint::Path(live.path().to_owned()),
+        ];
+        assert_eq!(
+            discover(candidates),
+            Some(Endpoint::Path(live.path().to_owned()))
+        );
+        assert_eq!(discover(vec![]), None);
+    }
+}
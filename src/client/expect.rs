@@ -0,0 +1,272 @@
+//! Holding request bodies back until the server is ready for them
+//!
+//! A client uploading a large body can send `Expect: 100-continue` and wait for the server's
+//! `100 Continue` before sending any of it, so that a daemon turning the request away, such
+//! as by its `Content-Length`, answers straight away instead of after the whole body has been
+//! streamed at it. hyper's client sends the header, but not the wait: it ignores interim
+//! responses and streams the body as soon as the request head is written.
+//!
+//! `send` does the waiting. It sends a single request over a connection of its own, watching
+//! the connection for the server's first response, and starts the body only once that is a
+//! `100 Continue`. A final response arriving first is returned without any of the body having
+//! been sent. Servers which don't answer `Expect` at all get the body once a wait passes.
+
+// Std lib
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+// Third party
+use futures::channel::oneshot;
+use futures::future::Either;
+use futures::stream::{self, StreamExt};
+use hyper::body::HttpBody;
+use hyper::client::conn;
+use hyper::header::{HeaderValue, CONTENT_LENGTH, EXPECT};
+use hyper::{Body, Request, Response};
+use pin_project_lite::pin_project;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time;
+
+// the status line of a response, up to and including its status code
+const STATUS_LINE: usize = b"HTTP/1.1 100".len();
+
+/// Send `req` over `stream` with `Expect: 100-continue`, holding its body back until the
+/// server answers `100 Continue`, or `wait` passes without an answer.
+///
+/// The request's uri may be a `hyperlocal::Uri`, as it is sent in origin form. A body of
+/// known size is sent with its `Content-Length`, others chunked. The connection carries this
+/// one request, and must be spawned from within a tokio runtime.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use hyper::{Body, Method, Request};
+/// use hyperlocal::client::expect;
+/// use hyperlocal::Uri;
+/// use tokio::net::UnixStream;
+///
+/// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// let stream = UnixStream::connect("/var/run/images.sock").await?;
+/// let req = Request::builder()
+///     .method(Method::POST)
+///     .uri(Uri::new("/var/run/images.sock", "/images"))
+///     .body(Body::from(vec![0; 64 * 1024 * 1024]))?;
+/// let response = expect::send(stream, req, Duration::from_secs(1)).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub async fn send<S>(stream: S, req: Request<Body>, wait: Duration) -> hyper::Result<Response<Body>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (answered, answer) = oneshot::channel();
+    let stream = Watched {
+        stream,
+        status_line: Vec::with_capacity(STATUS_LINE),
+        answered: Some(answered),
+    };
+    let (mut sender, connection) = conn::handshake(stream).await?;
+    tokio::spawn(async move {
+        let _ = connection.await;
+    });
+
+    let (mut parts, body) = req.into_parts();
+    if let Some(path_and_query) = parts.uri.path_and_query() {
+        parts.uri = path_and_query
+            .as_str()
+            .parse()
+            .expect("a path is a valid uri");
+    }
+    parts
+        .headers
+        .insert(EXPECT, HeaderValue::from_static("100-continue"));
+    if let Some(length) = body.size_hint().exact() {
+        parts
+            .headers
+            .entry(CONTENT_LENGTH)
+            .or_insert_with(|| length.into());
+    }
+    let deferred = async move {
+        let proceed = match time::timeout(wait, answer).await {
+            Ok(Ok(continued)) => continued,
+            // the server closed the connection without answering
+            Ok(Err(_)) => false,
+            // the server may not know `Expect`, so send the body regardless
+            Err(_) => true,
+        };
+        if proceed {
+            Either::Left(body)
+        } else {
+            // the server has already given its final response
+            Either::Right(stream::pending())
+        }
+    };
+    let body = Body::wrap_stream(stream::once(deferred).flatten());
+    sender.send_request(Request::from_parts(parts, body)).await
+}
+
+pin_project! {
+    /// A stream telling whether the first response read from it is a `100 Continue`.
+    struct Watched<S> {
+        #[pin]
+        stream: S,
+        status_line: Vec<u8>,
+        answered: Option<oneshot::Sender<bool>>,
+    }
+}
+
+impl<S> AsyncRead for Watched<S>
+where
+    S: AsyncRead,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let filled = buf.filled().len();
+        let polled = this.stream.poll_read(cx, buf);
+        if this.answered.is_some() {
+            let read = &buf.filled()[filled..];
+            let wanted = STATUS_LINE - this.status_line.len();
+            this.status_line
+                .extend_from_slice(&read[..std::cmp::min(wanted, read.len())]);
+            if this.status_line.len() == STATUS_LINE {
+                let continued = this.status_line.ends_with(b" 100");
+                if let Some(answered) = this.answered.take() {
+                    let _ = answered.send(continued);
+                }
+            } else if let Poll::Ready(Ok(())) = polled {
+                if read.is_empty() {
+                    // closed before a whole status line was read
+                    *this.answered = None;
+                }
+            }
+        }
+        polled
+    }
+}
+
+impl<S> AsyncWrite for Watched<S>
+where
+    S: AsyncWrite,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.project().stream.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_shutdown(cx)
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::*;
+    use crate::server::limit::Limit;
+    use crate::server::Server;
+    use crate::testing::TempSocket;
+    use futures::FutureExt;
+    use hyper::service::service_fn;
+    use hyper::StatusCode;
+    use tokio::net::UnixStream;
+
+    #[test]
+    fn bodies_are_held_back_until_the_server_continues() {
+        let socket = TempSocket::new().unwrap();
+        let server = Server::bind(
+            socket.path(),
+            Limit::new(16, || {
+                service_fn(|req: Request<Body>| async move {
+                    let body = hyper::body::to_bytes(req.into_body()).await?;
+                    Ok::<_, hyper::Error>(Response::new(Body::from(body)))
+                })
+            }),
+        )
+        .unwrap();
+        let (shutdown, stopped) = oneshot::channel::<()>();
+        let serving = std::thread::spawn(move || server.run_until(stopped.map(|_| Ok(()))));
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let post = |body: Body| {
+                Request::post(crate::Uri::new(socket.path(), "/"))
+                    .body(body)
+                    .unwrap()
+            };
+            // well within the wait, so the body was sent on `100 Continue`
+            let wait = Duration::from_secs(30);
+            let within = Duration::from_secs(5);
+
+            let stream = UnixStream::connect(socket.path()).await.unwrap();
+            let sending = send(stream, post(Body::from(vec![0; 1 << 20])), wait);
+            let response = time::timeout(within, sending).await.unwrap().unwrap();
+            assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+            let stream = UnixStream::connect(socket.path()).await.unwrap();
+            let sending = send(stream, post(Body::from("hello")), wait);
+            let response = time::timeout(within, sending).await.unwrap().unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert_eq!(&body[..], b"hello");
+        });
+        drop(runtime);
+        drop(shutdown);
+        serving.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn nothing_of_the_body_is_sent_before_continuing() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::UnixListener;
+
+        let socket = TempSocket::new().unwrap();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let listener = UnixListener::bind(socket.path()).unwrap();
+            let serving = tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    let mut byte = [0];
+                    stream.read_exact(&mut byte).await.unwrap();
+                    head.push(byte[0]);
+                }
+                let mut body = [0; 5];
+                let early = time::timeout(Duration::from_millis(100), stream.read(&mut body));
+                assert!(early.await.is_err(), "body sent before continuing");
+                stream
+                    .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+                    .await
+                    .unwrap();
+                stream.read_exact(&mut body).await.unwrap();
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                    .await
+                    .unwrap();
+                (String::from_utf8(head).unwrap(), body)
+            });
+
+            let stream = UnixStream::connect(socket.path()).await.unwrap();
+            let req = Request::put("/").body(Body::from("hello")).unwrap();
+            let response = send(stream, req, Duration::from_secs(30)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let (head, body) = serving.await.unwrap();
+            assert!(head.contains("expect: 100-continue\r\n"), "{}", head);
+            assert!(head.contains("content-length: 5\r\n"), "{}", head);
+            assert_eq!(&body, b"hello");
+        });
+    }
+}
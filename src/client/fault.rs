@@ -0,0 +1,365 @@
+//! Injecting faults into client connections, for testing resilience
+//!
+//! A `FaultyConnector` wraps another connector, such as a `UnixConnector`, and makes the
+//! connections it opens misbehave as its `Faults` describe: reads are delayed, writes are cut
+//! short, connections are reset part way through, or responses are truncated. Faults are
+//! injected by byte counts rather than at random, so a test sees the same failure every run,
+//! and can be limited to a client's first connections, so that its retries can be seen to
+//! succeed.
+
+// Std lib
+use std::future::Future;
+use std::io::{self, IoSlice};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+// Third party
+use futures::ready;
+use hyper::client::connect::{Connected, Connection};
+use hyper::service::Service;
+use hyper::Uri;
+use pin_project_lite::pin_project;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{self, Sleep};
+
+/// The faults to inject into connections.
+///
+/// Clones share a count of the connections made, which `connections` limits faults to.
+///
+/// # Examples
+///
+/// ```no_run
+/// use hyperlocal::client::fault::{Faults, FaultyConnector};
+/// use hyperlocal::UnixConnector;
+///
+/// // the first connection is reset after sending 16 bytes, later ones are left alone
+/// let faults = Faults::new().reset_after(16).connections(1);
+/// let client = hyper::Client::builder()
+///     .build::<_, hyper::Body>(FaultyConnector::new(UnixConnector::new(), faults));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Faults {
+    latency: Option<Duration>,
+    max_write: Option<usize>,
+    reset_after: Option<u64>,
+    truncate_after: Option<u64>,
+    connections: Option<usize>,
+    made: Arc<AtomicUsize>,
+}
+
+impl Faults {
+    /// Create a set of faults which injects none.
+    pub fn new() -> Self {
+        Faults::default()
+    }
+
+    /// Delay every read by `latency`.
+    pub fn latency(mut self, latency: Duration) -> Self {
+        self.latency = Some(latency);
+        self
+    }
+
+    /// Write at most `max_write` bytes at a time, so that callers must handle partial writes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_write` is zero.
+    pub fn partial_writes(mut self, max_write: usize) -> Self {
+        assert!(max_write > 0, "writes must make progress");
+        self.max_write = Some(max_write);
+        self
+    }
+
+    /// Reset connections once `bytes` have been written to them, failing every later read and
+    /// write with `io::ErrorKind::ConnectionReset`.
+    pub fn reset_after(mut self, bytes: u64) -> Self {
+        self.reset_after = Some(bytes);
+        self
+    }
+
+    /// End what is read from connections after `bytes`, as if the server had closed them.
+    pub fn truncate_after(mut self, bytes: u64) -> Self {
+        self.truncate_after = Some(bytes);
+        self
+    }
+
+    /// Only inject faults into the first `connections` made, leaving later ones alone.
+    pub fn connections(mut self, connections: usize) -> Self {
+        self.connections = Some(connections);
+        self
+    }
+
+    /// Return the number of connections made with these faults.
+    pub fn connections_made(&self) -> usize {
+        self.made.load(Ordering::SeqCst)
+    }
+
+    /// Wrap `stream` in these faults, counting it as a connection made.
+    ///
+    /// Streams beyond the number of connections faults are limited to are wrapped unharmed.
+    pub fn wrap<S>(&self, stream: S) -> FaultyStream<S> {
+        let made = self.made.fetch_add(1, Ordering::SeqCst);
+        let faulty = match self.connections {
+            Some(connections) => made < connections,
+            None => true,
+        };
+        FaultyStream {
+            stream,
+            faults: if faulty { self.clone() } else { Faults::new() },
+            delay: None,
+            read: 0,
+            written: 0,
+        }
+    }
+}
+
+pin_project! {
+    /// A stream misbehaving as its `Faults` describe.
+    pub struct FaultyStream<S> {
+        #[pin]
+        stream: S,
+        faults: Faults,
+        delay: Option<Pin<Box<Sleep>>>,
+        read: u64,
+        written: u64,
+    }
+}
+
+impl<S> FaultyStream<S> {
+    /// Return a reference to the wrapped stream.
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    fn is_reset(&self) -> bool {
+        matches!(self.faults.reset_after, Some(bytes) if self.written >= bytes)
+    }
+}
+
+fn reset() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ConnectionReset,
+        "fault injected: connection reset",
+    )
+}
+
+impl<S> AsyncRead for FaultyStream<S>
+where
+    S: AsyncRead,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.is_reset() {
+            return Poll::Ready(Err(reset()));
+        }
+        let this = self.project();
+        if let Some(latency) = this.faults.latency {
+            let delay = this
+                .delay
+                .get_or_insert_with(|| Box::pin(time::sleep(latency)));
+            ready!(delay.as_mut().poll(cx));
+        }
+        let allowed = match this.faults.truncate_after {
+            Some(bytes) => bytes.saturating_sub(*this.read),
+            None => u64::MAX,
+        };
+        if allowed == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        let limit = std::cmp::min(allowed, buf.remaining() as u64) as usize;
+        let mut limited = buf.take(limit);
+        ready!(this.stream.poll_read(cx, &mut limited))?;
+        let read = limited.filled().len();
+        // the bytes were read straight into `buf`'s unfilled part
+        unsafe {
+            buf.assume_init(read);
+        }
+        buf.advance(read);
+        *this.read += read as u64;
+        *this.delay = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for FaultyStream<S>
+where
+    S: AsyncWrite,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.is_reset() {
+            return Poll::Ready(Err(reset()));
+        }
+        let this = self.project();
+        let mut limit = this.faults.max_write.unwrap_or(usize::MAX);
+        if let Some(bytes) = this.faults.reset_after {
+            limit = std::cmp::min(limit, (bytes - *this.written) as usize);
+        }
+        let buf = &buf[..std::cmp::min(limit, buf.len())];
+        let written = ready!(this.stream.poll_write(cx, buf))?;
+        *this.written += written as u64;
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        // faults apply per write, so write the first non-empty buffer alone
+        let buf = bufs
+            .iter()
+            .find(|buf| !buf.is_empty())
+            .map_or(&[][..], |buf| &**buf);
+        self.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_shutdown(cx)
+    }
+}
+
+impl<S> AsRawFd for FaultyStream<S>
+where
+    S: AsRawFd,
+{
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+impl<S> Connection for FaultyStream<S>
+where
+    S: Connection,
+{
+    fn connected(&self) -> Connected {
+        self.stream.connected()
+    }
+}
+
+/// A connector whose connections misbehave as its `Faults` describe.
+#[derive(Clone, Debug)]
+pub struct FaultyConnector<C> {
+    inner: C,
+    faults: Faults,
+}
+
+impl<C> FaultyConnector<C> {
+    /// Wraps `inner`, injecting `faults` into the connections it makes.
+    pub fn new(inner: C, faults: Faults) -> Self {
+        FaultyConnector { inner, faults }
+    }
+
+    /// Return the faults injected.
+    pub fn faults(&self) -> &Faults {
+        &self.faults
+    }
+}
+
+impl<C> Service<Uri> for FaultyConnector<C>
+where
+    C: Service<Uri>,
+{
+    type Response = FaultyStream<C::Response>;
+    type Error = C::Error;
+    type Future = FaultyConnecting<C::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        FaultyConnecting {
+            future: self.inner.call(uri),
+            faults: self.faults.clone(),
+        }
+    }
+}
+
+pin_project! {
+    /// A future resolving to a `FaultyStream` once the wrapped connector has connected.
+    pub struct FaultyConnecting<F> {
+        #[pin]
+        future: F,
+        faults: Faults,
+    }
+}
+
+impl<F, S, E> Future for FaultyConnecting<F>
+where
+    F: Future<Output = Result<S, E>>,
+{
+    type Output = Result<FaultyStream<S>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let stream = ready!(this.future.poll(cx))?;
+        Poll::Ready(Ok(this.faults.wrap(stream)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixStream;
+
+    #[test]
+    fn faults_cut_writes_short_and_truncate_reads() {
+        let faults = Faults::new()
+            .partial_writes(3)
+            .truncate_after(4)
+            .connections(1);
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let (client, mut server) = UnixStream::pair().unwrap();
+            let mut client = faults.wrap(client);
+            assert_eq!(client.write(b"hello").await.unwrap(), 3);
+            server.write_all(b"goodbye").await.unwrap();
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, b"good");
+
+            // only the first connection is faulty
+            let (client, mut server) = UnixStream::pair().unwrap();
+            let mut client = faults.wrap(client);
+            assert_eq!(client.write(b"hello").await.unwrap(), 5);
+            server.read_exact(&mut [0; 5]).await.unwrap();
+            server.write_all(b"goodbye").await.unwrap();
+            drop(server);
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, b"goodbye");
+        });
+        assert_eq!(faults.connections_made(), 2);
+    }
+
+    #[test]
+    fn faults_reset_connections() {
+        let faults = Faults::new().reset_after(2);
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let (client, _server) = UnixStream::pair().unwrap();
+            let mut client = faults.wrap(client);
+            assert_eq!(client.write(b"hello").await.unwrap(), 2);
+            let err = client.write(b"llo").await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+            let err = client.read(&mut [0; 4]).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        });
+    }
+}
@@ -0,0 +1,291 @@
+//! Resolving the server to connect to from the environment
+//!
+//! Command line tools conventionally take the daemon they talk to from an environment
+//! variable, such as Docker's `DOCKER_HOST`, holding a `unix://` socket path, an `npipe://`
+//! pipe or a `tcp://` address. `Host` parses those, and `HostConnector` connects to
+//! whichever one was given, so that tools built on hyperlocal follow the same conventions.
+
+// Std lib
+use std::env;
+use std::fmt;
+use std::future::Future;
+use std::io::{self, IoSlice};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+// Third party
+use hyper::client::connect::{Connected, Connection};
+use hyper::service::Service;
+use hyper::Uri as HyperUri;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+use super::{EndpointConnector, UnixStream};
+use crate::endpoint::{Endpoint, ParseEndpointError};
+use crate::error::Error;
+
+/// The variable Docker's tools name their daemon in.
+pub const DOCKER_HOST: &str = "DOCKER_HOST";
+
+const TCP: &str = "tcp://";
+
+/// A server to connect to, on the local host or over TCP.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Host {
+    /// A unix domain socket, or another local `Endpoint`.
+    Local(Endpoint),
+    /// A TCP address, as `host:port`.
+    Tcp(String),
+}
+
+impl Host {
+    /// Parse the host named in the environment variable `var`, if it is set and not empty.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use hyperlocal::client::host::{Host, DOCKER_HOST};
+    /// use hyperlocal::Endpoint;
+    ///
+    /// let host = Host::from_env(DOCKER_HOST)
+    ///     .unwrap()
+    ///     .unwrap_or_else(|| Host::Local(Endpoint::Path("/var/run/docker.sock".into())));
+    /// ```
+    pub fn from_env(var: &str) -> Result<Option<Host>, ParseEndpointError> {
+        match env::var(var) {
+            Ok(host) if !host.is_empty() => host.parse().map(Some),
+            _ => Ok(None),
+        }
+    }
+}
+
+impl FromStr for Host {
+    type Err = ParseEndpointError;
+
+    /// Parse `tcp://host:port`, or anything an `Endpoint` parses.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix(TCP) {
+            Some(addr) => match addr.rsplit_once(':') {
+                Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                    Ok(Host::Tcp(addr.to_owned()))
+                }
+                _ => Err(ParseEndpointError::new(s, "expected a host:port address")),
+            },
+            None => s.parse().map(Host::Local),
+        }
+    }
+}
+
+impl fmt::Display for Host {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Host::Local(ref endpoint) => endpoint.fmt(f),
+            Host::Tcp(ref addr) => write!(f, "{}{}", TCP, addr),
+        }
+    }
+}
+
+impl From<Endpoint> for Host {
+    fn from(endpoint: Endpoint) -> Self {
+        Host::Local(endpoint)
+    }
+}
+
+/// A type which implements hyper's client connector interface, connecting to a single
+/// `Host` whatever the uri requested.
+///
+/// Requests are made with plain `http` uris, whose authority is only sent along as the
+/// `Host` header.
+///
+/// # Examples
+///
+/// ```rust
+/// use hyperlocal::client::host::{Host, HostConnector, DOCKER_HOST};
+///
+/// let host = Host::from_env(DOCKER_HOST)
+///     .unwrap()
+///     .unwrap_or_else(|| "unix:///var/run/docker.sock".parse().unwrap());
+/// let client = hyper::Client::builder().build::<_, hyper::Body>(HostConnector::new(host));
+/// let version = client.get("http://docker/version".parse().unwrap());
+/// ```
+#[derive(Clone, Debug)]
+pub struct HostConnector {
+    host: Arc<Host>,
+    local: Option<EndpointConnector>,
+}
+
+impl HostConnector {
+    /// Create a connector connecting to `host`.
+    pub fn new(host: Host) -> Self {
+        let local = match host {
+            Host::Local(ref endpoint) => Some(EndpointConnector::new(endpoint.clone())),
+            Host::Tcp(_) => None,
+        };
+        HostConnector {
+            host: Arc::new(host),
+            local,
+        }
+    }
+
+    /// Return the host connected to.
+    pub fn host(&self) -> &Host {
+        &self.host
+    }
+}
+
+impl Service<HyperUri> for HostConnector {
+    type Response = HostStream;
+    type Error = Error;
+    type Future = HostConnecting;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: HyperUri) -> Self::Future {
+        let connecting: Pin<Box<dyn Future<Output = _> + Send>> = match self.local {
+            Some(ref mut local) => {
+                let connecting = local.call(uri);
+                Box::pin(async move { connecting.await.map(HostStream::Local) })
+            }
+            None => {
+                let host = self.host.clone();
+                Box::pin(async move {
+                    let addr = match *host {
+                        Host::Tcp(ref addr) => addr,
+                        Host::Local(_) => unreachable!("local hosts have a connector"),
+                    };
+                    let stream = TcpStream::connect(addr.as_str())
+                        .await
+                        .map_err(|err| Error::connect(&host, err))?;
+                    // requests are small and latency matters more than packet counts
+                    stream
+                        .set_nodelay(true)
+                        .map_err(|err| Error::connect(&host, err))?;
+                    Ok(HostStream::Tcp(stream))
+                })
+            }
+        };
+        HostConnecting { inner: connecting }
+    }
+}
+
+/// The future returned by `HostConnector`, resolving to a connected `HostStream`.
+pub struct HostConnecting {
+    inner: Pin<Box<dyn Future<Output = Result<HostStream, Error>> + Send>>,
+}
+
+impl Future for HostConnecting {
+    type Output = Result<HostStream, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.inner.as_mut().poll(cx)
+    }
+}
+
+impl fmt::Debug for HostConnecting {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HostConnecting").finish()
+    }
+}
+
+/// A connection made by a `HostConnector`.
+#[derive(Debug)]
+pub enum HostStream {
+    /// A connection to a local endpoint.
+    Local(UnixStream),
+    /// A TCP connection.
+    Tcp(TcpStream),
+}
+
+impl AsyncRead for HostStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            HostStream::Local(stream) => Pin::new(stream).poll_read(cx, buf),
+            HostStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for HostStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            HostStream::Local(stream) => Pin::new(stream).poll_write(cx, buf),
+            HostStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            HostStream::Local(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            HostStream::Tcp(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            HostStream::Local(stream) => stream.is_write_vectored(),
+            HostStream::Tcp(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            HostStream::Local(stream) => Pin::new(stream).poll_flush(cx),
+            HostStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            HostStream::Local(stream) => Pin::new(stream).poll_shutdown(cx),
+            HostStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+impl Connection for HostStream {
+    fn connected(&self) -> Connected {
+        match self {
+            HostStream::Local(stream) => stream.connected(),
+            HostStream::Tcp(stream) => stream.connected(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hosts_parse_docker_host_values() {
+        assert_eq!(
+            "unix:///var/run/docker.sock".parse::<Host>().unwrap(),
+            Host::Local(Endpoint::Path("/var/run/docker.sock".into()))
+        );
+        assert_eq!(
+            "npipe:////./pipe/docker_engine".parse::<Host>().unwrap(),
+            Host::Local(Endpoint::NamedPipe(r"\\.\pipe\docker_engine".into()))
+        );
+        let tcp = "tcp://127.0.0.1:2375".parse::<Host>().unwrap();
+        assert_eq!(tcp, Host::Tcp("127.0.0.1:2375".into()));
+        assert_eq!(tcp.to_string(), "tcp://127.0.0.1:2375");
+        for invalid in &["tcp://", "tcp://localhost", "tcp://:2375", "ssh://host"] {
+            assert!(invalid.parse::<Host>().is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,430 @@
+//! Client-side latency metrics
+//!
+//! Three latencies are reported per endpoint to a `Metrics` hook: the time taken to connect,
+//! reported by a `UnixConnector` registered with `UnixConnector#metrics`, and the time to the
+//! first byte of a response and to its last, reported by a `Timed` client. `Latencies` keeps
+//! a `Histogram` of each, so regressions on the socket path show up as shifted quantiles.
+
+// Std lib
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+// Third party
+use futures::ready;
+use hyper::body::HttpBody;
+use hyper::service::Service;
+use hyper::{HeaderMap, Request, Response};
+use pin_project_lite::pin_project;
+
+use crate::endpoint::Endpoint;
+use crate::Uri;
+
+/// The number of buckets in a `Histogram`.
+pub const BUCKETS: usize = 32;
+
+/// Hooks invoked as a client connects and receives responses.
+///
+/// Every method has an empty default, so implementations need only override the latencies
+/// they are interested in. `endpoint` is `None` for connections and requests whose endpoint
+/// isn't known, such as those of a `testing::DuplexConnector`.
+pub trait Metrics: Send + Sync {
+    /// A connection to `endpoint` was made in `elapsed`.
+    fn connected(&self, _endpoint: Option<&Endpoint>, _elapsed: Duration) {}
+
+    /// The head of a response from `endpoint` arrived `elapsed` after its request was sent.
+    fn first_byte(&self, _endpoint: Option<&Endpoint>, _elapsed: Duration) {}
+
+    /// The body of a response from `endpoint` ended `elapsed` after its request was sent.
+    fn completed(&self, _endpoint: Option<&Endpoint>, _elapsed: Duration) {}
+}
+
+/// A histogram of latencies, in buckets whose bounds double from 1µs.
+///
+/// Bucket `i` counts latencies below `2^i` microseconds, and above its predecessor's bound; the
+/// last bucket counts everything longer, too.
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    /// Create an empty histogram.
+    pub fn new() -> Self {
+        Histogram {
+            buckets: Default::default(),
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    /// Count `latency`.
+    pub fn record(&self, latency: Duration) {
+        let micros = latency.as_micros();
+        let bucket = (128 - micros.leading_zeros()) as usize;
+        self.buckets[bucket.min(BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+        self.sum
+            .fetch_add(latency.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Return the number of latencies counted.
+    pub fn count(&self) -> u64 {
+        self.counts().iter().sum()
+    }
+
+    /// Return the sum of the latencies counted.
+    pub fn sum(&self) -> Duration {
+        Duration::from_nanos(self.sum.load(Ordering::Relaxed))
+    }
+
+    /// Return the upper bound of the bucket holding the `quantile`th latency, between 0 and 1,
+    /// or `None` if no latencies have been counted.
+    pub fn quantile(&self, quantile: f64) -> Option<Duration> {
+        let counts = self.counts();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let rank = ((total as f64 * quantile.clamp(0.0, 1.0)).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(Histogram::bound(bucket));
+            }
+        }
+        Some(Histogram::bound(BUCKETS - 1))
+    }
+
+    /// Return the upper bound and count of each bucket, from the shortest.
+    pub fn buckets(&self) -> Vec<(Duration, u64)> {
+        self.counts()
+            .iter()
+            .enumerate()
+            .map(|(bucket, count)| (Histogram::bound(bucket), *count))
+            .collect()
+    }
+
+    fn counts(&self) -> [u64; BUCKETS] {
+        let mut counts = [0; BUCKETS];
+        for (count, bucket) in counts.iter_mut().zip(self.buckets.iter()) {
+            *count = bucket.load(Ordering::Relaxed);
+        }
+        counts
+    }
+
+    fn bound(bucket: usize) -> Duration {
+        Duration::from_micros(1 << bucket)
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram::new()
+    }
+}
+
+impl fmt::Debug for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Histogram")
+            .field("count", &self.count())
+            .field("sum", &self.sum())
+            .finish()
+    }
+}
+
+/// The latency histograms of one endpoint.
+#[derive(Debug, Default)]
+pub struct EndpointLatencies {
+    connect: Histogram,
+    first_byte: Histogram,
+    total: Histogram,
+}
+
+impl EndpointLatencies {
+    /// Return the histogram of times taken to connect.
+    pub fn connect(&self) -> &Histogram {
+        &self.connect
+    }
+
+    /// Return the histogram of times to the first byte of a response.
+    pub fn first_byte(&self) -> &Histogram {
+        &self.first_byte
+    }
+
+    /// Return the histogram of times to the end of a response.
+    pub fn total(&self) -> &Histogram {
+        &self.total
+    }
+}
+
+/// A `Metrics` implementation keeping latency histograms per endpoint.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::sync::Arc;
+///
+/// use hyper::service::Service;
+/// use hyperlocal::client::metrics::{Latencies, Timed};
+/// use hyperlocal::{Endpoint, UnixConnector};
+///
+/// # async fn run() -> Result<(), hyper::Error> {
+/// let latencies = Arc::new(Latencies::default());
+/// let mut connector = UnixConnector::new();
+/// connector.metrics(latencies.clone());
+/// let mut client = Timed::new(
+///     hyper::Client::builder().build::<_, hyper::Body>(connector),
+///     latencies.clone(),
+/// );
+/// let uri: hyper::Uri = hyperlocal::Uri::new("/var/run/docker.sock", "/info").into();
+/// client
+///     .call(hyper::Request::get(uri).body(hyper::Body::empty()).unwrap())
+///     .await?;
+///
+/// let endpoint = Endpoint::Path("/var/run/docker.sock".into());
+/// if let Some(docker) = latencies.endpoint(Some(&endpoint)) {
+///     println!("p99 time to first byte: {:?}", docker.first_byte().quantile(0.99));
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Default)]
+pub struct Latencies {
+    endpoints: Mutex<HashMap<Option<Endpoint>, Arc<EndpointLatencies>>>,
+}
+
+impl Latencies {
+    /// Return the latencies recorded for `endpoint`, if any were.
+    pub fn endpoint(&self, endpoint: Option<&Endpoint>) -> Option<Arc<EndpointLatencies>> {
+        self.lock().get(&endpoint.cloned()).cloned()
+    }
+
+    /// Return every endpoint latencies were recorded for, along with them.
+    pub fn endpoints(&self) -> Vec<(Option<Endpoint>, Arc<EndpointLatencies>)> {
+        self.lock()
+            .iter()
+            .map(|(endpoint, latencies)| (endpoint.clone(), latencies.clone()))
+            .collect()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<Option<Endpoint>, Arc<EndpointLatencies>>> {
+        self.endpoints.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn of(&self, endpoint: Option<&Endpoint>) -> Arc<EndpointLatencies> {
+        self.lock().entry(endpoint.cloned()).or_default().clone()
+    }
+}
+
+impl Metrics for Latencies {
+    fn connected(&self, endpoint: Option<&Endpoint>, elapsed: Duration) {
+        self.of(endpoint).connect.record(elapsed);
+    }
+
+    fn first_byte(&self, endpoint: Option<&Endpoint>, elapsed: Duration) {
+        self.of(endpoint).first_byte.record(elapsed);
+    }
+
+    fn completed(&self, endpoint: Option<&Endpoint>, elapsed: Duration) {
+        self.of(endpoint).total.record(elapsed);
+    }
+}
+
+/// A client reporting the time to the first and last byte of every response to a `Metrics`.
+///
+/// This wraps any client `Service`, such as a `hyper::Client`. The endpoint of each request is
+/// read from its `hyperlocal::Uri`.
+#[derive(Clone)]
+pub struct Timed<S> {
+    inner: S,
+    metrics: Arc<dyn Metrics>,
+}
+
+impl<S> Timed<S> {
+    /// Wraps `inner`, reporting latencies to `metrics`.
+    pub fn new<M>(inner: S, metrics: Arc<M>) -> Self
+    where
+        M: Metrics + 'static,
+    {
+        Timed { inner, metrics }
+    }
+
+    /// Return a reference to the wrapped client.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S, B, R> Service<Request<B>> for Timed<S>
+where
+    S: Service<Request<B>, Response = Response<R>>,
+{
+    type Response = Response<TimedBody<R>>;
+    type Error = S::Error;
+    type Future = Timing<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let endpoint = Uri::socket_path(req.uri()).map(|path| Endpoint::Path(path.into()));
+        Timing {
+            future: self.inner.call(req),
+            timer: Some(Timer {
+                metrics: self.metrics.clone(),
+                endpoint,
+                started: Instant::now(),
+            }),
+        }
+    }
+}
+
+/// The state needed to report a request's latencies.
+struct Timer {
+    metrics: Arc<dyn Metrics>,
+    endpoint: Option<Endpoint>,
+    started: Instant,
+}
+
+pin_project! {
+    /// A future reporting the time to a response's first byte once it arrives.
+    pub struct Timing<F> {
+        #[pin]
+        future: F,
+        timer: Option<Timer>,
+    }
+}
+
+impl<F, R, E> Future for Timing<F>
+where
+    F: Future<Output = Result<Response<R>, E>>,
+{
+    type Output = Result<Response<TimedBody<R>>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let response = ready!(this.future.poll(cx))?;
+        let timer = this.timer.take().expect("polled after complete");
+        timer
+            .metrics
+            .first_byte(timer.endpoint.as_ref(), timer.started.elapsed());
+        Poll::Ready(Ok(response.map(|body| TimedBody {
+            body,
+            timer: Some(timer),
+        })))
+    }
+}
+
+/// A response body reporting the time to its last byte once it ends.
+pub struct TimedBody<B> {
+    body: B,
+    timer: Option<Timer>,
+}
+
+impl<B> TimedBody<B> {
+    fn complete(&mut self) {
+        if let Some(timer) = self.timer.take() {
+            timer
+                .metrics
+                .completed(timer.endpoint.as_ref(), timer.started.elapsed());
+        }
+    }
+}
+
+impl<B> HttpBody for TimedBody<B>
+where
+    B: HttpBody + Unpin,
+    B::Error: Into<Box<dyn StdError + Send + Sync>>,
+{
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let polled = ready!(Pin::new(&mut self.body).poll_data(cx));
+        if let None | Some(Err(_)) = polled {
+            self.complete();
+        }
+        Poll::Ready(polled)
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        let polled = ready!(Pin::new(&mut self.body).poll_trailers(cx));
+        self.complete();
+        Poll::Ready(polled)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> hyper::body::SizeHint {
+        self.body.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histograms_bucket_by_doubling_bounds() {
+        let histogram = Histogram::new();
+        assert_eq!(histogram.quantile(0.5), None);
+        histogram.record(Duration::from_micros(0));
+        histogram.record(Duration::from_micros(3));
+        histogram.record(Duration::from_micros(3));
+        histogram.record(Duration::from_millis(5));
+        assert_eq!(histogram.count(), 4);
+        assert_eq!(histogram.quantile(0.25), Some(Duration::from_micros(1)));
+        assert_eq!(histogram.quantile(0.5), Some(Duration::from_micros(4)));
+        assert_eq!(histogram.quantile(1.0), Some(Duration::from_micros(8192)));
+        assert_eq!(histogram.sum(), Duration::from_micros(5006));
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn timed_clients_report_first_and_last_bytes() {
+        use crate::server::Http;
+        use crate::{testing, PeerCred};
+        use hyper::service::service_fn;
+        use hyper::{Body, Client};
+
+        let latencies = Arc::new(Latencies::default());
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let (connector, incoming) = testing::pair(PeerCred::new(0, 0, None)).unwrap();
+            let serve = Http::new().serve_incoming(incoming, || {
+                service_fn(|req: Request<Body>| async move {
+                    Ok::<_, hyper::Error>(Response::new(req.into_body()))
+                })
+            });
+            tokio::spawn(serve.with_graceful_shutdown(futures::future::pending::<()>()));
+
+            let client = Client::builder().build::<_, Body>(connector);
+            let mut client = Timed::new(client, latencies.clone());
+            let req = Request::post("http://localhost/")
+                .body(Body::from("hello"))
+                .unwrap();
+            let response = client.call(req).await.unwrap();
+            let received = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert_eq!(&received[..], b"hello");
+        });
+        let unknown = latencies.endpoint(None).unwrap();
+        assert_eq!(unknown.first_byte().count(), 1);
+        assert_eq!(unknown.total().count(), 1);
+        assert_eq!(unknown.connect().count(), 0);
+    }
+}
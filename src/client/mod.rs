@@ -1,16 +1,40 @@
 //! Hyper client bindings for unix domain sockets
 
 // Std lib
-use std::io;
+use std::fmt;
+use std::fs;
+use std::future::Future;
+use std::io::{self, IoSlice};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
 
 // Third party
-use futures::{Async, Future, Poll};
-use hyper::client::connect::{Connect, Connected, Destination};
-use tokio_uds::{ConnectFuture as StreamConnectFuture, UnixStream};
+use hyper::client::connect::{Connected, Connection};
+use hyper::service::Service;
+use hyper::Uri as HyperUri;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UnixStream as TokioUnixStream;
 
-use super::Uri;
+use super::{Uri, TLS_SCHEME};
+use crate::credentials::PeerCred;
+use crate::endpoint::Endpoint;
+use crate::error::Error;
+use crate::transport::{self, Tokio};
 
-const UNIX_SCHEME: &str = "unix";
+pub mod discover;
+pub mod expect;
+pub mod fault;
+pub mod host;
+pub mod metrics;
+pub mod reaper;
+
+use self::metrics::Metrics;
+use self::reaper::{Activity, Reaper};
 
 /// A type which implements hyper's client connector interface
 /// for unix domain sockets
@@ -22,73 +46,532 @@ const UNIX_SCHEME: &str = "unix";
 /// # examples
 ///
 /// ```no_run
-/// extern crate hyper;
-/// extern crate hyperlocal;
-///
 /// let client = hyper::Client::builder()
 ///    .build::<_, hyper::Body>(hyperlocal::UnixConnector::new());
 /// ```
 #[derive(Clone)]
-pub struct UnixConnector;
+pub struct UnixConnector {
+    enforce_unix: bool,
+    preflight: bool,
+    reaper: Option<Reaper>,
+    metrics: Option<Arc<dyn Metrics>>,
+}
 
 impl UnixConnector {
     pub fn new() -> Self {
-        UnixConnector
+        UnixConnector {
+            enforce_unix: true,
+            preflight: false,
+            reaper: None,
+            metrics: None,
+        }
+    }
+
+    /// Set whether to only connect to `unix` uris, which is the default.
+    ///
+    /// TLS connectors wrapping a `UnixConnector` disable this to be handed the connections
+    /// for `https+unix` uris too, which they then handshake over. On its own a
+    /// `UnixConnector` never speaks TLS, so leave this enabled unless it is wrapped.
+    pub fn enforce_unix(&mut self, enforce: bool) {
+        self.enforce_unix = enforce;
+    }
+
+    /// Set whether to check the socket path with `check_socket` before each connection.
+    ///
+    /// Connecting to a missing path or to a file that isn't a socket then fails with an
+    /// `Error::SocketNotFound` or `Error::NotASocket` naming it, rather than with whatever
+    /// error the connection attempt happens to return.
+    pub fn preflight(&mut self, check: bool) {
+        self.preflight = check;
+    }
+
+    /// Register every connection made with `reaper`, which closes those left idle in hyper's
+    /// pool for longer than its `max_idle`.
+    ///
+    /// See `reaper::Reaper`. Connecting then requires a tokio runtime with its timer enabled.
+    pub fn reap_idle(&mut self, reaper: &Reaper) {
+        self.reaper = Some(reaper.clone());
+    }
+
+    /// Report the time taken to make each connection to `metrics`, such as
+    /// `metrics::Latencies`.
+    pub fn metrics<M>(&mut self, metrics: Arc<M>)
+    where
+        M: Metrics + 'static,
+    {
+        self.metrics = Some(metrics);
+    }
+}
+
+impl Default for UnixConnector {
+    fn default() -> Self {
+        UnixConnector::new()
     }
 }
 
-impl Connect for UnixConnector {
-    type Transport = UnixStream;
-    type Error = io::Error;
+impl Service<HyperUri> for UnixConnector {
+    type Response = UnixStream;
+    type Error = Error;
     type Future = ConnectFuture;
 
-    fn connect(&self, destination: Destination) -> Self::Future {
-        ConnectFuture::Start(destination)
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
     }
+
+    fn call(&mut self, uri: HyperUri) -> Self::Future {
+        let enforce_unix = self.enforce_unix;
+        let preflight = self.preflight;
+        let reaper = self.reaper.clone();
+        let metrics = self.metrics.clone();
+        #[cfg(feature = "otel")]
+        let span = crate::otel::connect_span(Uri::socket_path(&uri).as_deref());
+        let connecting = async move {
+            let scheme = uri.scheme_str().unwrap_or("");
+            if scheme == TLS_SCHEME && enforce_unix {
+                let source = io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{} uris need a TLS connector, not a bare UnixConnector",
+                        TLS_SCHEME
+                    ),
+                );
+                return Err(Error::connect(&uri, source));
+            }
+            if preflight {
+                if let Some(path) = Uri::socket_path(&uri) {
+                    check_socket(path)?;
+                }
+            }
+            let started = Instant::now();
+            let stream = transport::connect::<Tokio>(&uri).await;
+            #[cfg(feature = "log")]
+            if let Err(ref err) = stream {
+                crate::logs::connect_failed(Uri::socket_path(&uri).as_deref(), err);
+            }
+            let stream = stream?;
+            let endpoint = Uri::socket_path(&uri).map(|path| Endpoint::Path(path.into()));
+            if let Some(ref metrics) = metrics {
+                metrics.connected(endpoint.as_ref(), started.elapsed());
+            }
+            let stream = UnixStream::new(stream, endpoint);
+            #[cfg(feature = "otel")]
+            crate::otel::record_peer(&tracing::Span::current(), stream.info.peer_cred());
+            #[cfg(feature = "log")]
+            crate::logs::connected(Uri::socket_path(&uri).as_deref(), stream.info.peer_cred());
+            Ok(match reaper {
+                Some(ref reaper) => stream.reaped_by(reaper),
+                None => stream,
+            })
+        };
+        #[cfg(feature = "otel")]
+        let connecting = tracing::Instrument::instrument(connecting, span);
+        ConnectFuture::new(connecting)
+    }
+}
+
+/// The future returned by `UnixConnector` and `EndpointConnector`, resolving to a
+/// connected `UnixStream`.
+pub struct ConnectFuture {
+    inner: Pin<Box<dyn Future<Output = Result<UnixStream, Error>> + Send>>,
 }
 
-pub enum ConnectFuture {
-    Start(Destination),
-    Connect(StreamConnectFuture),
+impl ConnectFuture {
+    fn new<F>(future: F) -> Self
+    where
+        F: Future<Output = Result<UnixStream, Error>> + Send + 'static,
+    {
+        ConnectFuture {
+            inner: Box::pin(future),
+        }
+    }
 }
 
 impl Future for ConnectFuture {
-    type Item = (UnixStream, Connected);
-    type Error = io::Error;
-
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        loop {
-            let next_state = match self {
-                ConnectFuture::Start(destination) => {
-                    if destination.scheme() != UNIX_SCHEME {
-                        return Err(io::Error::new(
-                            io::ErrorKind::InvalidInput,
-                            format!("Invalid uri {:?}", destination),
-                        ));
-                    }
-
-                    let path = match Uri::socket_path_dest(&destination) {
-                        Some(path) => path,
-
-                        None => {
-                            return Err(io::Error::new(
-                                io::ErrorKind::InvalidInput,
-                                format!("Invalid uri {:?}", destination),
-                            ))
-                        }
-                    };
-
-                    ConnectFuture::Connect(UnixStream::connect(&path))
-                }
+    type Output = Result<UnixStream, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.inner.as_mut().poll(cx)
+    }
+}
+
+impl fmt::Debug for ConnectFuture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ConnectFuture").finish()
+    }
+}
+
+/// Who answered the requests made over a connection from one of hyperlocal's connectors.
+///
+/// Connectors hand this to hyper as the connection's extra data, so every response received
+/// over the connection carries it in its extensions.
+///
+/// # Examples
+///
+/// ```no_run
+/// use hyperlocal::client::ServerInfo;
+///
+/// # async fn run() -> Result<(), hyper::Error> {
+/// let client = hyper::Client::builder().build::<_, hyper::Body>(hyperlocal::UnixConnector::new());
+/// let response = client
+///     .get(hyperlocal::Uri::new("/var/run/docker.sock", "/info").into())
+///     .await?;
+/// if let Some(uid) = response.extensions().get::<ServerInfo>().and_then(ServerInfo::uid) {
+///     println!("answered by uid {}", uid);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServerInfo {
+    peer_cred: Option<PeerCred>,
+    endpoint: Option<Endpoint>,
+}
+
+impl ServerInfo {
+    /// Return the credentials of the server process, if they could be queried.
+    pub fn peer_cred(&self) -> Option<&PeerCred> {
+        self.peer_cred.as_ref()
+    }
+
+    /// Return the effective user id of the server process, if it could be queried.
+    pub fn uid(&self) -> Option<u32> {
+        self.peer_cred.map(|peer_cred| peer_cred.uid())
+    }
+
+    /// Return the endpoint connected to, as requested rather than as shortened to fit a
+    /// `sockaddr_un`.
+    ///
+    /// This is `None` for the in-memory connections of a `testing::DuplexConnector`.
+    pub fn endpoint(&self) -> Option<&Endpoint> {
+        self.endpoint.as_ref()
+    }
+}
+
+/// A connection made by one of hyperlocal's connectors.
+///
+/// This wraps tokio's `UnixStream`, which hyper can't use as a client connection itself.
+#[derive(Debug)]
+pub struct UnixStream {
+    // dropped before the stream, which must stay open for as long as a reaper may shut it down
+    activity: Option<Activity>,
+    inner: TokioUnixStream,
+    info: ServerInfo,
+}
+
+impl UnixStream {
+    pub(crate) fn new(inner: TokioUnixStream, endpoint: Option<Endpoint>) -> Self {
+        // credentials don't change over the lifetime of a connection, so query them once
+        let peer_cred = PeerCred::of(&inner).ok();
+        UnixStream {
+            activity: None,
+            inner,
+            info: ServerInfo {
+                peer_cred,
+                endpoint,
+            },
+        }
+    }
+
+    /// Register this connection with `reaper`.
+    pub(crate) fn reaped_by(mut self, reaper: &Reaper) -> Self {
+        self.activity = Some(reaper.track(self.inner.as_raw_fd(), self.info.endpoint.clone()));
+        self
+    }
+
+    /// Record traffic on this connection with its reaper, if it has one.
+    fn touch<T>(&self, poll: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        if let (Some(ref activity), Poll::Ready(Ok(_))) = (&self.activity, &poll) {
+            activity.touch();
+        }
+        poll
+    }
+
+    /// Return who is answering requests made over this connection.
+    pub fn server_info(&self) -> &ServerInfo {
+        &self.info
+    }
+
+    /// Return the credentials of the server process, if they could be queried.
+    pub fn peer_cred(&self) -> Option<&PeerCred> {
+        self.info.peer_cred()
+    }
+
+    /// Return the process id of the server process, on platforms reporting it.
+    pub fn peer_pid(&self) -> Option<i32> {
+        self.info.peer_cred().and_then(PeerCred::pid)
+    }
+
+    /// Return a reference to the underlying stream.
+    pub fn get_ref(&self) -> &TokioUnixStream {
+        &self.inner
+    }
+
+    /// Return the underlying stream.
+    pub fn into_inner(mut self) -> TokioUnixStream {
+        self.activity = None;
+        self.inner
+    }
+}
 
-                ConnectFuture::Connect(f) => match f.poll() {
-                    Ok(Async::Ready(stream)) => return Ok(Async::Ready((stream, Connected::new()))),
-                    Ok(Async::NotReady) => return Ok(Async::NotReady),
-                    Err(err) => return Err(err),
-                },
-            };
+impl AsRawFd for UnixStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl AsyncRead for UnixStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.touch(poll)
+    }
+}
+
+impl AsyncWrite for UnixStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.touch(poll)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+        self.touch(poll)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl Connection for UnixStream {
+    fn connected(&self) -> Connected {
+        Connected::new().extra(self.info.clone())
+    }
+}
+
+/// A type which implements hyper's client connector interface, connecting to a single
+/// `Endpoint` whatever the uri requested.
+///
+/// As the endpoint is fixed, uris need not name a socket; any `http` uri will do.
+///
+/// # examples
+///
+/// ```no_run
+/// use hyperlocal::{Endpoint, EndpointConnector};
+///
+/// let endpoint = Endpoint::Abstract(b"containerd".to_vec());
+/// let client = hyper::Client::builder()
+///    .build::<_, hyper::Body>(EndpointConnector::new(endpoint));
+/// let info = client.get("http://localhost/info".parse().unwrap());
+/// ```
+#[derive(Clone, Debug)]
+pub struct EndpointConnector {
+    endpoint: Arc<Endpoint>,
+    preflight: bool,
+}
+
+impl EndpointConnector {
+    /// Connect to `endpoint`.
+    pub fn new(endpoint: Endpoint) -> Self {
+        EndpointConnector {
+            endpoint: Arc::new(endpoint),
+            preflight: false,
+        }
+    }
+
+    /// Set whether to check the endpoint with `check_socket` before each connection, if it
+    /// is a path.
+    ///
+    /// See `UnixConnector#preflight`.
+    pub fn preflight(&mut self, check: bool) {
+        self.preflight = check;
+    }
 
-            *self = next_state;
+    /// Return the endpoint connected to.
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+}
+
+impl Service<HyperUri> for EndpointConnector {
+    type Response = UnixStream;
+    type Error = Error;
+    type Future = ConnectFuture;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _: HyperUri) -> Self::Future {
+        let endpoint = self.endpoint.clone();
+        let preflight = self.preflight;
+        ConnectFuture::new(async move {
+            if let (true, Endpoint::Path(ref path)) = (preflight, &*endpoint) {
+                check_socket(path)?;
+            }
+            endpoint
+                .connect_async()
+                .await
+                .map(|stream| UnixStream::new(stream, Some((*endpoint).clone())))
+                .map_err(|err| Error::connect(&endpoint, err))
+        })
+    }
+}
+
+/// Check that a unix domain socket exists at `path`, so that connecting to it can only fail
+/// for lack of a server listening there.
+///
+/// Fails with `Error::SocketNotFound` if nothing exists at `path`, with `Error::NotASocket`
+/// if something other than a socket does, and with an `Error::Connect` if `path` couldn't be
+/// looked up at all, say for lack of permission to search one of its directories.
+///
+/// # Examples
+///
+/// ```rust
+/// use hyperlocal::{client::check_socket, Error};
+///
+/// match check_socket("/etc/passwd") {
+///     Err(Error::NotASocket { path }) => println!("{} is not a socket", path.display()),
+///     _ => panic!("expected a regular file"),
+/// }
+/// ```
+pub fn check_socket<P>(path: P) -> Result<(), Error>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    match fs::metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => Ok(()),
+        Ok(_) => Err(Error::NotASocket {
+            path: path.to_owned(),
+        }),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Err(Error::SocketNotFound {
+            path: path.to_owned(),
+        }),
+        Err(err) => Err(Error::connect(path.display(), err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connectors_reject_non_unix_uris() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let mut connector = UnixConnector::new();
+        match runtime.block_on(connector.call("http://localhost/".parse().unwrap())) {
+            Err(Error::Uri(uri)) => assert_eq!(uri, "http://localhost/"),
+            _ => panic!("expected an invalid uri"),
+        }
+        let https: HyperUri = Uri::https("foo.sock", "/").into();
+        match runtime.block_on(connector.call(https)) {
+            Err(Error::Connect { source, .. }) => {
+                assert_eq!(source.kind(), io::ErrorKind::InvalidInput)
+            }
+            _ => panic!("expected https+unix uris to be refused"),
+        }
+    }
+
+    #[test]
+    fn connections_carry_server_info() {
+        let socket = crate::testing::TempSocket::new().unwrap();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let stream = runtime.block_on(async {
+            let _listener = tokio::net::UnixListener::bind(socket.path()).unwrap();
+            UnixConnector::new()
+                .call(Uri::new(socket.path(), "/").into())
+                .await
+                .unwrap()
+        });
+        let info = stream.server_info();
+        assert_eq!(info.uid(), Some(unsafe { libc::getuid() }));
+        assert_eq!(
+            info.endpoint(),
+            Some(&Endpoint::Path(socket.path().to_owned()))
+        );
+    }
+
+    #[test]
+    fn endpoint_connectors_connect_without_blocking() {
+        let socket = crate::testing::TempSocket::new().unwrap();
+        let _listener = std::os::unix::net::UnixListener::bind(socket.path()).unwrap();
+        let name = format!("hyperlocal-connector-test-{}", std::process::id()).into_bytes();
+        let _abstract_listener = Endpoint::Abstract(name.clone()).listen().unwrap();
+        // a single thread, which a blocking connect would stall
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        for endpoint in [
+            Endpoint::Path(socket.path().to_owned()),
+            Endpoint::Abstract(name),
+        ] {
+            let mut connector = EndpointConnector::new(endpoint.clone());
+            let stream = runtime
+                .block_on(connector.call("http://localhost/".parse().unwrap()))
+                .unwrap();
+            assert_eq!(stream.server_info().endpoint(), Some(&endpoint));
+        }
+    }
+
+    #[test]
+    fn streams_write_vectored() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let (client, mut server) = TokioUnixStream::pair().unwrap();
+            let mut client = UnixStream::new(client, None);
+            assert!(client.is_write_vectored());
+            let bufs = [IoSlice::new(b"head "), IoSlice::new(b"body")];
+            let written = client.write_vectored(&bufs).await.unwrap();
+            assert_eq!(written, 9);
+            let mut received = [0; 9];
+            server.read_exact(&mut received).await.unwrap();
+            assert_eq!(&received, b"head body");
+        });
+    }
+
+    #[test]
+    fn preflight_reports_missing_paths_and_other_files() {
+        let socket = crate::testing::TempSocket::new().unwrap();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let mut connector = UnixConnector::new();
+        connector.preflight(true);
+        let connect = |connector: &mut UnixConnector| {
+            runtime.block_on(connector.call(Uri::new(socket.path(), "/").into()))
+        };
+        match connect(&mut connector) {
+            Err(Error::SocketNotFound { path }) => assert_eq!(path, socket.path()),
+            _ => panic!("expected the socket to be missing"),
+        }
+        fs::write(socket.path(), b"").unwrap();
+        match connect(&mut connector) {
+            Err(Error::NotASocket { path }) => assert_eq!(path, socket.path()),
+            _ => panic!("expected a regular file"),
         }
+        fs::remove_file(socket.path()).unwrap();
+        let _listener = std::os::unix::net::UnixListener::bind(socket.path()).unwrap();
+        assert!(check_socket(socket.path()).is_ok());
     }
 }
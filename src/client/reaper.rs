@@ -0,0 +1,298 @@
+//! Closing pooled connections nobody is using
+//!
+//! hyper keeps connections to a server in its pool after their responses have been read, so
+//! that later requests can reuse them. A connection to a daemon which has since been restarted
+//! keeps the old instance's socket open, and with it, for a daemon draining its connections
+//! before exiting, the old instance itself. A `Reaper` registered with
+//! `UnixConnector#reap_idle` closes connections which have sent and received nothing for its
+//! `max_idle`, so hyper drops them from its pool, and reports each one to its `on_reap` hook.
+//!
+//! Idleness is judged from a connection's traffic, so `max_idle` must be longer than any
+//! server takes to start answering a request, lest a slow response be cut off.
+
+// Std lib
+use std::cmp;
+use std::fmt;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::{Duration, Instant};
+
+// Third party
+use tokio::time;
+
+use crate::endpoint::Endpoint;
+
+/// The shortest time between two sweeps of a `Reaper`'s connections.
+const MIN_SWEEP: Duration = Duration::from_millis(10);
+
+/// A connection closed by a `Reaper`.
+#[derive(Clone, Debug)]
+pub struct Reaped {
+    endpoint: Option<Endpoint>,
+    age: Duration,
+    idle: Duration,
+}
+
+impl Reaped {
+    /// Return the endpoint the connection was made to, if known.
+    pub fn endpoint(&self) -> Option<&Endpoint> {
+        self.endpoint.as_ref()
+    }
+
+    /// Return how long the connection had been open.
+    pub fn age(&self) -> Duration {
+        self.age
+    }
+
+    /// Return how long the connection had been idle.
+    pub fn idle(&self) -> Duration {
+        self.idle
+    }
+}
+
+type OnReap = Arc<dyn Fn(&Reaped) + Send + Sync>;
+
+/// A background task closing connections idle for longer than a given time.
+///
+/// The task is spawned onto the current tokio runtime when the first connection is
+/// registered with it, and runs until the `Reaper`, its clones and the connectors it was
+/// registered with have all been dropped.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use hyperlocal::client::reaper::Reaper;
+/// use hyperlocal::UnixConnector;
+///
+/// let reaper = Reaper::new(Duration::from_secs(30)).on_reap(|reaped| {
+///     eprintln!("closed a connection idle for {:?}", reaped.idle());
+/// });
+/// let mut connector = UnixConnector::new();
+/// connector.reap_idle(&reaper);
+/// let client = hyper::Client::builder().build::<_, hyper::Body>(connector);
+/// ```
+#[derive(Clone)]
+pub struct Reaper {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    max_idle: Duration,
+    on_reap: Option<OnReap>,
+    epoch: Instant,
+    tracked: Mutex<Vec<Arc<Tracked>>>,
+    sweeping: AtomicBool,
+}
+
+impl Reaper {
+    /// Create a reaper closing connections idle for longer than `max_idle`.
+    pub fn new(max_idle: Duration) -> Self {
+        Reaper {
+            shared: Arc::new(Shared {
+                max_idle,
+                on_reap: None,
+                epoch: Instant::now(),
+                tracked: Mutex::new(Vec::new()),
+                sweeping: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// Call `on_reap` with every connection this reaper closes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the reaper has been cloned or registered with a connector.
+    pub fn on_reap<F>(mut self, on_reap: F) -> Self
+    where
+        F: Fn(&Reaped) + Send + Sync + 'static,
+    {
+        Arc::get_mut(&mut self.shared)
+            .expect("on_reap must be set before a reaper is shared")
+            .on_reap = Some(Arc::new(on_reap));
+        self
+    }
+
+    /// Return the time after which idle connections are closed.
+    pub fn max_idle(&self) -> Duration {
+        self.shared.max_idle
+    }
+
+    /// Return the number of open connections registered with this reaper.
+    pub fn tracked(&self) -> usize {
+        let mut tracked = self.shared.tracked();
+        tracked.retain(|tracked| tracked.is_open());
+        tracked.len()
+    }
+
+    /// Register the connection `fd` to `endpoint`, spawning the sweeping task if need be.
+    pub(crate) fn track(&self, fd: RawFd, endpoint: Option<Endpoint>) -> Activity {
+        let now = self.shared.now();
+        let tracked = Arc::new(Tracked {
+            fd: Mutex::new(Some(fd)),
+            endpoint,
+            opened: now,
+            active: AtomicU64::new(now),
+        });
+        self.shared.tracked().push(tracked.clone());
+        if !self.shared.sweeping.swap(true, Ordering::AcqRel) {
+            tokio::spawn(sweep(Arc::downgrade(&self.shared)));
+        }
+        Activity {
+            tracked,
+            shared: Arc::downgrade(&self.shared),
+        }
+    }
+}
+
+impl fmt::Debug for Reaper {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Reaper")
+            .field("max_idle", &self.shared.max_idle)
+            .finish()
+    }
+}
+
+impl Shared {
+    /// Return the time since this reaper was created, in nanoseconds.
+    fn now(&self) -> u64 {
+        self.epoch.elapsed().as_nanos() as u64
+    }
+
+    fn tracked(&self) -> MutexGuard<'_, Vec<Arc<Tracked>>> {
+        self.tracked.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Close every connection idle for longer than `max_idle`, forgetting closed ones.
+    fn reap(&self) {
+        let now = self.now();
+        let max_idle = self.max_idle.as_nanos() as u64;
+        let mut reaped = Vec::new();
+        self.tracked().retain(|tracked| {
+            let mut fd = tracked.fd();
+            let raw = match *fd {
+                Some(raw) => raw,
+                None => return false,
+            };
+            let idle = now.saturating_sub(tracked.active.load(Ordering::Relaxed));
+            if idle <= max_idle {
+                return true;
+            }
+            // the fd is still owned by its stream, so it can't have been reused; shutting it
+            // down makes hyper see the connection close and drop it from the pool
+            unsafe {
+                libc::shutdown(raw, libc::SHUT_RDWR);
+            }
+            *fd = None;
+            reaped.push(Reaped {
+                endpoint: tracked.endpoint.clone(),
+                age: Duration::from_nanos(now.saturating_sub(tracked.opened)),
+                idle: Duration::from_nanos(idle),
+            });
+            false
+        });
+        if let Some(ref on_reap) = self.on_reap {
+            reaped.iter().for_each(|reaped| on_reap(reaped));
+        }
+    }
+}
+
+/// Sweep `shared`'s connections until the reaper is dropped.
+async fn sweep(shared: Weak<Shared>) {
+    let period = match shared.upgrade() {
+        Some(shared) => cmp::max(shared.max_idle / 2, MIN_SWEEP),
+        None => return,
+    };
+    let mut ticks = time::interval(period);
+    loop {
+        ticks.tick().await;
+        match shared.upgrade() {
+            Some(shared) => shared.reap(),
+            None => return,
+        }
+    }
+}
+
+/// A connection registered with a `Reaper`.
+struct Tracked {
+    // cleared once the connection is closed, by the reaper or by its stream being dropped
+    fd: Mutex<Option<RawFd>>,
+    endpoint: Option<Endpoint>,
+    opened: u64,
+    active: AtomicU64,
+}
+
+impl Tracked {
+    fn fd(&self) -> MutexGuard<'_, Option<RawFd>> {
+        self.fd.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn is_open(&self) -> bool {
+        self.fd().is_some()
+    }
+}
+
+/// A stream's handle on its registration with a `Reaper`, recording its traffic.
+///
+/// Dropping the handle unregisters the stream, so it must be dropped before the stream closes.
+pub(crate) struct Activity {
+    tracked: Arc<Tracked>,
+    shared: Weak<Shared>,
+}
+
+impl Activity {
+    /// Record traffic on the connection, resetting its idle time.
+    pub(crate) fn touch(&self) {
+        if let Some(shared) = self.shared.upgrade() {
+            self.tracked.active.store(shared.now(), Ordering::Relaxed);
+        }
+    }
+}
+
+impl fmt::Debug for Activity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Activity").finish()
+    }
+}
+
+impl Drop for Activity {
+    fn drop(&mut self) {
+        *self.tracked.fd() = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::UnixStream;
+
+    #[test]
+    fn idle_connections_are_closed() {
+        let reaped = Arc::new(AtomicUsize::new(0));
+        let counted = reaped.clone();
+        let reaper = Reaper::new(Duration::from_millis(20)).on_reap(move |reaped| {
+            assert!(reaped.idle() >= Duration::from_millis(20));
+            counted.fetch_add(1, Ordering::SeqCst);
+        });
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            use std::os::unix::io::AsRawFd;
+            let (client, mut server) = UnixStream::pair().unwrap();
+            let _activity = reaper.track(client.as_raw_fd(), None);
+            assert_eq!(reaper.tracked(), 1);
+            // the server sees the connection close once the reaper shuts it down
+            let mut buf = [0u8; 1];
+            assert_eq!(server.read(&mut buf).await.unwrap(), 0);
+            while reaped.load(Ordering::SeqCst) == 0 {
+                time::sleep(Duration::from_millis(1)).await;
+            }
+            drop(client);
+        });
+        assert_eq!(reaper.tracked(), 0);
+    }
+}
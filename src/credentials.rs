@@ -0,0 +1,470 @@
+//! Credentials of the process on the other end of a unix domain socket
+
+// Std lib
+use std::error::Error as StdError;
+use std::ffi::CString;
+use std::fmt;
+use std::io;
+use std::ops::Not;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::str::FromStr;
+
+// Third party
+use hyper::Request;
+
+use crate::error::Error;
+use crate::probes;
+
+/// The credentials of a unix domain socket peer, as reported by the operating system.
+///
+/// These reflect the peer's identity when the connection was established, and can't be
+/// forged by the peer process itself.
+///
+/// `PeerCred`s display as, and parse from, `uid=1000 gid=1000 pid=4242`, leaving out the
+/// pid when it isn't known:
+///
+/// ```rust
+/// use hyperlocal::PeerCred;
+///
+/// let cred: PeerCred = "uid=0 gid=0 pid=1".parse().unwrap();
+/// assert_eq!(cred, PeerCred::new(0, 0, Some(1)));
+/// assert_eq!(PeerCred::new(1000, 100, None).to_string(), "uid=1000 gid=100");
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PeerCred {
+    uid: u32,
+    gid: u32,
+    pid: Option<i32>,
+}
+
+impl PeerCred {
+    /// Creates a new `PeerCred` from its parts.
+    pub fn new(uid: u32, gid: u32, pid: Option<i32>) -> Self {
+        PeerCred { uid, gid, pid }
+    }
+
+    /// Query the credentials of the peer connected to `socket`.
+    pub fn of<S>(socket: &S) -> Result<Self, Error>
+    where
+        S: AsRawFd,
+    {
+        peer_cred(socket.as_raw_fd()).map_err(Error::Credentials)
+    }
+
+    /// Return the effective user id of the peer process.
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    /// Return the effective group id of the peer process.
+    pub fn gid(&self) -> u32 {
+        self.gid
+    }
+
+    /// Return the process id of the peer process, on platforms reporting it.
+    pub fn pid(&self) -> Option<i32> {
+        self.pid
+    }
+}
+
+impl fmt::Display for PeerCred {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "uid={} gid={}", self.uid, self.gid)?;
+        if let Some(pid) = self.pid {
+            write!(f, " pid={}", pid)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for PeerCred {
+    type Err = ParseCredError;
+
+    /// Parse whitespace separated `uid=`, `gid=` and optional `pid=` fields, in any order.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason| ParseCredError {
+            input: s.to_owned(),
+            reason,
+        };
+        let (mut uid, mut gid, mut pid) = (None, None, None);
+        for field in s.split_whitespace() {
+            let mut parts = field.splitn(2, '=');
+            let (key, value) = match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) => (key, value),
+                _ => return Err(invalid("expected key=value fields")),
+            };
+            let duplicate = match key {
+                "uid" => uid
+                    .replace(value.parse().map_err(|_| invalid("invalid uid"))?)
+                    .is_some(),
+                "gid" => gid
+                    .replace(value.parse().map_err(|_| invalid("invalid gid"))?)
+                    .is_some(),
+                "pid" => pid
+                    .replace(value.parse().map_err(|_| invalid("invalid pid"))?)
+                    .is_some(),
+                _ => return Err(invalid("unknown field, expected uid, gid or pid")),
+            };
+            if duplicate {
+                return Err(invalid("repeated field"));
+            }
+        }
+        match (uid, gid) {
+            (Some(uid), Some(gid)) => Ok(PeerCred::new(uid, gid, pid)),
+            _ => Err(invalid("missing uid or gid")),
+        }
+    }
+}
+
+/// The error returned when parsing a `PeerCred` fails.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseCredError {
+    input: String,
+    reason: &'static str,
+}
+
+impl fmt::Display for ParseCredError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid credentials {:?}: {}", self.input, self.reason)
+    }
+}
+
+impl StdError for ParseCredError {}
+
+/// A rule over peer credentials, built from simple matchers combined with `and`, `or` and
+/// `!`.
+///
+/// Policies can be checked directly against a request, say in a handler, or required of a
+/// route with `server::guard::Require::Policy`. Peers whose credentials couldn't be queried
+/// match no matcher but `anyone` and `unavailable`, negated ones included, so that a policy
+/// only admits them if `unavailable` is explicitly or-ed in.
+///
+/// # Examples
+///
+/// ```rust
+/// use hyperlocal::credentials::CredPolicy;
+/// use hyperlocal::PeerCred;
+///
+/// let policy = CredPolicy::uid(0).or(CredPolicy::gid_named("docker"));
+/// assert!(policy.allows(Some(&PeerCred::new(0, 0, None))));
+/// assert!(!policy.allows(None));
+///
+/// let not_root = !CredPolicy::uid(0);
+/// assert!(not_root.allows(Some(&PeerCred::new(1000, 1000, Some(42)))));
+/// assert!(!not_root.allows(None));
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CredPolicy {
+    rule: Rule,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Rule {
+    Anyone,
+    Uid(u32),
+    Gid(u32),
+    Pid(i32),
+    SameUid,
+    Unavailable,
+    /// A user or group name which didn't resolve, matching no one.
+    Unknown(String),
+    Not(Box<Rule>),
+    And(Box<Rule>, Box<Rule>),
+    Or(Box<Rule>, Box<Rule>),
+}
+
+impl CredPolicy {
+    /// Match every peer, even those whose credentials couldn't be queried.
+    pub fn anyone() -> Self {
+        CredPolicy { rule: Rule::Anyone }
+    }
+
+    /// Match peers running as the user `uid`.
+    pub fn uid(uid: u32) -> Self {
+        CredPolicy {
+            rule: Rule::Uid(uid),
+        }
+    }
+
+    /// Match peers running as the user named `name`.
+    ///
+    /// The name is looked up once, now. If no such user exists the policy matches no one.
+    pub fn uid_named(name: &str) -> Self {
+        match lookup(name, Lookup::User) {
+            Some(uid) => CredPolicy::uid(uid),
+            None => CredPolicy {
+                rule: Rule::Unknown(format!("user {}", name)),
+            },
+        }
+    }
+
+    /// Match peers whose primary group is `gid`. Supplementary groups aren't considered, as
+    /// peer credentials don't carry them.
+    pub fn gid(gid: u32) -> Self {
+        CredPolicy {
+            rule: Rule::Gid(gid),
+        }
+    }
+
+    /// Match peers whose primary group is the group named `name`.
+    ///
+    /// The name is looked up once, now. If no such group exists the policy matches no one.
+    pub fn gid_named(name: &str) -> Self {
+        match lookup(name, Lookup::Group) {
+            Some(gid) => CredPolicy::gid(gid),
+            None => CredPolicy {
+                rule: Rule::Unknown(format!("group {}", name)),
+            },
+        }
+    }
+
+    /// Match the peer process `pid`. Peers on platforms not reporting pids never match.
+    pub fn pid(pid: i32) -> Self {
+        CredPolicy {
+            rule: Rule::Pid(pid),
+        }
+    }
+
+    /// Match peers running as the same user as this process.
+    pub fn same_uid() -> Self {
+        CredPolicy {
+            rule: Rule::SameUid,
+        }
+    }
+
+    /// Match peers whose credentials couldn't be queried.
+    ///
+    /// Querying fails on platforms or kernels not supporting it, and for sockets it isn't
+    /// supported on. Combined with `or`, this lets a policy decide whether such peers are let
+    /// in, rather than them being refused by every matcher:
+    ///
+    /// ```rust
+    /// use hyperlocal::credentials::CredPolicy;
+    ///
+    /// let policy = CredPolicy::same_uid().or(CredPolicy::unavailable());
+    /// assert!(policy.allows(None));
+    /// ```
+    pub fn unavailable() -> Self {
+        CredPolicy {
+            rule: Rule::Unavailable,
+        }
+    }
+
+    /// Match peers matching both this policy and `other`.
+    pub fn and(self, other: CredPolicy) -> Self {
+        CredPolicy {
+            rule: Rule::And(Box::new(self.rule), Box::new(other.rule)),
+        }
+    }
+
+    /// Match peers matching either this policy or `other`.
+    pub fn or(self, other: CredPolicy) -> Self {
+        CredPolicy {
+            rule: Rule::Or(Box::new(self.rule), Box::new(other.rule)),
+        }
+    }
+
+    /// Return true if a peer with credentials `peer_cred` matches this policy.
+    pub fn allows(&self, peer_cred: Option<&PeerCred>) -> bool {
+        let allowed = self.rule.allows(peer_cred);
+        probes::cred_check(peer_cred, allowed);
+        #[cfg(feature = "log")]
+        crate::logs::cred_checked(peer_cred, allowed);
+        allowed
+    }
+
+    /// Return true if the peer which sent `req` matches this policy, going by the
+    /// `PeerCred` servers insert into every request's extensions.
+    pub fn allows_request<B>(&self, req: &Request<B>) -> bool {
+        self.allows(req.extensions().get::<PeerCred>())
+    }
+}
+
+impl Not for CredPolicy {
+    type Output = CredPolicy;
+
+    /// Match peers not matching this policy.
+    ///
+    /// Peers whose credentials couldn't be queried still don't match; or `unavailable` into
+    /// the result to admit them.
+    fn not(self) -> Self::Output {
+        CredPolicy {
+            rule: Rule::Not(Box::new(self.rule)),
+        }
+    }
+}
+
+impl Rule {
+    fn allows(&self, peer_cred: Option<&PeerCred>) -> bool {
+        match (self, peer_cred) {
+            (Rule::Anyone, _) => true,
+            // a peer whose credentials are unknown can't be shown not to match either
+            (Rule::Not(rule), Some(_)) => !rule.allows(peer_cred),
+            (Rule::And(a, b), _) => a.allows(peer_cred) && b.allows(peer_cred),
+            (Rule::Or(a, b), _) => a.allows(peer_cred) || b.allows(peer_cred),
+            (Rule::Unavailable, peer_cred) => peer_cred.is_none(),
+            (_, None) | (Rule::Unknown(_), _) => false,
+            (&Rule::Uid(uid), Some(peer_cred)) => peer_cred.uid() == uid,
+            (&Rule::Gid(gid), Some(peer_cred)) => peer_cred.gid() == gid,
+            (&Rule::Pid(pid), Some(peer_cred)) => peer_cred.pid() == Some(pid),
+            (Rule::SameUid, Some(peer_cred)) => peer_cred.uid() == unsafe { libc::geteuid() },
+        }
+    }
+}
+
+enum Lookup {
+    User,
+    Group,
+}
+
+/// Resolve the user or group `name` to its id, from the system's user database.
+fn lookup(name: &str, kind: Lookup) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    let mut buf = vec![0 as libc::c_char; 1024];
+    loop {
+        let (ret, id) = unsafe {
+            match kind {
+                Lookup::User => {
+                    let mut passwd: libc::passwd = std::mem::zeroed();
+                    let mut result = std::ptr::null_mut();
+                    let ret = libc::getpwnam_r(
+                        name.as_ptr(),
+                        &mut passwd,
+                        buf.as_mut_ptr(),
+                        buf.len(),
+                        &mut result,
+                    );
+                    (
+                        ret,
+                        if result.is_null() {
+                            None
+                        } else {
+                            Some(passwd.pw_uid)
+                        },
+                    )
+                }
+                Lookup::Group => {
+                    let mut group: libc::group = std::mem::zeroed();
+                    let mut result = std::ptr::null_mut();
+                    let ret = libc::getgrnam_r(
+                        name.as_ptr(),
+                        &mut group,
+                        buf.as_mut_ptr(),
+                        buf.len(),
+                        &mut result,
+                    );
+                    (
+                        ret,
+                        if result.is_null() {
+                            None
+                        } else {
+                            Some(group.gr_gid)
+                        },
+                    )
+                }
+            }
+        };
+        // entries with many members may not fit, so retry with more room
+        if ret == libc::ERANGE && buf.len() < 1 << 20 {
+            let len = buf.len() * 2;
+            buf.resize(len, 0);
+            continue;
+        }
+        return id;
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_cred(fd: RawFd) -> io::Result<PeerCred> {
+    use std::mem;
+
+    let mut ucred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut ucred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(PeerCred::new(ucred.uid, ucred.gid, Some(ucred.pid)))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn peer_cred(fd: RawFd) -> io::Result<PeerCred> {
+    let mut uid = 0;
+    let mut gid = 0;
+    if unsafe { libc::getpeereid(fd, &mut uid, &mut gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(PeerCred::new(uid, gid, None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn peer_creds_describe_the_current_process() {
+        let (a, _b) = UnixStream::pair().unwrap();
+        let cred = PeerCred::of(&a).unwrap();
+        assert_eq!(cred.uid(), unsafe { libc::geteuid() });
+        assert_eq!(cred.gid(), unsafe { libc::getegid() });
+        if cfg!(target_os = "linux") {
+            assert_eq!(cred.pid(), Some(unsafe { libc::getpid() }));
+        }
+    }
+
+    #[test]
+    fn peer_creds_parse_and_display() {
+        for cred in &[PeerCred::new(0, 0, Some(1)), PeerCred::new(1000, 100, None)] {
+            assert_eq!(cred.to_string().parse::<PeerCred>().unwrap(), *cred);
+        }
+        assert_eq!(
+            "pid=7  gid=2 uid=1".parse::<PeerCred>().unwrap(),
+            PeerCred::new(1, 2, Some(7))
+        );
+        for invalid in &[
+            "",
+            "uid=0",
+            "uid=0 gid=x",
+            "uid=0 gid=0 uid=1",
+            "uid=0 gid=0 euid=0",
+        ] {
+            assert!(invalid.parse::<PeerCred>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn cred_policies_combine_matchers() {
+        let root = PeerCred::new(0, 0, Some(1));
+        let user = PeerCred::new(1000, 27, None);
+        let policy = CredPolicy::uid(0).or(CredPolicy::gid(27).and(!CredPolicy::pid(7)));
+        assert!(policy.allows(Some(&root)));
+        assert!(policy.allows(Some(&user)));
+        assert!(!policy.allows(Some(&PeerCred::new(1000, 27, Some(7)))));
+        assert!(!policy.allows(None));
+        assert!(!(!CredPolicy::uid(0)).allows(None));
+        assert!(!(!CredPolicy::unavailable()).allows(None));
+        assert!((!CredPolicy::uid(0))
+            .or(CredPolicy::unavailable())
+            .allows(None));
+        assert!(CredPolicy::unavailable().allows(None));
+        assert!(!CredPolicy::unavailable().allows(Some(&root)));
+
+        assert_eq!(CredPolicy::uid_named("root"), CredPolicy::uid(0));
+        let nobody = CredPolicy::gid_named("hyperlocal-no-such-group");
+        assert!(!nobody.allows(Some(&root)));
+    }
+}
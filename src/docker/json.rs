@@ -0,0 +1,315 @@
+//! Just enough JSON to read the Docker Engine API's responses
+
+// Std lib
+use std::fmt;
+
+/// A parsed JSON value.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Return the member `key` of an object, if this is one and it has that member.
+    pub(crate) fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(members) => members
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Value::Number(n) if n >= 0.0 && n.fract() == 0.0 => Some(n as u64),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn members(&self) -> &[(String, Value)] {
+        match self {
+            Value::Object(members) => members,
+            _ => &[],
+        }
+    }
+
+    /// Return the string member `key`, or an empty string if there is none.
+    pub(crate) fn string(&self, key: &str) -> String {
+        self.get(key)
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_owned()
+    }
+}
+
+/// The error returned for malformed JSON, with the byte offset it was found at.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ParseError {
+    offset: usize,
+    reason: &'static str,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid json at byte {}: {}", self.offset, self.reason)
+    }
+}
+
+/// Parse `input` as a single JSON value.
+pub(crate) fn parse(input: &str) -> Result<Value, ParseError> {
+    let mut parser = Parser {
+        input: input.as_bytes(),
+        offset: 0,
+    };
+    let value = parser.value(0)?;
+    parser.whitespace();
+    if parser.offset != parser.input.len() {
+        return Err(parser.error("trailing characters"));
+    }
+    Ok(value)
+}
+
+/// How deeply arrays and objects may nest, so that hostile input can't exhaust the stack.
+const MAX_DEPTH: usize = 128;
+
+struct Parser<'a> {
+    input: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, reason: &'static str) -> ParseError {
+        ParseError {
+            offset: self.offset,
+            reason,
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.offset).copied()
+    }
+
+    fn whitespace(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') = self.peek() {
+            self.offset += 1;
+        }
+    }
+
+    fn literal(&mut self, literal: &str, value: Value) -> Result<Value, ParseError> {
+        if self.input[self.offset..].starts_with(literal.as_bytes()) {
+            self.offset += literal.len();
+            Ok(value)
+        } else {
+            Err(self.error("unknown literal"))
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, ParseError> {
+        if depth > MAX_DEPTH {
+            return Err(self.error("nested too deeply"));
+        }
+        self.whitespace();
+        match self.peek() {
+            Some(b'n') => self.literal("null", Value::Null),
+            Some(b't') => self.literal("true", Value::Bool(true)),
+            Some(b'f') => self.literal("false", Value::Bool(false)),
+            Some(b'"') => self.string().map(Value::String),
+            Some(b'[') => {
+                self.offset += 1;
+                let mut values = Vec::new();
+                self.whitespace();
+                if self.peek() == Some(b']') {
+                    self.offset += 1;
+                    return Ok(Value::Array(values));
+                }
+                loop {
+                    values.push(self.value(depth + 1)?);
+                    self.whitespace();
+                    match self.peek() {
+                        Some(b',') => self.offset += 1,
+                        Some(b']') => {
+                            self.offset += 1;
+                            return Ok(Value::Array(values));
+                        }
+                        _ => return Err(self.error("expected , or ]")),
+                    }
+                }
+            }
+            Some(b'{') => {
+                self.offset += 1;
+                let mut members = Vec::new();
+                self.whitespace();
+                if self.peek() == Some(b'}') {
+                    self.offset += 1;
+                    return Ok(Value::Object(members));
+                }
+                loop {
+                    self.whitespace();
+                    if self.peek() != Some(b'"') {
+                        return Err(self.error("expected a member name"));
+                    }
+                    let name = self.string()?;
+                    self.whitespace();
+                    if self.peek() != Some(b':') {
+                        return Err(self.error("expected :"));
+                    }
+                    self.offset += 1;
+                    members.push((name, self.value(depth + 1)?));
+                    self.whitespace();
+                    match self.peek() {
+                        Some(b',') => self.offset += 1,
+                        Some(b'}') => {
+                            self.offset += 1;
+                            return Ok(Value::Object(members));
+                        }
+                        _ => return Err(self.error("expected , or }")),
+                    }
+                }
+            }
+            Some(b'-') | Some(b'0'..=b'9') => self.number(),
+            Some(_) => Err(self.error("unexpected character")),
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+
+    fn number(&mut self) -> Result<Value, ParseError> {
+        let start = self.offset;
+        while let Some(b'-') | Some(b'+') | Some(b'.') | Some(b'e') | Some(b'E')
+        | Some(b'0'..=b'9') = self.peek()
+        {
+            self.offset += 1;
+        }
+        // the input is a str and the number ascii, so this slice is on char boundaries
+        let number = std::str::from_utf8(&self.input[start..self.offset]).unwrap_or_default();
+        number.parse().map(Value::Number).map_err(|_| ParseError {
+            offset: start,
+            reason: "invalid number",
+        })
+    }
+
+    fn string(&mut self) -> Result<String, ParseError> {
+        // skip the opening quote
+        self.offset += 1;
+        let mut bytes = Vec::new();
+        loop {
+            match self.peek() {
+                None => return Err(self.error("unterminated string")),
+                Some(b'"') => {
+                    self.offset += 1;
+                    // only whole utf-8 sequences and escapes were copied
+                    return String::from_utf8(bytes).map_err(|_| self.error("invalid utf-8"));
+                }
+                Some(b'\\') => {
+                    self.offset += 1;
+                    let simple = match self.peek() {
+                        Some(b'"') => Some('"'),
+                        Some(b'\\') => Some('\\'),
+                        Some(b'/') => Some('/'),
+                        Some(b'b') => Some('\u{8}'),
+                        Some(b'f') => Some('\u{c}'),
+                        Some(b'n') => Some('\n'),
+                        Some(b'r') => Some('\r'),
+                        Some(b't') => Some('\t'),
+                        Some(b'u') => None,
+                        _ => return Err(self.error("invalid escape")),
+                    };
+                    self.offset += 1;
+                    let escaped = match simple {
+                        Some(escaped) => escaped,
+                        None => self.unicode()?,
+                    };
+                    let mut utf8 = [0; 4];
+                    bytes.extend_from_slice(escaped.encode_utf8(&mut utf8).as_bytes());
+                }
+                Some(byte) if byte < 0x20 => return Err(self.error("control character")),
+                Some(byte) => {
+                    bytes.push(byte);
+                    self.offset += 1;
+                }
+            }
+        }
+    }
+
+    /// Parse the four hex digits of a `\u` escape, and the low surrogate following a high one.
+    fn unicode(&mut self) -> Result<char, ParseError> {
+        let high = self.hex4()?;
+        let code = if (0xd800..0xdc00).contains(&high) {
+            if !self.input[self.offset..].starts_with(b"\\u") {
+                return Err(self.error("unpaired surrogate"));
+            }
+            self.offset += 2;
+            let low = self.hex4()?;
+            if !(0xdc00..0xe000).contains(&low) {
+                return Err(self.error("unpaired surrogate"));
+            }
+            0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+        } else {
+            high
+        };
+        std::char::from_u32(code).ok_or_else(|| self.error("invalid code point"))
+    }
+
+    fn hex4(&mut self) -> Result<u32, ParseError> {
+        let digits = self
+            .input
+            .get(self.offset..self.offset + 4)
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .ok_or_else(|| self.error("invalid \\u escape"))?;
+        self.offset += 4;
+        Ok(digits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_values() {
+        let value = parse(
+            r#" {"Id": "abc", "Names": ["/web"], "Created": 1700000000, "Up": true,
+                "Labels": {}, "Note": "tab\tquote\" \u00e9 \ud83d\ude00", "Gone": null} "#,
+        )
+        .unwrap();
+        assert_eq!(value.string("Id"), "abc");
+        assert_eq!(
+            value.get("Names").and_then(Value::as_array),
+            Some(&[Value::String("/web".into())][..])
+        );
+        assert_eq!(
+            value.get("Created").and_then(Value::as_u64),
+            Some(1_700_000_000)
+        );
+        assert_eq!(value.get("Up"), Some(&Value::Bool(true)));
+        assert_eq!(value.string("Note"), "tab\tquote\" \u{e9} \u{1f600}");
+        assert_eq!(value.get("Gone"), Some(&Value::Null));
+
+        assert!(parse("[1, 2").is_err());
+        assert!(parse("{\"a\" 1}").is_err());
+        assert!(parse("\"\\ud83d\"").is_err());
+        assert!(parse("1 2").is_err());
+        assert!(parse(&"[".repeat(MAX_DEPTH + 2)).is_err());
+    }
+}
@@ -0,0 +1,478 @@
+//! A thin client for the Docker Engine API
+//!
+//! `Docker` covers the handful of calls most programs talking to `docker.sock` make, pinging
+//! the daemon, querying its version, listing containers and following its events, returning
+//! typed values rather than raw JSON. Anything else can be requested through `Docker#get`,
+//! or by building a `hyper::Client` over a `UnixConnector` directly.
+
+// Std lib
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error as StdError;
+use std::fmt;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+// Third party
+use futures::ready;
+use futures::stream::Stream;
+use hyper::body::{Bytes, HttpBody};
+use hyper::{Body, Client, Response, StatusCode};
+
+use self::json::Value;
+use crate::{Authority, UnixConnector};
+
+mod json;
+
+/// The socket the Docker daemon listens on by default.
+pub const DEFAULT_SOCKET: &str = "/var/run/docker.sock";
+
+/// A client for the Docker Engine API listening at a unix domain socket.
+///
+/// # Examples
+///
+/// ```no_run
+/// use hyperlocal::docker::Docker;
+///
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), hyperlocal::docker::Error> {
+/// let docker = Docker::from_env()?;
+/// docker.ping().await?;
+/// for container in docker.containers(false).await? {
+///     println!("{} {}", container.id, container.image);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Docker {
+    client: Client<UnixConnector>,
+    socket: PathBuf,
+    authority: Authority,
+}
+
+impl Docker {
+    /// Create a client for the daemon listening at `socket`.
+    pub fn connect<P>(socket: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        Docker {
+            client: Client::builder().build(UnixConnector::new()),
+            socket: socket.as_ref().to_owned(),
+            authority: Authority::new(socket),
+        }
+    }
+
+    /// Create a client for the daemon `DOCKER_HOST` names, which must be a `unix://` url, or
+    /// for the one listening at `DEFAULT_SOCKET` if it is unset or empty.
+    ///
+    /// Any other `DOCKER_HOST`, such as a `tcp://` or `npipe://` url, is an
+    /// `Error::UnsupportedHost`, rather than quietly talking to a different daemon.
+    pub fn from_env() -> Result<Self, Error> {
+        Docker::from_host(env::var("DOCKER_HOST").ok().as_deref())
+    }
+
+    fn from_host(host: Option<&str>) -> Result<Self, Error> {
+        match host {
+            None | Some("") => Ok(Docker::connect(DEFAULT_SOCKET)),
+            Some(host) => match host.strip_prefix("unix://") {
+                Some(socket) => Ok(Docker::connect(socket)),
+                None => Err(Error::UnsupportedHost(host.to_owned())),
+            },
+        }
+    }
+
+    /// Return the path of the daemon's socket.
+    pub fn socket(&self) -> &Path {
+        &self.socket
+    }
+
+    /// Check the daemon is up, with `GET /_ping`.
+    pub async fn ping(&self) -> Result<(), Error> {
+        let body = self.get("/_ping").await?;
+        if body != "OK" {
+            return Err(Error::Unexpected(
+                "ping answered with something other than OK",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Return the daemon's version, with `GET /version`.
+    pub async fn version(&self) -> Result<Version, Error> {
+        let value = self.get_json("/version").await?;
+        Ok(Version {
+            version: value.string("Version"),
+            api_version: value.string("ApiVersion"),
+            min_api_version: value.string("MinAPIVersion"),
+            os: value.string("Os"),
+            arch: value.string("Arch"),
+            kernel_version: value.string("KernelVersion"),
+        })
+    }
+
+    /// List the running containers, or all of them if `all` is set, with
+    /// `GET /containers/json`.
+    pub async fn containers(&self, all: bool) -> Result<Vec<Container>, Error> {
+        let path = if all {
+            "/containers/json?all=true"
+        } else {
+            "/containers/json"
+        };
+        let value = self.get_json(path).await?;
+        let containers = value
+            .as_array()
+            .ok_or(Error::Unexpected("containers aren't an array"))?;
+        Ok(containers.iter().map(Container::from_json).collect())
+    }
+
+    /// Follow the daemon's events as they happen, with `GET /events`.
+    pub async fn events(&self) -> Result<Events, Error> {
+        let response = self.request("/events").await?;
+        Ok(Events {
+            body: response.into_body(),
+            chunk: Bytes::new(),
+            partial: Vec::new(),
+        })
+    }
+
+    /// Return the body of the response to `GET path`, failing unless it is successful.
+    ///
+    /// `path` should include a leading slash, and may include a query string. A body the
+    /// daemon wrote in a single chunk is returned as is, without being copied.
+    pub async fn get(&self, path: &str) -> Result<Bytes, Error> {
+        let response = self.request(path).await?;
+        Ok(hyper::body::to_bytes(response.into_body()).await?)
+    }
+
+    async fn get_json(&self, path: &str) -> Result<Value, Error> {
+        let body = self.get(path).await?;
+        parse(&body)
+    }
+
+    async fn request(&self, path: &str) -> Result<Response<Body>, Error> {
+        let response = self.client.get(self.authority.uri(path)).await?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        // the daemon explains errors with a json object's message
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        let message = match parse(&body) {
+            Ok(value) => value.string("message"),
+            Err(_) => String::from_utf8_lossy(&body).into_owned(),
+        };
+        Err(Error::Status { status, message })
+    }
+}
+
+fn parse(body: &[u8]) -> Result<Value, Error> {
+    let body = std::str::from_utf8(body).map_err(|_| Error::Unexpected("body isn't utf-8"))?;
+    json::parse(body).map_err(|err| Error::Json(err.to_string()))
+}
+
+/// The version of a Docker daemon.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Version {
+    /// The version of the daemon, such as `24.0.7`.
+    pub version: String,
+    /// The newest API version the daemon speaks, such as `1.43`.
+    pub api_version: String,
+    /// The oldest API version the daemon speaks.
+    pub min_api_version: String,
+    /// The operating system the daemon runs on, such as `linux`.
+    pub os: String,
+    /// The architecture the daemon runs on, such as `amd64`.
+    pub arch: String,
+    /// The version of the kernel the daemon runs on.
+    pub kernel_version: String,
+}
+
+/// A container, as listed by `Docker#containers`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Container {
+    /// The container's id.
+    pub id: String,
+    /// The container's names, each with a leading slash.
+    pub names: Vec<String>,
+    /// The image the container was created from.
+    pub image: String,
+    /// The container's state, such as `running` or `exited`.
+    pub state: String,
+    /// A description of the container's state, such as `Up 2 hours`.
+    pub status: String,
+    /// When the container was created, in seconds since the unix epoch.
+    pub created: u64,
+    /// The container's labels.
+    pub labels: BTreeMap<String, String>,
+}
+
+impl Container {
+    fn from_json(value: &Value) -> Self {
+        Container {
+            id: value.string("Id"),
+            names: value
+                .get("Names")
+                .and_then(Value::as_array)
+                .unwrap_or_default()
+                .iter()
+                .filter_map(Value::as_str)
+                .map(str::to_owned)
+                .collect(),
+            image: value.string("Image"),
+            state: value.string("State"),
+            status: value.string("Status"),
+            created: value.get("Created").and_then(Value::as_u64).unwrap_or(0),
+            labels: strings(value.get("Labels")),
+        }
+    }
+}
+
+/// An event reported by the daemon, as yielded by `Events`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Event {
+    /// The kind of object the event concerns, such as `container` or `network`.
+    pub kind: String,
+    /// What happened, such as `start` or `die`.
+    pub action: String,
+    /// The id of the object the event concerns.
+    pub actor: String,
+    /// The attributes of the object, such as a container's `name` and `image`.
+    pub attributes: BTreeMap<String, String>,
+    /// When the event happened, in seconds since the unix epoch.
+    pub time: u64,
+}
+
+impl Event {
+    fn from_json(value: &Value) -> Self {
+        let actor = value.get("Actor");
+        Event {
+            kind: value.string("Type"),
+            action: value.string("Action"),
+            actor: actor.map(|actor| actor.string("ID")).unwrap_or_default(),
+            attributes: strings(actor.and_then(|actor| actor.get("Attributes"))),
+            time: value.get("time").and_then(Value::as_u64).unwrap_or(0),
+        }
+    }
+}
+
+/// Return the string members of the object `value`.
+fn strings(value: Option<&Value>) -> BTreeMap<String, String> {
+    value
+        .map(Value::members)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|(name, value)| Some((name.clone(), value.as_str()?.to_owned())))
+        .collect()
+}
+
+/// The stream of events the daemon reports, as returned by `Docker#events`.
+///
+/// The stream ends when the daemon closes the connection.
+pub struct Events {
+    body: Body,
+    // what is left of the last chunk read, from which whole lines are sliced without copying
+    chunk: Bytes,
+    // the start of a line split across chunks
+    partial: Vec<u8>,
+}
+
+impl fmt::Debug for Events {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Events").finish()
+    }
+}
+
+impl Stream for Events {
+    type Item = Result<Event, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            // the daemon writes one json object per line
+            if let Some(end) = this.chunk.iter().position(|&byte| byte == b'\n') {
+                let mut line = this.chunk.split_to(end + 1);
+                if !this.partial.is_empty() {
+                    this.partial.extend_from_slice(&line);
+                    line = Bytes::from(mem::take(&mut this.partial));
+                }
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+                return Poll::Ready(Some(parse(&line).map(|value| Event::from_json(&value))));
+            }
+            this.partial.extend_from_slice(&this.chunk);
+            this.chunk.clear();
+            match ready!(Pin::new(&mut this.body).poll_data(cx)) {
+                Some(Ok(data)) => this.chunk = data,
+                Some(Err(err)) => return Poll::Ready(Some(Err(err.into()))),
+                None if this.partial.iter().all(u8::is_ascii_whitespace) => {
+                    return Poll::Ready(None)
+                }
+                // a final event the daemon didn't end with a newline
+                None => this.chunk = Bytes::from_static(b"\n"),
+            }
+        }
+    }
+}
+
+/// The error returned by a `Docker` call.
+#[derive(Debug)]
+pub enum Error {
+    /// The request couldn't be made, or its response couldn't be read.
+    Http(hyper::Error),
+    /// The daemon answered with an unsuccessful status, and the message it gave.
+    Status {
+        /// The status of the response.
+        status: StatusCode,
+        /// The daemon's explanation.
+        message: String,
+    },
+    /// The daemon's response wasn't valid JSON.
+    Json(String),
+    /// The daemon's response wasn't what the call expected.
+    Unexpected(&'static str),
+    /// `DOCKER_HOST` names a daemon that isn't listening at a unix domain socket.
+    UnsupportedHost(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Http(err) => write!(f, "docker request failed: {}", err),
+            Error::Status { status, message } => {
+                write!(f, "docker answered {}: {}", status, message)
+            }
+            Error::Json(err) => write!(f, "docker response is {}", err),
+            Error::Unexpected(reason) => write!(f, "unexpected docker response: {}", reason),
+            Error::UnsupportedHost(host) => {
+                write!(f, "DOCKER_HOST {} is not a unix:// url", host)
+            }
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::Http(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<hyper::Error> for Error {
+    fn from(err: hyper::Error) -> Self {
+        Error::Http(err)
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::*;
+    use crate::testing::{Mock, MockServer};
+    use futures::StreamExt;
+    use hyper::Method;
+
+    #[test]
+    fn docker_clients_read_typed_responses() {
+        let server = MockServer::start().unwrap();
+        server.register(Mock::new(Method::GET, "/_ping").body("OK"));
+        server.register(
+            Mock::new(Method::GET, "/version").body(r#"{"Version":"24.0.7","ApiVersion":"1.43"}"#),
+        );
+        server.register(Mock::new(Method::GET, "/containers/json").body(
+            r#"[{"Id":"abc","Names":["/web"],"Image":"nginx","State":"running",
+                 "Status":"Up 2 hours","Created":1700000000,"Labels":{"app":"web"}}]"#,
+        ));
+        server.register(Mock::new(Method::GET, "/events").body(
+            "{\"Type\":\"container\",\"Action\":\"start\",\"Actor\":{\"ID\":\"abc\",\
+             \"Attributes\":{\"name\":\"web\"}},\"time\":1700000001}\n\
+             {\"Type\":\"container\",\"Action\":\"die\",\"Actor\":{\"ID\":\"abc\"}}",
+        ));
+        server.register(
+            Mock::new(Method::GET, "/containers/gone/json")
+                .status(StatusCode::NOT_FOUND)
+                .body(r#"{"message":"No such container: gone"}"#),
+        );
+
+        let docker = Docker::connect(server.path());
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            docker.ping().await.unwrap();
+            let version = docker.version().await.unwrap();
+            assert_eq!(version.version, "24.0.7");
+            assert_eq!(version.api_version, "1.43");
+
+            let containers = docker.containers(false).await.unwrap();
+            assert_eq!(containers.len(), 1);
+            assert_eq!(containers[0].names, ["/web"]);
+            assert_eq!(containers[0].created, 1_700_000_000);
+            assert_eq!(containers[0].labels["app"], "web");
+
+            let events: Vec<Event> = docker
+                .events()
+                .await
+                .unwrap()
+                .map(Result::unwrap)
+                .collect()
+                .await;
+            assert_eq!(events.len(), 2);
+            assert_eq!(events[0].action, "start");
+            assert_eq!(events[0].attributes["name"], "web");
+            assert_eq!(events[1].action, "die");
+
+            match docker.get("/containers/gone/json").await.unwrap_err() {
+                Error::Status { status, message } => {
+                    assert_eq!(status, StatusCode::NOT_FOUND);
+                    assert_eq!(message, "No such container: gone");
+                }
+                err => panic!("unexpected error {}", err),
+            }
+        });
+    }
+
+    #[test]
+    fn events_are_read_across_chunks() {
+        let chunks = vec![
+            "{\"Action\":\"start\"}\n{\"Act",
+            "ion\":\"st",
+            "op\"}\n\n{\"Action\":\"die\"}",
+        ];
+        let body = Body::wrap_stream(futures::stream::iter(
+            chunks.into_iter().map(Ok::<_, hyper::Error>),
+        ));
+        let events = Events {
+            body,
+            chunk: Bytes::new(),
+            partial: Vec::new(),
+        };
+        let actions: Vec<String> =
+            futures::executor::block_on(events.map(|event| event.unwrap().action).collect());
+        assert_eq!(actions, ["start", "stop", "die"]);
+    }
+
+    #[test]
+    fn only_unix_hosts_are_accepted() {
+        let socket = |host| Docker::from_host(host).map(|docker| docker.socket().to_owned());
+        assert_eq!(socket(None).unwrap(), Path::new(DEFAULT_SOCKET));
+        assert_eq!(socket(Some("")).unwrap(), Path::new(DEFAULT_SOCKET));
+        assert_eq!(
+            socket(Some("unix:///run/user/1000/docker.sock")).unwrap(),
+            Path::new("/run/user/1000/docker.sock")
+        );
+        for host in &[
+            "tcp://127.0.0.1:2375",
+            "npipe:////./pipe/docker_engine",
+            "unix:/x",
+        ] {
+            match socket(Some(host)) {
+                Err(Error::UnsupportedHost(ref unsupported)) if unsupported == host => (),
+                other => panic!("{} gave {:?}", host, other),
+            }
+        }
+    }
+}
@@ -0,0 +1,403 @@
+//! Addresses servers listen on and clients connect to
+//!
+//! An `Endpoint` names a socket without tying an application to one kind of address:
+//! `Server::bind_endpoint` listens on one and `EndpointConnector` connects to one, so that
+//! where a daemon's socket lives can be left entirely to configuration.
+//!
+//! Endpoints parse from, and display as, the address syntaxes used by Docker, containerd and
+//! CRI tooling:
+//!
+//! ```rust
+//! use hyperlocal::Endpoint;
+//!
+//! let docker: Endpoint = "unix:///var/run/docker.sock".parse().unwrap();
+//! assert_eq!(docker, Endpoint::Path("/var/run/docker.sock".into()));
+//! let pipe: Endpoint = "npipe:////./pipe/docker_engine".parse().unwrap();
+//! assert_eq!(pipe, Endpoint::NamedPipe(r"\\.\pipe\docker_engine".into()));
+//! let vm: Endpoint = "vsock://3:1024".parse().unwrap();
+//! assert_eq!(vm, Endpoint::Vsock { cid: 3, port: 1024 });
+//! ```
+
+// Std lib
+#[cfg(feature = "serde")]
+use std::borrow::Cow;
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener as StdUnixListener, UnixStream as StdUnixStream};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::paths;
+
+/// An address which a server can listen on, or a client can connect to.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    /// A unix domain socket bound to a path on the filesystem.
+    Path(PathBuf),
+    /// A unix domain socket bound to a name in Linux's abstract namespace, which needs no
+    /// file and disappears with the last socket bound to it.
+    Abstract(Vec<u8>),
+    /// A Windows named pipe, such as `\\.\pipe\docker_engine`. Named pipes are not
+    /// supported on unix, so binding or connecting to one always fails.
+    NamedPipe(String),
+    /// A `AF_VSOCK` socket address, for talking to virtual machines. Not supported yet, so
+    /// binding or connecting to one always fails.
+    Vsock {
+        /// The context id of the machine.
+        cid: u32,
+        /// The port on that machine.
+        port: u32,
+    },
+    /// A unix domain socket inherited as an open file descriptor, say from a supervisor.
+    /// Servers may listen on an inherited listener; clients can't connect to one.
+    ///
+    /// Listening duplicates the descriptor rather than taking it over, so the endpoint can
+    /// be listened on more than once, and the descriptor is left open for its owner to close.
+    Fd(RawFd),
+}
+
+impl Endpoint {
+    /// Return a listener for this endpoint, and whether the socket file behind it, if any,
+    /// was created by binding it.
+    #[cfg_attr(not(feature = "server"), allow(dead_code))]
+    pub(crate) fn listen(&self) -> io::Result<(StdUnixListener, bool)> {
+        match *self {
+            Endpoint::Path(ref path) => Ok((StdUnixListener::bind(path)?, true)),
+            Endpoint::Abstract(ref name) => Ok((bind_abstract(name)?, false)),
+            Endpoint::Fd(fd) => Ok((inherited_listener(fd)?, false)),
+            Endpoint::NamedPipe(_) | Endpoint::Vsock { .. } => Err(self.unsupported()),
+        }
+    }
+
+    /// Connect a blocking stream to this endpoint.
+    ///
+    /// Connecting to a unix domain socket completes straight away, or fails, unless the
+    /// listener's backlog is full.
+    #[cfg_attr(not(feature = "client"), allow(dead_code))]
+    pub(crate) fn connect(&self) -> io::Result<StdUnixStream> {
+        match *self {
+            Endpoint::Path(ref path) => {
+                let path = paths::shorten(path)?;
+                StdUnixStream::connect(path.path())
+            }
+            Endpoint::Abstract(ref name) => connect_abstract(name),
+            Endpoint::Fd(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "can't connect to an inherited file descriptor",
+            )),
+            Endpoint::NamedPipe(_) | Endpoint::Vsock { .. } => Err(self.unsupported()),
+        }
+    }
+
+    /// Connect a tokio stream to this endpoint, without blocking the runtime.
+    ///
+    /// Abstract names, which tokio can't connect to, are connected to on the blocking pool.
+    #[cfg(feature = "client")]
+    pub(crate) async fn connect_async(&self) -> io::Result<tokio::net::UnixStream> {
+        let stream = match *self {
+            Endpoint::Path(ref path) => {
+                // the directory a shortened path goes through must stay open while connecting
+                let path = paths::shorten(path)?;
+                return tokio::net::UnixStream::connect(path.path()).await;
+            }
+            Endpoint::Abstract(ref name) => {
+                let name = name.clone();
+                tokio::task::spawn_blocking(move || connect_abstract(&name))
+                    .await
+                    .map_err(io::Error::other)??
+            }
+            Endpoint::Fd(_) | Endpoint::NamedPipe(_) | Endpoint::Vsock { .. } => self.connect()?,
+        };
+        stream.set_nonblocking(true)?;
+        tokio::net::UnixStream::from_std(stream)
+    }
+
+    #[cfg_attr(not(any(feature = "client", feature = "server")), allow(dead_code))]
+    fn unsupported(&self) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{:?} endpoints are not supported on this platform", self),
+        )
+    }
+}
+
+/// Scheme prefixes, as written in endpoint strings.
+const UNIX: &str = "unix://";
+const UNIX_ABSTRACT: &str = "unix-abstract:";
+const NPIPE: &str = "npipe://";
+const VSOCK: &str = "vsock://";
+const FD: &str = "fd://";
+
+impl FromStr for Endpoint {
+    type Err = ParseEndpointError;
+
+    /// Parse `unix:///path`, or a bare absolute path, `unix-abstract:name`,
+    /// `npipe:////./pipe/name`, `vsock://cid:port` or `fd://n`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason| ParseEndpointError::new(s, reason);
+        if s.starts_with('/') {
+            return Ok(Endpoint::Path(s.into()));
+        }
+        if let Some(path) = s.strip_prefix(UNIX) {
+            if path.is_empty() {
+                return Err(invalid("missing socket path"));
+            }
+            return Ok(Endpoint::Path(path.into()));
+        }
+        if let Some(name) = s.strip_prefix(UNIX_ABSTRACT) {
+            if name.is_empty() {
+                return Err(invalid("missing abstract socket name"));
+            }
+            return Ok(Endpoint::Abstract(name.as_bytes().to_vec()));
+        }
+        if let Some(pipe) = s.strip_prefix(NPIPE) {
+            // Docker writes `\\.\pipe\name` with forward slashes, after a `//` authority
+            if !pipe.starts_with("//") || pipe.len() == 2 {
+                return Err(invalid("expected a pipe path like //./pipe/name"));
+            }
+            return Ok(Endpoint::NamedPipe(pipe.replace('/', "\\")));
+        }
+        if let Some(addr) = s.strip_prefix(VSOCK) {
+            let mut parts = addr.splitn(2, ':');
+            let cid = parts.next().and_then(|cid| cid.parse().ok());
+            let port = parts.next().and_then(|port| port.parse().ok());
+            return match (cid, port) {
+                (Some(cid), Some(port)) => Ok(Endpoint::Vsock { cid, port }),
+                _ => Err(invalid("expected a numeric cid:port")),
+            };
+        }
+        if let Some(fd) = s.strip_prefix(FD) {
+            return match fd.parse::<RawFd>() {
+                Ok(fd) if fd >= 0 => Ok(Endpoint::Fd(fd)),
+                _ => Err(invalid("expected a file descriptor number")),
+            };
+        }
+        Err(invalid("unknown endpoint scheme"))
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Endpoint::Path(ref path) => write!(f, "{}{}", UNIX, path.display()),
+            Endpoint::Abstract(ref name) => {
+                write!(f, "{}{}", UNIX_ABSTRACT, String::from_utf8_lossy(name))
+            }
+            Endpoint::NamedPipe(ref pipe) => write!(f, "{}{}", NPIPE, pipe.replace('\\', "/")),
+            Endpoint::Vsock { cid, port } => write!(f, "{}{}:{}", VSOCK, cid, port),
+            Endpoint::Fd(fd) => write!(f, "{}{}", FD, fd),
+        }
+    }
+}
+
+/// The error returned when parsing an `Endpoint` fails.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseEndpointError {
+    input: String,
+    reason: &'static str,
+}
+
+impl ParseEndpointError {
+    pub(crate) fn new(input: &str, reason: &'static str) -> Self {
+        ParseEndpointError {
+            input: input.to_owned(),
+            reason,
+        }
+    }
+}
+
+impl fmt::Display for ParseEndpointError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid endpoint {:?}: {}", self.input, self.reason)
+    }
+}
+
+impl StdError for ParseEndpointError {}
+
+/// Endpoints are serialized as their string form, so they read naturally in configuration
+/// files.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Endpoint {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Endpoint {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = <Cow<str>>::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Return a listener on a close-on-exec duplicate of the listener inherited as `fd`, after
+/// checking it is one.
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+fn inherited_listener(fd: RawFd) -> io::Result<StdUnixListener> {
+    let mut listening: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ACCEPTCONN,
+            &mut listening as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if listening == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("file descriptor {} is not a listening socket", fd),
+        ));
+    }
+    // `fd` isn't ours to close, and may be listened on again
+    let duplicate = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+    if duplicate < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { StdUnixListener::from_raw_fd(duplicate) })
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+fn bind_abstract(name: &[u8]) -> io::Result<StdUnixListener> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::SocketAddr;
+
+    StdUnixListener::bind_addr(&SocketAddr::from_abstract_name(name)?)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[cfg_attr(not(feature = "client"), allow(dead_code))]
+fn connect_abstract(name: &[u8]) -> io::Result<StdUnixStream> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::SocketAddr;
+
+    StdUnixStream::connect_addr(&SocketAddr::from_abstract_name(name)?)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+fn bind_abstract(_: &[u8]) -> io::Result<StdUnixListener> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "abstract sockets are only supported on Linux",
+    ))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+#[cfg_attr(not(feature = "client"), allow(dead_code))]
+fn connect_abstract(_: &[u8]) -> io::Result<StdUnixStream> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "abstract sockets are only supported on Linux",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::IntoRawFd;
+
+    #[test]
+    fn endpoints_listen_and_connect() {
+        let name = format!("hyperlocal-test-{}", std::process::id()).into_bytes();
+        let endpoint = Endpoint::Abstract(name);
+        let (listener, created) = endpoint.listen().unwrap();
+        assert!(!created);
+        endpoint.connect().unwrap();
+        listener.accept().unwrap();
+
+        let fd = listener.into_raw_fd();
+        let inherited = Endpoint::Fd(fd);
+        let (first, _) = inherited.listen().unwrap();
+        let (second, _) = inherited.clone().listen().unwrap();
+        drop(first);
+        drop(second);
+        // the inherited descriptor itself is still open
+        assert!(unsafe { libc::fcntl(fd, libc::F_GETFD) } >= 0);
+        unsafe { libc::close(fd) };
+        assert_eq!(
+            inherited.connect().unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+
+        let vsock = Endpoint::Vsock { cid: 3, port: 1024 };
+        assert_eq!(
+            vsock.listen().unwrap_err().kind(),
+            io::ErrorKind::Unsupported
+        );
+    }
+
+    #[test]
+    fn endpoints_parse_and_display() {
+        let cases = [
+            (
+                "unix:///run/containerd/containerd.sock",
+                Endpoint::Path("/run/containerd/containerd.sock".into()),
+            ),
+            (
+                "unix://relative.sock",
+                Endpoint::Path("relative.sock".into()),
+            ),
+            (
+                "unix-abstract:containerd",
+                Endpoint::Abstract(b"containerd".to_vec()),
+            ),
+            (
+                "npipe:////./pipe/docker_engine",
+                Endpoint::NamedPipe(r"\\.\pipe\docker_engine".into()),
+            ),
+            ("vsock://3:1024", Endpoint::Vsock { cid: 3, port: 1024 }),
+            ("fd://3", Endpoint::Fd(3)),
+        ];
+        for &(input, ref expected) in &cases {
+            let endpoint: Endpoint = input.parse().unwrap();
+            assert_eq!(&endpoint, expected);
+            assert_eq!(endpoint.to_string(), input);
+        }
+        assert_eq!(
+            "/var/run/docker.sock".parse::<Endpoint>().unwrap(),
+            Endpoint::Path("/var/run/docker.sock".into())
+        );
+        for invalid in &[
+            "unix://",
+            "tcp://localhost:2375",
+            "vsock://3",
+            "fd://-1",
+            "npipe://",
+        ] {
+            assert!(invalid.parse::<Endpoint>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn endpoints_deserialize_from_strings() {
+        use serde::de::value::{Error, StrDeserializer};
+        use serde::de::IntoDeserializer;
+        use serde::Deserialize;
+
+        let input: StrDeserializer<Error> = "unix:///run/app.sock".into_deserializer();
+        assert_eq!(
+            Endpoint::deserialize(input).unwrap(),
+            Endpoint::Path("/run/app.sock".into())
+        );
+        let input: StrDeserializer<Error> = "tcp://localhost:80".into_deserializer();
+        assert!(Endpoint::deserialize(input).is_err());
+    }
+}
@@ -0,0 +1,166 @@
+//! Errors binding, connecting to and identifying the peers of unix domain sockets
+
+// Std lib
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// The error returned by hyperlocal's servers, connectors and credential queries, saying
+/// which of them failed and why.
+///
+/// Errors convert into `io::Error`s of the same kind as their source, so functions returning
+/// `io::Result` can still use `?` on them.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[cfg(feature = "server")]
+/// # fn main() {
+/// use std::io;
+///
+/// use hyper::service::service_fn;
+/// use hyperlocal::server::Server;
+/// use hyperlocal::Error;
+///
+/// let result = Server::bind("/no/such/dir/app.sock", || {
+///     service_fn(|req| async move { Ok::<_, hyper::Error>(hyper::Response::new(req.into_body())) })
+/// });
+/// match result {
+///     Err(Error::Bind { ref source, .. }) if source.kind() == io::ErrorKind::NotFound => (),
+///     _ => panic!("expected the directory to be missing"),
+/// }
+/// # }
+/// # #[cfg(not(feature = "server"))]
+/// # fn main() {}
+/// ```
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Binding a listener to `address` failed.
+    Bind {
+        /// The address bound, as a path or endpoint string.
+        address: String,
+        /// The underlying error.
+        source: io::Error,
+    },
+    /// Connecting to the server at `address` failed.
+    Connect {
+        /// The address connected to, as a path or endpoint string.
+        address: String,
+        /// The underlying error.
+        source: io::Error,
+    },
+    /// Nothing exists at `path`, found checking it before connecting.
+    SocketNotFound {
+        /// The path connected to.
+        path: PathBuf,
+    },
+    /// The file at `path` isn't a unix domain socket, found checking it before connecting.
+    NotASocket {
+        /// The path connected to.
+        path: PathBuf,
+    },
+    /// Querying the credentials of a peer failed.
+    Credentials(io::Error),
+    /// A uri didn't name a unix domain socket, as built by `hyperlocal::Uri`.
+    Uri(String),
+}
+
+impl Error {
+    #[cfg_attr(not(feature = "server"), allow(dead_code))]
+    pub(crate) fn bind<A>(address: A, source: io::Error) -> Self
+    where
+        A: fmt::Display,
+    {
+        Error::Bind {
+            address: address.to_string(),
+            source,
+        }
+    }
+
+    pub(crate) fn connect<A>(address: A, source: io::Error) -> Self
+    where
+        A: fmt::Display,
+    {
+        Error::Connect {
+            address: address.to_string(),
+            source,
+        }
+    }
+
+    /// Return the kind of the underlying `io::Error`, `io::ErrorKind::NotFound` for missing
+    /// sockets, or `io::ErrorKind::InvalidInput` for files which aren't sockets and invalid uris.
+    pub fn io_kind(&self) -> io::ErrorKind {
+        match *self {
+            Error::Bind { ref source, .. }
+            | Error::Connect { ref source, .. }
+            | Error::Credentials(ref source) => source.kind(),
+            Error::SocketNotFound { .. } => io::ErrorKind::NotFound,
+            Error::NotASocket { .. } | Error::Uri(_) => io::ErrorKind::InvalidInput,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Bind {
+                ref address,
+                ref source,
+            } => write!(f, "failed to bind {}: {}", address, source),
+            Error::Connect {
+                ref address,
+                ref source,
+            } => write!(f, "failed to connect to {}: {}", address, source),
+            Error::SocketNotFound { ref path } => {
+                write!(f, "no socket to connect to at {}", path.display())
+            }
+            Error::NotASocket { ref path } => {
+                write!(f, "{} is not a unix domain socket", path.display())
+            }
+            Error::Credentials(ref source) => {
+                write!(f, "failed to query peer credentials: {}", source)
+            }
+            Error::Uri(ref uri) => write!(f, "not a unix domain socket uri: {}", uri),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match *self {
+            Error::Bind { ref source, .. }
+            | Error::Connect { ref source, .. }
+            | Error::Credentials(ref source) => Some(source),
+            Error::SocketNotFound { .. } | Error::NotASocket { .. } | Error::Uri(_) => None,
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        io::Error::new(err.io_kind(), err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_keep_their_source_kind() {
+        let err = Error::bind(
+            "app.sock",
+            io::Error::new(io::ErrorKind::AddrInUse, "in use"),
+        );
+        assert_eq!(err.to_string(), "failed to bind app.sock: in use");
+        assert!(err.source().is_some());
+        let io_err: io::Error = err.into();
+        assert_eq!(io_err.kind(), io::ErrorKind::AddrInUse);
+        assert_eq!(
+            io::Error::from(Error::Uri("http://localhost/".into())).kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+}
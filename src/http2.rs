@@ -0,0 +1,146 @@
+//! HTTP/2 flow control over unix domain sockets
+//!
+//! HTTP/2 caps how much data a peer may send before it is acknowledged, per stream and per
+//! connection. The defaults are sized for the internet, where a large window only buys
+//! buffering; over a local socket, bandwidth is plentiful and latency negligible, so the
+//! defaults leave throughput on the table for large bodies. `Windows` sets both sizes, or
+//! turns on hyper's adaptive windows, for clients through `Windows#configure` and for servers
+//! through `server::Http#http2_windows` and `server::Server#http2_windows`.
+
+#[cfg(feature = "client")]
+use hyper::client::Builder;
+
+/// The stream window of `Windows::local()`, in bytes.
+pub const LOCAL_STREAM_WINDOW: u32 = 8 * 1024 * 1024;
+
+/// The connection window of `Windows::local()`, in bytes.
+pub const LOCAL_CONNECTION_WINDOW: u32 = 32 * 1024 * 1024;
+
+/// HTTP/2 flow control window settings.
+///
+/// Sizes left unset keep hyper's defaults. Enabling adaptive windows overrides both sizes.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[cfg(feature = "client")]
+/// # fn main() {
+/// use hyperlocal::http2::Windows;
+///
+/// let mut builder = hyper::Client::builder();
+/// Windows::local().configure(builder.http2_only(true));
+/// let client = builder.build::<_, hyper::Body>(hyperlocal::UnixConnector::new());
+/// # }
+/// # #[cfg(not(feature = "client"))]
+/// # fn main() {}
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Windows {
+    stream: Option<u32>,
+    connection: Option<u32>,
+    adaptive: bool,
+}
+
+impl Windows {
+    /// Create settings which keep hyper's defaults.
+    pub fn new() -> Self {
+        Windows::default()
+    }
+
+    /// Create settings with windows sized for local links: `LOCAL_STREAM_WINDOW` per stream
+    /// and `LOCAL_CONNECTION_WINDOW` per connection.
+    pub fn local() -> Self {
+        Windows::new()
+            .stream(LOCAL_STREAM_WINDOW)
+            .connection(LOCAL_CONNECTION_WINDOW)
+    }
+
+    /// Set the initial window of each stream to `size` bytes.
+    pub fn stream(mut self, size: u32) -> Self {
+        self.stream = Some(size);
+        self
+    }
+
+    /// Set the initial window of each connection to `size` bytes.
+    pub fn connection(mut self, size: u32) -> Self {
+        self.connection = Some(size);
+        self
+    }
+
+    /// Grow windows with the measured bandwidth-delay product of each connection instead.
+    pub fn adaptive(mut self, adaptive: bool) -> Self {
+        self.adaptive = adaptive;
+        self
+    }
+
+    /// Return the initial stream window, if one is set.
+    pub fn stream_size(&self) -> Option<u32> {
+        self.stream
+    }
+
+    /// Return the initial connection window, if one is set.
+    pub fn connection_size(&self) -> Option<u32> {
+        self.connection
+    }
+
+    /// Return true if windows are adaptive.
+    pub fn is_adaptive(&self) -> bool {
+        self.adaptive
+    }
+
+    /// Apply these settings to a hyper client `builder`.
+    #[cfg(feature = "client")]
+    pub fn configure<'a>(&self, builder: &'a mut Builder) -> &'a mut Builder {
+        builder
+            .http2_initial_stream_window_size(self.stream)
+            .http2_initial_connection_window_size(self.connection)
+            .http2_adaptive_window(self.adaptive)
+    }
+
+    #[cfg(feature = "server")]
+    pub(crate) fn configure_server(&self, http: &mut hyper::server::conn::Http) {
+        http.http2_initial_stream_window_size(self.stream)
+            .http2_initial_connection_window_size(self.connection)
+            .http2_adaptive_window(self.adaptive);
+    }
+}
+
+#[cfg(all(test, feature = "client", feature = "server"))]
+mod tests {
+    use super::*;
+    use crate::server::Http;
+    use crate::testing;
+    use crate::PeerCred;
+    use futures::future;
+    use hyper::service::service_fn;
+    use hyper::{Body, Client, Request, Response};
+
+    #[test]
+    fn large_windows_carry_large_bodies() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let (connector, incoming) = testing::pair(PeerCred::new(0, 0, None)).unwrap();
+            let serve =
+                Http::new()
+                    .http2_windows(&Windows::local())
+                    .serve_incoming(incoming, || {
+                        service_fn(|req: Request<Body>| async move {
+                            Ok::<_, hyper::Error>(Response::new(req.into_body()))
+                        })
+                    });
+            tokio::spawn(serve.with_graceful_shutdown(future::pending::<()>()));
+
+            let mut builder = Client::builder();
+            Windows::local().configure(builder.http2_only(true));
+            let client = builder.build::<_, Body>(connector);
+            let sent = vec![7u8; 4 * 1024 * 1024];
+            let req = Request::post("http://localhost/")
+                .body(Body::from(sent.clone()))
+                .unwrap();
+            let response = client.request(req).await.unwrap();
+            assert_eq!(response.version(), hyper::Version::HTTP_2);
+            let received = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert_eq!(received.len(), sent.len());
+        });
+    }
+}
@@ -1,25 +1,54 @@
 //! hyperlocal provides [hyper](http://github.com/hyperium/hyper) client and server bindings
-//! for [unix domain sockets](https://github.com/tokio-rs/tokio-uds)
+//! for [unix domain sockets](https://docs.rs/tokio/1/tokio/net/struct.UnixStream.html)
 //!
 //! See the `hyperlocal::UnixConnector` docs for how to configure hyper clients and the `hyperlocal::server::Http` docs
 //! for how to configure hyper servers
-
-extern crate futures;
-extern crate hex;
-extern crate hyper;
-extern crate tokio;
-extern crate tokio_io;
-extern crate tokio_uds;
+//!
+//! Both are built by default. The `client` and `server` features can be disabled to leave
+//! either out, along with the dependencies only it needs.
 
 use std::borrow::Cow;
-use std::path::Path;
+#[cfg(feature = "url")]
+use std::convert::TryFrom;
+#[cfg(feature = "url")]
+use std::error::Error as StdError;
+#[cfg(feature = "url")]
+use std::fmt;
+use std::path::{Path, PathBuf};
 
 use hex::FromHex;
+use hyper::http::uri::{Authority as HyperAuthority, Scheme};
 use hyper::Uri as HyperUri;
 
+#[cfg(feature = "client")]
 pub mod client;
+pub mod credentials;
+#[cfg(feature = "docker")]
+pub mod docker;
+pub mod endpoint;
+pub mod error;
+pub mod http2;
+#[cfg(feature = "log")]
+mod logs;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod paths;
+pub mod probes;
+#[cfg(feature = "server")]
 pub mod server;
-pub use client::UnixConnector;
+pub mod tap;
+pub mod testing;
+pub mod transport;
+#[cfg(feature = "client")]
+pub use crate::client::{EndpointConnector, UnixConnector};
+pub use crate::credentials::{ParseCredError, PeerCred};
+pub use crate::endpoint::Endpoint;
+pub use crate::error::Error;
+
+/// The scheme of uris for plain HTTP over unix domain sockets.
+const UNIX_SCHEME: &str = "unix";
+/// The scheme of uris for HTTP over TLS over unix domain sockets.
+const TLS_SCHEME: &str = "https+unix";
 
 /// A type which implements `Into` for hyper's  `hyper::Uri` type
 /// targetting unix domain sockets.
@@ -29,9 +58,6 @@ pub use client::UnixConnector;
 /// and for creating requests
 ///
 /// ```no_run
-/// extern crate hyper;
-/// extern crate hyperlocal;
-///
 /// let url: hyper::Uri = hyperlocal::Uri::new(
 ///   "/path/to/socket", "/urlpath?key=value"
 ///  ).into();
@@ -43,9 +69,9 @@ pub struct Uri<'a> {
     encoded: Cow<'a, str>,
 }
 
-impl<'a> Into<HyperUri> for Uri<'a> {
-    fn into(self) -> HyperUri {
-        self.encoded.as_ref().parse().unwrap()
+impl<'a> From<Uri<'a>> for HyperUri {
+    fn from(uri: Uri<'a>) -> Self {
+        uri.encoded.as_ref().parse().unwrap()
     }
 }
 
@@ -56,13 +82,36 @@ impl<'a> Uri<'a> {
     where
         P: AsRef<Path>,
     {
-        let host = hex::encode(socket.as_ref().to_string_lossy().as_bytes());
-        let host_str = format!("unix://{}:0{}", host, path);
         Uri {
-            encoded: Cow::Owned(host_str),
+            encoded: Cow::Owned(Self::encode(socket.as_ref(), path)),
         }
     }
 
+    /// Produces a new `Uri` with the `https+unix` scheme, for servers speaking TLS over their
+    /// domain socket.
+    ///
+    /// Such uris are only connected to by a TLS connector wrapping a `UnixConnector` which
+    /// has been told to allow them, with `UnixConnector#enforce_unix(false)`, and which
+    /// performs the TLS handshake over the connection, just as hyper's `HttpConnector` is
+    /// wrapped to connect to `https` uris.
+    pub fn https<P>(socket: P, path: &'a str) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        Uri {
+            encoded: Cow::Owned(Self::encode_with(TLS_SCHEME, socket.as_ref(), path)),
+        }
+    }
+
+    fn encode(socket: &Path, path: &str) -> String {
+        Self::encode_with(UNIX_SCHEME, socket, path)
+    }
+
+    fn encode_with(scheme: &str, socket: &Path, path: &str) -> String {
+        let host = hex::encode(socket.to_string_lossy().as_bytes());
+        format!("{}://{}:0{}", scheme, host, path)
+    }
+
     // fixme: would like to just use hyper::Result and hyper::error::UriError here
     // but UriError its not exposed for external use
     fn socket_path(uri: &HyperUri) -> Option<String> {
@@ -75,15 +124,270 @@ impl<'a> Uri<'a> {
             })
             .next()
     }
+}
+
+/// Converts `url::Url`s naming a unix domain socket, in one of two forms:
+///
+/// * `unix:///path/to/socket`, for the root path of the server listening on the socket. A
+///   query string is kept. `unix` urls made by `Uri` itself convert back too.
+/// * `http+unix://%2Fpath%2Fto%2Fsocket/request/path?query`, with the percent-encoded socket
+///   path as the host, as used by `requests-unixsocket` and others `https+unix` urls convert
+///   the same way, into uris like those of `Uri::https`.
+///
+/// ```rust
+/// use std::convert::TryFrom;
+///
+/// let url = url::Url::parse("http+unix://%2Fvar%2Frun%2Fdocker.sock/containers/json").unwrap();
+/// let uri: hyper::Uri = hyperlocal::Uri::try_from(&url).unwrap().into();
+/// let expected: hyper::Uri = hyperlocal::Uri::new("/var/run/docker.sock", "/containers/json").into();
+/// assert_eq!(uri, expected);
+/// ```
+#[cfg(feature = "url")]
+impl<'u> TryFrom<&'u url::Url> for Uri<'static> {
+    type Error = UrlError;
+
+    fn try_from(url: &'u url::Url) -> Result<Self, Self::Error> {
+        let invalid = |reason| UrlError {
+            url: url.as_str().to_owned(),
+            reason,
+        };
+        if !url.username().is_empty() || url.password().is_some() {
+            return Err(invalid("unix domain socket urls can't carry credentials"));
+        }
+        let host = url.host_str().unwrap_or("");
+        let (socket, path) = match url.scheme() {
+            "unix" if host.is_empty() => {
+                if url.path().is_empty() || url.path() == "/" {
+                    return Err(invalid("missing socket path"));
+                }
+                (
+                    unescape(url.path()).ok_or_else(|| invalid("invalid socket path"))?,
+                    "/",
+                )
+            }
+            "unix" => {
+                let raw = Vec::from_hex(host)
+                    .map_err(|_| invalid("expected a hex encoded socket path as the host"))?;
+                (
+                    String::from_utf8(raw).map_err(|_| invalid("invalid socket path"))?,
+                    url.path(),
+                )
+            }
+            "http+unix" | "https+unix" => {
+                if host.is_empty() {
+                    return Err(invalid("missing percent-encoded socket path as the host"));
+                }
+                (
+                    unescape(host).ok_or_else(|| invalid("invalid socket path"))?,
+                    url.path(),
+                )
+            }
+            _ => return Err(invalid("expected a unix, http+unix or https+unix url")),
+        };
+        let scheme = if url.scheme() == TLS_SCHEME {
+            TLS_SCHEME
+        } else {
+            UNIX_SCHEME
+        };
+        let path = if path.is_empty() { "/" } else { path };
+        let path = match url.query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path.to_owned(),
+        };
+        Ok(Uri {
+            encoded: Cow::Owned(Uri::encode_with(scheme, Path::new(&socket), &path)),
+        })
+    }
+}
+
+#[cfg(feature = "url")]
+impl TryFrom<url::Url> for Uri<'static> {
+    type Error = UrlError;
+
+    fn try_from(url: url::Url) -> Result<Self, Self::Error> {
+        Uri::try_from(&url)
+    }
+}
+
+/// The error returned when a `url::Url` can't be converted into a `Uri`.
+#[cfg(feature = "url")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UrlError {
+    url: String,
+    reason: &'static str,
+}
+
+#[cfg(feature = "url")]
+impl fmt::Display for UrlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "invalid unix domain socket url {:?}: {}",
+            self.url, self.reason
+        )
+    }
+}
+
+#[cfg(feature = "url")]
+impl StdError for UrlError {}
+
+/// A socket path encoded once into a uri authority, for building many uris to the same
+/// socket without encoding it again.
+///
+/// ```no_run
+/// let docker = hyperlocal::Authority::new("/var/run/docker.sock");
+/// for id in 0..1_000 {
+///     let url: hyper::Uri = docker.uri(&format!("/containers/{}/json", id));
+/// }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Authority {
+    scheme: Scheme,
+    authority: HyperAuthority,
+}
+
+impl Authority {
+    /// Encode the path of the socket at `socket`.
+    pub fn new<P>(socket: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        let uri: HyperUri = Uri::new(socket, "/").into();
+        let parts = uri.into_parts();
+        Authority {
+            scheme: parts.scheme.expect("unix uris have a scheme"),
+            authority: parts.authority.expect("unix uris have an authority"),
+        }
+    }
 
-    fn socket_path_dest(dest: &hyper::client::connect::Destination) -> Option<String> {
-        format!("unix://{}", dest.host())
-            .parse()
-            .ok()
-            .and_then(|uri| Self::socket_path(&uri))
+    /// Return the uri for `path`, which should include a leading slash and may include a
+    /// query string.
+    ///
+    /// Only `path` is parsed; the scheme and authority are shared with every other uri
+    /// built from this `Authority`. Panics if `path` is not a valid path and query.
+    pub fn uri(&self, path: &str) -> HyperUri {
+        HyperUri::builder()
+            .scheme(self.scheme.clone())
+            .authority(self.authority.clone())
+            .path_and_query(path)
+            .build()
+            .expect("invalid uri path")
     }
 }
 
+/// A builder for `Uri`s, escaping each path segment and query pair it is given.
+///
+/// ```no_run
+/// let id = "web 1";
+/// let url: hyper::Uri = hyperlocal::UriBuilder::new("/var/run/docker.sock")
+///     .push("containers")
+///     .push(id)
+///     .query("force", "true")
+///     .into();
+/// ```
+#[derive(Clone, Debug)]
+pub struct UriBuilder {
+    socket: PathBuf,
+    path: String,
+    query: String,
+}
+
+impl UriBuilder {
+    /// Start building a `Uri` for the root path of the server listening at `socket`.
+    pub fn new<P>(socket: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        UriBuilder {
+            socket: socket.as_ref().to_owned(),
+            path: String::new(),
+            query: String::new(),
+        }
+    }
+
+    /// Append `segment` to the path, escaping any `/`, `?`, `%` or other character which
+    /// may not appear in a path segment.
+    pub fn push<S>(mut self, segment: S) -> Self
+    where
+        S: AsRef<str>,
+    {
+        self.path.push('/');
+        escape(&mut self.path, segment.as_ref(), is_pchar);
+        self
+    }
+
+    /// Append the pair `key=value` to the query string, escaping both.
+    pub fn query<K, V>(mut self, key: K, value: V) -> Self
+    where
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        self.query
+            .push(if self.query.is_empty() { '?' } else { '&' });
+        escape(&mut self.query, key.as_ref(), is_unreserved);
+        self.query.push('=');
+        escape(&mut self.query, value.as_ref(), is_unreserved);
+        self
+    }
+
+    /// Build the `Uri`.
+    pub fn build(&self) -> Uri<'static> {
+        let path = if self.path.is_empty() {
+            "/"
+        } else {
+            &self.path
+        };
+        Uri {
+            encoded: Cow::Owned(Uri::encode(
+                &self.socket,
+                &format!("{}{}", path, self.query),
+            )),
+        }
+    }
+}
+
+impl From<UriBuilder> for HyperUri {
+    fn from(builder: UriBuilder) -> Self {
+        builder.build().into()
+    }
+}
+
+fn is_unreserved(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"-._~".contains(&byte)
+}
+
+fn is_pchar(byte: u8) -> bool {
+    is_unreserved(byte) || b"!$&'()*+,;=:@".contains(&byte)
+}
+
+/// Append `value` to `out`, percent-encoding every byte for which `allowed` is false.
+fn escape(out: &mut String, value: &str, allowed: fn(u8) -> bool) {
+    for &byte in value.as_bytes() {
+        if allowed(byte) {
+            out.push(byte as char);
+        } else {
+            out.push_str(&format!("%{:02X}", byte));
+        }
+    }
+}
+
+/// Decode the percent-encoded bytes of `value`, returning `None` if it is malformed or not
+/// utf-8 once decoded.
+#[cfg(feature = "url")]
+fn unescape(value: &str) -> Option<String> {
+    let mut out = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            out.push(Vec::from_hex(hex).ok()?[0]);
+        } else {
+            out.push(byte);
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -118,4 +422,77 @@ mod tests {
             Uri::socket_path(&"unix://google.com".parse().unwrap())
         );
     }
+
+    #[test]
+    fn uri_builders_escape_segments_and_queries() {
+        let uri: HyperUri = UriBuilder::new("foo.sock")
+            .push("containers")
+            .push("web 1/../x?")
+            .query("filters", "{\"a\":1}")
+            .query("force", "true")
+            .into();
+        let expected: HyperUri =
+            "unix://666f6f2e736f636b:0/containers/web%201%2F..%2Fx%3F?filters=%7B%22a%22%3A1%7D&force=true"
+                .parse()
+                .unwrap();
+        assert_eq!(uri, expected);
+
+        let root: HyperUri = UriBuilder::new("foo.sock").into();
+        assert_eq!(
+            root,
+            "unix://666f6f2e736f636b:0/".parse::<HyperUri>().unwrap()
+        );
+    }
+
+    #[cfg(feature = "url")]
+    #[test]
+    fn urls_into_unix_uris() {
+        use std::convert::TryFrom;
+        use url::Url;
+
+        let cases = [
+            ("unix:///var/run/docker.sock", "/"),
+            ("unix:///var/run/docker.sock?all=1", "/?all=1"),
+            (
+                "unix://2f7661722f72756e2f646f636b65722e736f636b:0/info",
+                "/info",
+            ),
+            ("http+unix://%2Fvar%2Frun%2Fdocker.sock", "/"),
+            (
+                "http+unix://%2Fvar%2Frun%2Fdocker.sock/containers/json?all=1",
+                "/containers/json?all=1",
+            ),
+        ];
+        for &(url, path) in &cases {
+            let uri: HyperUri = Uri::try_from(Url::parse(url).unwrap()).unwrap().into();
+            let expected: HyperUri = Uri::new("/var/run/docker.sock", path).into();
+            assert_eq!(uri, expected, "{}", url);
+        }
+        let url = Url::parse("https+unix://%2Fvar%2Frun%2Fdocker.sock/info").unwrap();
+        let uri: HyperUri = Uri::try_from(url).unwrap().into();
+        let expected: HyperUri = Uri::https("/var/run/docker.sock", "/info").into();
+        assert_eq!(uri, expected);
+        for &url in &[
+            "http://localhost/",
+            "unix://",
+            "unix://docker.sock/",
+            "http+unix://user@%2Ftmp%2Fx.sock/",
+        ] {
+            assert!(Uri::try_from(Url::parse(url).unwrap()).is_err(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn https_uris_carry_their_scheme() {
+        let uri: HyperUri = Uri::https("foo.sock", "/").into();
+        assert_eq!(uri.scheme().unwrap().as_str(), "https+unix");
+        assert_eq!(Uri::socket_path(&uri).unwrap(), "foo.sock");
+    }
+
+    #[test]
+    fn authorities_build_unix_uris() {
+        let authority = Authority::new("foo.sock");
+        let expected: HyperUri = Uri::new("foo.sock", "/containers/json?all=1").into();
+        assert_eq!(authority.uri("/containers/json?all=1"), expected);
+    }
 }
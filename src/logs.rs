@@ -0,0 +1,104 @@
+//! Logging connection events through the `log` facade
+//!
+//! With the `log` feature, hyperlocal reports the events the `otel` feature records as spans
+//! to whichever `log` implementation the application installed, for applications not using
+//! tracing. Connections made are logged under the `hyperlocal::client` target, connections
+//! accepted, and panics serving them, under `hyperlocal::server` and credential checks under
+//! `hyperlocal::credentials`, so that each can be filtered on its own.
+
+// Std lib
+use std::fmt;
+
+use crate::credentials::PeerCred;
+
+/// Displays a peer's credentials, or that they couldn't be queried.
+struct Peer<'a>(Option<&'a PeerCred>);
+
+impl<'a> fmt::Display for Peer<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(peer_cred) => peer_cred.fmt(f),
+            None => f.write_str("credentials unavailable"),
+        }
+    }
+}
+
+/// Log a connection made to the socket at `path`, to a peer with `peer_cred`.
+#[cfg(feature = "client")]
+pub(crate) fn connected(path: Option<&str>, peer_cred: Option<&PeerCred>) {
+    log::debug!(
+        target: "hyperlocal::client",
+        "connected to {} ({})",
+        path.unwrap_or("unknown socket"),
+        Peer(peer_cred)
+    );
+}
+
+/// Log a failure to connect to the socket at `path`.
+#[cfg(feature = "client")]
+pub(crate) fn connect_failed(path: Option<&str>, err: &dyn fmt::Display) {
+    log::debug!(
+        target: "hyperlocal::client",
+        "failed to connect to {}: {}",
+        path.unwrap_or("unknown socket"),
+        err
+    );
+}
+
+/// Log a connection accepted on the socket at `path`, from a peer with `peer_cred`.
+#[cfg(feature = "server")]
+pub(crate) fn accepted(path: Option<&std::path::Path>, peer_cred: Option<&PeerCred>) {
+    match path {
+        Some(path) => log::debug!(
+            target: "hyperlocal::server",
+            "accepted a connection on {} ({})",
+            path.display(),
+            Peer(peer_cred)
+        ),
+        None => log::debug!(
+            target: "hyperlocal::server",
+            "accepted a connection ({})",
+            Peer(peer_cred)
+        ),
+    }
+}
+
+/// Log a connection having panicked, for servers without a `Server#on_panic` callback.
+#[cfg(feature = "server")]
+pub(crate) fn panicked(panic: &crate::server::panic::Panic) {
+    log::error!(
+        target: "hyperlocal::server",
+        "connection panicked ({}): {}",
+        Peer(panic.peer_cred()),
+        panic.message()
+    );
+}
+
+/// Log a peer with `peer_cred` having been allowed, or not, by a credential policy.
+pub(crate) fn cred_checked(peer_cred: Option<&PeerCred>, allowed: bool) {
+    if allowed {
+        log::trace!(
+            target: "hyperlocal::credentials",
+            "allowed peer ({})",
+            Peer(peer_cred)
+        );
+    } else {
+        log::info!(
+            target: "hyperlocal::credentials",
+            "refused peer ({})",
+            Peer(peer_cred)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peers_display_their_credentials() {
+        let peer_cred = PeerCred::new(1000, 100, Some(7));
+        assert_eq!(Peer(Some(&peer_cred)).to_string(), "uid=1000 gid=100 pid=7");
+        assert_eq!(Peer(None).to_string(), "credentials unavailable");
+    }
+}
@@ -0,0 +1,500 @@
+//! Distributed tracing across unix domain socket hops
+//!
+//! With the `otel` feature, hyperlocal emits `tracing` spans for the connections its
+//! connectors make and its servers accept, and for the requests sent through `Propagating`
+//! clients and served by `Traced` services. Spans carry OpenTelemetry semantic attributes:
+//! `network.transport`, `server.address` for the socket path, `http.request.method`,
+//! `url.path` and `http.response.status_code`, along with the peer's `unix.peer.pid`,
+//! `unix.peer.uid` and `unix.peer.gid`. Exporting them is left to the application's
+//! subscriber, such as `tracing-opentelemetry`'s layer.
+//!
+//! Trace context crosses each hop in a W3C `traceparent` header. `Propagating` clients send
+//! one for every request, continuing the `TraceContext` found in the request's extensions or
+//! starting a new trace, and `Traced` services continue the one they receive, inserting it
+//! into the request's extensions for handlers to hand on. Every request span records the
+//! `trace_id`, `span_id` and `parent_span_id` it was given, so hops can be stitched together.
+
+// Std lib
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+
+// Third party
+use futures::ready;
+use hyper::header::{HeaderMap, HeaderValue};
+use hyper::service::Service;
+use hyper::{Request, Response};
+use pin_project_lite::pin_project;
+use tracing::field::Empty;
+use tracing::instrument::{Instrument, Instrumented};
+use tracing::Span;
+
+use crate::credentials::PeerCred;
+#[cfg(feature = "server")]
+use crate::server::NewService;
+
+/// The header trace context is propagated in.
+pub const TRACEPARENT: &str = "traceparent";
+
+/// The position of a request in a distributed trace, as carried by a `traceparent` header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TraceContext {
+    trace_id: u128,
+    span_id: u64,
+    sampled: bool,
+}
+
+impl TraceContext {
+    /// Start a new, sampled, trace.
+    pub fn root() -> Self {
+        TraceContext {
+            trace_id: (u128::from(random()) << 64) | u128::from(random()),
+            span_id: random(),
+            sampled: true,
+        }
+    }
+
+    /// Return the context of a new span in this context's trace, whose parent is this span.
+    pub fn child(&self) -> Self {
+        TraceContext {
+            span_id: random(),
+            ..*self
+        }
+    }
+
+    /// Return the id of the trace.
+    pub fn trace_id(&self) -> u128 {
+        self.trace_id
+    }
+
+    /// Return the id of the span.
+    pub fn span_id(&self) -> u64 {
+        self.span_id
+    }
+
+    /// Return true if the trace is being recorded.
+    pub fn is_sampled(&self) -> bool {
+        self.sampled
+    }
+
+    /// Read the context from the `traceparent` header in `headers`, if there is a valid one.
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        headers.get(TRACEPARENT)?.to_str().ok()?.parse().ok()
+    }
+
+    /// Write the context into `headers` as a `traceparent` header.
+    pub fn inject(&self, headers: &mut HeaderMap) {
+        let value = HeaderValue::from_str(&self.to_string()).expect("contexts are valid headers");
+        headers.insert(TRACEPARENT, value);
+    }
+}
+
+impl fmt::Display for TraceContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "00-{:032x}-{:016x}-{:02x}",
+            self.trace_id, self.span_id, self.sampled as u8
+        )
+    }
+}
+
+/// An error parsing a `traceparent` header.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseTraceContextError(String);
+
+impl fmt::Display for ParseTraceContextError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid traceparent {:?}", self.0)
+    }
+}
+
+impl std::error::Error for ParseTraceContextError {}
+
+impl FromStr for TraceContext {
+    type Err = ParseTraceContextError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseTraceContextError(s.to_owned());
+        let hex = |part: &str, len: usize| {
+            if part.len() == len
+                && part
+                    .bytes()
+                    .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+            {
+                u128::from_str_radix(part, 16).ok()
+            } else {
+                None
+            }
+        };
+        let mut parts = s.split('-');
+        let version = parts
+            .next()
+            .and_then(|part| hex(part, 2))
+            .ok_or_else(invalid)?;
+        let trace_id = parts
+            .next()
+            .and_then(|part| hex(part, 32))
+            .ok_or_else(invalid)?;
+        let span_id = parts
+            .next()
+            .and_then(|part| hex(part, 16))
+            .ok_or_else(invalid)?;
+        let flags = parts
+            .next()
+            .and_then(|part| hex(part, 2))
+            .ok_or_else(invalid)?;
+        // later versions may append fields, but version 00 has exactly four
+        let rest = parts.next();
+        if version == 0xff || (version == 0 && rest.is_some()) || trace_id == 0 || span_id == 0 {
+            return Err(invalid());
+        }
+        Ok(TraceContext {
+            trace_id,
+            span_id: span_id as u64,
+            sampled: flags & 1 == 1,
+        })
+    }
+}
+
+/// Return a random, non-zero, id.
+fn random() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    loop {
+        // every RandomState is seeded afresh
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+        if let Ok(now) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+            hasher.write_u128(now.as_nanos());
+        }
+        let id = hasher.finish();
+        if id != 0 {
+            return id;
+        }
+    }
+}
+
+/// Record the credentials of the peer at the other end of a span's connection.
+pub(crate) fn record_peer(span: &Span, peer_cred: Option<&PeerCred>) {
+    if let Some(peer_cred) = peer_cred {
+        span.record("unix.peer.uid", peer_cred.uid());
+        span.record("unix.peer.gid", peer_cred.gid());
+        if let Some(pid) = peer_cred.pid() {
+            span.record("unix.peer.pid", pid);
+        }
+    }
+}
+
+/// Return the span of a connection being made to the socket at `path`.
+#[cfg(feature = "client")]
+pub(crate) fn connect_span(path: Option<&str>) -> Span {
+    tracing::info_span!(
+        "connect",
+        otel.kind = "client",
+        network.transport = "unix",
+        server.address = path.unwrap_or(""),
+        unix.peer.pid = Empty,
+        unix.peer.uid = Empty,
+        unix.peer.gid = Empty,
+    )
+}
+
+/// Record a connection accepted on the socket at `path`.
+#[cfg(feature = "server")]
+pub(crate) fn accepted(path: Option<&std::path::Path>, peer_cred: Option<&PeerCred>) {
+    let span = tracing::info_span!(
+        "accept",
+        otel.kind = "server",
+        network.transport = "unix",
+        server.address = %path.map(|path| path.display().to_string()).unwrap_or_default(),
+        unix.peer.pid = Empty,
+        unix.peer.uid = Empty,
+        unix.peer.gid = Empty,
+    );
+    record_peer(&span, peer_cred);
+}
+
+/// Return the span of a request in `context`, whose parent span is `parent`.
+fn request_span<B>(
+    kind: &'static str,
+    req: &Request<B>,
+    context: &TraceContext,
+    parent: Option<u64>,
+) -> Span {
+    let span = tracing::info_span!(
+        "request",
+        otel.kind = kind,
+        network.transport = "unix",
+        server.address = Empty,
+        http.request.method = %req.method(),
+        url.path = req.uri().path(),
+        http.response.status_code = Empty,
+        unix.peer.pid = Empty,
+        unix.peer.uid = Empty,
+        unix.peer.gid = Empty,
+        trace_id = %format_args!("{:032x}", context.trace_id),
+        span_id = %format_args!("{:016x}", context.span_id),
+        parent_span_id = Empty,
+    );
+    if let Some(parent) = parent {
+        span.record("parent_span_id", format_args!("{:016x}", parent));
+    }
+    span
+}
+
+/// A `NewService` tracing every request served by the services it wraps.
+///
+/// # Examples
+///
+/// ```rust
+/// use hyper::service::service_fn;
+/// use hyperlocal::otel::Traced;
+/// use hyperlocal::server::Server;
+///
+/// # if let Err(err) = std::fs::remove_file("hyperlocal_test_otel_server.sock") {
+/// #   if err.kind() != std::io::ErrorKind::NotFound {
+/// #     panic!("{}", err)
+/// #   }
+/// # }
+/// #
+/// let server = Server::bind(
+///    "hyperlocal_test_otel_server.sock",
+///    Traced::new(
+///        || service_fn(|req| async move { Ok::<_, hyper::Error>(hyper::Response::new(req.into_body())) })
+///    ),
+/// ).unwrap();
+/// ```
+#[cfg(feature = "server")]
+#[derive(Clone, Debug)]
+pub struct Traced<S> {
+    new_service: S,
+}
+
+#[cfg(feature = "server")]
+impl<S> Traced<S> {
+    /// Wraps `new_service`.
+    pub fn new(new_service: S) -> Self {
+        Traced { new_service }
+    }
+}
+
+#[cfg(feature = "server")]
+impl<S> NewService for Traced<S>
+where
+    S: NewService,
+{
+    type ReqBody = S::ReqBody;
+    type ResBody = S::ResBody;
+    type Error = S::Error;
+    type Service = TracedService<S::Service>;
+    type Future = Tracing<S::Future>;
+    type InitError = S::InitError;
+
+    fn new_service(&self) -> Self::Future {
+        self.new_service_for(None)
+    }
+
+    fn new_service_for(&self, peer_cred: Option<&PeerCred>) -> Self::Future {
+        Tracing {
+            future: self.new_service.new_service_for(peer_cred),
+        }
+    }
+}
+
+pin_project! {
+    /// A future resolving to a `TracedService` once the wrapped service has been created.
+    pub struct Tracing<F> {
+        #[pin]
+        future: F,
+    }
+}
+
+impl<F, S, E> Future for Tracing<F>
+where
+    F: Future<Output = Result<S, E>>,
+{
+    type Output = Result<TracedService<S>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let inner = ready!(self.project().future.poll(cx))?;
+        Poll::Ready(Ok(TracedService::new(inner)))
+    }
+}
+
+/// A `Service` tracing each request it serves, continuing the trace its client started.
+#[derive(Clone, Debug)]
+pub struct TracedService<S> {
+    inner: S,
+}
+
+impl<S> TracedService<S> {
+    /// Wraps `inner`.
+    pub fn new(inner: S) -> Self {
+        TracedService { inner }
+    }
+}
+
+impl<S, B, R> Service<Request<B>> for TracedService<S>
+where
+    S: Service<Request<B>, Response = Response<R>>,
+{
+    type Response = Response<R>;
+    type Error = S::Error;
+    type Future = Recording<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<B>) -> Self::Future {
+        let parent = TraceContext::from_headers(req.headers());
+        let context = parent.map_or_else(TraceContext::root, |parent| parent.child());
+        let span = request_span(
+            "server",
+            &req,
+            &context,
+            parent.map(|parent| parent.span_id),
+        );
+        record_peer(&span, req.extensions().get::<PeerCred>());
+        req.extensions_mut().insert(context);
+        Recording {
+            future: self.inner.call(req).instrument(span),
+        }
+    }
+}
+
+/// A client propagating trace context in a `traceparent` header of every request it sends,
+/// tracing each of them.
+///
+/// This wraps any client `Service`, such as a `hyper::Client`.
+#[cfg(feature = "client")]
+#[derive(Clone, Debug)]
+pub struct Propagating<S> {
+    inner: S,
+}
+
+#[cfg(feature = "client")]
+impl<S> Propagating<S> {
+    /// Wraps `inner`.
+    pub fn new(inner: S) -> Self {
+        Propagating { inner }
+    }
+
+    /// Return a reference to the wrapped client.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+#[cfg(feature = "client")]
+impl<S, B, R> Service<Request<B>> for Propagating<S>
+where
+    S: Service<Request<B>, Response = Response<R>>,
+{
+    type Response = Response<R>;
+    type Error = S::Error;
+    type Future = Recording<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<B>) -> Self::Future {
+        let parent = req.extensions().get::<TraceContext>().copied();
+        let context = parent.map_or_else(TraceContext::root, |parent| parent.child());
+        let span = request_span(
+            "client",
+            &req,
+            &context,
+            parent.map(|parent| parent.span_id),
+        );
+        if let Some(path) = crate::Uri::socket_path(req.uri()) {
+            span.record("server.address", path.as_str());
+        }
+        context.inject(req.headers_mut());
+        Recording {
+            future: self.inner.call(req).instrument(span),
+        }
+    }
+}
+
+pin_project! {
+    /// A future recording the status of a request's response in the request's span.
+    pub struct Recording<F> {
+        #[pin]
+        future: Instrumented<F>,
+    }
+}
+
+impl<F, R, E> Future for Recording<F>
+where
+    F: Future<Output = Result<Response<R>, E>>,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = self.project();
+        let response = ready!(this.future.as_mut().poll(cx))?;
+        this.future
+            .span()
+            .record("http.response.status_code", response.status().as_u16());
+        Poll::Ready(Ok(response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trace_contexts_round_trip_through_headers() {
+        let context = TraceContext::root();
+        let mut headers = HeaderMap::new();
+        context.inject(&mut headers);
+        assert_eq!(TraceContext::from_headers(&headers), Some(context));
+
+        let child = context.child();
+        assert_eq!(child.trace_id(), context.trace_id());
+        assert_ne!(child.span_id(), context.span_id());
+
+        let parsed: TraceContext = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00"
+            .parse()
+            .unwrap();
+        assert_eq!(parsed.trace_id(), 0x4bf92f3577b34da6a3ce929d0e0e4736);
+        assert!(!parsed.is_sampled());
+        for invalid in &[
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+        ] {
+            assert!(invalid.parse::<TraceContext>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn traced_services_continue_traces() {
+        use futures::executor::block_on;
+        use hyper::service::service_fn;
+        use hyper::Body;
+
+        let mut service = TracedService::new(service_fn(|req: Request<Body>| async move {
+            let context = req.extensions().get::<TraceContext>().copied().unwrap();
+            Ok::<_, hyper::Error>(Response::new(Body::from(context.to_string())))
+        }));
+        let parent = TraceContext::root();
+        let mut req = Request::new(Body::empty());
+        parent.inject(req.headers_mut());
+        let response = block_on(service.call(req)).unwrap();
+        let body = block_on(hyper::body::to_bytes(response.into_body())).unwrap();
+        let context: TraceContext = std::str::from_utf8(&body).unwrap().parse().unwrap();
+        assert_eq!(context.trace_id(), parent.trace_id());
+        assert_ne!(context.span_id(), parent.span_id());
+    }
+}
@@ -0,0 +1,412 @@
+//! Conventional locations for unix domain socket files
+//!
+//! Daemons running as a regular user shouldn't put their sockets in a system-wide directory,
+//! or in a world-writable one like `/tmp` where another user could claim the path first.
+//! These helpers resolve a socket path the way the [XDG Base Directory
+//! Specification](https://specifications.freedesktop.org/basedir-spec/latest/) prescribes for
+//! runtime files, so that servers and their clients agree on where a socket lives.
+//!
+//! `expand` fills placeholders into a socket path taken from configuration, so that several
+//! instances of a daemon sharing one configuration can each bind a socket of their own.
+//!
+//! Socket paths are limited to the size of `sockaddr_un`'s `sun_path`, a little over 100
+//! bytes, which deeply nested runtime directories can exceed. `UnixConnector` connects to
+//! longer paths on Linux by way of `/proc/self/fd`, failing with a `PathTooLong` error
+//! elsewhere, or when even that is too long.
+
+// Std lib
+use std::env;
+use std::error::Error as StdError;
+use std::ffi::{CString, OsString};
+use std::fmt;
+use std::fs::{self, DirBuilder};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Return the directory for the current user's runtime files.
+///
+/// This is `$XDG_RUNTIME_DIR` when it is set to an absolute path, `/run` for root, and
+/// `/run/user/$UID` when that exists. Otherwise there is no per-user runtime directory, and
+/// `None` is returned.
+pub fn runtime_dir() -> Option<PathBuf> {
+    resolve_runtime_dir(env::var_os("XDG_RUNTIME_DIR"), uid(), |dir| dir.is_dir())
+}
+
+/// Return the conventional path of the socket `name` for the application `app`.
+///
+/// This is `name` in an `app` directory of the user's `runtime_dir`, for instance
+/// `$XDG_RUNTIME_DIR/myapp/api.sock`. Without a runtime directory it falls back to an
+/// `app-$UID` directory in the system's temporary directory, which `create_socket_dir`
+/// refuses to use unless it is private to the current user.
+///
+/// # Examples
+///
+/// ```rust
+/// let path = hyperlocal::paths::socket_path("myapp", "api.sock");
+/// assert!(path.ends_with("api.sock"));
+/// ```
+pub fn socket_path(app: &str, name: &str) -> PathBuf {
+    resolve_socket_path(runtime_dir(), env::temp_dir(), uid(), app, name)
+}
+
+/// Create the directory a socket is to be bound in, and any missing parents, accessible only
+/// by the current user.
+///
+/// Permissions of directories which already exist are left alone. A directory in one
+/// anyone can write to, such as the `app-$UID` directory `socket_path` falls back to in the
+/// system's temporary directory, could have been created by another user to intercept the
+/// socket, so such a directory must be a real directory, owned by the current user and
+/// accessible only by them. Fails with `io::ErrorKind::PermissionDenied` otherwise.
+pub fn create_socket_dir<P>(socket_path: P) -> io::Result<()>
+where
+    P: AsRef<Path>,
+{
+    let dir = match socket_path.as_ref().parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => return Ok(()),
+    };
+    DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    let shared = match dir.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    if fs::metadata(shared)?.mode() & 0o002 == 0 {
+        return Ok(());
+    }
+    let metadata = fs::symlink_metadata(dir)?;
+    if !metadata.is_dir() || metadata.uid() != uid() || metadata.mode() & 0o777 != 0o700 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} must be a directory owned by uid {} with mode 0700",
+                dir.display(),
+                uid()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Expand the placeholders in a socket path template.
+///
+/// The following placeholders are recognized:
+///
+/// * `%pid%`, the id of the current process
+/// * `%uid%`, the id of the current user
+/// * `%instance%`, the provided `instance` name
+/// * `%runtime_dir%`, the current user's `runtime_dir`
+/// * `%%`, a literal `%`
+/// * `${NAME}`, the value of the environment variable `NAME`
+///
+/// Fails with `io::ErrorKind::InvalidInput` on an unknown or malformed placeholder, or one
+/// which has no value, such as `%instance%` without an `instance` or an unset environment
+/// variable.
+///
+/// # Examples
+///
+/// ```rust
+/// use std::path::Path;
+///
+/// let path = hyperlocal::paths::expand("/run/myapp/%instance%.sock", Some("blue")).unwrap();
+/// assert_eq!(path, Path::new("/run/myapp/blue.sock"));
+/// ```
+pub fn expand(template: &str, instance: Option<&str>) -> io::Result<PathBuf> {
+    expand_with(template, |placeholder| match placeholder {
+        Placeholder::Pid => Some(process::id().to_string().into()),
+        Placeholder::Uid => Some(uid().to_string().into()),
+        Placeholder::Instance => instance.map(OsString::from),
+        Placeholder::RuntimeDir => runtime_dir().map(PathBuf::into_os_string),
+        Placeholder::Env(name) => env::var_os(name),
+    })
+}
+
+enum Placeholder<'a> {
+    Pid,
+    Uid,
+    Instance,
+    RuntimeDir,
+    Env(&'a str),
+}
+
+fn expand_with<F>(template: &str, value: F) -> io::Result<PathBuf>
+where
+    F: Fn(Placeholder) -> Option<OsString>,
+{
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+    let unterminated = || invalid(format!("unterminated placeholder in {:?}", template));
+    let mut expanded = OsString::new();
+    let mut rest = template;
+    while let Some(start) = rest.find(['%', '$']) {
+        expanded.push(&rest[..start]);
+        rest = &rest[start..];
+        let (name, placeholder) = if let Some(after) = rest.strip_prefix("%%") {
+            expanded.push("%");
+            rest = after;
+            continue;
+        } else if let Some(after) = rest.strip_prefix('%') {
+            let end = after.find('%').ok_or_else(unterminated)?;
+            let placeholder = match &after[..end] {
+                "pid" => Placeholder::Pid,
+                "uid" => Placeholder::Uid,
+                "instance" => Placeholder::Instance,
+                "runtime_dir" => Placeholder::RuntimeDir,
+                other => return Err(invalid(format!("unknown placeholder %{}%", other))),
+            };
+            (&rest[..end + 2], placeholder)
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after.find('}').ok_or_else(unterminated)?;
+            (&rest[..end + 3], Placeholder::Env(&after[..end]))
+        } else {
+            // a lone `$`
+            expanded.push("$");
+            rest = &rest[1..];
+            continue;
+        };
+        let value = value(placeholder)
+            .ok_or_else(|| invalid(format!("no value for placeholder {}", name)))?;
+        expanded.push(value);
+        rest = &rest[name.len()..];
+    }
+    expanded.push(rest);
+    Ok(PathBuf::from(expanded))
+}
+
+/// Return the length of the longest path a unix domain socket can be bound or connected to
+/// directly.
+pub fn max_socket_path_len() -> usize {
+    let addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    // leaving room for the terminating nul
+    addr.sun_path.len() - 1
+}
+
+/// The error a socket path too long to connect to is reported with, as the inner error of
+/// an `io::Error` of kind `InvalidInput`.
+#[derive(Clone, Debug)]
+pub struct PathTooLong {
+    path: PathBuf,
+}
+
+impl PathTooLong {
+    /// Return the path which was too long.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl fmt::Display for PathTooLong {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "socket path {} is longer than {} bytes",
+            self.path.display(),
+            max_socket_path_len()
+        )
+    }
+}
+
+impl StdError for PathTooLong {}
+
+/// Return the error reporting `path` as too long to bind or connect to.
+pub(crate) fn too_long(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        PathTooLong {
+            path: path.to_owned(),
+        },
+    )
+}
+
+/// A path a socket can be connected to directly, standing in for a longer one.
+#[cfg_attr(not(feature = "client"), allow(dead_code))]
+pub(crate) struct Short {
+    path: PathBuf,
+    // keeps the socket's directory open for as long as the path refers to it
+    _dir: Option<OwnedFd>,
+}
+
+#[cfg_attr(not(feature = "client"), allow(dead_code))]
+impl Short {
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Return a path short enough to connect to the socket at `path` through.
+///
+/// On Linux, a path too long is reached through the socket's directory, opened and referred
+/// to with `/proc/self/fd`.
+#[cfg_attr(not(feature = "client"), allow(dead_code))]
+pub(crate) fn shorten(path: &Path) -> io::Result<Short> {
+    let too_long = || too_long(path);
+    if path.as_os_str().len() <= max_socket_path_len() {
+        return Ok(Short {
+            path: path.to_owned(),
+            _dir: None,
+        });
+    }
+    if !cfg!(any(target_os = "linux", target_os = "android")) {
+        return Err(too_long());
+    }
+    let (dir, name) = match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) => (dir, name),
+        _ => return Err(too_long()),
+    };
+    let dir = open_dir(dir)?;
+    let short = Path::new("/proc/self/fd")
+        .join(dir.as_raw_fd().to_string())
+        .join(name);
+    if short.as_os_str().len() > max_socket_path_len() {
+        return Err(too_long());
+    }
+    Ok(Short {
+        path: short,
+        _dir: Some(dir),
+    })
+}
+
+#[cfg_attr(not(feature = "client"), allow(dead_code))]
+fn open_dir(dir: &Path) -> io::Result<OwnedFd> {
+    let dir = CString::new(dir.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let fd = unsafe {
+        libc::open(
+            dir.as_ptr(),
+            libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn uid() -> u32 {
+    unsafe { libc::getuid() }
+}
+
+fn resolve_runtime_dir<F>(xdg_runtime_dir: Option<OsString>, uid: u32, exists: F) -> Option<PathBuf>
+where
+    F: Fn(&Path) -> bool,
+{
+    // the spec has relative paths be ignored
+    if let Some(dir) = xdg_runtime_dir.map(PathBuf::from) {
+        if dir.is_absolute() {
+            return Some(dir);
+        }
+    }
+    if uid == 0 {
+        return Some(PathBuf::from("/run"));
+    }
+    let dir = PathBuf::from(format!("/run/user/{}", uid));
+    if exists(&dir) {
+        Some(dir)
+    } else {
+        None
+    }
+}
+
+fn resolve_socket_path(
+    runtime_dir: Option<PathBuf>,
+    temp_dir: PathBuf,
+    uid: u32,
+    app: &str,
+    name: &str,
+) -> PathBuf {
+    match runtime_dir {
+        Some(dir) => dir.join(app).join(name),
+        None => temp_dir.join(format!("{}-{}", app, uid)).join(name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runtime_dir_follows_xdg_then_falls_back() {
+        let exists = |_: &Path| true;
+        let missing = |_: &Path| false;
+        assert_eq!(
+            resolve_runtime_dir(Some("/xdg".into()), 1000, missing),
+            Some(PathBuf::from("/xdg"))
+        );
+        assert_eq!(
+            resolve_runtime_dir(Some("relative".into()), 1000, exists),
+            Some(PathBuf::from("/run/user/1000"))
+        );
+        assert_eq!(
+            resolve_runtime_dir(None, 0, missing),
+            Some(PathBuf::from("/run"))
+        );
+        assert_eq!(resolve_runtime_dir(None, 1000, missing), None);
+        assert_eq!(
+            resolve_socket_path(None, "/tmp".into(), 1000, "myapp", "api.sock"),
+            PathBuf::from("/tmp/myapp-1000/api.sock")
+        );
+    }
+
+    #[test]
+    fn expand_fills_placeholders() {
+        let value = |placeholder: Placeholder| match placeholder {
+            Placeholder::Pid => Some("42".into()),
+            Placeholder::Env("HOME") => Some("/home/me".into()),
+            _ => None,
+        };
+        assert_eq!(
+            expand_with("${HOME}/app-%pid%-100%%$.sock", value).unwrap(),
+            PathBuf::from("/home/me/app-42-100%$.sock")
+        );
+        let err = expand_with("/run/%instance%.sock", value).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(expand_with("/run/%bogus%.sock", value).is_err());
+        assert!(expand_with("/run/%pid.sock", value).is_err());
+    }
+
+    #[test]
+    fn shorten_reaches_long_socket_paths() {
+        use crate::testing::TempSocket;
+        use std::fs;
+        use std::os::unix::net::{UnixListener, UnixStream};
+
+        let socket = TempSocket::new().unwrap();
+        let dir = socket.path().parent().unwrap().join("d".repeat(120));
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("api.sock");
+        assert!(path.as_os_str().len() > max_socket_path_len());
+
+        let _listener = UnixListener::bind(shorten(&path).unwrap().path()).unwrap();
+        UnixStream::connect(shorten(&path).unwrap().path()).unwrap();
+
+        let err = shorten(&dir.join("s".repeat(120))).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.get_ref().unwrap().is::<PathTooLong>());
+    }
+
+    #[test]
+    fn socket_dirs_in_shared_dirs_must_be_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let shared = env::temp_dir().join(format!("hyperlocal-shared-{}", process::id()));
+        fs::create_dir(&shared).unwrap();
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o1777)).unwrap();
+        let socket = shared.join("myapp-1000").join("api.sock");
+        create_socket_dir(&socket).unwrap();
+        let mode = fs::metadata(socket.parent().unwrap()).unwrap().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        // as if someone else had prepared the directory for us
+        fs::set_permissions(socket.parent().unwrap(), fs::Permissions::from_mode(0o777)).unwrap();
+        let err = create_socket_dir(&socket).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        fs::remove_dir(socket.parent().unwrap()).unwrap();
+        std::os::unix::fs::symlink(env::temp_dir(), socket.parent().unwrap()).unwrap();
+        let err = create_socket_dir(&socket).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        fs::remove_dir_all(&shared).unwrap();
+    }
+}
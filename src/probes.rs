@@ -0,0 +1,216 @@
+//! USDT probes for tracing hyperlocal in production
+//!
+//! With the `usdt` feature, on Linux x86_64 and aarch64, hyperlocal compiles SystemTap-style
+//! static probes into the binaries it is linked into, under the `hyperlocal` provider. A probe
+//! is a single `nop` until a tracer such as bpftrace attaches to it, so leaving them compiled
+//! in costs next to nothing. Without the feature, or on other targets, the probes compile to
+//! nothing at all.
+//!
+//! | probe           | arguments                                         |
+//! |-----------------|---------------------------------------------------|
+//! | `accept`        | peer pid, peer uid, peer gid                      |
+//! | `cred_check`    | peer pid, peer uid, 1 if the peer was allowed, else 0 |
+//! | `request_start` | request number, peer pid                          |
+//! | `request_end`   | request number, response status, or 0 on error    |
+//!
+//! Credentials which couldn't be queried are reported as -1. Request numbers are unique within
+//! the process, pairing each `request_end` with its `request_start`. For example, to print the
+//! latency of every request a daemon serves:
+//!
+//! ```text
+//! bpftrace -e '
+//!   usdt:/usr/bin/mydaemon:hyperlocal:request_start { @start[arg0] = nsecs; }
+//!   usdt:/usr/bin/mydaemon:hyperlocal:request_end /@start[arg0]/ {
+//!     printf("%d %dus\n", arg1, (nsecs - @start[arg0]) / 1000);
+//!     delete(@start[arg0]);
+//!   }'
+//! ```
+
+// Std lib
+#[cfg(feature = "server")]
+use std::future::Future;
+#[cfg(feature = "server")]
+use std::pin::Pin;
+#[cfg(feature = "server")]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "server")]
+use std::task::{Context, Poll};
+
+// Third party
+#[cfg(feature = "server")]
+use futures::ready;
+#[cfg(feature = "server")]
+use hyper::Response;
+#[cfg(feature = "server")]
+use pin_project_lite::pin_project;
+
+use crate::credentials::PeerCred;
+
+/// Emit the probe `$name`, whose stapsdt argument description is `$args`, with `$arg`s.
+#[cfg(all(feature = "usdt", target_os = "linux", target_arch = "x86_64"))]
+macro_rules! probe {
+    ($name:literal, $args:literal $(, $arg:expr)*) => {
+        unsafe {
+            std::arch::asm!(
+                stapsdt!($name, $args),
+                $(in(reg) $arg as i64,)*
+                options(att_syntax, nomem, nostack, preserves_flags)
+            )
+        }
+    };
+}
+
+/// Emit the probe `$name`, whose stapsdt argument description is `$args`, with `$arg`s.
+#[cfg(all(feature = "usdt", target_os = "linux", target_arch = "aarch64"))]
+macro_rules! probe {
+    ($name:literal, $args:literal $(, $arg:expr)*) => {
+        unsafe {
+            std::arch::asm!(
+                stapsdt!($name, $args),
+                $(in(reg) $arg as i64,)*
+                options(nomem, nostack, preserves_flags)
+            )
+        }
+    };
+}
+
+/// Evaluate a probe's arguments, for targets without probes.
+#[cfg(not(all(
+    feature = "usdt",
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+macro_rules! probe {
+    ($name:literal, $args:literal $(, $arg:expr)*) => {{
+        $(let _ = $arg;)*
+    }};
+}
+
+/// The assembly of a probe: a `nop` to attach to, and a `.note.stapsdt` describing it.
+#[cfg(all(
+    feature = "usdt",
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+macro_rules! stapsdt {
+    ($name:literal, $args:literal) => {
+        concat!(
+            "990: nop\n",
+            ".pushsection .note.stapsdt, \"\", \"note\"\n",
+            ".balign 4\n",
+            ".4byte 992f-991f, 994f-993f, 3\n",
+            "991: .asciz \"stapsdt\"\n",
+            "992: .balign 4\n",
+            "993: .8byte 990b\n",
+            ".8byte _.stapsdt.base\n",
+            ".8byte 0\n",
+            ".asciz \"hyperlocal\"\n",
+            ".asciz \"",
+            $name,
+            "\"\n",
+            ".asciz \"",
+            $args,
+            "\"\n",
+            "994: .balign 4\n",
+            ".popsection\n",
+            // the base tracers use to find where the binary was loaded, once per object
+            ".ifndef _.stapsdt.base\n",
+            ".pushsection .stapsdt.base, \"aGR\", \"progbits\", .stapsdt.base, comdat\n",
+            ".weak _.stapsdt.base\n",
+            ".hidden _.stapsdt.base\n",
+            "_.stapsdt.base: .space 1\n",
+            ".size _.stapsdt.base, 1\n",
+            ".popsection\n",
+            ".endif\n",
+        )
+    };
+}
+
+/// Return the pid, uid and gid of a peer, with -1 for any which aren't known.
+fn ids(peer_cred: Option<&PeerCred>) -> (i64, i64, i64) {
+    match peer_cred {
+        Some(peer_cred) => (
+            peer_cred.pid().map_or(-1, i64::from),
+            i64::from(peer_cred.uid()),
+            i64::from(peer_cred.gid()),
+        ),
+        None => (-1, -1, -1),
+    }
+}
+
+/// Fire `accept` for a connection from `peer_cred`.
+#[cfg(feature = "server")]
+pub(crate) fn accept(peer_cred: Option<&PeerCred>) {
+    let (pid, uid, gid) = ids(peer_cred);
+    probe!("accept", "-8@{0} -8@{1} -8@{2}", pid, uid, gid);
+}
+
+/// Fire `cred_check` for `peer_cred` having been allowed, or not, by a policy.
+pub(crate) fn cred_check(peer_cred: Option<&PeerCred>, allowed: bool) {
+    let (pid, uid, _) = ids(peer_cred);
+    probe!("cred_check", "-8@{0} -8@{1} -8@{2}", pid, uid, allowed);
+}
+
+/// Fire `request_start` for a request from `peer_cred`, returning its number.
+#[cfg(feature = "server")]
+pub(crate) fn request_start(peer_cred: Option<&PeerCred>) -> u64 {
+    static REQUESTS: AtomicU64 = AtomicU64::new(0);
+    let request = REQUESTS.fetch_add(1, Ordering::Relaxed) + 1;
+    let (pid, _, _) = ids(peer_cred);
+    probe!("request_start", "-8@{0} -8@{1}", request, pid);
+    request
+}
+
+#[cfg(feature = "server")]
+pin_project! {
+    /// A future firing `request_end` once its request has been answered.
+    pub struct Probed<F> {
+        #[pin]
+        future: F,
+        request: u64,
+    }
+}
+
+#[cfg(feature = "server")]
+impl<F> Probed<F> {
+    pub(crate) fn new(future: F, request: u64) -> Self {
+        Probed { future, request }
+    }
+}
+
+#[cfg(feature = "server")]
+impl<F, B, E> Future for Probed<F>
+where
+    F: Future<Output = Result<Response<B>, E>>,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let result = ready!(this.future.poll(cx));
+        let status = result
+            .as_ref()
+            .map_or(0, |response| response.status().as_u16());
+        probe!("request_end", "-8@{0} -8@{1}", *this.request, status);
+        Poll::Ready(result)
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::future;
+
+    #[test]
+    fn probed_futures_pass_responses_through() {
+        assert_eq!(ids(None), (-1, -1, -1));
+        assert_eq!(
+            ids(Some(&PeerCred::new(1000, 100, Some(7)))),
+            (7, 1000, 100)
+        );
+        let response = Response::builder().status(204).body(()).unwrap();
+        let probed = Probed::new(future::ok::<_, ()>(response), 1);
+        assert_eq!(block_on(probed).unwrap().status(), 204);
+    }
+}
@@ -0,0 +1,85 @@
+//! Deciding what to do when accepting a connection fails
+
+// Std lib
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How a server recovers from failing to accept a connection, as decided by the callback
+/// registered with `Server::on_accept_error`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Recovery {
+    /// Carry on accepting straight away.
+    Skip,
+    /// Stop accepting for the given time, then carry on.
+    Backoff(Duration),
+    /// Stop serving, failing with the error.
+    Abort,
+}
+
+/// A policy recovering from the errors which are expected to clear up by themselves.
+///
+/// Running out of file descriptors or memory backs off for 100 milliseconds, giving
+/// in-flight connections a chance to finish and free some up. Connections aborted by their
+/// peer before they could be accepted are skipped. Anything else aborts.
+///
+/// # Examples
+///
+/// ```rust
+/// use hyper::service::service_fn;
+/// use hyperlocal::server::{accept, Server};
+///
+/// # if let Err(err) = std::fs::remove_file("hyperlocal_test_accept_server.sock") {
+/// #   if err.kind() != std::io::ErrorKind::NotFound {
+/// #     panic!("{}", err)
+/// #   }
+/// # }
+/// #
+/// let server = Server::bind(
+///    "hyperlocal_test_accept_server.sock",
+///    || service_fn(|req| async move { Ok::<_, hyper::Error>(hyper::Response::new(req.into_body())) })
+/// )
+/// .unwrap()
+/// .on_accept_error(|err| {
+///     eprintln!("failed to accept a connection: {}", err);
+///     accept::transient(err)
+/// });
+/// ```
+pub fn transient(err: &io::Error) -> Recovery {
+    match err.raw_os_error() {
+        Some(libc::EMFILE) | Some(libc::ENFILE) | Some(libc::ENOBUFS) | Some(libc::ENOMEM) => {
+            return Recovery::Backoff(Duration::from_millis(100));
+        }
+        _ => {}
+    }
+    match err.kind() {
+        io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::Interrupted => Recovery::Skip,
+        _ => Recovery::Abort,
+    }
+}
+
+/// A type-erased accept error callback, as registered through `Server::on_accept_error`.
+pub(crate) type OnAcceptError = Arc<dyn Fn(&io::Error) -> Recovery + Send + Sync>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transient_recovers_from_expected_errors() {
+        assert_eq!(
+            transient(&io::Error::from_raw_os_error(libc::EMFILE)),
+            Recovery::Backoff(Duration::from_millis(100))
+        );
+        assert_eq!(
+            transient(&io::ErrorKind::ConnectionAborted.into()),
+            Recovery::Skip
+        );
+        assert_eq!(
+            transient(&io::Error::from_raw_os_error(libc::EBADF)),
+            Recovery::Abort
+        );
+    }
+}
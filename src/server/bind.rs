@@ -0,0 +1,272 @@
+//! Options applied to a socket as a server binds it
+//!
+//! Who may connect to a unix domain socket bound to a path is decided by the permissions
+//! and ownership of its file, so daemons serving a group of users typically restrict the
+//! socket to that group right after binding it. `BindOptions` collects those settings so
+//! that they can come from configuration, and with the `serde` feature be deserialized
+//! along with the rest of it.
+//!
+//! Services binding a socket under a directory of their own, such as `/run/<name>/`, can
+//! also have that directory created as they bind, rather than relying on tmpfiles or an init
+//! script to prepare it.
+
+// Std lib
+use std::ffi::{CString, OsString};
+use std::fs::{self, DirBuilder, Permissions};
+use std::io;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixListener as StdUnixListener;
+use std::path::{Path, PathBuf};
+
+/// Settings applied to a listener as it is bound, by `Server::bind_with`.
+///
+/// Every setting is optional, leaving the system default in place when unset.
+///
+/// # Examples
+///
+/// ```rust
+/// use hyperlocal::server::bind::BindOptions;
+///
+/// let options = BindOptions::new().mode(0o660).group(999).backlog(1024);
+///
+/// // create /run/myapp/ if it is missing, readable by the same group
+/// let options = options.create_dirs(0o750).dir_group(999);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
+pub struct BindOptions {
+    mode: Option<u32>,
+    owner: Option<u32>,
+    group: Option<u32>,
+    backlog: Option<i32>,
+    create_dirs: Option<u32>,
+    dir_owner: Option<u32>,
+    dir_group: Option<u32>,
+}
+
+impl BindOptions {
+    /// Create options leaving every setting at its default.
+    pub fn new() -> Self {
+        BindOptions::default()
+    }
+
+    /// Set the permissions of the socket file, such as `0o660`.
+    pub fn mode(mut self, mode: u32) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// Set the user owning the socket file, which usually requires running as root.
+    pub fn owner(mut self, uid: u32) -> Self {
+        self.owner = Some(uid);
+        self
+    }
+
+    /// Set the group owning the socket file.
+    pub fn group(mut self, gid: u32) -> Self {
+        self.group = Some(gid);
+        self
+    }
+
+    /// Set the length of the queue of connections waiting to be accepted.
+    pub fn backlog(mut self, backlog: i32) -> Self {
+        self.backlog = Some(backlog);
+        self
+    }
+
+    /// Create any missing parent directories of the socket file, with the permissions
+    /// `mode`, such as `0o750`, before binding it.
+    ///
+    /// Like `mkdir -p`, directories which already exist are left as they are. `mode` is
+    /// applied as given, whatever the process's umask.
+    pub fn create_dirs(mut self, mode: u32) -> Self {
+        self.create_dirs = Some(mode);
+        self
+    }
+
+    /// Set the user owning the directories `create_dirs` creates.
+    pub fn dir_owner(mut self, uid: u32) -> Self {
+        self.dir_owner = Some(uid);
+        self
+    }
+
+    /// Set the group owning the directories `create_dirs` creates.
+    pub fn dir_group(mut self, gid: u32) -> Self {
+        self.dir_group = Some(gid);
+        self
+    }
+
+    /// Create the missing parent directories of the socket file at `path`, if these options
+    /// ask for it.
+    pub(crate) fn prepare(&self, path: &Path) -> io::Result<()> {
+        let mode = match self.create_dirs {
+            Some(mode) => mode,
+            None => return Ok(()),
+        };
+        let missing: Vec<&Path> = path
+            .ancestors()
+            .skip(1)
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .take_while(|dir| !dir.exists())
+            .collect();
+        // outermost first, so that each is created inside its parent
+        for dir in missing.into_iter().rev() {
+            match DirBuilder::new().mode(mode).create(dir) {
+                Ok(()) => {}
+                // created by someone else in the meantime, so not ours to set up
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists && dir.is_dir() => continue,
+                Err(err) => return Err(err),
+            }
+            fs::set_permissions(dir, Permissions::from_mode(mode))?;
+            chown(dir, self.dir_owner, self.dir_group)?;
+        }
+        Ok(())
+    }
+
+    /// Bind a listener to the socket file at `path`, with its mode and ownership set before
+    /// any client can reach it.
+    ///
+    /// A socket file can only be given its permissions once it is bound, and is listening
+    /// by then, so when these options set any it is bound in a private directory next to
+    /// `path`, set up there and only then linked into place. Binding fails with
+    /// `io::ErrorKind::AddrInUse` if `path` already exists, just as binding it directly would.
+    ///
+    /// Note that the returned listener's local address reports the name it was bound to in
+    /// the private directory, which is removed again before returning.
+    pub(crate) fn bind(&self, path: &Path) -> io::Result<StdUnixListener> {
+        if self.mode.is_none() && self.owner.is_none() && self.group.is_none() {
+            return StdUnixListener::bind(path);
+        }
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let private = PrivateDir::create(dir)?;
+        // kept short, as socket paths are limited to about a hundred bytes
+        let staged = private.path.join("s");
+        let listener = StdUnixListener::bind(&staged)?;
+        chown(&staged, self.owner, self.group)?;
+        if let Some(mode) = self.mode {
+            fs::set_permissions(&staged, Permissions::from_mode(mode))?;
+        }
+        fs::hard_link(&staged, path).map_err(|err| {
+            if err.kind() == io::ErrorKind::AlreadyExists {
+                io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} already exists", path.display()),
+                )
+            } else {
+                err
+            }
+        })?;
+        Ok(listener)
+    }
+
+    /// Apply the options which concern the listening socket rather than its file to
+    /// `listener`.
+    pub(crate) fn apply(&self, listener: &StdUnixListener) -> io::Result<()> {
+        if let Some(backlog) = self.backlog {
+            // listening again on a listening socket only changes its backlog
+            if unsafe { libc::listen(listener.as_raw_fd(), backlog) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A directory only the current user can enter, removed along with its contents on drop.
+struct PrivateDir {
+    path: PathBuf,
+}
+
+impl PrivateDir {
+    /// Create a uniquely named directory in `parent`, with mode `0o700`.
+    fn create(parent: &Path) -> io::Result<Self> {
+        let template = parent.join(".hyperlocal-XXXXXX");
+        let template = CString::new(template.into_os_string().into_vec())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let template = template.into_raw();
+        let created = unsafe { libc::mkdtemp(template) };
+        let template = unsafe { CString::from_raw(template) };
+        if created.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(PrivateDir {
+            path: PathBuf::from(OsString::from_vec(template.into_bytes())),
+        })
+    }
+}
+
+impl Drop for PrivateDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Change the owners of `path`, leaving whichever of them isn't given unchanged.
+fn chown(path: &Path, owner: Option<u32>, group: Option<u32>) -> io::Result<()> {
+    if owner.is_none() && group.is_none() {
+        return Ok(());
+    }
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // an id of -1 leaves that id unchanged
+    let uid = owner.unwrap_or(u32::MAX) as libc::uid_t;
+    let gid = group.unwrap_or(u32::MAX) as libc::gid_t;
+    if unsafe { libc::chown(c_path.as_ptr(), uid, gid) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempSocket;
+
+    #[test]
+    fn bind_options_apply_mode_and_backlog() {
+        let socket = TempSocket::new().unwrap();
+        let gid = unsafe { libc::getgid() };
+        let options = BindOptions::new().mode(0o660).group(gid).backlog(16);
+        let listener = options.bind(socket.path()).unwrap();
+        options.apply(&listener).unwrap();
+        let mode = fs::metadata(socket.path()).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+        std::os::unix::net::UnixStream::connect(socket.path()).unwrap();
+
+        // the private directory the socket was staged in is gone
+        let dir = socket.path().parent().unwrap();
+        let staged = fs::read_dir(dir).unwrap().any(|entry| {
+            let name = entry.unwrap().file_name();
+            name.to_string_lossy().starts_with(".hyperlocal-")
+        });
+        assert!(!staged);
+        let err = options.bind(socket.path()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+    }
+
+    #[test]
+    fn bind_options_create_missing_dirs() {
+        let socket = TempSocket::new().unwrap();
+        let root = socket.path().with_extension("d");
+        let path = root.join("run/myapp/api.sock");
+        let gid = unsafe { libc::getgid() };
+        let options = BindOptions::new().create_dirs(0o750).dir_group(gid);
+        options.prepare(&path).unwrap();
+        for dir in &[root.as_path(), path.parent().unwrap()] {
+            let mode = fs::metadata(dir).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o750, "{}", dir.display());
+        }
+        // existing directories are left alone
+        fs::set_permissions(&root, Permissions::from_mode(0o700)).unwrap();
+        options.prepare(&path).unwrap();
+        let mode = fs::metadata(&root).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,414 @@
+//! Compressing response bodies
+//!
+//! `Compress` encodes the bodies of the responses of the services it wraps with gzip or
+//! zstd, whichever the client prefers according to its `Accept-Encoding` header. Bodies are
+//! encoded as they stream, so compressed output may lag behind what the service has
+//! written until the body ends. Available with the `compression` feature.
+
+// Std lib
+use std::error::Error as StdError;
+use std::future::Future;
+use std::io::{self, Write};
+use std::mem;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+// Third party
+use bytes::Bytes;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::{ready, Stream};
+use hyper::body::HttpBody;
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::service::Service;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use pin_project_lite::pin_project;
+
+use super::NewService;
+use crate::credentials::PeerCred;
+
+/// The size below which bodies of a known length are left uncompressed, by default.
+const MIN_SIZE: u64 = 1024;
+
+/// A content coding `Compress` can encode responses with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    /// `gzip`
+    Gzip,
+    /// `zstd`
+    Zstd,
+}
+
+impl Encoding {
+    /// Return the name of this coding, as found in `Accept-Encoding` and `Content-Encoding`.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Encoding::Gzip => "gzip",
+            Encoding::Zstd => "zstd",
+        }
+    }
+}
+
+/// Return the coding preferred by a client sending `headers`, or `None` if it accepts
+/// neither gzip nor zstd.
+///
+/// Codings are ranked by their quality values, with zstd breaking ties, and a `*` applies to
+/// codings not listed explicitly.
+pub fn negotiate(headers: &HeaderMap) -> Option<Encoding> {
+    let mut gzip = None;
+    let mut zstd = None;
+    let mut any = None;
+    let values = headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','));
+    for value in values {
+        let mut params = value.split(';');
+        let coding = params.next().unwrap_or("").trim();
+        let quality = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .filter_map(|q| q.trim().parse::<f32>().ok())
+            .next()
+            .unwrap_or(1.0);
+        if coding.eq_ignore_ascii_case("gzip") || coding.eq_ignore_ascii_case("x-gzip") {
+            gzip = Some(quality);
+        } else if coding.eq_ignore_ascii_case("zstd") {
+            zstd = Some(quality);
+        } else if coding == "*" {
+            any = Some(quality);
+        }
+    }
+    let gzip = gzip.or(any).unwrap_or(0.0);
+    let zstd = zstd.or(any).unwrap_or(0.0);
+    if zstd > 0.0 && zstd >= gzip {
+        Some(Encoding::Zstd)
+    } else if gzip > 0.0 {
+        Some(Encoding::Gzip)
+    } else {
+        None
+    }
+}
+
+/// A `NewService` compressing the response bodies of the services it wraps.
+///
+/// Responses are left as they are if they already have a `Content-Encoding`, have no body,
+/// answer a `HEAD` request, or have a `Content-Length` below the minimum size, 1 KiB unless
+/// set otherwise with `Compress::min_size`. Bodies streamed without a known length are always
+/// compressed.
+///
+/// # Examples
+///
+/// ```rust
+/// use hyper::service::service_fn;
+/// use hyperlocal::server::{compress::Compress, Server};
+///
+/// # if let Err(err) = std::fs::remove_file("hyperlocal_test_compress_server.sock") {
+/// #   if err.kind() != std::io::ErrorKind::NotFound {
+/// #     panic!("{}", err)
+/// #   }
+/// # }
+/// #
+/// let server = Server::bind(
+///    "hyperlocal_test_compress_server.sock",
+///    Compress::new(
+///        || service_fn(|req| async move { Ok::<_, hyper::Error>(hyper::Response::new(req.into_body())) })
+///    )
+///    .min_size(4096)
+/// ).unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct Compress<S> {
+    new_service: S,
+    min_size: u64,
+}
+
+impl<S> Compress<S> {
+    /// Wraps `new_service`.
+    pub fn new(new_service: S) -> Self {
+        Compress {
+            new_service,
+            min_size: MIN_SIZE,
+        }
+    }
+
+    /// Leave bodies known to be smaller than `min_size` bytes uncompressed.
+    pub fn min_size(mut self, min_size: u64) -> Self {
+        self.min_size = min_size;
+        self
+    }
+}
+
+impl<S> NewService for Compress<S>
+where
+    S: NewService<ReqBody = Body, ResBody = Body>,
+{
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = S::Error;
+    type Service = CompressService<S::Service>;
+    type Future = Compressing<S::Future>;
+    type InitError = S::InitError;
+
+    fn new_service(&self) -> Self::Future {
+        self.new_service_for(None)
+    }
+
+    fn new_service_for(&self, peer_cred: Option<&PeerCred>) -> Self::Future {
+        Compressing {
+            future: self.new_service.new_service_for(peer_cred),
+            min_size: self.min_size,
+        }
+    }
+}
+
+pin_project! {
+    /// A future resolving to a `CompressService` once the wrapped service has been created.
+    pub struct Compressing<F> {
+        #[pin]
+        future: F,
+        min_size: u64,
+    }
+}
+
+impl<F, S, E> Future for Compressing<F>
+where
+    F: Future<Output = Result<S, E>>,
+{
+    type Output = Result<CompressService<S>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let inner = ready!(this.future.poll(cx))?;
+        Poll::Ready(Ok(CompressService {
+            inner,
+            min_size: *this.min_size,
+        }))
+    }
+}
+
+/// A `Service` compressing the response bodies of another `Service`.
+#[derive(Clone, Debug)]
+pub struct CompressService<S> {
+    inner: S,
+    min_size: u64,
+}
+
+impl<S> Service<Request<Body>> for CompressService<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = Compressed<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let encoding = if req.method() == Method::HEAD {
+            None
+        } else {
+            negotiate(req.headers())
+        };
+        Compressed {
+            future: self.inner.call(req),
+            encoding,
+            min_size: self.min_size,
+        }
+    }
+}
+
+pin_project! {
+    /// A response future whose body is compressed once it resolves.
+    pub struct Compressed<F> {
+        #[pin]
+        future: F,
+        encoding: Option<Encoding>,
+        min_size: u64,
+    }
+}
+
+impl<F, E> Future for Compressed<F>
+where
+    F: Future<Output = Result<Response<Body>, E>>,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let response = ready!(this.future.poll(cx))?;
+        let encoding = match *this.encoding {
+            Some(encoding) if compressible(&response, *this.min_size) => encoding,
+            _ => return Poll::Ready(Ok(response)),
+        };
+        let (mut head, body) = response.into_parts();
+        let encoder = match Encoder::new(encoding) {
+            Ok(encoder) => encoder,
+            Err(_) => return Poll::Ready(Ok(Response::from_parts(head, body))),
+        };
+        head.headers.remove(header::CONTENT_LENGTH);
+        head.headers.insert(
+            header::CONTENT_ENCODING,
+            HeaderValue::from_static(encoding.as_str()),
+        );
+        head.headers.append(
+            header::VARY,
+            HeaderValue::from_static(header::ACCEPT_ENCODING.as_str()),
+        );
+        let body = Body::wrap_stream(Encode {
+            body,
+            encoder: Some(encoder),
+        });
+        Poll::Ready(Ok(Response::from_parts(head, body)))
+    }
+}
+
+/// Return true if `response` is worth compressing.
+fn compressible(response: &Response<Body>, min_size: u64) -> bool {
+    let status = response.status();
+    if status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+    {
+        return false;
+    }
+    let headers = response.headers();
+    if headers.contains_key(header::CONTENT_ENCODING) {
+        return false;
+    }
+    let length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<u64>().ok())
+        .or_else(|| HttpBody::size_hint(response.body()).exact());
+    match length {
+        Some(length) => length >= min_size,
+        None => !response.body().is_end_stream(),
+    }
+}
+
+enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> io::Result<Self> {
+        Ok(match encoding {
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(Vec::new(), Compression::default())),
+            Encoding::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(
+                Vec::new(),
+                zstd::DEFAULT_COMPRESSION_LEVEL,
+            )?),
+        })
+    }
+
+    /// Encode `data`, returning whatever output the encoder has produced so far.
+    fn encode(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(match *self {
+            Encoder::Gzip(ref mut encoder) => {
+                encoder.write_all(data)?;
+                mem::take(encoder.get_mut())
+            }
+            Encoder::Zstd(ref mut encoder) => {
+                encoder.write_all(data)?;
+                mem::take(encoder.get_mut())
+            }
+        })
+    }
+
+    /// Return the encoder's remaining output.
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+/// A body stream compressing another.
+struct Encode {
+    body: Body,
+    encoder: Option<Encoder>,
+}
+
+impl Stream for Encode {
+    type Item = Result<Bytes, Box<dyn StdError + Send + Sync>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let polled = ready!(Pin::new(&mut self.body).poll_next(cx));
+            let encoder = match self.encoder {
+                Some(ref mut encoder) => encoder,
+                None => return Poll::Ready(None),
+            };
+            match polled {
+                Some(Ok(chunk)) => {
+                    let output = encoder.encode(&chunk)?;
+                    if !output.is_empty() {
+                        return Poll::Ready(Some(Ok(output.into())));
+                    }
+                }
+                Some(Err(err)) => return Poll::Ready(Some(Err(err.into()))),
+                None => {
+                    let output = self.encoder.take().expect("checked above").finish()?;
+                    return Poll::Ready(Some(Ok(output.into())));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use futures::executor::block_on;
+    use hyper::service::service_fn;
+    use std::io::Read;
+
+    #[test]
+    fn negotiate_prefers_highest_quality() {
+        let negotiated = |accept: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT_ENCODING, accept.parse().unwrap());
+            negotiate(&headers)
+        };
+        assert_eq!(negotiated("gzip, deflate"), Some(Encoding::Gzip));
+        assert_eq!(negotiated("gzip, zstd"), Some(Encoding::Zstd));
+        assert_eq!(negotiated("zstd;q=0.5, gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiated("*;q=0.1, gzip;q=0"), Some(Encoding::Zstd));
+        assert_eq!(negotiated("br, identity"), None);
+        assert_eq!(negotiate(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn compress_encodes_large_bodies() {
+        let inventory = "{\"id\":\"container\"},".repeat(100);
+        let body = inventory.clone();
+        let compress = Compress::new(move || {
+            let body = body.clone();
+            service_fn(move |_| {
+                let body = body.clone();
+                async move { Ok::<_, hyper::Error>(Response::new(Body::from(body))) }
+            })
+        });
+        let mut service = block_on(compress.new_service()).unwrap();
+        let req = Request::get("/containers")
+            .header("accept-encoding", "gzip")
+            .body(Body::empty())
+            .unwrap();
+        let response = block_on(service.call(req)).unwrap();
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+
+        let compressed = block_on(hyper::body::to_bytes(response.into_body())).unwrap();
+        assert!(compressed.len() < inventory.len());
+        let mut decoded = String::new();
+        GzDecoder::new(&compressed[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, inventory);
+    }
+}
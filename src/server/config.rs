@@ -0,0 +1,229 @@
+//! Server settings read from configuration
+//!
+//! Daemons usually take where to listen, who may connect and how patient to be with clients
+//! from a configuration file rather than from code. `ServerConfig` gathers the endpoint, the
+//! socket's `BindOptions`, and the limits and timeouts hyper applies to each connection, so
+//! that with the `serde` feature the whole of it can be deserialized as one table and handed
+//! to `Server::from_config`.
+//!
+//! Timeouts are written in seconds, fractions allowed, when (de)serialized:
+//!
+//! ```toml
+//! endpoint = "unix:///run/myapp.sock"
+//! max_buf_size = 65536
+//! header_read_timeout = 5
+//! keep_alive_interval = 20.5
+//!
+//! [socket]
+//! mode = 0o660
+//! backlog = 1024
+//! ```
+
+// Std lib
+use std::time::Duration;
+
+// Third party
+use hyper::server::conn::Http as HyperHttp;
+
+use super::bind::BindOptions;
+use crate::endpoint::Endpoint;
+
+/// Where and how a `Server` listens, and the limits and timeouts of the connections it serves.
+///
+/// Settings left unset keep hyper's defaults.
+///
+/// # Examples
+///
+/// ```rust
+/// use std::time::Duration;
+///
+/// use hyperlocal::server::bind::BindOptions;
+/// use hyperlocal::server::config::ServerConfig;
+///
+/// let config = ServerConfig::new("unix:///run/myapp.sock".parse().unwrap())
+///     .socket(BindOptions::new().mode(0o660))
+///     .max_buf_size(64 * 1024)
+///     .header_read_timeout(Duration::from_secs(5));
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
+pub struct ServerConfig {
+    endpoint: Endpoint,
+    #[cfg_attr(feature = "serde", serde(default))]
+    socket: BindOptions,
+    #[cfg_attr(feature = "serde", serde(default))]
+    max_buf_size: Option<usize>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    max_concurrent_streams: Option<u32>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    max_header_list_size: Option<u32>,
+    #[cfg_attr(feature = "serde", serde(default, with = "seconds"))]
+    header_read_timeout: Option<Duration>,
+    #[cfg_attr(feature = "serde", serde(default, with = "seconds"))]
+    keep_alive_interval: Option<Duration>,
+    #[cfg_attr(feature = "serde", serde(default, with = "seconds"))]
+    keep_alive_timeout: Option<Duration>,
+}
+
+impl ServerConfig {
+    /// Create a config listening on `endpoint`, leaving every other setting at its default.
+    pub fn new(endpoint: Endpoint) -> Self {
+        ServerConfig {
+            endpoint,
+            socket: BindOptions::default(),
+            max_buf_size: None,
+            max_concurrent_streams: None,
+            max_header_list_size: None,
+            header_read_timeout: None,
+            keep_alive_interval: None,
+            keep_alive_timeout: None,
+        }
+    }
+
+    /// Apply `options` to the socket as it is bound.
+    pub fn socket(mut self, options: BindOptions) -> Self {
+        self.socket = options;
+        self
+    }
+
+    /// Cap the size of the buffer a connection reads requests into, in bytes.
+    pub fn max_buf_size(mut self, size: usize) -> Self {
+        self.max_buf_size = Some(size);
+        self
+    }
+
+    /// Cap the number of concurrent HTTP/2 streams a client may open per connection.
+    pub fn max_concurrent_streams(mut self, max: u32) -> Self {
+        self.max_concurrent_streams = Some(max);
+        self
+    }
+
+    /// Cap the size of the HTTP/2 headers a client may send, in bytes.
+    pub fn max_header_list_size(mut self, size: u32) -> Self {
+        self.max_header_list_size = Some(size);
+        self
+    }
+
+    /// Close HTTP/1 connections whose clients take longer than `timeout` to send a request's
+    /// headers.
+    pub fn header_read_timeout(mut self, timeout: Duration) -> Self {
+        self.header_read_timeout = Some(timeout);
+        self
+    }
+
+    /// Ping HTTP/2 clients every `interval` to check the connection is alive.
+    pub fn keep_alive_interval(mut self, interval: Duration) -> Self {
+        self.keep_alive_interval = Some(interval);
+        self
+    }
+
+    /// Close HTTP/2 connections whose clients don't answer a keep-alive ping within
+    /// `timeout`.
+    ///
+    /// Only takes effect along with a `keep_alive_interval`.
+    pub fn keep_alive_timeout(mut self, timeout: Duration) -> Self {
+        self.keep_alive_timeout = Some(timeout);
+        self
+    }
+
+    /// Return the endpoint to listen on.
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
+    /// Return the options applied to the socket as it is bound.
+    pub fn socket_options(&self) -> &BindOptions {
+        &self.socket
+    }
+
+    /// Apply the limits and timeouts to `http`.
+    pub(crate) fn configure(&self, http: &mut HyperHttp) {
+        if let Some(size) = self.max_buf_size {
+            http.max_buf_size(size);
+        }
+        if let Some(max) = self.max_concurrent_streams {
+            http.http2_max_concurrent_streams(max);
+        }
+        if let Some(size) = self.max_header_list_size {
+            http.http2_max_header_list_size(size);
+        }
+        if let Some(timeout) = self.header_read_timeout {
+            http.http1_header_read_timeout(timeout);
+        }
+        if let Some(interval) = self.keep_alive_interval {
+            http.http2_keep_alive_interval(interval);
+        }
+        if let Some(timeout) = self.keep_alive_timeout {
+            http.http2_keep_alive_timeout(timeout);
+        }
+    }
+}
+
+/// (De)serializing optional durations as a number of seconds.
+#[cfg(feature = "serde")]
+mod seconds {
+    // Std lib
+    use std::time::Duration;
+
+    // Third party
+    use serde::de::Error as _;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub(super) fn serialize<S>(
+        duration: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match duration {
+            Some(duration) => serializer.serialize_some(&duration.as_secs_f64()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub(super) fn deserialize<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match Option::<f64>::deserialize(deserializer)? {
+            Some(secs) if secs.is_finite() && secs >= 0.0 => {
+                Ok(Some(Duration::from_secs_f64(secs)))
+            }
+            Some(secs) => Err(D::Error::custom(format!(
+                "invalid number of seconds: {}",
+                secs
+            ))),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Server;
+    use crate::testing::TempSocket;
+    use hyper::service::service_fn;
+    use hyper::{Body, Response};
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn servers_bind_from_config() {
+        let socket = TempSocket::new().unwrap();
+        let config = ServerConfig::new(Endpoint::Path(socket.path().to_owned()))
+            .socket(BindOptions::new().mode(0o600))
+            .max_buf_size(16 * 1024)
+            .header_read_timeout(Duration::from_secs(5));
+        let _server = Server::from_config(&config, || {
+            service_fn(|_| async { Ok::<_, hyper::Error>(Response::new(Body::empty())) })
+        })
+        .unwrap();
+        let mode = std::fs::metadata(socket.path())
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
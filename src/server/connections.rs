@@ -0,0 +1,215 @@
+//! Introspecting the connections a server is serving
+//!
+//! A `Connections` handle registered with a server, through `Server::connections`, tracks
+//! every connection from the moment it is accepted until it closes, along with the
+//! credentials of its peer. An admin endpoint or a debug command can then list who is
+//! currently connected to the socket, and tell whether the server is still accepting
+//! connections or draining those it has.
+
+// Std lib
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::credentials::PeerCred;
+
+/// What a server registered with a `Connections` is doing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    /// The server is accepting connections.
+    Serving,
+    /// The server stopped accepting connections, and some are still open.
+    Draining,
+    /// The server stopped accepting connections, and all of them have closed.
+    Stopped,
+}
+
+/// A shared view of the connections open on a server.
+///
+/// # Examples
+///
+/// ```rust
+/// use hyper::service::service_fn;
+/// use hyperlocal::server::connections::{Connections, State};
+/// use hyperlocal::server::Server;
+///
+/// # if let Err(err) = std::fs::remove_file("hyperlocal_test_connections_server.sock") {
+/// #   if err.kind() != std::io::ErrorKind::NotFound {
+/// #     panic!("{}", err)
+/// #   }
+/// # }
+/// #
+/// let connections = Connections::new();
+/// let server = Server::bind(
+///    "hyperlocal_test_connections_server.sock",
+///    || service_fn(|req| async move { Ok::<_, hyper::Error>(hyper::Response::new(req.into_body())) })
+/// )
+/// .unwrap()
+/// .connections(&connections);
+///
+/// assert_eq!(connections.state(), State::Serving);
+/// assert_eq!(connections.count(), 0);
+/// drop(server);
+/// assert_eq!(connections.state(), State::Stopped);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Connections {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    next_id: AtomicU64,
+    // the number of servers, or workers of one, accepting connections
+    listening: AtomicUsize,
+    open: Mutex<BTreeMap<u64, ConnectionInfo>>,
+}
+
+impl Connections {
+    /// Create a handle tracking no connections, with no server registered.
+    pub fn new() -> Self {
+        Connections::default()
+    }
+
+    /// Return the number of connections currently open.
+    pub fn count(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Return the connections currently open, oldest first.
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        self.lock().values().cloned().collect()
+    }
+
+    /// Return whether the server is accepting connections, draining or stopped.
+    ///
+    /// A handle not yet registered with a server is `Stopped`.
+    pub fn state(&self) -> State {
+        if self.inner.listening.load(Ordering::Acquire) > 0 {
+            State::Serving
+        } else if self.count() > 0 {
+            State::Draining
+        } else {
+            State::Stopped
+        }
+    }
+
+    /// Start tracking a connection just accepted from a peer with `peer_cred`.
+    pub(crate) fn track(&self, peer_cred: Option<PeerCred>) -> Tracked {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        let info = ConnectionInfo {
+            id,
+            peer_cred,
+            accepted: Instant::now(),
+        };
+        self.lock().insert(id, info);
+        Tracked {
+            connections: self.clone(),
+            id,
+        }
+    }
+
+    /// Mark a server as accepting connections until the returned guard is dropped.
+    pub(crate) fn listening(&self) -> Listening {
+        self.inner.listening.fetch_add(1, Ordering::AcqRel);
+        Listening {
+            connections: self.clone(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<u64, ConnectionInfo>> {
+        self.inner.open.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A connection open on a server.
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    id: u64,
+    peer_cred: Option<PeerCred>,
+    accepted: Instant,
+}
+
+impl ConnectionInfo {
+    /// Return an id for the connection, unique among those tracked by the same `Connections`.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Return the credentials of the peer, if they could be queried.
+    pub fn peer_cred(&self) -> Option<&PeerCred> {
+        self.peer_cred.as_ref()
+    }
+
+    /// Return how long ago the connection was accepted.
+    pub fn age(&self) -> Duration {
+        self.accepted.elapsed()
+    }
+}
+
+/// A guard removing its connection from a `Connections` when dropped.
+pub(crate) struct Tracked {
+    connections: Connections,
+    id: u64,
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.connections.lock().remove(&self.id);
+    }
+}
+
+/// A guard counting a server as accepting connections for as long as it lives.
+///
+/// Clones count separately, so that each worker of a server is counted until it stops.
+pub(crate) struct Listening {
+    connections: Connections,
+}
+
+impl Listening {
+    pub(crate) fn connections(&self) -> &Connections {
+        &self.connections
+    }
+}
+
+impl Clone for Listening {
+    fn clone(&self) -> Self {
+        self.connections.listening()
+    }
+}
+
+impl Drop for Listening {
+    fn drop(&mut self) {
+        self.connections
+            .inner
+            .listening
+            .fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connections_report_peers_and_drain_state() {
+        let connections = Connections::new();
+        assert_eq!(connections.state(), State::Stopped);
+        let listening = connections.listening();
+        let first = connections.track(Some(PeerCred::new(1000, 100, Some(7))));
+        let second = connections.track(None);
+        assert_eq!(connections.state(), State::Serving);
+        let open = connections.list();
+        assert_eq!(open.len(), 2);
+        assert_eq!(open[0].peer_cred().map(PeerCred::uid), Some(1000));
+        assert!(open[1].peer_cred().is_none());
+
+        drop(listening);
+        drop(first);
+        assert_eq!(connections.state(), State::Draining);
+        assert_eq!(connections.list()[0].id(), open[1].id());
+        drop(second);
+        assert_eq!(connections.state(), State::Stopped);
+    }
+}
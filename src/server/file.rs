@@ -0,0 +1,146 @@
+//! Streaming files as response bodies
+//!
+//! `FileBody` streams a file, or a range of one, as a response body of a known length, so
+//! daemons serving layer blobs or logs needn't read them into memory first.
+//!
+//! hyper frames and writes response bodies itself, so a body can only hand it bytes already
+//! read from the file. Connections taken over from hyper have no such framing: once a
+//! connection has been upgraded, `Socket::send_file` sends a file to the peer with
+//! `sendfile(2)`, straight from the page cache.
+
+// Std lib
+use std::cmp;
+use std::io::{self, SeekFrom};
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+// Third party
+use bytes::Bytes;
+use futures::ready;
+use hyper::body::{HttpBody, SizeHint};
+use hyper::HeaderMap;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncSeekExt, ReadBuf};
+
+/// The most bytes read from the file for a single chunk of the body.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A response body streaming a range of a file.
+///
+/// # Examples
+///
+/// ```rust
+/// use hyper::service::service_fn;
+/// use hyper::{Body, Request, Response};
+/// use hyperlocal::server::file::FileBody;
+///
+/// let new_service = || {
+///     service_fn(|_: Request<Body>| async {
+///         let body = FileBody::open("Cargo.toml").await?;
+///         Ok::<_, std::io::Error>(Response::new(body))
+///     })
+/// };
+/// ```
+#[derive(Debug)]
+pub struct FileBody {
+    file: File,
+    remaining: u64,
+}
+
+impl FileBody {
+    /// Stream the whole of the file at `path`.
+    pub async fn open<P>(path: P) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let file = File::open(path).await?;
+        let len = file.metadata().await?.len();
+        Ok(FileBody {
+            file,
+            remaining: len,
+        })
+    }
+
+    /// Stream `len` bytes of `file` starting from `offset`, or fewer if the file ends first.
+    pub async fn range(mut file: File, offset: u64, len: u64) -> io::Result<Self> {
+        file.seek(SeekFrom::Start(offset)).await?;
+        Ok(FileBody {
+            file,
+            remaining: len,
+        })
+    }
+}
+
+impl HttpBody for FileBody {
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        if self.remaining == 0 {
+            return Poll::Ready(None);
+        }
+        let mut chunk = vec![0; cmp::min(self.remaining, CHUNK_SIZE as u64) as usize];
+        let mut buf = ReadBuf::new(&mut chunk);
+        ready!(Pin::new(&mut self.file).poll_read(cx, &mut buf))?;
+        let read = buf.filled().len();
+        if read == 0 {
+            // the file was truncated since the range was chosen
+            self.remaining = 0;
+            return Poll::Ready(Some(Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "file ended before the body did",
+            ))));
+        }
+        chunk.truncate(read);
+        self.remaining -= read as u64;
+        Poll::Ready(Some(Ok(chunk.into())))
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Poll::Ready(Ok(None))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.remaining == 0
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::with_exact(self.remaining)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_bodies_stream_ranges() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let expected = std::fs::read("Cargo.toml").unwrap();
+        runtime.block_on(async {
+            let body = FileBody::open("Cargo.toml").await.unwrap();
+            assert_eq!(body.size_hint().exact(), Some(expected.len() as u64));
+            let read = hyper::body::to_bytes(body).await.unwrap();
+            assert_eq!(read, expected);
+
+            let file = File::open("Cargo.toml").await.unwrap();
+            let body = FileBody::range(file, 3, 5).await.unwrap();
+            let read = hyper::body::to_bytes(body).await.unwrap();
+            assert_eq!(read, expected[3..8]);
+
+            let file = File::open("Cargo.toml").await.unwrap();
+            let body = FileBody::range(file, 0, expected.len() as u64 + 1)
+                .await
+                .unwrap();
+            let err = hyper::body::to_bytes(body).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        });
+    }
+}
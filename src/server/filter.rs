@@ -0,0 +1,47 @@
+//! Deciding whether to serve a connection before it reaches hyper
+
+// Std lib
+use std::future::{Future, IntoFuture};
+use std::pin::Pin;
+use std::sync::Arc;
+
+use super::Accepted;
+use crate::credentials::PeerCred;
+
+/// The outcome of an accept filter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Decision {
+    /// Serve the connection.
+    Proceed,
+    /// Close the connection without serving it.
+    Drop,
+}
+
+/// A connection waiting on its accept filter's decision.
+pub(crate) type Filtering = Pin<Box<dyn Future<Output = (Decision, Accepted)> + Send>>;
+
+/// A type-erased accept filter, as registered through `Server::accept_filter`.
+#[derive(Clone)]
+pub(crate) struct AcceptFilter {
+    filter: Arc<dyn Fn(Accepted) -> Filtering + Send + Sync>,
+}
+
+impl AcceptFilter {
+    pub(crate) fn new<F, R>(filter: F) -> Self
+    where
+        F: Fn(Option<PeerCred>) -> R + Send + Sync + 'static,
+        R: IntoFuture<Output = Decision>,
+        R::IntoFuture: Send + 'static,
+    {
+        AcceptFilter {
+            filter: Arc::new(move |accepted: Accepted| {
+                let deciding = filter(accepted.peer_cred).into_future();
+                Box::pin(async move { (deciding.await, accepted) })
+            }),
+        }
+    }
+
+    pub(crate) fn filter(&self, accepted: Accepted) -> Filtering {
+        (self.filter)(accepted)
+    }
+}
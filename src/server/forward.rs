@@ -0,0 +1,274 @@
+//! Exposing a protected unix domain socket over TCP
+//!
+//! Daemons like `dockerd` only listen on a unix domain socket, whose permissions decide who
+//! may talk to them. Exposing such a socket over TCP with `socat` hands that control to
+//! anyone who can reach the port. `Forward` is a safer alternative: it only forwards
+//! requests bearing a shared token, can be restricted to read-only requests, and refuses to
+//! listen beyond the loopback interface without a token.
+
+// Std lib
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+// Third party
+use futures::future::{self, Either, Ready};
+use futures::ready;
+use hyper::header::{self, HeaderValue};
+use hyper::service::{make_service_fn, Service};
+use hyper::{Body, Method, Request, Response, Server as HyperServer, StatusCode};
+use tokio::runtime::Runtime;
+
+use super::proxy::{Proxy, ProxyService};
+use super::NewService;
+use crate::client::UnixConnector;
+
+/// A `NewService` forwarding authorized requests to a unix domain socket.
+///
+/// A `Forward` is typically run with `Forward::run`, but may be served by any `Server`, say
+/// one wrapped in further layers.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use hyperlocal::server::forward::Forward;
+///
+/// Forward::new("/var/run/docker.sock")
+///     .token("s3cr3t")
+///     .read_only()
+///     .run(&"0.0.0.0:2375".parse().unwrap())
+///     .unwrap();
+/// ```
+pub struct Forward {
+    proxy: Proxy<UnixConnector>,
+    token: Option<Arc<String>>,
+    read_only: bool,
+}
+
+impl Forward {
+    /// Forward requests to the server listening on the unix domain socket at `socket`.
+    pub fn new<P>(socket: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        Forward {
+            proxy: Proxy::unix(socket),
+            token: None,
+            read_only: false,
+        }
+    }
+
+    /// Only forward requests with an `Authorization: Bearer` header carrying `token`,
+    /// answering others with `401 Unauthorized`.
+    ///
+    /// The `Authorization` header is removed from the requests forwarded, so the token is
+    /// never seen by the upstream.
+    pub fn token<T>(mut self, token: T) -> Self
+    where
+        T: Into<String>,
+    {
+        self.token = Some(Arc::new(token.into()));
+        self
+    }
+
+    /// Only forward `GET` and `HEAD` requests, answering others with
+    /// `405 Method Not Allowed`.
+    ///
+    /// Requests asking to upgrade the connection are answered with `403 Forbidden` whatever
+    /// their method, as an upgraded connection, such as an attach websocket, carries data
+    /// both ways.
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    /// Start a new tokio runtime, and serve on the TCP address `addr` on it.
+    ///
+    /// Fails with `io::ErrorKind::InvalidInput` if `addr` is not a loopback address and no
+    /// token is required.
+    pub fn run(self, addr: &SocketAddr) -> io::Result<()> {
+        if self.token.is_none() && !addr.ip().is_loopback() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("refusing to listen on {} without requiring a token", addr),
+            ));
+        }
+        Runtime::new()?.block_on(async move {
+            HyperServer::try_bind(addr)
+                .map_err(|e| io::Error::new(io::ErrorKind::AddrNotAvailable, e.to_string()))?
+                .serve(make_service_fn(move |_| self.new_service()))
+                .await
+                .map_err(|e| io::Error::other(format!("failed to serve: {}", e)))
+        })
+    }
+}
+
+impl NewService for Forward {
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = hyper::Error;
+    type Service = ForwardService;
+    type Future = Forwarding;
+    type InitError = io::Error;
+
+    fn new_service(&self) -> Self::Future {
+        Forwarding {
+            future: self.proxy.new_service(),
+            token: self.token.clone(),
+            read_only: self.read_only,
+        }
+    }
+}
+
+/// A future resolving to a `ForwardService` once its proxy has been created.
+pub struct Forwarding {
+    future: Ready<io::Result<ProxyService<UnixConnector>>>,
+    token: Option<Arc<String>>,
+    read_only: bool,
+}
+
+impl Future for Forwarding {
+    type Output = io::Result<ForwardService>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let proxy = ready!(Pin::new(&mut self.future).poll(cx))?;
+        Poll::Ready(Ok(ForwardService {
+            proxy,
+            token: self.token.take(),
+            read_only: self.read_only,
+        }))
+    }
+}
+
+/// A `Service` forwarding authorized requests to a unix domain socket.
+pub struct ForwardService {
+    proxy: ProxyService<UnixConnector>,
+    token: Option<Arc<String>>,
+    read_only: bool,
+}
+
+impl Service<Request<Body>> for ForwardService {
+    type Response = Response<Body>;
+    type Error = hyper::Error;
+    type Future = Either<
+        Ready<Result<Response<Body>, hyper::Error>>,
+        <ProxyService<UnixConnector> as Service<Request<Body>>>::Future,
+    >;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.proxy.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        if let Some(ref token) = self.token {
+            let authorized = req
+                .headers()
+                .get(header::AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .is_some_and(|presented| same(presented.trim().as_bytes(), token.as_bytes()));
+            if !authorized {
+                let mut response = reject(StatusCode::UNAUTHORIZED, "unauthorized\n");
+                response
+                    .headers_mut()
+                    .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+                return Either::Left(future::ok(response));
+            }
+            req.headers_mut().remove(header::AUTHORIZATION);
+        }
+        if self.read_only && req.method() != Method::GET && req.method() != Method::HEAD {
+            let mut response = reject(StatusCode::METHOD_NOT_ALLOWED, "read only\n");
+            response
+                .headers_mut()
+                .insert(header::ALLOW, HeaderValue::from_static("GET, HEAD"));
+            return Either::Left(future::ok(response));
+        }
+        if self.read_only && wants_upgrade(&req) {
+            let response = reject(StatusCode::FORBIDDEN, "read only\n");
+            return Either::Left(future::ok(response));
+        }
+        Either::Right(self.proxy.call(req))
+    }
+}
+
+/// Return true if `req` asks to upgrade its connection to another protocol.
+fn wants_upgrade(req: &Request<Body>) -> bool {
+    req.headers().contains_key(header::UPGRADE)
+        || req
+            .headers()
+            .get_all(header::CONNECTION)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|option| option.trim().eq_ignore_ascii_case("upgrade"))
+}
+
+fn reject(status: StatusCode, body: &'static str) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response
+}
+
+/// Compare `a` and `b` in time independent of where they first differ.
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn forward_requires_token_and_read_only_methods() {
+        let forward = Forward::new("hyperlocal_test_forward.sock")
+            .token("s3cr3t")
+            .read_only();
+        let mut service = block_on(forward.new_service()).unwrap();
+        let mut call = |method, authorization: &str| {
+            let mut req = Request::new(Body::empty());
+            *req.method_mut() = method;
+            req.headers_mut()
+                .insert(header::AUTHORIZATION, authorization.parse().unwrap());
+            match service.call(req) {
+                Either::Left(rejected) => block_on(rejected).unwrap().status(),
+                Either::Right(_) => StatusCode::OK,
+            }
+        };
+        assert_eq!(call(Method::GET, "Bearer guess"), StatusCode::UNAUTHORIZED);
+        assert_eq!(call(Method::GET, "s3cr3t"), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            call(Method::POST, "Bearer s3cr3t"),
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+        assert_eq!(call(Method::GET, "Bearer s3cr3t"), StatusCode::OK);
+
+        let err = Forward::new("hyperlocal_test_forward.sock")
+            .run(&"0.0.0.0:0".parse().unwrap())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn read_only_forwards_refuse_upgrades() {
+        let forward = Forward::new("hyperlocal_test_forward.sock").read_only();
+        let mut service = block_on(forward.new_service()).unwrap();
+        let mut call = |connection: &str| {
+            let req = Request::get("/containers/web/attach/ws?stdin=1")
+                .header(header::CONNECTION, connection)
+                .body(Body::empty())
+                .unwrap();
+            match service.call(req) {
+                Either::Left(rejected) => block_on(rejected).unwrap().status(),
+                Either::Right(_) => StatusCode::OK,
+            }
+        };
+        assert_eq!(call("keep-alive, Upgrade"), StatusCode::FORBIDDEN);
+        assert_eq!(call("upgrade"), StatusCode::FORBIDDEN);
+        assert_eq!(call("keep-alive"), StatusCode::OK);
+    }
+}
@@ -0,0 +1,364 @@
+//! Requiring peer credentials per route
+
+// Std lib
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+// Third party
+use futures::future::{self, Either, Ready};
+use futures::ready;
+use hyper::service::Service;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use pin_project_lite::pin_project;
+
+use super::metrics::{Counters, Metrics};
+use super::NewService;
+use crate::credentials::{CredPolicy, PeerCred};
+
+/// The peer credentials a route requires.
+///
+/// Requests whose credentials couldn't be queried only satisfy `Anyone`, or a `Policy` allowing
+/// them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Require {
+    /// Any peer.
+    Anyone,
+    /// Peers running as root.
+    Root,
+    /// Peers running as the given user.
+    Uid(u32),
+    /// Peers whose primary group is the given group. Supplementary groups aren't
+    /// considered, as peer credentials don't carry them.
+    Gid(u32),
+    /// Peers running as the same user as the server.
+    SameUid,
+    /// Peers satisfying any of the given requirements.
+    OneOf(Vec<Require>),
+    /// Peers matching the given policy.
+    Policy(CredPolicy),
+}
+
+impl Require {
+    /// Return true if a peer with credentials `peer_cred` satisfies this requirement.
+    pub fn allows(&self, peer_cred: Option<&PeerCred>) -> bool {
+        let peer_cred = match (self, peer_cred) {
+            (Require::Anyone, _) => return true,
+            (Require::OneOf(requirements), _) => {
+                return requirements.iter().any(|require| require.allows(peer_cred))
+            }
+            (Require::Policy(policy), _) => return policy.allows(peer_cred),
+            (_, None) => return false,
+            (_, Some(peer_cred)) => peer_cred,
+        };
+        match *self {
+            Require::Root => peer_cred.uid() == 0,
+            Require::Uid(uid) => peer_cred.uid() == uid,
+            Require::Gid(gid) => peer_cred.gid() == gid,
+            Require::SameUid => peer_cred.uid() == unsafe { libc::geteuid() },
+            Require::Anyone | Require::OneOf(_) | Require::Policy(_) => unreachable!(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Route {
+    method: Option<Method>,
+    prefix: String,
+    require: Require,
+}
+
+impl Route {
+    fn matches(&self, method: &Method, path: &str) -> bool {
+        if self.method.as_ref().is_some_and(|m| m != method) {
+            return false;
+        }
+        // match whole path segments, so that `/a` covers `/a/b` but not `/ab`
+        let prefix = self.prefix.trim_end_matches('/');
+        match path.strip_prefix(prefix) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        }
+    }
+}
+
+/// Return `path` percent-decoded, with empty and `.` segments removed and `..` segments
+/// resolved, or `None` if it has malformed escapes or climbs above the root.
+///
+/// Routes are matched against the normalized path, so that `//admin`, `/x/../admin` and
+/// `/%61dmin` are all held to the requirements of `/admin`.
+fn normalize(path: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(path.len());
+    let mut bytes = path.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte != b'%' {
+            decoded.push(byte);
+            continue;
+        }
+        let hex = [bytes.next()?, bytes.next()?];
+        let hex = std::str::from_utf8(&hex).ok()?;
+        decoded.push(u8::from_str_radix(hex, 16).ok()?);
+    }
+    let decoded = String::from_utf8_lossy(&decoded);
+    let mut segments = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => (),
+            ".." => {
+                segments.pop()?;
+            }
+            segment => segments.push(segment),
+        }
+    }
+    Some(format!("/{}", segments.join("/")))
+}
+
+/// Answer with `status` and `body`, without calling the wrapped service.
+fn refuse<E>(status: StatusCode, body: &'static str) -> Ready<Result<Response<Body>, E>> {
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    future::ok(response)
+}
+
+/// A `NewService` answering requests with `403 Forbidden` unless their peer satisfies the
+/// credentials required by their route.
+///
+/// Routes are matched by path prefix, on whole path segments, and optionally by method, in
+/// the order they were added. Requests matching no route require `Anyone`, unless set
+/// otherwise with `Guard::otherwise`.
+///
+/// Paths are percent-decoded and have their `.` and `..` segments resolved and repeated
+/// slashes collapsed before being matched, so that spelling a path differently doesn't
+/// escape its route. Requests whose path has a malformed escape, or climbs above the root,
+/// are answered with `400 Bad Request`. The wrapped service still sees the path as sent.
+///
+/// # Examples
+///
+/// ```rust
+/// use hyper::service::service_fn;
+/// use hyper::Method;
+/// use hyperlocal::server::{guard::{Guard, Require}, Server};
+///
+/// # if let Err(err) = std::fs::remove_file("hyperlocal_test_guard_server.sock") {
+/// #   if err.kind() != std::io::ErrorKind::NotFound {
+/// #     panic!("{}", err)
+/// #   }
+/// # }
+/// #
+/// let operators = 27;
+/// let server = Server::bind(
+///    "hyperlocal_test_guard_server.sock",
+///    Guard::new(
+///        || service_fn(|req| async move { Ok::<_, hyper::Error>(hyper::Response::new(req.into_body())) })
+///    )
+///    .route(Some(Method::GET), "/containers", Require::OneOf(vec![Require::Root, Require::Gid(operators)]))
+///    .route(None, "/containers", Require::Root)
+///    .otherwise(Require::SameUid)
+/// ).unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct Guard<S> {
+    new_service: S,
+    routes: Arc<Vec<Route>>,
+    default: Require,
+    counters: Option<Arc<Counters>>,
+}
+
+impl<S> Guard<S> {
+    /// Wraps `new_service`, with no routes.
+    pub fn new(new_service: S) -> Self {
+        Guard {
+            new_service,
+            routes: Arc::new(Vec::new()),
+            default: Require::Anyone,
+            counters: None,
+        }
+    }
+
+    /// Require `require` of requests for paths under `prefix` and, if given, with `method`.
+    pub fn route(mut self, method: Option<Method>, prefix: &str, require: Require) -> Self {
+        Arc::make_mut(&mut self.routes).push(Route {
+            method,
+            prefix: prefix.to_owned(),
+            require,
+        });
+        self
+    }
+
+    /// Require `require` of requests matching no route.
+    pub fn otherwise(mut self, require: Require) -> Self {
+        self.default = require;
+        self
+    }
+
+    /// Count the requests answered with `403 Forbidden` in `counters`, as reported by
+    /// `Counters#forbidden`.
+    pub fn counters(mut self, counters: Arc<Counters>) -> Self {
+        self.counters = Some(counters);
+        self
+    }
+}
+
+impl<S> NewService for Guard<S>
+where
+    S: NewService<ReqBody = Body, ResBody = Body>,
+{
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = S::Error;
+    type Service = GuardService<S::Service>;
+    type Future = Guarding<S::Future>;
+    type InitError = S::InitError;
+
+    fn new_service(&self) -> Self::Future {
+        self.new_service_for(None)
+    }
+
+    fn new_service_for(&self, peer_cred: Option<&PeerCred>) -> Self::Future {
+        Guarding {
+            future: self.new_service.new_service_for(peer_cred),
+            routes: self.routes.clone(),
+            default: self.default.clone(),
+            counters: self.counters.clone(),
+        }
+    }
+}
+
+pin_project! {
+    /// A future resolving to a `GuardService` once the wrapped service has been created.
+    pub struct Guarding<F> {
+        #[pin]
+        future: F,
+        routes: Arc<Vec<Route>>,
+        default: Require,
+        counters: Option<Arc<Counters>>,
+    }
+}
+
+impl<F, S, E> Future for Guarding<F>
+where
+    F: Future<Output = Result<S, E>>,
+{
+    type Output = Result<GuardService<S>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let inner = ready!(this.future.poll(cx))?;
+        Poll::Ready(Ok(GuardService {
+            inner,
+            routes: this.routes.clone(),
+            default: this.default.clone(),
+            counters: this.counters.clone(),
+        }))
+    }
+}
+
+/// A `Service` checking the credentials required by each request's route in front of
+/// another `Service`.
+#[derive(Clone, Debug)]
+pub struct GuardService<S> {
+    inner: S,
+    routes: Arc<Vec<Route>>,
+    default: Require,
+    counters: Option<Arc<Counters>>,
+}
+
+impl<S> Service<Request<Body>> for GuardService<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = Either<S::Future, Ready<Result<Response<Body>, S::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let path = match normalize(req.uri().path()) {
+            Some(path) => path,
+            None => return Either::Right(refuse(StatusCode::BAD_REQUEST, "bad request\n")),
+        };
+        let require = self
+            .routes
+            .iter()
+            .find(|route| route.matches(req.method(), &path))
+            .map_or(&self.default, |route| &route.require);
+        if require.allows(req.extensions().get::<PeerCred>()) {
+            return Either::Left(self.inner.call(req));
+        }
+        if let Some(ref counters) = self.counters {
+            counters.request_forbidden();
+        }
+        Either::Right(refuse(StatusCode::FORBIDDEN, "forbidden\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use hyper::service::service_fn;
+
+    #[test]
+    fn guard_checks_route_requirements() {
+        let counters = Arc::new(Counters::default());
+        let guard = Guard::new(|| {
+            service_fn(|_| async { Ok::<_, hyper::Error>(Response::new(Body::empty())) })
+        })
+        .route(Some(Method::GET), "/items", Require::Gid(100))
+        .route(None, "/items", Require::Root)
+        .counters(counters.clone());
+        let mut service = block_on(guard.new_service()).unwrap();
+        let mut call = |method, path: &str, peer_cred| {
+            let mut req = Request::new(Body::empty());
+            *req.method_mut() = method;
+            *req.uri_mut() = path.parse().unwrap();
+            if let Some(peer_cred) = peer_cred {
+                req.extensions_mut().insert(peer_cred);
+            }
+            block_on(service.call(req)).unwrap().status()
+        };
+        let member = Some(PeerCred::new(1000, 100, None));
+        let root = Some(PeerCred::new(0, 0, None));
+
+        assert_eq!(call(Method::GET, "/items/1", member), StatusCode::OK);
+        assert_eq!(
+            call(Method::DELETE, "/items/1", member),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(call(Method::DELETE, "/items/1", root), StatusCode::OK);
+        assert_eq!(call(Method::GET, "/itemsx", None), StatusCode::OK);
+        assert_eq!(call(Method::GET, "/items", None), StatusCode::FORBIDDEN);
+        assert_eq!(counters.forbidden(), 2);
+    }
+
+    #[test]
+    fn guard_matches_normalized_paths() {
+        let guard = Guard::new(|| {
+            service_fn(|_| async { Ok::<_, hyper::Error>(Response::new(Body::empty())) })
+        })
+        .route(None, "/admin", Require::Root);
+        let mut service = block_on(guard.new_service()).unwrap();
+        let mut call = |path: &str| {
+            let mut req = Request::new(Body::empty());
+            *req.uri_mut() = path.parse().unwrap();
+            block_on(service.call(req)).unwrap().status()
+        };
+
+        for path in &[
+            "//admin",
+            "/x/../admin",
+            "/%61dmin",
+            "/./admin/",
+            "/x/%2e%2e/admin",
+        ] {
+            assert_eq!(call(path), StatusCode::FORBIDDEN, "{}", path);
+        }
+        assert_eq!(call("/../admin"), StatusCode::BAD_REQUEST);
+        assert_eq!(call("/%6"), StatusCode::BAD_REQUEST);
+        assert_eq!(call("/admins"), StatusCode::OK);
+    }
+}
@@ -0,0 +1,135 @@
+//! Handing a bound listener over to another process
+//!
+//! This supports the classic graceful upgrade: a running server spawns its replacement,
+//! which inherits the listening socket and starts accepting on it straight away, while the
+//! original process stops accepting and drains the connections it already has. The socket
+//! file is never unlinked or rebound, so clients don't notice the switch.
+//!
+//! The listener's file descriptor and socket path are passed to the new process through the
+//! `HYPERLOCAL_LISTEN_FD` and `HYPERLOCAL_LISTEN_PATH` environment variables, which
+//! `inherited` (and `Server::bind_or_inherit`) read back.
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! use std::{env, process::Command, thread};
+//!
+//! use futures::channel::oneshot;
+//! use futures::FutureExt;
+//! use hyper::service::service_fn;
+//! use hyperlocal::server::Server;
+//!
+//! let server = Server::bind_or_inherit(
+//!    "hyperlocal_test_handoff_server.sock",
+//!    || service_fn(|req| async move { Ok::<_, hyper::Error>(hyper::Response::new(req.into_body())) })
+//! ).unwrap();
+//!
+//! let handoff = server.handoff();
+//! let (upgraded, upgrading) = oneshot::channel();
+//! thread::spawn(move || {
+//!     // wait for whatever triggers an upgrade here
+//!     let exe = env::current_exe().unwrap();
+//!     handoff.spawn(&mut Command::new(exe)).unwrap();
+//!     let _ = upgraded.send(());
+//! });
+//!
+//! server.run_until(upgrading.map(|_| Ok(()))).unwrap();
+//! ```
+
+// Std lib
+use std::env;
+use std::io;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::UnixListener as StdUnixListener;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Child, Command};
+use std::sync::{Arc, Mutex};
+
+use super::Bound;
+
+/// The environment variable carrying an inherited listener's file descriptor.
+pub const LISTEN_FD: &str = "HYPERLOCAL_LISTEN_FD";
+
+/// The environment variable carrying an inherited listener's socket path.
+pub const LISTEN_PATH: &str = "HYPERLOCAL_LISTEN_PATH";
+
+/// A handle for handing a running server's listener to a new process, obtained from
+/// `Server::handoff` or `Serve::handoff`.
+#[derive(Clone)]
+pub struct Handoff {
+    bound: Arc<Mutex<Bound>>,
+}
+
+impl Handoff {
+    pub(crate) fn new(bound: Arc<Mutex<Bound>>) -> Self {
+        Handoff { bound }
+    }
+
+    /// Spawn `command` with the server's listener inherited.
+    ///
+    /// Once the new process has been spawned, this server no longer owns its socket file and
+    /// won't remove it on shutdown. It is up to the caller to then shut this server down,
+    /// typically by resolving the signal given to `Server::run_until`.
+    pub fn spawn(&self, command: &mut Command) -> io::Result<Child> {
+        let mut bound = self.bound.lock().unwrap_or_else(|e| e.into_inner());
+        let fd = bound.fd.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotConnected,
+                "server has no listener to hand off",
+            )
+        })?;
+        // a close-on-exec duplicate, made inheritable only in the child
+        let inheritable = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+        if inheritable < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        command.env(LISTEN_FD, inheritable.to_string());
+        if let Some(ref path) = bound.path {
+            command.env(LISTEN_PATH, path);
+        }
+        unsafe {
+            command.pre_exec(move || {
+                if libc::fcntl(inheritable, libc::F_SETFD, 0) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let child = command.spawn();
+        unsafe {
+            libc::close(inheritable);
+        }
+        let child = child?;
+        bound.path = None;
+        Ok(child)
+    }
+}
+
+/// Take the listener handed to this process by a `Handoff`, if there is one, along with the
+/// path of its socket file.
+///
+/// The environment variables describing the listener are removed, so that processes spawned
+/// later don't mistake it for their own.
+pub fn inherited() -> io::Result<Option<(StdUnixListener, Option<PathBuf>)>> {
+    let fd = match env::var(LISTEN_FD) {
+        Ok(fd) => fd,
+        Err(env::VarError::NotPresent) => return Ok(None),
+        Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+    };
+    env::remove_var(LISTEN_FD);
+    let path = env::var_os(LISTEN_PATH).map(PathBuf::from);
+    env::remove_var(LISTEN_PATH);
+
+    let fd = fd.parse::<libc::c_int>().map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid {} {:?}: {}", LISTEN_FD, fd, err),
+        )
+    })?;
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let listener = unsafe { StdUnixListener::from_raw_fd(fd) };
+    Ok(Some((listener, path)))
+}
@@ -0,0 +1,234 @@
+//! Health and readiness endpoints for probes
+//!
+//! `Health` answers `GET /healthz` and `GET /readyz` in front of another service, handing
+//! every other request through. `/healthz` succeeds for as long as the server answers at all.
+//! `/readyz` reflects a `Status`: it fails with `503 Service Unavailable` unless the server
+//! the status is registered with, through `Server::health`, is listening and not draining, and
+//! the application hasn't marked itself unready.
+
+// Std lib
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+// Third party
+use futures::future::{self, Either, Ready};
+use futures::ready;
+use hyper::service::Service;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use pin_project_lite::pin_project;
+
+use super::NewService;
+use crate::credentials::PeerCred;
+
+/// The path answered with the server's liveness.
+pub const HEALTHZ: &str = "/healthz";
+
+/// The path answered with the server's readiness.
+pub const READYZ: &str = "/readyz";
+
+/// A shared readiness state, reported by `/readyz`.
+///
+/// A new status is ready as far as the application is concerned, but only serving once
+/// registered with a server which is accepting connections.
+#[derive(Clone, Debug)]
+pub struct Status {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    ready: AtomicBool,
+    serving: AtomicBool,
+}
+
+impl Status {
+    /// Create a status, ready but not yet serving.
+    pub fn new() -> Self {
+        Status {
+            inner: Arc::new(Inner {
+                ready: AtomicBool::new(true),
+                serving: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// Mark the application as ready, or not, to handle requests, say while it warms a cache.
+    pub fn set_ready(&self, ready: bool) {
+        self.inner.ready.store(ready, Ordering::Release);
+    }
+
+    /// Return true if the server is listening and not draining, and the application is ready.
+    pub fn is_ready(&self) -> bool {
+        self.inner.serving.load(Ordering::Acquire) && self.inner.ready.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn set_serving(&self, serving: bool) {
+        self.inner.serving.store(serving, Ordering::Release);
+    }
+}
+
+impl Default for Status {
+    fn default() -> Self {
+        Status::new()
+    }
+}
+
+/// A `NewService` answering health and readiness probes in front of the services it wraps.
+///
+/// # Examples
+///
+/// ```rust
+/// use hyper::service::service_fn;
+/// use hyperlocal::server::{health::{Health, Status}, Server};
+///
+/// # if let Err(err) = std::fs::remove_file("hyperlocal_test_health_server.sock") {
+/// #   if err.kind() != std::io::ErrorKind::NotFound {
+/// #     panic!("{}", err)
+/// #   }
+/// # }
+/// #
+/// let status = Status::new();
+/// let server = Server::bind(
+///    "hyperlocal_test_health_server.sock",
+///    Health::new(
+///        status.clone(),
+///        || service_fn(|req| async move { Ok::<_, hyper::Error>(hyper::Response::new(req.into_body())) })
+///    )
+/// )
+/// .unwrap()
+/// .health(&status);
+///
+/// assert!(status.is_ready());
+/// ```
+#[derive(Clone, Debug)]
+pub struct Health<S> {
+    new_service: S,
+    status: Status,
+}
+
+impl<S> Health<S> {
+    /// Wraps `new_service`, reporting readiness from `status`.
+    pub fn new(status: Status, new_service: S) -> Self {
+        Health {
+            new_service,
+            status,
+        }
+    }
+}
+
+impl<S> NewService for Health<S>
+where
+    S: NewService<ReqBody = Body, ResBody = Body>,
+{
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = S::Error;
+    type Service = HealthService<S::Service>;
+    type Future = Probing<S::Future>;
+    type InitError = S::InitError;
+
+    fn new_service(&self) -> Self::Future {
+        self.new_service_for(None)
+    }
+
+    fn new_service_for(&self, peer_cred: Option<&PeerCred>) -> Self::Future {
+        Probing {
+            future: self.new_service.new_service_for(peer_cred),
+            status: Some(self.status.clone()),
+        }
+    }
+}
+
+pin_project! {
+    /// A future resolving to a `HealthService` once the wrapped service has been created.
+    pub struct Probing<F> {
+        #[pin]
+        future: F,
+        status: Option<Status>,
+    }
+}
+
+impl<F, S, E> Future for Probing<F>
+where
+    F: Future<Output = Result<S, E>>,
+{
+    type Output = Result<HealthService<S>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let inner = ready!(this.future.poll(cx))?;
+        Poll::Ready(Ok(HealthService {
+            inner,
+            status: this.status.take().expect("polled after complete"),
+        }))
+    }
+}
+
+/// A `Service` answering health and readiness probes in front of another `Service`.
+#[derive(Clone, Debug)]
+pub struct HealthService<S> {
+    inner: S,
+    status: Status,
+}
+
+impl<S> Service<Request<Body>> for HealthService<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = Either<Ready<Result<Response<Body>, S::Error>>, S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return Either::Right(self.inner.call(req));
+        }
+        let ok = match req.uri().path() {
+            HEALTHZ => true,
+            READYZ => self.status.is_ready(),
+            _ => return Either::Right(self.inner.call(req)),
+        };
+        let (status, body) = if ok {
+            (StatusCode::OK, "ok\n")
+        } else {
+            (StatusCode::SERVICE_UNAVAILABLE, "not ready\n")
+        };
+        let mut response = Response::new(Body::from(body));
+        *response.status_mut() = status;
+        Either::Left(future::ok(response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use hyper::service::service_fn;
+
+    #[test]
+    fn readyz_reflects_status() {
+        let status = Status::new();
+        let health = Health::new(status.clone(), || {
+            service_fn(|_| async { Ok::<_, hyper::Error>(Response::new(Body::empty())) })
+        });
+        let mut service = block_on(health.new_service()).unwrap();
+        let probe = |service: &mut HealthService<_>, path| {
+            let req = Request::get(path).body(Body::empty()).unwrap();
+            block_on(service.call(req)).unwrap().status()
+        };
+
+        assert_eq!(probe(&mut service, READYZ), StatusCode::SERVICE_UNAVAILABLE);
+        status.set_serving(true);
+        assert_eq!(probe(&mut service, READYZ), StatusCode::OK);
+        status.set_ready(false);
+        assert_eq!(probe(&mut service, READYZ), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(probe(&mut service, HEALTHZ), StatusCode::OK);
+    }
+}
@@ -0,0 +1,97 @@
+//! Serving a single connection inherited as standard input
+//!
+//! Super-servers such as inetd, or systemd sockets with `Accept=yes`, accept connections
+//! themselves and spawn a process per connection, with the connected socket as its standard
+//! input and output. `run` serves HTTP on that socket until the client closes it, so the
+//! process can then simply exit.
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! use hyper::service::service_fn;
+//! use hyperlocal::server::inetd;
+//!
+//! fn main() {
+//!     if let Err(err) = inetd::run(|| {
+//!         service_fn(|req| async move { Ok::<_, hyper::Error>(hyper::Response::new(req.into_body())) })
+//!     }) {
+//!         eprintln!("failed to serve connection: {}", err);
+//!         std::process::exit(1);
+//!     }
+//! }
+//! ```
+
+// Std lib
+use std::error::Error as StdError;
+use std::io;
+use std::mem;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixStream as StdUnixStream;
+
+// Third party
+use hyper::server::conn::Http as HyperHttp;
+use hyper::service::Service;
+use hyper::{Body, Request};
+use tokio::net::UnixStream;
+use tokio::runtime::Runtime;
+
+use super::socket::Socket;
+use super::{Connecting, NewService};
+use crate::credentials::PeerCred;
+
+/// The descriptor super-servers hand connections over on.
+const STDIN: RawFd = 0;
+
+/// Serve the connected socket inherited as standard input on a new tokio runtime, returning
+/// once the connection has closed.
+///
+/// Fails with `io::ErrorKind::InvalidInput` if standard input isn't a socket. Standard input
+/// is closed when this returns.
+pub fn run<S>(new_service: S) -> io::Result<()>
+where
+    S: NewService<ReqBody = Body>,
+    S::Future: Send + 'static,
+    S::Service: Send + 'static,
+    <S::Service as Service<Request<Body>>>::Future: Send + 'static,
+{
+    let stream = inherited()?;
+    let runtime = Runtime::new()?;
+    runtime.block_on(async move {
+        let stream = UnixStream::from_std(stream)?;
+        let peer_cred = PeerCred::of(&stream).ok();
+        let connecting = Connecting {
+            service_future: new_service.new_service_for(peer_cred.as_ref()),
+            peer_cred,
+            fds: None,
+            active: None,
+            tracked: None,
+            stream: Some(Socket::new(stream).with_peer_cred(peer_cred)),
+            protocol: HyperHttp::new(),
+        };
+        let connection = connecting.await.map_err(|e| {
+            let e: Box<dyn StdError + Send + Sync> = e.into();
+            io::Error::other(format!("failed to serve connection: {}", e))
+        })?;
+        connection
+            .with_upgrades()
+            .await
+            .map_err(|e| io::Error::other(format!("failed to serve connection: {}", e)))
+    })
+}
+
+/// Take ownership of standard input, checking it is a socket.
+fn inherited() -> io::Result<StdUnixStream> {
+    let mut stat: libc::stat = unsafe { mem::zeroed() };
+    if unsafe { libc::fstat(STDIN, &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if stat.st_mode & libc::S_IFMT != libc::S_IFSOCK {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "standard input is not a socket",
+        ));
+    }
+    let stream = unsafe { StdUnixStream::from_raw_fd(STDIN) };
+    stream.set_nonblocking(true)?;
+    Ok(stream)
+}
@@ -0,0 +1,394 @@
+//! Request body size limits
+//!
+//! Local clients are trusted to be on the same host, not to be well behaved. Without a limit
+//! a client can stream an unbounded body into whatever buffer a handler collects it into.
+//!
+//! Clients uploading large bodies can send `Expect: 100-continue` and hold the body back until
+//! the server is ready for it. hyper only answers `100 Continue` once a service starts reading
+//! the body, so a request `Limit` turns away by its `Content-Length` is answered with
+//! `413 Payload Too Large` before the client has sent any of it. hyper's own client sends the
+//! header but not the interim wait, streaming the body straight away, so clients wanting the
+//! wait use `hyperlocal::client::expect::send`.
+
+// Std lib
+use std::error;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+// Third party
+use bytes::Bytes;
+use futures::future::{self, Either, Ready};
+use futures::{ready, Stream, StreamExt};
+use hyper::header::CONTENT_LENGTH;
+use hyper::service::Service;
+use hyper::{Body, Request, Response, StatusCode};
+use pin_project_lite::pin_project;
+
+use super::NewService;
+use crate::credentials::PeerCred;
+
+type BoxError = Box<dyn error::Error + Send + Sync>;
+
+/// A request extension overriding the server-wide body limit for a single request.
+///
+/// When present in a request's extensions, the contained number of bytes is used
+/// in place of the limit a `Limit` was created with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BodyLimit(pub u64);
+
+/// A `NewService` which caps the size of request bodies handed to the services it wraps.
+///
+/// Requests declaring a `Content-Length` over the limit are answered with
+/// `413 Payload Too Large` without reaching the wrapped service. Bodies without a declared
+/// length, such as chunked ones, are checked as they stream, and yield a
+/// `LengthLimitExceeded` error to the wrapped service once the limit is crossed. A service
+/// failing with that error, or with one caused by it, is answered with
+/// `413 Payload Too Large` too.
+///
+/// # Examples
+///
+/// ```rust
+/// use hyper::service::service_fn;
+/// use hyperlocal::server::{limit::Limit, Server};
+///
+/// # if let Err(err) = std::fs::remove_file("hyperlocal_test_limit_server.sock") {
+/// #   if err.kind() != std::io::ErrorKind::NotFound {
+/// #     panic!("{}", err)
+/// #   }
+/// # }
+/// #
+/// let server = Server::bind(
+///    "hyperlocal_test_limit_server.sock",
+///    Limit::new(
+///        64 * 1024,
+///        || service_fn(|req| async move { Ok::<_, hyper::Error>(hyper::Response::new(req.into_body())) })
+///    )
+/// ).unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct Limit<S> {
+    new_service: S,
+    limit: u64,
+}
+
+impl<S> Limit<S> {
+    /// Wraps `new_service` so that request bodies may be at most `limit` bytes.
+    pub fn new(limit: u64, new_service: S) -> Self {
+        Limit { new_service, limit }
+    }
+}
+
+impl<S> NewService for Limit<S>
+where
+    S: NewService<ReqBody = Body, ResBody = Body>,
+{
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = BoxError;
+    type Service = LimitService<S::Service>;
+    type Future = Limiting<S::Future>;
+    type InitError = S::InitError;
+
+    fn new_service(&self) -> Self::Future {
+        self.new_service_for(None)
+    }
+
+    fn new_service_for(&self, peer_cred: Option<&PeerCred>) -> Self::Future {
+        Limiting {
+            future: self.new_service.new_service_for(peer_cred),
+            limit: self.limit,
+        }
+    }
+}
+
+pin_project! {
+    /// A future resolving to a `LimitService` once the wrapped service has been created.
+    pub struct Limiting<F> {
+        #[pin]
+        future: F,
+        limit: u64,
+    }
+}
+
+impl<F, S, E> Future for Limiting<F>
+where
+    F: Future<Output = Result<S, E>>,
+{
+    type Output = Result<LimitService<S>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let inner = ready!(this.future.poll(cx))?;
+        Poll::Ready(Ok(LimitService {
+            inner,
+            limit: *this.limit,
+        }))
+    }
+}
+
+/// A `Service` enforcing a request body limit in front of another `Service`.
+#[derive(Clone, Debug)]
+pub struct LimitService<S> {
+    inner: S,
+    limit: u64,
+}
+
+impl<S> Service<Request<Body>> for LimitService<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+    S::Error: Into<BoxError>,
+{
+    type Response = Response<Body>;
+    type Error = BoxError;
+    type Future = Either<Limited<S::Future>, Ready<Result<Response<Body>, BoxError>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let limit = req
+            .extensions()
+            .get::<BodyLimit>()
+            .map_or(self.limit, |override_limit| override_limit.0);
+        let declared = req
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok());
+        if declared.is_some_and(|length| length > limit) {
+            return Either::Right(future::ok(payload_too_large()));
+        }
+        let (parts, body) = req.into_parts();
+        let body = Body::wrap_stream(LimitedBody {
+            body,
+            limit,
+            remaining: limit,
+        });
+        Either::Left(Limited {
+            future: self.inner.call(Request::from_parts(parts, body)),
+        })
+    }
+}
+
+pin_project! {
+    /// A future resolving to a `LimitService`'s response, or to `413 Payload Too Large` if
+    /// the wrapped service failed on the request body crossing its limit.
+    pub struct Limited<F> {
+        #[pin]
+        future: F,
+    }
+}
+
+impl<F, E> Future for Limited<F>
+where
+    F: Future<Output = Result<Response<Body>, E>>,
+    E: Into<BoxError>,
+{
+    type Output = Result<Response<Body>, BoxError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match ready!(self.project().future.poll(cx)) {
+            Ok(response) => Poll::Ready(Ok(response)),
+            Err(err) => {
+                let err = err.into();
+                if exceeded(&*err) {
+                    Poll::Ready(Ok(payload_too_large()))
+                } else {
+                    Poll::Ready(Err(err))
+                }
+            }
+        }
+    }
+}
+
+/// Return true if `err`, or any error it was caused by, is a `LengthLimitExceeded`.
+fn exceeded(err: &(dyn error::Error + 'static)) -> bool {
+    let mut cause = Some(err);
+    while let Some(err) = cause {
+        if err.is::<LengthLimitExceeded>() {
+            return true;
+        }
+        cause = err.source();
+    }
+    false
+}
+
+/// The error yielded by a limited request body when it grows past its limit.
+#[derive(Debug)]
+pub struct LengthLimitExceeded {
+    limit: u64,
+}
+
+impl LengthLimitExceeded {
+    /// Return the limit, in bytes, which was exceeded.
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+}
+
+impl fmt::Display for LengthLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "request body exceeded limit of {} bytes", self.limit)
+    }
+}
+
+impl error::Error for LengthLimitExceeded {}
+
+struct LimitedBody<B> {
+    body: B,
+    limit: u64,
+    remaining: u64,
+}
+
+impl<B, E> Stream for LimitedBody<B>
+where
+    B: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: Into<Box<dyn error::Error + Send + Sync>>,
+{
+    type Item = Result<Bytes, Box<dyn error::Error + Send + Sync>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match ready!(self.body.poll_next_unpin(cx)) {
+            Some(Ok(chunk)) => {
+                let length = chunk.len() as u64;
+                if length > self.remaining {
+                    let limit = self.limit;
+                    return Poll::Ready(Some(Err(Box::new(LengthLimitExceeded { limit }))));
+                }
+                self.remaining -= length;
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            Some(Err(err)) => Poll::Ready(Some(Err(err.into()))),
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+fn payload_too_large() -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::PAYLOAD_TOO_LARGE;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::{stream, TryStreamExt};
+
+    fn limited(
+        chunks: Vec<&'static str>,
+        limit: u64,
+    ) -> LimitedBody<impl Stream<Item = Result<Bytes, hyper::Error>>> {
+        LimitedBody {
+            body: stream::iter(chunks.into_iter().map(|chunk| Ok(Bytes::from(chunk)))),
+            limit,
+            remaining: limit,
+        }
+    }
+
+    #[test]
+    fn limited_bodies_within_limit_pass_through() {
+        let body: Vec<u8> = block_on(
+            limited(vec!["foo", "bar"], 6)
+                .map_ok(|chunk| chunk.to_vec())
+                .try_concat(),
+        )
+        .unwrap();
+        assert_eq!(&body[..], b"foobar");
+    }
+
+    #[test]
+    fn limited_bodies_over_limit_fail() {
+        let err = block_on(limited(vec!["foo", "bar"], 5).try_collect::<Vec<_>>()).unwrap_err();
+        assert!(err.downcast_ref::<LengthLimitExceeded>().is_some());
+    }
+
+    #[test]
+    fn expect_continue_is_only_answered_within_limit() {
+        use crate::server::Server;
+        use crate::testing::TempSocket;
+        use hyper::service::service_fn;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::UnixStream;
+
+        let socket = TempSocket::new().unwrap();
+        let server = Server::bind(
+            socket.path(),
+            Limit::new(16, || {
+                service_fn(|req: Request<Body>| async move {
+                    let body = hyper::body::to_bytes(req.into_body()).await?;
+                    Ok::<_, hyper::Error>(Response::new(Body::from(body)))
+                })
+            }),
+        )
+        .unwrap();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            tokio::spawn(server.serve.serve_with_shutdown(future::pending::<()>()));
+            let post = |length| {
+                format!(
+                    "POST / HTTP/1.1\r\nhost: localhost\r\ncontent-length: {}\r\n\
+                     expect: 100-continue\r\nconnection: close\r\n\r\n",
+                    length
+                )
+            };
+
+            let mut rejected = UnixStream::connect(socket.path()).await.unwrap();
+            rejected.write_all(post(1 << 20).as_bytes()).await.unwrap();
+            let mut response = String::new();
+            rejected.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 413 "), "{}", response);
+
+            let mut accepted = UnixStream::connect(socket.path()).await.unwrap();
+            accepted.write_all(post(5).as_bytes()).await.unwrap();
+            let mut interim = [0; 25];
+            accepted.read_exact(&mut interim).await.unwrap();
+            assert_eq!(&interim[..], b"HTTP/1.1 100 Continue\r\n\r\n");
+            accepted.write_all(b"hello").await.unwrap();
+            let mut response = String::new();
+            accepted.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+            assert!(response.ends_with("hello"), "{}", response);
+        });
+    }
+
+    #[test]
+    fn chunked_bodies_over_limit_are_answered_with_payload_too_large() {
+        use crate::server::Server;
+        use crate::testing::TempSocket;
+        use hyper::service::service_fn;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::UnixStream;
+
+        let socket = TempSocket::new().unwrap();
+        let server = Server::bind(
+            socket.path(),
+            Limit::new(8, || {
+                service_fn(|req: Request<Body>| async move {
+                    let body = hyper::body::to_bytes(req.into_body()).await?;
+                    Ok::<_, hyper::Error>(Response::new(Body::from(body)))
+                })
+            }),
+        )
+        .unwrap();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            tokio::spawn(server.serve.serve_with_shutdown(future::pending::<()>()));
+            let mut stream = UnixStream::connect(socket.path()).await.unwrap();
+            stream
+                .write_all(
+                    b"POST / HTTP/1.1\r\nhost: localhost\r\ntransfer-encoding: chunked\r\n\
+                      connection: close\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n",
+                )
+                .await
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 413 "), "{}", response);
+        });
+    }
+}
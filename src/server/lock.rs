@@ -0,0 +1,79 @@
+//! Guarding a socket path against being bound by more than one process
+
+// Std lib
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// An exclusive advisory lock on a socket path.
+///
+/// The lock is taken with `flock` on a sidecar file named after the socket, with a `.lock`
+/// suffix, and is released when the `BindLock` is dropped or the process exits. The sidecar
+/// file itself is left in place, since removing it would let two processes lock
+/// different files for the same path.
+///
+/// Holding the lock means no other cooperating process is serving on the socket path, so
+/// a socket file found there is stale and safe to remove before binding.
+#[derive(Debug)]
+pub struct BindLock {
+    // held open for as long as the lock is
+    _file: File,
+    path: PathBuf,
+}
+
+impl BindLock {
+    /// Lock `socket_path`, failing with `io::ErrorKind::AddrInUse` if another process
+    /// already holds the lock.
+    pub fn acquire<P>(socket_path: P) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let socket_path = socket_path.as_ref();
+        let mut path = OsString::from(socket_path);
+        path.push(".lock");
+        let path = PathBuf::from(path);
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(&path)?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::WouldBlock {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} is locked by another process", socket_path.display()),
+                ));
+            }
+            return Err(err);
+        }
+        Ok(BindLock { _file: file, path })
+    }
+
+    /// Return the path of the sidecar lock file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn bind_locks_are_exclusive() {
+        let socket_path = "hyperlocal_test_bind_lock.sock";
+        let lock = BindLock::acquire(socket_path).unwrap();
+        // flock locks are per open file, so a second open in the same process contends
+        let err = BindLock::acquire(socket_path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        drop(lock);
+        let lock = BindLock::acquire(socket_path).unwrap();
+        fs::remove_file(lock.path()).unwrap();
+    }
+}
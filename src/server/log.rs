@@ -0,0 +1,587 @@
+//! Per-request access logging
+//!
+//! Knowing which local process made which request is often the reason to serve over a unix
+//! domain socket in the first place, so each entry records the peer's credentials alongside
+//! the request line, response status and latency.
+//!
+//! Entries are recorded once the response body has been written, or dropped because the
+//! connection closed first, and are written to standard error by default. The `Json` sink
+//! writes them as one JSON object per line instead, for log shippers to ingest.
+
+// Std lib
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::{self, Write};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Third party
+use bytes::Buf;
+use futures::ready;
+use hyper::body::{HttpBody, SizeHint};
+use hyper::service::Service;
+use hyper::{HeaderMap, Method, Request, Response, StatusCode};
+use pin_project_lite::pin_project;
+
+use super::request_id::RequestId;
+use super::NewService;
+use crate::credentials::PeerCred;
+
+/// A destination for access log entries.
+///
+/// Any `Fn(&Entry)` closure may be used as a `Sink`.
+pub trait Sink {
+    /// Record a single access log entry.
+    fn record(&self, entry: &Entry);
+}
+
+impl<F> Sink for F
+where
+    F: Fn(&Entry),
+{
+    fn record(&self, entry: &Entry) {
+        (*self)(entry)
+    }
+}
+
+/// A `Sink` writing one line per entry to standard error.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stderr;
+
+impl Sink for Stderr {
+    fn record(&self, entry: &Entry) {
+        let _ = writeln!(io::stderr(), "{}", entry);
+    }
+}
+
+/// A `Sink` writing one JSON object per entry and line to a writer.
+///
+/// Objects have the fields `time`, the time the request was received in seconds since the
+/// unix epoch, `method`, `path`, `status`, `uid`, `gid`, `pid`, `request_id`, `bytes`, the
+/// number of response body bytes written, and the timings `head_ms`, until the service
+/// produced a response head, and `total_ms`, until its body was written. Fields which are
+/// unknown are `null`.
+///
+/// # Examples
+///
+/// ```rust
+/// use hyper::service::service_fn;
+/// use hyperlocal::server::{log::{AccessLog, Json}, Server};
+///
+/// # if let Err(err) = std::fs::remove_file("hyperlocal_test_json_log_server.sock") {
+/// #   if err.kind() != std::io::ErrorKind::NotFound {
+/// #     panic!("{}", err)
+/// #   }
+/// # }
+/// #
+/// let server = Server::bind(
+///    "hyperlocal_test_json_log_server.sock",
+///    AccessLog::with_sink(
+///        || service_fn(|req| async move { Ok::<_, hyper::Error>(hyper::Response::new(req.into_body())) }),
+///        Json::new(std::io::stdout()),
+///    ),
+/// ).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct Json<W> {
+    writer: Mutex<W>,
+}
+
+impl<W> Json<W> {
+    /// Write entries to `writer`.
+    pub fn new(writer: W) -> Self {
+        Json {
+            writer: Mutex::new(writer),
+        }
+    }
+}
+
+impl Json<File> {
+    /// Append entries to the file at `path`, creating it if need be.
+    pub fn append<P>(path: P) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        Ok(Json::new(file))
+    }
+}
+
+impl<W> Sink for Json<W>
+where
+    W: Write,
+{
+    fn record(&self, entry: &Entry) {
+        let mut line = entry.to_json();
+        line.push('\n');
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        // write each entry whole, so that entries from other threads can't interleave
+        let _ = writer
+            .write_all(line.as_bytes())
+            .and_then(|_| writer.flush());
+    }
+}
+
+/// A record of a single request served.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    method: Method,
+    path: String,
+    status: Option<StatusCode>,
+    started: SystemTime,
+    latency: Duration,
+    total: Duration,
+    bytes: u64,
+    peer_cred: Option<PeerCred>,
+    request_id: Option<RequestId>,
+}
+
+impl Entry {
+    /// Return the request method.
+    pub fn method(&self) -> &Method {
+        &self.method
+    }
+
+    /// Return the request path, excluding any query string.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Return the response status, or `None` if the service failed to produce a response.
+    pub fn status(&self) -> Option<StatusCode> {
+        self.status
+    }
+
+    /// Return the time the request was received.
+    pub fn started(&self) -> SystemTime {
+        self.started
+    }
+
+    /// Return the time taken for the service to produce a response head.
+    pub fn latency(&self) -> Duration {
+        self.latency
+    }
+
+    /// Return the time taken to produce the response and write its body, or until the body
+    /// was dropped unfinished.
+    pub fn total(&self) -> Duration {
+        self.total
+    }
+
+    /// Return the number of response body bytes written.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Return the credentials of the process which made the request, if known.
+    pub fn peer_cred(&self) -> Option<&PeerCred> {
+        self.peer_cred.as_ref()
+    }
+
+    /// Return the id assigned to the request by a `request_id::RequestIds` wrapping the
+    /// access log, if any.
+    pub fn request_id(&self) -> Option<&RequestId> {
+        self.request_id.as_ref()
+    }
+
+    /// Encode this entry as a JSON object, as written by the `Json` sink.
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{");
+        let time = self
+            .started
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        json.push_str(&format!("\"time\":{:.6},\"method\":", time));
+        push_json_str(&mut json, self.method.as_str());
+        json.push_str(",\"path\":");
+        push_json_str(&mut json, &self.path);
+        json.push_str(",\"status\":");
+        push_json_opt(&mut json, self.status.map(|status| status.as_u16()));
+        json.push_str(",\"uid\":");
+        push_json_opt(&mut json, self.peer_cred.map(|peer_cred| peer_cred.uid()));
+        json.push_str(",\"gid\":");
+        push_json_opt(&mut json, self.peer_cred.map(|peer_cred| peer_cred.gid()));
+        json.push_str(",\"pid\":");
+        push_json_opt(
+            &mut json,
+            self.peer_cred.and_then(|peer_cred| peer_cred.pid()),
+        );
+        json.push_str(",\"request_id\":");
+        match self.request_id {
+            Some(ref request_id) => push_json_str(&mut json, &request_id.to_string()),
+            None => json.push_str("null"),
+        }
+        json.push_str(&format!(
+            ",\"bytes\":{},\"head_ms\":{:.3},\"total_ms\":{:.3}}}",
+            self.bytes,
+            millis(self.latency),
+            millis(self.total)
+        ));
+        json
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1_000.0
+}
+
+fn push_json_opt<T: fmt::Display>(json: &mut String, value: Option<T>) {
+    match value {
+        Some(value) => json.push_str(&value.to_string()),
+        None => json.push_str("null"),
+    }
+}
+
+/// Append `value` to `json` as a quoted, escaped JSON string.
+fn push_json_str(json: &mut String, value: &str) {
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.peer_cred {
+            Some(peer_cred) => {
+                write!(f, "uid={} gid={} ", peer_cred.uid(), peer_cred.gid())?;
+                match peer_cred.pid() {
+                    Some(pid) => write!(f, "pid={} ", pid)?,
+                    None => f.write_str("pid=- ")?,
+                }
+            }
+            None => f.write_str("uid=- gid=- pid=- ")?,
+        }
+        write!(f, "\"{} {}\" ", self.method, self.path)?;
+        match self.status {
+            Some(status) => write!(f, "{} ", status.as_u16())?,
+            None => f.write_str("- ")?,
+        }
+        let latency = self.latency;
+        write!(
+            f,
+            "{}.{:03}ms",
+            latency.as_secs() * 1_000 + u64::from(latency.subsec_millis()),
+            latency.subsec_micros() % 1_000
+        )
+    }
+}
+
+/// A `NewService` recording an access log `Entry` for every request served by the services
+/// it wraps.
+///
+/// # Examples
+///
+/// ```rust
+/// use hyper::service::service_fn;
+/// use hyperlocal::server::{log::{AccessLog, Entry}, Server};
+///
+/// # if let Err(err) = std::fs::remove_file("hyperlocal_test_access_log_server.sock") {
+/// #   if err.kind() != std::io::ErrorKind::NotFound {
+/// #     panic!("{}", err)
+/// #   }
+/// # }
+/// #
+/// let server = Server::bind(
+///    "hyperlocal_test_access_log_server.sock",
+///    AccessLog::with_sink(
+///        || service_fn(|req| async move { Ok::<_, hyper::Error>(hyper::Response::new(req.into_body())) }),
+///        |entry: &Entry| println!("{}", entry),
+///    ),
+/// ).unwrap();
+/// ```
+pub struct AccessLog<S, L = Stderr> {
+    new_service: S,
+    sink: Arc<L>,
+}
+
+impl<S> AccessLog<S> {
+    /// Wraps `new_service`, logging to standard error.
+    pub fn new(new_service: S) -> Self {
+        AccessLog::with_sink(new_service, Stderr)
+    }
+}
+
+impl<S, L> AccessLog<S, L> {
+    /// Wraps `new_service`, logging to the provided `Sink`.
+    pub fn with_sink(new_service: S, sink: L) -> Self {
+        AccessLog {
+            new_service,
+            sink: Arc::new(sink),
+        }
+    }
+}
+
+impl<S, L> NewService for AccessLog<S, L>
+where
+    S: NewService,
+    S::ResBody: Unpin,
+    L: Sink + Send + Sync + 'static,
+{
+    type ReqBody = S::ReqBody;
+    type ResBody = LoggedBody<S::ResBody, L>;
+    type Error = S::Error;
+    type Service = AccessLogService<S::Service, L>;
+    type Future = Logging<S::Future, L>;
+    type InitError = S::InitError;
+
+    fn new_service(&self) -> Self::Future {
+        self.new_service_for(None)
+    }
+
+    fn new_service_for(&self, peer_cred: Option<&PeerCred>) -> Self::Future {
+        Logging {
+            future: self.new_service.new_service_for(peer_cred),
+            sink: Some(self.sink.clone()),
+        }
+    }
+}
+
+pin_project! {
+    /// A future resolving to an `AccessLogService` once the wrapped service has been created.
+    pub struct Logging<F, L> {
+        #[pin]
+        future: F,
+        sink: Option<Arc<L>>,
+    }
+}
+
+impl<F, L, S, E> Future for Logging<F, L>
+where
+    F: Future<Output = Result<S, E>>,
+{
+    type Output = Result<AccessLogService<S, L>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let inner = ready!(this.future.poll(cx))?;
+        Poll::Ready(Ok(AccessLogService {
+            inner,
+            sink: this.sink.take().expect("polled after complete"),
+        }))
+    }
+}
+
+/// A `Service` recording an access log `Entry` for each request it serves.
+pub struct AccessLogService<S, L> {
+    inner: S,
+    sink: Arc<L>,
+}
+
+impl<S, L, B, R> Service<Request<B>> for AccessLogService<S, L>
+where
+    S: Service<Request<B>, Response = Response<R>>,
+    L: Sink + Send + Sync + 'static,
+{
+    type Response = Response<LoggedBody<R, L>>;
+    type Error = S::Error;
+    type Future = Logged<S::Future, L>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let entry = Entry {
+            method: req.method().clone(),
+            path: req.uri().path().to_owned(),
+            status: None,
+            started: SystemTime::now(),
+            latency: Duration::default(),
+            total: Duration::default(),
+            bytes: 0,
+            peer_cred: req.extensions().get::<PeerCred>().cloned(),
+            request_id: req.extensions().get::<RequestId>().cloned(),
+        };
+        Logged {
+            future: self.inner.call(req),
+            started: Instant::now(),
+            entry: Some(entry),
+            sink: self.sink.clone(),
+        }
+    }
+}
+
+pin_project! {
+    /// A future timing the response head of a request, whose body records its access log
+    /// `Entry` once written.
+    pub struct Logged<F, L> {
+        #[pin]
+        future: F,
+        started: Instant,
+        entry: Option<Entry>,
+        sink: Arc<L>,
+    }
+}
+
+impl<F, L, B, E> Future for Logged<F, L>
+where
+    F: Future<Output = Result<Response<B>, E>>,
+    L: Sink,
+{
+    type Output = Result<Response<LoggedBody<B, L>>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let result = ready!(this.future.poll(cx));
+        let mut entry = this.entry.take().expect("polled after complete");
+        entry.latency = this.started.elapsed();
+        match result {
+            Ok(response) => {
+                entry.status = Some(response.status());
+                let started = *this.started;
+                let sink = this.sink.clone();
+                Poll::Ready(Ok(response.map(|body| LoggedBody {
+                    body,
+                    started,
+                    entry: Some(entry),
+                    sink,
+                })))
+            }
+            Err(err) => {
+                entry.total = entry.latency;
+                this.sink.record(&entry);
+                Poll::Ready(Err(err))
+            }
+        }
+    }
+}
+
+/// A response body recording its request's access log `Entry` once written or dropped.
+pub struct LoggedBody<B, L: Sink> {
+    body: B,
+    started: Instant,
+    entry: Option<Entry>,
+    sink: Arc<L>,
+}
+
+impl<B, L: Sink> LoggedBody<B, L> {
+    fn record(&mut self) {
+        if let Some(mut entry) = self.entry.take() {
+            entry.total = self.started.elapsed();
+            self.sink.record(&entry);
+        }
+    }
+}
+
+impl<B, L> HttpBody for LoggedBody<B, L>
+where
+    B: HttpBody + Unpin,
+    L: Sink,
+{
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let polled = Pin::new(&mut self.body).poll_data(cx);
+        match polled {
+            Poll::Ready(Some(Ok(ref data))) => {
+                if let Some(ref mut entry) = self.entry {
+                    entry.bytes += data.remaining() as u64;
+                }
+            }
+            Poll::Pending => (),
+            Poll::Ready(None) | Poll::Ready(Some(Err(_))) => self.record(),
+        }
+        polled
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.body).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+impl<B, L: Sink> Drop for LoggedBody<B, L> {
+    fn drop(&mut self) {
+        self.record();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_display_peer_request_status_and_latency() {
+        let entry = Entry {
+            method: Method::GET,
+            path: "/containers/json".into(),
+            status: Some(StatusCode::OK),
+            started: UNIX_EPOCH,
+            latency: Duration::from_micros(1_234),
+            total: Duration::from_micros(1_234),
+            bytes: 0,
+            peer_cred: Some(PeerCred::new(1000, 100, Some(4242))),
+            request_id: None,
+        };
+        assert_eq!(
+            entry.to_string(),
+            "uid=1000 gid=100 pid=4242 \"GET /containers/json\" 200 1.234ms"
+        );
+    }
+
+    #[test]
+    fn entries_display_unknown_peers_and_failures() {
+        let entry = Entry {
+            method: Method::POST,
+            path: "/".into(),
+            status: None,
+            started: UNIX_EPOCH,
+            latency: Duration::from_millis(2),
+            total: Duration::from_millis(2),
+            bytes: 0,
+            peer_cred: None,
+            request_id: None,
+        };
+        assert_eq!(entry.to_string(), "uid=- gid=- pid=- \"POST /\" - 2.000ms");
+    }
+
+    #[test]
+    fn entries_encode_as_json() {
+        let entry = Entry {
+            method: Method::GET,
+            path: "/images/\"latest\"".into(),
+            status: Some(StatusCode::NOT_FOUND),
+            started: UNIX_EPOCH + Duration::from_millis(1_500),
+            latency: Duration::from_micros(1_234),
+            total: Duration::from_micros(5_678),
+            bytes: 42,
+            peer_cred: Some(PeerCred::new(1000, 100, None)),
+            request_id: None,
+        };
+        assert_eq!(
+            entry.to_json(),
+            "{\"time\":1.500000,\"method\":\"GET\",\"path\":\"/images/\\\"latest\\\"\",\
+             \"status\":404,\"uid\":1000,\"gid\":100,\"pid\":null,\"request_id\":null,\
+             \"bytes\":42,\"head_ms\":1.234,\"total_ms\":5.678}"
+        );
+    }
+}
@@ -0,0 +1,150 @@
+//! Socket-level server metrics
+
+// Std lib
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Hooks invoked as a server accepts, rejects and closes connections.
+///
+/// Every method has an empty default, so implementations need only override the events
+/// they are interested in. Register an implementation with `Server::metrics`.
+pub trait Metrics: Send + Sync {
+    /// A connection was accepted.
+    fn connection_accepted(&self) {}
+
+    /// A connection previously accepted was closed, whether or not it was served.
+    fn connection_closed(&self) {}
+
+    /// Accepting a connection failed.
+    fn accept_failed(&self, _err: &io::Error) {}
+
+    /// A connection was dropped by the server's accept filter.
+    fn connection_rejected(&self) {}
+
+    /// A request was answered with `403 Forbidden` by a `guard::Guard`.
+    fn request_forbidden(&self) {}
+}
+
+/// A `Metrics` implementation keeping simple counts of connection events.
+///
+/// # Examples
+///
+/// ```rust
+/// use std::sync::Arc;
+///
+/// use hyper::service::service_fn;
+/// use hyperlocal::server::{metrics::Counters, Server};
+///
+/// # if let Err(err) = std::fs::remove_file("hyperlocal_test_metrics_server.sock") {
+/// #   if err.kind() != std::io::ErrorKind::NotFound {
+/// #     panic!("{}", err)
+/// #   }
+/// # }
+/// #
+/// let counters = Arc::new(Counters::default());
+/// let server = Server::bind(
+///    "hyperlocal_test_metrics_server.sock",
+///    || service_fn(|req| async move { Ok::<_, hyper::Error>(hyper::Response::new(req.into_body())) })
+/// )
+/// .unwrap()
+/// .metrics(counters.clone());
+///
+/// assert_eq!(counters.active(), 0);
+/// ```
+#[derive(Debug, Default)]
+pub struct Counters {
+    accepted: AtomicUsize,
+    closed: AtomicUsize,
+    accept_errors: AtomicUsize,
+    rejected: AtomicUsize,
+    forbidden: AtomicUsize,
+}
+
+impl Counters {
+    /// Return the number of connections accepted.
+    pub fn accepted(&self) -> usize {
+        self.accepted.load(Ordering::Relaxed)
+    }
+
+    /// Return the number of connections currently open.
+    pub fn active(&self) -> usize {
+        // read closed first, so a concurrent close can't make it overtake accepted
+        let closed = self.closed.load(Ordering::Acquire);
+        self.accepted.load(Ordering::Acquire).saturating_sub(closed)
+    }
+
+    /// Return the number of failed attempts to accept a connection.
+    pub fn accept_errors(&self) -> usize {
+        self.accept_errors.load(Ordering::Relaxed)
+    }
+
+    /// Return the number of connections dropped by the server's accept filter.
+    pub fn rejected(&self) -> usize {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Return the number of requests a `guard::Guard` counting into these counters answered
+    /// with `403 Forbidden`.
+    pub fn forbidden(&self) -> usize {
+        self.forbidden.load(Ordering::Relaxed)
+    }
+}
+
+impl Metrics for Counters {
+    fn connection_accepted(&self) {
+        self.accepted.fetch_add(1, Ordering::AcqRel);
+    }
+
+    fn connection_closed(&self) {
+        self.closed.fetch_add(1, Ordering::AcqRel);
+    }
+
+    fn accept_failed(&self, _err: &io::Error) {
+        self.accept_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn connection_rejected(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn request_forbidden(&self) {
+        self.forbidden.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// A guard reporting its connection as closed when dropped.
+pub(crate) struct Active {
+    metrics: Arc<dyn Metrics>,
+}
+
+impl Active {
+    pub(crate) fn new(metrics: Arc<dyn Metrics>) -> Self {
+        metrics.connection_accepted();
+        Active { metrics }
+    }
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        self.metrics.connection_closed();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_track_active_connections() {
+        let counters = Arc::new(Counters::default());
+        let first = Active::new(counters.clone());
+        let second = Active::new(counters.clone());
+        assert_eq!(counters.active(), 2);
+        drop(first);
+        assert_eq!(counters.accepted(), 2);
+        assert_eq!(counters.active(), 1);
+        drop(second);
+        assert_eq!(counters.active(), 0);
+    }
+}
@@ -1,29 +1,204 @@
 //! Hyper server bindings for unix domain sockets
+//!
+//! # Frameworks
+//!
+//! Frameworks built on hyper 0.14 can be served without glue of their own. An axum 0.6
+//! `Router` is a hyper service as it is, so a `Server` can serve clones of it, and the
+//! `PeerCred` inserted into every request is available to handlers through axum's
+//! `Extension` extractor:
+//!
+//! ```rust,ignore
+//! use axum::{routing::get, Extension, Router};
+//! use hyperlocal::{server::Server, PeerCred};
+//!
+//! async fn whoami(Extension(peer_cred): Extension<PeerCred>) -> String {
+//!     peer_cred.uid().to_string()
+//! }
+//!
+//! let router = Router::new().route("/whoami", get(whoami));
+//! Server::bind("/run/myapp.sock", move || router.clone())?.run()?;
+//! ```
+//!
+//! warp 0.3 filters become hyper services through `warp::service`, and read the `PeerCred`
+//! with `warp::ext::get`:
+//!
+//! ```rust,ignore
+//! use hyperlocal::{server::Server, PeerCred};
+//! use warp::Filter;
+//!
+//! let whoami = warp::path("whoami")
+//!     .and(warp::ext::get::<PeerCred>())
+//!     .map(|peer_cred: PeerCred| peer_cred.uid().to_string());
+//! Server::bind("/run/myapp.sock", move || warp::service(whoami.clone()))?.run()?;
+//! ```
+//!
+//! An `Incoming` is also already the stream of connections `warp::serve(...).run_incoming`
+//! takes, though warp then has no way of handing filters the peer's credentials.
+//!
+//! tonic implements its `Connected` trait for tokio's `UnixStream`, so gRPC servers can
+//! listen on an `Incoming` directly. tonic hands the peer's credentials to services itself,
+//! as the `peer_cred` of the `UdsConnectInfo` in each request's extensions:
+//!
+//! ```rust,ignore
+//! use hyperlocal::server::Incoming;
+//!
+//! let incoming = Incoming::new("/run/csi/csi.sock", None)?;
+//! tonic::transport::Server::builder()
+//!     .add_service(IdentityServer::new(driver))
+//!     .serve_with_incoming(incoming)
+//!     .await?;
+//! ```
+//!
+//! # TLS
+//!
+//! hyperlocal doesn't terminate TLS itself. A server speaking TLS over its socket, for
+//! `https+unix` clients presenting certificates, accepts from an `Incoming`, queries the
+//! peer's credentials before the handshake and hands both identities to its service. With
+//! tokio-rustls 0.24 and x509-parser, say:
+//!
+//! ```rust,ignore
+//! use futures::StreamExt;
+//! use hyper::server::conn::Http;
+//! use hyperlocal::{server::Incoming, PeerCred};
+//!
+//! #[derive(Clone)]
+//! struct ClientCert {
+//!     subject: String,
+//!     sans: Vec<String>,
+//! }
+//!
+//! let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(tls_config));
+//! let mut incoming = Incoming::new("/run/myapp.sock", None)?;
+//! while let Some(stream) = incoming.next().await {
+//!     let stream = stream?;
+//!     let peer_cred = PeerCred::of(&stream).ok();
+//!     let acceptor = acceptor.clone();
+//!     tokio::spawn(async move {
+//!         let tls = match acceptor.accept(stream).await {
+//!             Ok(tls) => tls,
+//!             Err(_) => return,
+//!         };
+//!         let cert = tls.get_ref().1.peer_certificates().and_then(|certs| {
+//!             let (_, cert) = x509_parser::parse_x509_certificate(&certs.first()?.0).ok()?;
+//!             let sans = cert
+//!                 .subject_alternative_name()
+//!                 .ok()
+//!                 .flatten()
+//!                 .map(|san| san.value.general_names.iter().map(|name| name.to_string()).collect())
+//!                 .unwrap_or_default();
+//!             Some(ClientCert { subject: cert.subject().to_string(), sans })
+//!         });
+//!         let service = service_fn(move |mut req| {
+//!             req.extensions_mut().insert(cert.clone());
+//!             if let Some(peer_cred) = peer_cred {
+//!                 req.extensions_mut().insert(peer_cred);
+//!             }
+//!             handle(req)
+//!         });
+//!         let _ = Http::new().serve_connection(tls, service).await;
+//!     });
+//! }
+//! ```
 
 // Std lib
-use std::error;
-use std::fmt;
+use std::error::Error as StdError;
+use std::fs;
+use std::future::{Future, IntoFuture};
 use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::os::unix::net::{SocketAddr, UnixListener as StdUnixListener};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
 
 // Third party
-use futures::{Async, Future, Poll, Stream};
-use hyper::body::Payload;
+#[cfg(feature = "client")]
+use futures::channel::mpsc::UnboundedReceiver as DuplexReceiver;
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::channel::oneshot;
+use futures::future::FutureExt;
+use futures::ready;
+use futures::stream::{FuturesUnordered, Stream, StreamExt};
+use hyper::body::HttpBody;
 use hyper::server::conn::{Connection as HyperConnection, Http as HyperHttp};
-use hyper::service::{NewService, Service};
-use hyper::Body;
-use tokio::{reactor::Handle, runtime::Runtime};
-use tokio_uds::{Incoming as UnixIncoming, UnixListener, UnixStream};
+use hyper::service::Service;
+use hyper::{Body, Request, Response};
+use pin_project_lite::pin_project;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::runtime::{Handle, Runtime};
+use tokio::time::{self, Sleep};
+
+pub mod accept;
+pub mod bind;
+#[cfg(feature = "compression")]
+pub mod compress;
+pub mod config;
+pub mod connections;
+pub mod file;
+pub mod filter;
+#[cfg(feature = "client")]
+pub mod forward;
+pub mod guard;
+pub mod handoff;
+pub mod health;
+pub mod inetd;
+pub mod limit;
+pub mod lock;
+pub mod log;
+pub mod metrics;
+pub mod panic;
+pub mod pause;
+pub mod prometheus;
+#[cfg(feature = "client")]
+pub mod proxy;
+pub mod quota;
+pub mod rebind;
+#[cfg(feature = "client")]
+pub mod relay;
+pub mod reload;
+pub mod request_id;
+pub mod select;
+pub mod service;
+pub mod shutdown;
+pub mod socket;
+pub mod throttle;
+pub mod tunnel;
+pub mod websocket;
+pub mod workers;
+
+use self::accept::{OnAcceptError, Recovery};
+use self::bind::BindOptions;
+use self::config::ServerConfig;
+use self::connections::{Connections, Listening, Tracked};
+use self::filter::{AcceptFilter, Decision, Filtering};
+use self::handoff::Handoff;
+use self::health::Status;
+use self::lock::BindLock;
+use self::metrics::{Active, Metrics};
+use self::panic::{Isolated, OnPanic, Panic};
+use self::pause::Pauser;
+use self::prometheus::{Exporter, MetricsSocket};
+use self::quota::Accounting;
+use self::rebind::{Rebind, Rebinder};
+pub use self::service::NewService;
+use self::shutdown::{Drained, Draining, Graceful};
+use self::socket::{FdQueue, ReceivedFds, Socket};
+use self::throttle::Throttle;
+use crate::credentials::PeerCred;
+use crate::endpoint::Endpoint;
+use crate::http2::Windows;
+use crate::probes::{self, Probed};
+use crate::tap::Tapper;
+use crate::Error;
 
 /// An instance of a unix domain socket server created through `Server::bind`.
 ///
 /// # Examples
 ///
 /// ```rust
-/// extern crate hyper;
-/// extern crate hyperlocal;
-///
 /// use hyper::service::service_fn;
 /// use hyperlocal::server::Server;
 ///
@@ -35,29 +210,54 @@ use tokio_uds::{Incoming as UnixIncoming, UnixListener, UnixStream};
 /// #
 /// let echo_server = Server::bind(
 ///    "hyperlocal_test_echo_server_1.sock",
-///    || service_fn(|req| Ok::<_, hyper::Error>(hyper::Response::new(req.into_body())))
+///    || service_fn(|req| async move { Ok::<_, hyper::Error>(hyper::Response::new(req.into_body())) })
 /// ).unwrap();
 /// ```
 pub struct Server<S> {
     serve: Serve<S>,
+    lock: Option<BindLock>,
+    metrics_socket: Option<MetricsSocket>,
 }
 
 impl<S> Server<S> {
     /// Binds a new server instance to a unix domain socket path.
     ///
     /// If the provided path exists, this method will return an error.
-    pub fn bind<P>(path: P, new_service: S) -> io::Result<Server<S>>
+    pub fn bind<P>(path: P, new_service: S) -> Result<Server<S>, Error>
     where
         P: AsRef<Path>,
         S: NewService<ReqBody = Body>,
-        S::ResBody: Payload,
-        S::Service: Send,
-        S::Error: Into<Box<error::Error + Send + Sync>>,
-        <S::Service as Service>::Future: Send + 'static,
     {
         let protocol = Http::new();
         let serve = protocol.serve_path(path, new_service)?;
-        Ok(Server { serve })
+        Ok(Server {
+            serve,
+            lock: None,
+            metrics_socket: None,
+        })
+    }
+
+    /// Binds a new server instance to a unix domain socket path, holding a `lock::BindLock`
+    /// on the path for as long as the server is running.
+    ///
+    /// If another process holds the lock, this method will return an `Error::Bind` whose
+    /// source is of kind `io::ErrorKind::AddrInUse`. Otherwise any socket file left behind at the path is
+    /// stale and is removed before binding.
+    pub fn bind_locked<P>(path: P, new_service: S) -> Result<Server<S>, Error>
+    where
+        P: AsRef<Path>,
+        S: NewService<ReqBody = Body>,
+    {
+        let bind_error = |err| Error::bind(path.as_ref().display(), err);
+        let lock = BindLock::acquire(path.as_ref()).map_err(bind_error)?;
+        if let Err(err) = fs::remove_file(path.as_ref()) {
+            if err.kind() != io::ErrorKind::NotFound {
+                return Err(bind_error(err));
+            }
+        }
+        let mut server = Server::bind(path, new_service)?;
+        server.lock = Some(lock);
+        Ok(server)
     }
 
     /// Return the local address of the underlying socket that this server is listening on.
@@ -65,36 +265,542 @@ impl<S> Server<S> {
         self.serve.incoming.local_addr()
     }
 
+    /// Binds a new server instance to the listener handed to this process by a
+    /// `handoff::Handoff`, or to a unix domain socket path when there is none.
+    ///
+    /// If no listener was inherited and the provided path exists, this method will return
+    /// an error.
+    pub fn bind_or_inherit<P>(path: P, new_service: S) -> Result<Server<S>, Error>
+    where
+        P: AsRef<Path>,
+        S: NewService<ReqBody = Body>,
+    {
+        let inherit_error = |err| Error::bind("an inherited listener", err);
+        let (listener, inherited_path) = match handoff::inherited().map_err(inherit_error)? {
+            Some(inherited) => inherited,
+            None => return Server::bind(path, new_service),
+        };
+        let mut incoming = Incoming::from_std(listener).map_err(inherit_error)?;
+        if inherited_path.is_some() {
+            incoming.path = inherited_path;
+        }
+        let serve = Http::new().serve_incoming(incoming, new_service);
+        Ok(Server {
+            serve,
+            lock: None,
+            metrics_socket: None,
+        })
+    }
+
+    /// Binds a new server instance to an `Endpoint`.
+    ///
+    /// Only socket files created by binding a path are removed when the server shuts down,
+    /// so files behind inherited listeners are left alone.
+    pub fn bind_endpoint(endpoint: &Endpoint, new_service: S) -> Result<Server<S>, Error>
+    where
+        S: NewService<ReqBody = Body>,
+    {
+        let bind_error = |err| Error::bind(endpoint, err);
+        let (listener, created) = endpoint.listen().map_err(bind_error)?;
+        let mut incoming = Incoming::from_std(listener).map_err(bind_error)?;
+        if !created {
+            incoming.path = None;
+        }
+        let serve = Http::new().serve_incoming(incoming, new_service);
+        Ok(Server {
+            serve,
+            lock: None,
+            metrics_socket: None,
+        })
+    }
+
+    /// Binds a new server instance to an `Endpoint`, applying `options` to the socket before
+    /// accepting connections on it.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use hyper::service::service_fn;
+    /// use hyperlocal::server::bind::BindOptions;
+    /// use hyperlocal::server::Server;
+    /// use hyperlocal::Endpoint;
+    /// # std::fs::remove_file("hyperlocal_test_bind_with.sock").ok();
+    ///
+    /// let endpoint: Endpoint = "unix://hyperlocal_test_bind_with.sock".parse().unwrap();
+    /// let options = BindOptions::new().mode(0o600);
+    /// let server = Server::bind_with(&endpoint, &options, || {
+    ///     service_fn(|req| async move { Ok::<_, hyper::Error>(hyper::Response::new(req.into_body())) })
+    /// })
+    /// .unwrap();
+    /// # std::fs::remove_file("hyperlocal_test_bind_with.sock").ok();
+    /// ```
+    pub fn bind_with(
+        endpoint: &Endpoint,
+        options: &BindOptions,
+        new_service: S,
+    ) -> Result<Server<S>, Error>
+    where
+        S: NewService<ReqBody = Body>,
+    {
+        let bind_error = |err| Error::bind(endpoint, err);
+        if let Endpoint::Path(ref path) = *endpoint {
+            options.prepare(path).map_err(bind_error)?;
+        }
+        let (listener, path) = match *endpoint {
+            Endpoint::Path(ref path) => (options.bind(path).map_err(bind_error)?, Some(path)),
+            _ => (endpoint.listen().map_err(bind_error)?.0, None),
+        };
+        let incoming = options
+            .apply(&listener)
+            .and_then(|()| Incoming::from_std(listener));
+        let mut incoming = match incoming {
+            Ok(incoming) => incoming,
+            Err(err) => {
+                // the socket file was created for this server, which won't be serving it
+                if let Some(path) = path {
+                    let _ = fs::remove_file(path);
+                }
+                return Err(bind_error(err));
+            }
+        };
+        // the listener may have been bound under another name before being linked to `path`
+        incoming.path = path.cloned();
+        let serve = Http::new().serve_incoming(incoming, new_service);
+        Ok(Server {
+            serve,
+            lock: None,
+            metrics_socket: None,
+        })
+    }
+
+    /// Bind the endpoint of `config`, applying its socket options, and serve connections with
+    /// its limits and timeouts.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use hyper::service::service_fn;
+    /// use hyperlocal::server::config::ServerConfig;
+    /// use hyperlocal::server::Server;
+    /// # std::fs::remove_file("hyperlocal_test_from_config.sock").ok();
+    ///
+    /// let config = ServerConfig::new("unix://hyperlocal_test_from_config.sock".parse().unwrap())
+    ///     .max_concurrent_streams(64);
+    /// let server = Server::from_config(&config, || {
+    ///     service_fn(|req| async move { Ok::<_, hyper::Error>(hyper::Response::new(req.into_body())) })
+    /// })
+    /// .unwrap();
+    /// # std::fs::remove_file("hyperlocal_test_from_config.sock").ok();
+    /// ```
+    pub fn from_config(config: &ServerConfig, new_service: S) -> Result<Server<S>, Error>
+    where
+        S: NewService<ReqBody = Body>,
+    {
+        let mut server =
+            Server::bind_with(config.endpoint(), config.socket_options(), new_service)?;
+        config.configure(&mut server.serve.protocol);
+        Ok(server)
+    }
+
+    /// Return a handle for moving this server to a new socket path while it is running.
+    pub fn rebinder(&self) -> Rebinder {
+        self.serve.rebinder()
+    }
+
+    /// Return a handle for handing this server's listener over to a new process while it
+    /// is running.
+    pub fn handoff(&self) -> Handoff {
+        self.serve.handoff()
+    }
+
+    /// Return a handle for pausing this server's accepting of connections while it is
+    /// running.
+    pub fn pauser(&self) -> Pauser {
+        self.serve.pauser()
+    }
+
+    /// Run `filter` on the credentials of each accepted connection before serving it.
+    ///
+    /// The future returned by `filter` decides whether the connection is served or closed,
+    /// and may take its time doing so, say to slow down a misbehaving peer, without holding
+    /// up other connections. `filter` is passed `None` on platforms where peer credentials
+    /// can't be queried.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use futures::future;
+    /// use hyper::service::service_fn;
+    /// use hyperlocal::server::{filter::Decision, Server};
+    ///
+    /// # if let Err(err) = std::fs::remove_file("hyperlocal_test_filter_server.sock") {
+    /// #   if err.kind() != std::io::ErrorKind::NotFound {
+    /// #     panic!("{}", err)
+    /// #   }
+    /// # }
+    /// #
+    /// let blocked_uids = vec![1001, 1002];
+    /// let server = Server::bind(
+    ///    "hyperlocal_test_filter_server.sock",
+    ///    || service_fn(|req| async move { Ok::<_, hyper::Error>(hyper::Response::new(req.into_body())) })
+    /// )
+    /// .unwrap()
+    /// .accept_filter(move |peer_cred| {
+    ///     let decision = match peer_cred {
+    ///         Some(peer_cred) if !blocked_uids.contains(&peer_cred.uid()) => Decision::Proceed,
+    ///         _ => Decision::Drop,
+    ///     };
+    ///     future::ready(decision)
+    /// });
+    /// ```
+    pub fn accept_filter<F, R>(mut self, filter: F) -> Self
+    where
+        F: Fn(Option<PeerCred>) -> R + Send + Sync + 'static,
+        R: IntoFuture<Output = Decision>,
+        R::IntoFuture: Send + 'static,
+    {
+        self.serve = self.serve.accept_filter(filter);
+        self
+    }
+
+    /// Report connection events to `metrics`, such as `metrics::Counters`.
+    pub fn metrics<M>(mut self, metrics: Arc<M>) -> Self
+    where
+        M: Metrics + 'static,
+    {
+        self.serve = self.serve.metrics(metrics);
+        self
+    }
+
+    /// Serve this server's connection counters to Prometheus, along with the rest of
+    /// `exporter`'s metrics, at `GET /metrics` on a second socket bound to `endpoint` with
+    /// `options`.
+    ///
+    /// The counters are those already given to `exporter` with `Exporter#counters`, so they
+    /// can be shared with a `guard::Guard`, or new ones otherwise, and replace any registered
+    /// with `Server#metrics`. The admin socket is served
+    /// from a thread of its own until the server is dropped, which removes its socket file.
+    /// See `prometheus`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use hyper::service::service_fn;
+    /// use hyperlocal::server::{bind::BindOptions, prometheus::Exporter, Server};
+    /// use hyperlocal::Endpoint;
+    /// # std::fs::remove_file("hyperlocal_test_prometheus_server.sock").ok();
+    ///
+    /// let metrics: Endpoint = "unix://hyperlocal_test_prometheus_metrics.sock".parse().unwrap();
+    /// let server = Server::bind(
+    ///    "hyperlocal_test_prometheus_server.sock",
+    ///    || service_fn(|req| async move { Ok::<_, hyper::Error>(hyper::Response::new(req.into_body())) })
+    /// )
+    /// .unwrap()
+    /// .metrics_socket(&metrics, &BindOptions::new().mode(0o660), Exporter::new())
+    /// .unwrap();
+    /// # drop(server);
+    /// # std::fs::remove_file("hyperlocal_test_prometheus_server.sock").ok();
+    /// ```
+    pub fn metrics_socket(
+        mut self,
+        endpoint: &Endpoint,
+        options: &BindOptions,
+        exporter: Exporter,
+    ) -> Result<Self, Error> {
+        let counters = exporter.exported_counters().cloned().unwrap_or_default();
+        let exporter = exporter.counters(counters.clone());
+        self.metrics_socket = Some(MetricsSocket::bind(endpoint, options, exporter)?);
+        Ok(self.metrics(counters))
+    }
+
+    /// Collect the file descriptors clients send over their connections, handing them to
+    /// requests as a `socket::ReceivedFds` extension.
+    pub fn receive_fds(mut self) -> Self {
+        self.serve = self.serve.receive_fds();
+        self
+    }
+
+    /// Apply HTTP/2 flow control `windows` to the connections this server serves.
+    pub fn http2_windows(mut self, windows: &Windows) -> Self {
+        self.serve = self.serve.http2_windows(windows);
+        self
+    }
+
+    /// Cap the size of the buffer an HTTP/1 connection reads requests into, in bytes.
+    pub fn http1_max_buf_size(mut self, size: usize) -> Self {
+        self.serve = self.serve.http1_max_buf_size(size);
+        self
+    }
+
+    /// Write response header names in title case, such as `Content-Length`, rather than
+    /// lowercased.
+    ///
+    /// Some embedded clients match header names case-sensitively.
+    pub fn http1_title_case_headers(mut self, enabled: bool) -> Self {
+        self.serve = self.serve.http1_title_case_headers(enabled);
+        self
+    }
+
+    /// Record the case of request header names as received, and write response header names
+    /// in the case recorded for them.
+    ///
+    /// `HeaderMap` lowercases names, so services never see the original case: hyper keeps it
+    /// in a private extension instead. This only matters to services forwarding requests and
+    /// responses on, like a proxy using a hyper client also preserving header case; headers
+    /// of responses a service builds itself are still written lowercased.
+    pub fn http1_preserve_header_case(mut self, enabled: bool) -> Self {
+        self.serve = self.serve.http1_preserve_header_case(enabled);
+        self
+    }
+
+    /// Keep answering HTTP/1 clients which shut down their writing half after sending a
+    /// request, rather than closing their connection.
+    pub fn http1_half_close(mut self, enabled: bool) -> Self {
+        self.serve = self.serve.http1_half_close(enabled);
+        self
+    }
+
+    /// Send the bytes read from and written to every connection to `tapper`, for debugging.
+    ///
+    /// See `hyperlocal::tap`.
+    pub fn tap(mut self, tapper: Tapper) -> Self {
+        self.serve = self.serve.tap(tapper);
+        self
+    }
+
+    /// Call `recover` with every error accepting a connection, to decide how the server
+    /// carries on.
+    ///
+    /// Without a callback, any error stops the server. `accept::transient` is a policy
+    /// riding out the errors expected to clear up by themselves, such as running out of
+    /// file descriptors.
+    pub fn on_accept_error<F>(mut self, recover: F) -> Self
+    where
+        F: Fn(&io::Error) -> Recovery + Send + Sync + 'static,
+    {
+        self.serve = self.serve.on_accept_error(recover);
+        self
+    }
+
+    /// Call `on_panic` when serving a connection panics, instead of logging the panic under
+    /// the `hyperlocal::server` target with the `log` feature.
+    ///
+    /// A panicking connection is closed, without affecting the server or its other
+    /// connections.
+    pub fn on_panic<F>(mut self, on_panic: F) -> Self
+    where
+        F: Fn(&Panic) + Send + Sync + 'static,
+    {
+        self.serve = self.serve.on_panic(on_panic);
+        self
+    }
+
+    /// Report this server's state to `status`, as answered on `/readyz` by a
+    /// `health::Health`.
+    ///
+    /// The status is serving from now until the server stops accepting connections, whether
+    /// because it is draining or because it was dropped.
+    pub fn health(mut self, status: &Status) -> Self {
+        self.serve = self.serve.health(status);
+        self
+    }
+
+    /// Track the connections this server serves, and its drain state, in `connections`.
+    ///
+    /// See `connections::Connections`.
+    pub fn connections(mut self, connections: &Connections) -> Self {
+        self.serve = self.serve.connections(connections);
+        self
+    }
+
+    /// Limit how fast each connection this server accepts is read from and written to.
+    ///
+    /// See `throttle::Throttle`.
+    pub fn throttle(mut self, throttle: Throttle) -> Self {
+        self.serve = self.serve.throttle(throttle);
+        self
+    }
+
+    /// Count the bytes transferred over this server's connections in `accounting`, under
+    /// the uid of each connection's peer.
+    ///
+    /// See `quota::Accounting`.
+    pub fn accounting(mut self, accounting: &Accounting) -> Self {
+        self.serve = self.serve.accounting(accounting);
+        self
+    }
+
     /// Start a new tokio runtime, and drive this server on it.
+    ///
+    /// Connections are served concurrently, each on its own task. A connection failing is
+    /// closed without affecting the server or its other connections.
     pub fn run(self) -> io::Result<()>
     where
         S: NewService<ReqBody = Body> + Send + 'static,
         S::Future: Send + 'static,
-        S::Service: Send,
-        S::InitError: fmt::Display,
-        <S::Service as Service>::ResBody: Payload,
-        <S::Service as Service>::Future: Send + 'static,
+        S::Service: Send + 'static,
+        <S::Service as Service<Request<Body>>>::Future: Send + 'static,
     {
         let runtime = Runtime::new()?;
+        let Server {
+            mut serve,
+            lock,
+            metrics_socket,
+        } = self;
+        let on_panic = serve.on_panic.clone();
 
-        runtime.block_on_all(self.serve.for_each(|connecting| {
-            connecting
-                .map_err(|e| {
-                    io::Error::new(
-                        io::ErrorKind::Other,
-                        format!("failed to serve connection: {}", e),
-                    )
-                })
-                .and_then(|connection| {
-                    connection.map_err(|e| {
-                        io::Error::new(
-                            io::ErrorKind::Other,
-                            format!("failed to serve connection: {}", e),
-                        )
-                    })
-                })
-        }))
+        let result = runtime.block_on(async move {
+            while let Some(connecting) = serve.next().await {
+                let connecting = connecting?;
+                let peer_cred = connecting.peer_cred;
+                let serving = serve_connection(connecting);
+                tokio::spawn(Isolated::new(serving, peer_cred, on_panic.clone()));
+            }
+            Ok(())
+        });
+        drop(lock);
+        drop(metrics_socket);
+        result
     }
+
+    /// Start a new tokio runtime, and drive this server on it until `signal` resolves.
+    ///
+    /// Once `signal` completes, the server stops accepting new connections, gracefully
+    /// shuts down the ones in flight and waits for them to finish. The socket file the
+    /// server is bound to is removed before this method returns, regardless of whether
+    /// serving failed.
+    ///
+    /// Should `signal` resolve to an error, such as `shutdown::signal()` failing to install
+    /// its handlers, the server shuts down all the same, and returns that error once drained.
+    ///
+    /// Pair this with `shutdown::signal()` to exit cleanly on `SIGINT` or `SIGTERM`.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// use hyper::service::service_fn;
+    /// use hyperlocal::server::{shutdown, Server};
+    ///
+    /// let server = Server::bind(
+    ///    "hyperlocal_test_signal_server.sock",
+    ///    || service_fn(|req| async move { Ok::<_, hyper::Error>(hyper::Response::new(req.into_body())) })
+    /// ).unwrap();
+    /// server.run_until(shutdown::signal()).unwrap();
+    /// ```
+    pub fn run_until<F, T>(self, signal: F) -> io::Result<()>
+    where
+        S: NewService<ReqBody = Body> + Send + 'static,
+        S::Future: Send + 'static,
+        S::Service: Send + 'static,
+        <S::Service as Service<Request<Body>>>::Future: Send + 'static,
+        F: Future<Output = io::Result<T>> + Send + 'static,
+    {
+        self.run_until_drained(signal, None).map(drop)
+    }
+
+    /// Like `run_until`, but report what became of the connections in flight once `signal`
+    /// completed, dropping any still open `drain_timeout` after it did.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// use std::time::Duration;
+    ///
+    /// use hyper::service::service_fn;
+    /// use hyperlocal::server::{shutdown::Triggers, Server};
+    ///
+    /// let server = Server::bind(
+    ///    "hyperlocal_test_drained_server.sock",
+    ///    || service_fn(|req| async move { Ok::<_, hyper::Error>(hyper::Response::new(req.into_body())) })
+    /// ).unwrap();
+    /// let drained = server
+    ///     .run_until_drained(Triggers::new().signal(), Some(Duration::from_secs(30)))
+    ///     .unwrap();
+    /// eprintln!(
+    ///     "drained {} connections and aborted {} in {:?}",
+    ///     drained.drained(),
+    ///     drained.aborted(),
+    ///     drained.duration()
+    /// );
+    /// ```
+    pub fn run_until_drained<F, T>(
+        self,
+        signal: F,
+        drain_timeout: Option<Duration>,
+    ) -> io::Result<Drained>
+    where
+        S: NewService<ReqBody = Body> + Send + 'static,
+        S::Future: Send + 'static,
+        S::Service: Send + 'static,
+        <S::Service as Service<Request<Body>>>::Future: Send + 'static,
+        F: Future<Output = io::Result<T>> + Send + 'static,
+    {
+        let bound = self.serve.bound.clone();
+        // kept aside, so that a failed signal is reported once the server has drained
+        let (signalled, failed) = oneshot::channel();
+        let signal = signal.map(move |result| {
+            if let Err(err) = result {
+                let _ = signalled.send(err);
+            }
+        });
+        let mut draining = self.serve.serve_with_shutdown(signal);
+        if let Some(timeout) = drain_timeout {
+            draining = draining.drain_timeout(timeout);
+        }
+        let result = Runtime::new()
+            .and_then(|runtime| runtime.block_on(draining))
+            .and_then(|drained| match failed.now_or_never() {
+                Some(Ok(err)) => Err(err),
+                _ => Ok(drained),
+            });
+        remove_socket(&bound, result)
+    }
+
+    /// Like `run_until`, but accept and serve connections on `workers` threads.
+    ///
+    /// Each worker runs its own single-threaded tokio runtime and accepts from its own
+    /// duplicate of the server's listener, so accepting and serving scale with the number of
+    /// workers. `signal` is waited on from the calling thread. Connection filters and
+    /// metrics apply to every worker, but a `Rebinder` doesn't move workers to a new
+    /// listener. See the `workers` module.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// use hyper::service::service_fn;
+    /// use hyperlocal::server::{shutdown, Server};
+    ///
+    /// let server = Server::bind(
+    ///    "hyperlocal_test_workers_server.sock",
+    ///    || service_fn(|req| async move { Ok::<_, hyper::Error>(hyper::Response::new(req.into_body())) })
+    /// ).unwrap();
+    /// server.run_workers_until(4, shutdown::signal()).unwrap();
+    /// ```
+    pub fn run_workers_until<F, T>(self, workers: usize, signal: F) -> io::Result<()>
+    where
+        S: NewService<ReqBody = Body> + Clone + Send + 'static,
+        S::Future: Send + 'static,
+        S::Service: Send + 'static,
+        <S::Service as Service<Request<Body>>>::Future: Send + 'static,
+        F: Future<Output = io::Result<T>>,
+    {
+        let result = workers::run(&self.serve, workers, signal);
+        remove_socket(&self.serve.bound, result)
+    }
+}
+
+/// Remove the socket file a server owns once it has stopped serving, keeping the first of
+/// `result`'s error or any error removing it.
+fn remove_socket<T>(bound: &Mutex<Bound>, result: io::Result<T>) -> io::Result<T> {
+    let path = bound.lock().unwrap_or_else(|e| e.into_inner()).path.take();
+    if let Some(path) = path {
+        if let Err(err) = fs::remove_file(path) {
+            if err.kind() != io::ErrorKind::NotFound {
+                return result.and(Err(err));
+            }
+        }
+    }
+    result
 }
 
 /// A stream mapping incoming connections to new services.
@@ -104,59 +810,498 @@ pub struct Serve<S> {
     incoming: Incoming,
     new_service: S,
     protocol: HyperHttp,
+    bound: Arc<Mutex<Bound>>,
+    retiring: Option<Incoming>,
+    rebind_tx: UnboundedSender<Rebind>,
+    rebind_rx: UnboundedReceiver<Rebind>,
+    pauser: Pauser,
+    filter: Option<AcceptFilter>,
+    filtering: FuturesUnordered<Filtering>,
+    metrics: Option<Arc<dyn Metrics>>,
+    receive_fds: bool,
+    tap: Option<Tapper>,
+    on_accept_error: Option<OnAcceptError>,
+    backoff: Option<Pin<Box<Sleep>>>,
+    on_panic: Option<OnPanic>,
+    health: Option<Status>,
+    connections: Option<Listening>,
+    throttle: Option<Throttle>,
+    accounting: Option<Accounting>,
+}
+
+impl<S> Serve<S> {
+    /// Return a handle for moving this `Serve` to a new socket path.
+    pub fn rebinder(&self) -> Rebinder {
+        Rebinder::new(self.rebind_tx.clone())
+    }
+
+    /// Return a handle for handing this `Serve`'s listener over to a new process.
+    pub fn handoff(&self) -> Handoff {
+        Handoff::new(self.bound.clone())
+    }
+
+    /// Return a handle for pausing this `Serve`'s accepting of connections.
+    pub fn pauser(&self) -> Pauser {
+        self.pauser.clone()
+    }
+
+    /// Run `filter` on the credentials of each accepted connection before serving it.
+    ///
+    /// See `Server::accept_filter`.
+    pub fn accept_filter<F, R>(mut self, filter: F) -> Self
+    where
+        F: Fn(Option<PeerCred>) -> R + Send + Sync + 'static,
+        R: IntoFuture<Output = Decision>,
+        R::IntoFuture: Send + 'static,
+    {
+        self.filter = Some(AcceptFilter::new(filter));
+        self
+    }
+
+    /// Report connection events to `metrics`.
+    pub fn metrics<M>(mut self, metrics: Arc<M>) -> Self
+    where
+        M: Metrics + 'static,
+    {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Collect the file descriptors clients send over their connections.
+    ///
+    /// See `Server::receive_fds`.
+    pub fn receive_fds(mut self) -> Self {
+        self.receive_fds = true;
+        self
+    }
+
+    /// Apply HTTP/2 flow control `windows` to the connections this `Serve` serves.
+    ///
+    /// See `Server::http2_windows`.
+    pub fn http2_windows(mut self, windows: &Windows) -> Self {
+        windows.configure_server(&mut self.protocol);
+        self
+    }
+
+    /// Cap the size of the buffer an HTTP/1 connection reads requests into.
+    ///
+    /// See `Server::http1_max_buf_size`.
+    pub fn http1_max_buf_size(mut self, size: usize) -> Self {
+        self.protocol.max_buf_size(size);
+        self
+    }
+
+    /// Write response header names in title case.
+    ///
+    /// See `Server::http1_title_case_headers`.
+    pub fn http1_title_case_headers(mut self, enabled: bool) -> Self {
+        self.protocol.http1_title_case_headers(enabled);
+        self
+    }
+
+    /// Record the case of request header names, for services forwarding requests on.
+    ///
+    /// See `Server::http1_preserve_header_case`.
+    pub fn http1_preserve_header_case(mut self, enabled: bool) -> Self {
+        self.protocol.http1_preserve_header_case(enabled);
+        self
+    }
+
+    /// Keep answering HTTP/1 clients which shut down their writing half.
+    ///
+    /// See `Server::http1_half_close`.
+    pub fn http1_half_close(mut self, enabled: bool) -> Self {
+        self.protocol.http1_half_close(enabled);
+        self
+    }
+
+    /// Send what travels over every connection to `tapper`.
+    ///
+    /// See `Server::tap`.
+    pub fn tap(mut self, tapper: Tapper) -> Self {
+        self.tap = Some(tapper);
+        self
+    }
+
+    /// Call `recover` with every error accepting a connection.
+    ///
+    /// See `Server::on_accept_error`. Backing off requires a tokio runtime with its timer
+    /// enabled.
+    pub fn on_accept_error<F>(mut self, recover: F) -> Self
+    where
+        F: Fn(&io::Error) -> Recovery + Send + Sync + 'static,
+    {
+        self.on_accept_error = Some(Arc::new(recover));
+        self
+    }
+
+    /// Call `on_panic` when serving a connection panics.
+    ///
+    /// See `Server::on_panic`.
+    pub fn on_panic<F>(mut self, on_panic: F) -> Self
+    where
+        F: Fn(&Panic) + Send + Sync + 'static,
+    {
+        self.on_panic = Some(Arc::new(on_panic));
+        self
+    }
+
+    /// Report this `Serve`'s state to `status`.
+    ///
+    /// See `Server::health`.
+    pub fn health(mut self, status: &Status) -> Self {
+        status.set_serving(true);
+        self.health = Some(status.clone());
+        self
+    }
+
+    /// Track the connections this `Serve` serves in `connections`.
+    ///
+    /// See `Server::connections`.
+    pub fn connections(mut self, connections: &Connections) -> Self {
+        self.connections = Some(connections.listening());
+        self
+    }
+
+    /// Limit how fast each connection is read from and written to.
+    ///
+    /// See `Server::throttle`.
+    pub fn throttle(mut self, throttle: Throttle) -> Self {
+        self.throttle = Some(throttle);
+        self
+    }
+
+    /// Count the bytes transferred over each connection in `accounting`.
+    ///
+    /// See `Server::accounting`.
+    pub fn accounting(mut self, accounting: &Accounting) -> Self {
+        self.accounting = Some(accounting.clone());
+        self
+    }
+
+    /// Switch to any listeners handed over by a `Rebinder`, and accept whatever is left in the
+    /// backlog of the listener being replaced.
+    fn poll_rebinds(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> io::Result<Option<(UnixStream, Option<PeerCred>)>> {
+        while let Poll::Ready(Some((listener, path))) = self.rebind_rx.poll_next_unpin(cx) {
+            let mut incoming = Incoming::from_std(listener)?;
+            incoming.path = Some(path);
+            let previous = mem::replace(&mut self.incoming, incoming);
+            if let Some(ref previous_path) = previous.path {
+                if previous.path != self.incoming.path {
+                    let _ = fs::remove_file(previous_path);
+                }
+            }
+            *self.bound.lock().unwrap_or_else(|e| e.into_inner()) = Bound::of(&self.incoming);
+            self.retiring = Some(previous);
+        }
+        if let Some(mut retiring) = self.retiring.take() {
+            if let Poll::Ready(Some(Ok(accepted))) = retiring.poll_accept(cx) {
+                self.retiring = Some(retiring);
+                return Ok(Some(accepted));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Wrap this `Serve` in a future which serves connections until `signal` resolves,
+    /// then waits for in-flight connections to finish.
+    ///
+    /// Connections are spawned onto the current tokio runtime, so the returned future must
+    /// be run from within one.
+    pub fn with_graceful_shutdown<F>(self, signal: F) -> Graceful<S, F>
+    where
+        F: Future,
+    {
+        Graceful::new(self, signal)
+    }
+
+    /// Like `with_graceful_shutdown`, but resolve to what became of the connections in
+    /// flight once `signal` completed.
+    ///
+    /// See `shutdown::Draining`.
+    pub fn serve_with_shutdown<F>(self, signal: F) -> Draining<S, F>
+    where
+        F: Future,
+    {
+        Draining::new(self, signal)
+    }
+}
+
+impl<S> Drop for Serve<S> {
+    fn drop(&mut self) {
+        // the listener is about to be closed, so its descriptor may no longer be handed off
+        self.bound.lock().unwrap_or_else(|e| e.into_inner()).fd = None;
+        if let Some(ref health) = self.health {
+            health.set_serving(false);
+        }
+    }
+}
+
+/// The listener a `Serve` is currently accepting on, shared with the handles which
+/// act on it from outside of the server.
+pub(crate) struct Bound {
+    /// The listener's descriptor, for as long as it is open.
+    pub(crate) fd: Option<RawFd>,
+    /// The socket file to remove on shutdown, if the server owns one.
+    pub(crate) path: Option<PathBuf>,
+}
+
+impl Bound {
+    fn of(incoming: &Incoming) -> Self {
+        Bound {
+            fd: incoming.fd,
+            path: incoming.path.clone(),
+        }
+    }
 }
 
+// nothing in a `Serve` is pinned; its backoff timer is boxed
+impl<S> Unpin for Serve<S> {}
+
 impl<S> Stream for Serve<S>
 where
     S: NewService<ReqBody = Body>,
 {
-    type Item = Connecting<S::Future>;
-    type Error = <UnixIncoming as Stream>::Error;
-
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        match self.incoming.poll()? {
-            Async::Ready(Some(stream)) => {
-                let service_future = self.new_service.new_service();
-                Ok(Async::Ready(Some(Connecting {
-                    service_future,
-                    stream: Some(stream),
-                    protocol: self.protocol.clone(),
-                })))
+    type Item = io::Result<Connecting<S::Future>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            match this.filtering.poll_next_unpin(cx) {
+                Poll::Ready(Some((Decision::Proceed, accepted))) => {
+                    return Poll::Ready(Some(Ok(this.connecting(accepted))));
+                }
+                Poll::Ready(Some((Decision::Drop, _))) => {
+                    if let Some(ref metrics) = this.metrics {
+                        metrics.connection_rejected();
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+            if let Some(ref mut backoff) = this.backoff {
+                ready!(backoff.as_mut().poll(cx));
+            }
+            this.backoff = None;
+            // connections queue up in the listener's backlog while paused
+            ready!(this.pauser.poll_resumed(cx));
+            let accepted = match this.poll_rebinds(cx) {
+                Ok(Some(accepted)) => Poll::Ready(Some(Ok(accepted))),
+                Ok(None) => this.incoming.poll_accept(cx),
+                Err(err) => Poll::Ready(Some(Err(err))),
+            };
+            let (stream, peer_cred) = match accepted {
+                Poll::Ready(Some(Ok(accepted))) => accepted,
+                Poll::Ready(None) if this.filtering.is_empty() => return Poll::Ready(None),
+                Poll::Ready(None) | Poll::Pending => return Poll::Pending,
+                Poll::Ready(Some(Err(err))) => {
+                    if let Some(ref metrics) = this.metrics {
+                        metrics.accept_failed(&err);
+                    }
+                    let recovery = match this.on_accept_error {
+                        Some(ref recover) => recover(&err),
+                        None => Recovery::Abort,
+                    };
+                    match recovery {
+                        Recovery::Skip => continue,
+                        Recovery::Backoff(duration) => {
+                            this.backoff = Some(Box::pin(time::sleep(duration)));
+                            continue;
+                        }
+                        Recovery::Abort => return Poll::Ready(Some(Err(err))),
+                    }
+                }
+            };
+            probes::accept(peer_cred.as_ref());
+            #[cfg(feature = "otel")]
+            crate::otel::accepted(this.incoming.path.as_deref(), peer_cred.as_ref());
+            #[cfg(feature = "log")]
+            crate::logs::accepted(this.incoming.path.as_deref(), peer_cred.as_ref());
+            let (stream, fds) = if this.receive_fds {
+                let fds = FdQueue::default();
+                (Socket::receiving_fds(stream, fds.clone()), Some(fds))
+            } else {
+                (Socket::new(stream), None)
+            };
+            let stream = stream.with_peer_cred(peer_cred);
+            let stream = match this.tap {
+                Some(ref tapper) => stream.tapped(tapper),
+                None => stream,
+            };
+            let stream = match this.throttle {
+                Some(ref throttle) => stream.throttled(throttle),
+                None => stream,
+            };
+            let stream = match (&this.accounting, peer_cred) {
+                (Some(accounting), Some(peer_cred)) => {
+                    stream.accounted(accounting.account(peer_cred.uid()))
+                }
+                _ => stream,
+            };
+            let accepted = Accepted {
+                stream,
+                peer_cred,
+                fds,
+                active: this.metrics.clone().map(Active::new),
+                tracked: this
+                    .connections
+                    .as_ref()
+                    .map(|listening| listening.connections().track(peer_cred)),
+            };
+            match this.filter {
+                Some(ref filter) => this.filtering.push(filter.filter(accepted)),
+                None => return Poll::Ready(Some(Ok(this.connecting(accepted)))),
             }
-            Async::Ready(None) => Ok(Async::Ready(None)),
-            Async::NotReady => Ok(Async::NotReady),
         }
     }
 }
 
-/// A future building a new `Service` to a `Connection`.
-///
-/// Wraps the future returned from `NewService` into one that returns a `Connection`.
-pub struct Connecting<F> {
-    service_future: F,
-    stream: Option<UnixStream>,
-    protocol: HyperHttp,
+impl<S> Serve<S>
+where
+    S: NewService<ReqBody = Body>,
+{
+    fn connecting(&self, accepted: Accepted) -> Connecting<S::Future> {
+        Connecting {
+            service_future: self
+                .new_service
+                .new_service_for(accepted.peer_cred.as_ref()),
+            peer_cred: accepted.peer_cred,
+            fds: accepted.fds,
+            active: accepted.active,
+            tracked: accepted.tracked,
+            stream: Some(accepted.stream),
+            protocol: self.protocol.clone(),
+        }
+    }
 }
 
-impl<F> Future for Connecting<F>
+/// A connection freshly accepted by a `Serve`.
+pub(crate) struct Accepted {
+    stream: Socket,
+    peer_cred: Option<PeerCred>,
+    fds: Option<FdQueue>,
+    active: Option<Active>,
+    tracked: Option<Tracked>,
+}
+
+pin_project! {
+    /// A future building a new `Service` to a `Connection`.
+    ///
+    /// Wraps the future returned from `NewService` into one that returns a `Connection`.
+    pub struct Connecting<F> {
+        #[pin]
+        service_future: F,
+        peer_cred: Option<PeerCred>,
+        fds: Option<FdQueue>,
+        active: Option<Active>,
+        tracked: Option<Tracked>,
+        stream: Option<Socket>,
+        protocol: HyperHttp,
+    }
+}
+
+impl<F> Connecting<F> {
+    /// Return the credentials of the connecting peer, if they could be queried.
+    ///
+    /// Credentials are queried once, when the connection is accepted, and this same value is
+    /// handed to each request served on the connection.
+    pub fn peer_cred(&self) -> Option<&PeerCred> {
+        self.peer_cred.as_ref()
+    }
+}
+
+impl<F, S, E, B> Future for Connecting<F>
 where
-    F: Future,
-    F::Item: Service<ReqBody = Body>,
-    <F::Item as Service>::ResBody: Payload,
-    <F::Item as Service>::Future: Send + 'static,
+    F: Future<Output = Result<S, E>>,
+    S: Service<Request<Body>, Response = Response<B>>,
+    S::Error: Into<Box<dyn StdError + Send + Sync>>,
+    S::Future: Send + 'static,
+    B: HttpBody<Data: Send, Error: Into<Box<dyn StdError + Send + Sync>>> + Send + 'static,
 {
-    type Item = HyperConnection<UnixStream, F::Item>;
-    type Error = F::Error;
+    type Output = Result<HyperConnection<Socket, WithPeerCred<S>>, E>;
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let service = match self.service_future.poll()? {
-            Async::Ready(service) => service,
-            Async::NotReady => return Ok(Async::NotReady),
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let inner = ready!(this.service_future.poll(cx))?;
+        let service = WithPeerCred {
+            inner,
+            peer_cred: *this.peer_cred,
+            fds: this.fds.take(),
+            _active: this.active.take(),
+            _tracked: this.tracked.take(),
         };
-        let stream = self.stream.take().expect("polled after complete");
-        Ok(Async::Ready(
-            self.protocol.serve_connection(stream, service),
-        ))
+        let stream = this.stream.take().expect("polled after complete");
+        Poll::Ready(Ok(this.protocol.serve_connection(stream, service)))
+    }
+}
+
+/// Serve a connection until it closes, with upgrades enabled.
+async fn serve_connection<F, S, E, B>(connecting: Connecting<F>)
+where
+    F: Future<Output = Result<S, E>>,
+    S: Service<Request<Body>, Response = Response<B>> + Send + 'static,
+    S::Error: Into<Box<dyn StdError + Send + Sync>>,
+    S::Future: Send + 'static,
+    B: HttpBody<Data: Send, Error: Into<Box<dyn StdError + Send + Sync>>> + Send + 'static,
+{
+    if let Ok(connection) = connecting.await {
+        let _ = connection.with_upgrades().await;
+    }
+}
+
+/// A `Service` inserting the `PeerCred` of its connection into the extensions of each request.
+///
+/// Credentials don't change over the lifetime of a connection, so they are queried once when
+/// the connection is accepted rather than for every request. Requests served on platforms
+/// where peer credentials can't be queried carry no `PeerCred`.
+///
+/// For servers receiving file descriptors, the descriptors received since the previous
+/// request are inserted too, as `socket::ReceivedFds`.
+pub struct WithPeerCred<S> {
+    inner: S,
+    peer_cred: Option<PeerCred>,
+    fds: Option<FdQueue>,
+    // the service lives exactly as long as its connection
+    _active: Option<Active>,
+    _tracked: Option<Tracked>,
+}
+
+impl<S> WithPeerCred<S> {
+    /// Return the credentials of the peer this service is serving, if they could be queried.
+    pub fn peer_cred(&self) -> Option<&PeerCred> {
+        self.peer_cred.as_ref()
+    }
+
+    /// Return a reference to the wrapped service.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S, B, R> Service<Request<B>> for WithPeerCred<S>
+where
+    S: Service<Request<B>, Response = Response<R>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Probed<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<B>) -> Self::Future {
+        if let Some(peer_cred) = self.peer_cred {
+            req.extensions_mut().insert(peer_cred);
+        }
+        if let Some(fds) = self.fds.as_ref().and_then(ReceivedFds::take) {
+            req.extensions_mut().insert(fds);
+        }
+        let request = probes::request_start(self.peer_cred.as_ref());
+        Probed::new(self.inner.call(req), request)
     }
 }
 
@@ -168,11 +1313,10 @@ where
 /// # Examples
 ///
 /// ```rust
-/// extern crate hyper;
-/// extern crate hyperlocal;
-///
 /// use std::os::unix::net::UnixListener;
-/// use hyper::{Response, rt::{Future, Stream}, service::service_fn};
+///
+/// use futures::StreamExt;
+/// use hyper::{service::service_fn, Response};
 /// use hyperlocal::server::{Http, Incoming};
 ///
 /// # if let Err(err) =  std::fs::remove_file("hyperlocal_test_echo_server_2.sock") {
@@ -182,28 +1326,28 @@ where
 /// # }
 /// #
 /// let listener = UnixListener::bind("hyperlocal_test_echo_server_2.sock").unwrap();
-/// let incoming = Incoming::from_std(listener, &Default::default()).unwrap();
-/// let serve = Http::new().serve_incoming(
+/// let incoming = Incoming::from_std(listener).unwrap();
+/// let mut serve = Http::new().serve_incoming(
 ///   incoming,
 ///   move || service_fn(
-///     |req| Ok::<_, hyper::Error>(Response::new(req.into_body()))
+///     |req| async move { Ok::<_, hyper::Error>(Response::new(req.into_body())) }
 ///   )
 ///  );
 ///
-/// let server = serve.for_each(|connecting| {
-///     connecting
-///     .then(|connection| {
-///         let connection = connection.unwrap();
-///         Ok::<_, hyper::Error>(connection)
-///     })
-///     .flatten()
-///     .map_err(|err| {
-///         std::io::Error::new(
-///             std::io::ErrorKind::Other,
-///             format!("failed to serve connection: {}", err),
-///         )
-///     })
-/// });
+/// let server = async move {
+///     while let Some(connecting) = serve.next().await {
+///         let connecting = connecting?;
+///         tokio::spawn(async move {
+///             if let Ok(connection) = connecting.await {
+///                 if let Err(err) = connection.await {
+///                     eprintln!("failed to serve connection: {}", err);
+///                 }
+///             }
+///         });
+///     }
+///     Ok::<_, std::io::Error>(())
+/// };
+/// # std::fs::remove_file("hyperlocal_test_echo_server_2.sock").ok();
 /// ```
 #[derive(Clone)]
 pub struct Http {
@@ -223,7 +1367,44 @@ impl Http {
         Http { inner: hyper_http }
     }
 
-    /// Bind the provided `path` with the default `Handle` and return `Serve`.
+    /// Apply HTTP/2 flow control `windows` to the connections served.
+    pub fn http2_windows(mut self, windows: &Windows) -> Self {
+        windows.configure_server(&mut self.inner);
+        self
+    }
+
+    /// Cap the size of the buffer an HTTP/1 connection reads requests into, in bytes.
+    pub fn http1_max_buf_size(mut self, size: usize) -> Self {
+        self.inner.max_buf_size(size);
+        self
+    }
+
+    /// Write response header names in title case, such as `Content-Length`, rather than
+    /// lowercased.
+    pub fn http1_title_case_headers(mut self, enabled: bool) -> Self {
+        self.inner.http1_title_case_headers(enabled);
+        self
+    }
+
+    /// Record the case of request header names as received, and write response header names
+    /// in the case recorded for them.
+    ///
+    /// This only matters to services forwarding requests and responses on, like a proxy, as
+    /// `HeaderMap` lowercases names and hyper keeps their original case in a private
+    /// extension.
+    pub fn http1_preserve_header_case(mut self, enabled: bool) -> Self {
+        self.inner.http1_preserve_header_case(enabled);
+        self
+    }
+
+    /// Keep answering HTTP/1 clients which shut down their writing half after sending a
+    /// request, rather than closing their connection.
+    pub fn http1_half_close(mut self, enabled: bool) -> Self {
+        self.inner.http1_half_close(enabled);
+        self
+    }
+
+    /// Bind the provided `path` and return `Serve`.
     ///
     /// This method will bind the unix domain socket path provided with
     /// a new UDS listener ready to accept connections. Each connection will be
@@ -231,20 +1412,18 @@ impl Http {
     /// connection.
     ///
     /// If the provided path already exists, this method will return an error.
-    pub fn serve_path<P, S>(&self, path: P, new_service: S) -> io::Result<Serve<S>>
+    pub fn serve_path<P, S>(&self, path: P, new_service: S) -> Result<Serve<S>, Error>
     where
         P: AsRef<Path>,
         S: NewService<ReqBody = Body>,
-        S::ResBody: Payload,
-        S::Service: Send,
-        S::Error: Into<Box<error::Error + Send + Sync>>,
-        <S::Service as Service>::Future: Send + 'static,
     {
-        let incoming = Incoming::new(path, None)?;
+        let incoming = Incoming::new(path.as_ref(), None)
+            .map_err(|err| Error::bind(path.as_ref().display(), err))?;
         Ok(self.serve_incoming(incoming, new_service))
     }
 
-    /// Bind the provided `path` with the `Handle` and return `Serve`.
+    /// Bind the provided `path`, registering it with the runtime of `handle`, and return
+    /// `Serve`.
     ///
     /// This method will bind the unix domain socket path provided with
     /// a new UDS listener ready to accept connections. Each connection will be
@@ -257,16 +1436,13 @@ impl Http {
         path: P,
         handle: &Handle,
         new_service: S,
-    ) -> io::Result<Serve<S>>
+    ) -> Result<Serve<S>, Error>
     where
         P: AsRef<Path>,
-        S: NewService<ReqBody = Body> + Send + 'static,
-        S::ResBody: Payload,
-        S::Service: Send,
-        S::Error: Into<Box<error::Error + Send + Sync>>,
-        <S::Service as Service>::Future: Send + 'static,
+        S: NewService<ReqBody = Body>,
     {
-        let incoming = Incoming::new(path, Some(handle))?;
+        let incoming = Incoming::new(path.as_ref(), Some(handle))
+            .map_err(|err| Error::bind(path.as_ref().display(), err))?;
         Ok(self.serve_incoming(incoming, new_service))
     }
 
@@ -274,17 +1450,39 @@ impl Http {
     pub fn serve_incoming<S>(&self, incoming: Incoming, new_service: S) -> Serve<S>
     where
         S: NewService<ReqBody = Body>,
-        S::ResBody: Payload,
-        S::Error: Into<Box<::std::error::Error + Send + Sync>>,
     {
+        let (rebind_tx, rebind_rx) = mpsc::unbounded();
         Serve {
+            bound: Arc::new(Mutex::new(Bound::of(&incoming))),
             incoming,
             new_service,
             protocol: self.inner.clone(),
+            retiring: None,
+            rebind_tx,
+            rebind_rx,
+            pauser: Pauser::new(),
+            filter: None,
+            filtering: FuturesUnordered::new(),
+            metrics: None,
+            receive_fds: false,
+            tap: None,
+            on_accept_error: None,
+            backoff: None,
+            on_panic: None,
+            health: None,
+            connections: None,
+            throttle: None,
+            accounting: None,
         }
     }
 }
 
+impl Default for Http {
+    fn default() -> Self {
+        Http::new()
+    }
+}
+
 impl From<HyperHttp> for Http {
     fn from(hyper_http: HyperHttp) -> Self {
         Http::from_hyper(hyper_http)
@@ -293,47 +1491,202 @@ impl From<HyperHttp> for Http {
 
 /// A stream of unix domain socket connections.
 pub struct Incoming {
-    inner: UnixIncoming,
+    inner: Accept,
     local_addr: SocketAddr,
+    path: Option<PathBuf>,
+    fd: Option<RawFd>,
+}
+
+/// Where an `Incoming` takes its connections from.
+enum Accept {
+    /// A listener not yet registered with a runtime, which it is on first being polled.
+    Std(Option<StdUnixListener>),
+    Listener(UnixListener),
+    /// The server ends of socket pairs made by a `testing::DuplexConnector`.
+    #[cfg(feature = "client")]
+    Duplex(DuplexReceiver<(UnixStream, PeerCred)>),
 }
 
 impl Incoming {
-    /// Bind a listener to the provided `path` with the provided `Handle`.
+    /// Bind a listener to the provided `path`.
     ///
-    /// If the `Handle` is `None`, the current runtime handle is used.
+    /// If `handle` is given, the listener is registered with its runtime right away.
+    /// Otherwise it is registered with whichever runtime first polls it.
     pub fn new<P>(path: P, handle: Option<&Handle>) -> io::Result<Self>
     where
         P: AsRef<Path>,
     {
-        let listener = StdUnixListener::bind(path)?;
-        match handle {
-            Some(handle) => Incoming::from_std(listener, handle),
-            None => {
-                let handle = Handle::current();
-                Incoming::from_std(listener, &handle)
-            }
+        let listener = StdUnixListener::bind(path.as_ref())?;
+        let mut incoming = Incoming::from_std(listener)?;
+        if let Some(handle) = handle {
+            let _guard = handle.enter();
+            incoming.register()?;
         }
+        incoming.path = Some(path.as_ref().to_path_buf());
+        Ok(incoming)
     }
 
-    /// Wrap the provided already-bound listener in a `tokio_uds` listener using the provided `Handle`.
-    pub fn from_std(listener: StdUnixListener, handle: &Handle) -> io::Result<Self> {
-        let listener = UnixListener::from_std(listener, handle)?;
+    /// Wrap the provided already-bound listener.
+    ///
+    /// The listener is switched to non-blocking mode, and is registered with whichever
+    /// tokio runtime first polls the `Incoming`.
+    pub fn from_std(listener: StdUnixListener) -> io::Result<Self> {
+        listener.set_nonblocking(true)?;
         let local_addr = listener.local_addr()?;
-        let inner = listener.incoming();
-        Ok(Incoming { inner, local_addr })
+        let path = local_addr.as_pathname().map(Path::to_path_buf);
+        let fd = Some(listener.as_raw_fd());
+        Ok(Incoming {
+            inner: Accept::Std(Some(listener)),
+            local_addr,
+            path,
+            fd,
+        })
+    }
+
+    /// Accept the server ends of the socket pairs sent to `rx`, along with the peer
+    /// credentials to report for them.
+    #[cfg(feature = "client")]
+    pub(crate) fn duplex(
+        rx: DuplexReceiver<(UnixStream, PeerCred)>,
+        local_addr: SocketAddr,
+    ) -> Self {
+        Incoming {
+            inner: Accept::Duplex(rx),
+            local_addr,
+            path: None,
+            fd: None,
+        }
     }
 
     /// Get the local address bound to this listener.
     pub fn local_addr(&self) -> &SocketAddr {
         &self.local_addr
     }
+
+    /// Register the listener with the current runtime, if it hasn't been already.
+    ///
+    /// The descriptor stays the same, so handles sharing it keep working.
+    fn register(&mut self) -> io::Result<()> {
+        if let Accept::Std(ref mut listener) = self.inner {
+            let listener = listener.take().expect("registering failed before");
+            self.inner = Accept::Listener(UnixListener::from_std(listener)?);
+        }
+        Ok(())
+    }
+
+    /// Accept the next connection, along with the credentials of its peer.
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<Option<io::Result<PeerStream>>> {
+        if let Err(err) = self.register() {
+            return Poll::Ready(Some(Err(err)));
+        }
+        match self.inner {
+            Accept::Std(_) => unreachable!("listener was just registered"),
+            Accept::Listener(ref listener) => {
+                let (stream, _) = match ready!(listener.poll_accept(cx)) {
+                    Ok(accepted) => accepted,
+                    Err(err) => return Poll::Ready(Some(Err(err))),
+                };
+                let peer_cred = PeerCred::of(&stream).ok();
+                Poll::Ready(Some(Ok((stream, peer_cred))))
+            }
+            #[cfg(feature = "client")]
+            Accept::Duplex(ref mut rx) => {
+                let accepted = ready!(rx.poll_next_unpin(cx));
+                Poll::Ready(accepted.map(|(stream, peer_cred)| Ok((stream, Some(peer_cred)))))
+            }
+        }
+    }
 }
 
+/// An accepted stream, along with the credentials of its peer if they could be queried.
+type PeerStream = (UnixStream, Option<PeerCred>);
+
 impl Stream for Incoming {
-    type Item = <UnixIncoming as Stream>::Item;
-    type Error = <UnixIncoming as Stream>::Error;
+    type Item = io::Result<UnixStream>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let accepted = ready!(self.poll_accept(cx));
+        Poll::Ready(accepted.map(|accepted| accepted.map(|(stream, _)| stream)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempSocket;
+    use hyper::service::service_fn;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn servers_answer_half_closed_connections() {
+        let socket = TempSocket::new().unwrap();
+        let server = Server::bind(socket.path(), || {
+            service_fn(|_| async { Ok::<_, hyper::Error>(Response::new(Body::from("ok"))) })
+        })
+        .unwrap()
+        .http1_half_close(true);
+        let runtime = Runtime::new().unwrap();
+        let response = runtime.block_on(async {
+            tokio::spawn(
+                server
+                    .serve
+                    .serve_with_shutdown(futures::future::pending::<()>()),
+            );
+            let mut stream = UnixStream::connect(socket.path()).await.unwrap();
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            stream.shutdown().await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        });
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nok"), "{}", response);
+    }
+
+    #[test]
+    fn servers_write_title_case_headers() {
+        let socket = TempSocket::new().unwrap();
+        let server = Server::bind(socket.path(), || {
+            service_fn(|_| async { Ok::<_, hyper::Error>(Response::new(Body::from("ok"))) })
+        })
+        .unwrap()
+        .http1_title_case_headers(true);
+        let runtime = Runtime::new().unwrap();
+        let response = runtime.block_on(async {
+            tokio::spawn(
+                server
+                    .serve
+                    .serve_with_shutdown(futures::future::pending::<()>()),
+            );
+            let mut stream = UnixStream::connect(socket.path()).await.unwrap();
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        });
+        assert!(
+            response.contains("\r\nContent-Length: 2\r\n"),
+            "{}",
+            response
+        );
+    }
 
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        self.inner.poll()
+    #[test]
+    fn servers_report_their_signal_failing() {
+        let socket = TempSocket::new().unwrap();
+        let server = Server::bind(socket.path(), || {
+            service_fn(|_| async { Ok::<_, hyper::Error>(Response::new(Body::empty())) })
+        })
+        .unwrap();
+        let failed = futures::future::ready(Err::<(), _>(io::Error::from(io::ErrorKind::Other)));
+        let err = server.run_until(failed).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert!(!socket.path().exists());
     }
 }
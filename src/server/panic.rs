@@ -0,0 +1,142 @@
+//! Keeping a server alive through panicking connections
+
+// Std lib
+use std::any::Any;
+use std::fmt;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use crate::credentials::PeerCred;
+
+/// A panic caught while serving a connection, reported to the callback registered with
+/// `Server::on_panic`.
+#[derive(Debug)]
+pub struct Panic {
+    peer_cred: Option<PeerCred>,
+    message: String,
+}
+
+impl Panic {
+    /// Return the credentials of the peer whose connection panicked, if they could be queried.
+    pub fn peer_cred(&self) -> Option<&PeerCred> {
+        self.peer_cred.as_ref()
+    }
+
+    /// Return the message the connection panicked with.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for Panic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.peer_cred {
+            Some(peer_cred) => {
+                write!(f, "uid={} gid={} ", peer_cred.uid(), peer_cred.gid())?;
+                match peer_cred.pid() {
+                    Some(pid) => write!(f, "pid={} ", pid)?,
+                    None => f.write_str("pid=- ")?,
+                }
+            }
+            None => f.write_str("uid=- gid=- pid=- ")?,
+        }
+        write!(f, "connection panicked: {}", self.message)
+    }
+}
+
+/// A type-erased panic callback, as registered through `Server::on_panic`.
+pub(crate) type OnPanic = Arc<dyn Fn(&Panic) + Send + Sync>;
+
+/// A future serving a connection, resolving as though the connection had closed if
+/// serving it panics.
+///
+/// Panics are reported to `on_panic`, or logged with the `log` feature when there is no
+/// callback.
+pub(crate) struct Isolated<F> {
+    inner: Option<Pin<Box<F>>>,
+    peer_cred: Option<PeerCred>,
+    on_panic: Option<OnPanic>,
+}
+
+impl<F> Isolated<F> {
+    pub(crate) fn new(inner: F, peer_cred: Option<PeerCred>, on_panic: Option<OnPanic>) -> Self {
+        Isolated {
+            inner: Some(Box::pin(inner)),
+            peer_cred,
+            on_panic,
+        }
+    }
+}
+
+impl<F> Future for Isolated<F>
+where
+    F: Future<Output = ()>,
+{
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let payload = match self.inner {
+            Some(ref mut inner) => {
+                match panic::catch_unwind(AssertUnwindSafe(|| inner.as_mut().poll(cx))) {
+                    Ok(polled) => return polled,
+                    Err(payload) => payload,
+                }
+            }
+            None => return Poll::Ready(()),
+        };
+        // whatever state the connection was left in, it can't be served any further
+        self.inner = None;
+        let panic = Panic {
+            peer_cred: self.peer_cred,
+            message: message(&*payload),
+        };
+        if let Some(ref on_panic) = self.on_panic {
+            on_panic(&panic);
+        } else {
+            #[cfg(feature = "log")]
+            crate::logs::panicked(&panic);
+        }
+        Poll::Ready(())
+    }
+}
+
+/// Return the message of a panic payload, as given to `panic!`.
+fn message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Box<dyn Any>".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use std::sync::Mutex;
+
+    #[test]
+    fn isolated_reports_panics() {
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let on_panic: OnPanic = {
+            let reported = reported.clone();
+            Arc::new(move |panic: &Panic| reported.lock().unwrap().push(panic.to_string()))
+        };
+        let panicking = future::lazy(|_| -> () { panic!("boom") });
+        let isolated = Isolated::new(
+            panicking,
+            Some(PeerCred::new(1000, 100, Some(42))),
+            Some(on_panic),
+        );
+        futures::executor::block_on(isolated);
+        assert_eq!(
+            *reported.lock().unwrap(),
+            vec!["uid=1000 gid=100 pid=42 connection panicked: boom".to_string()]
+        );
+    }
+}
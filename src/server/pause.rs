@@ -0,0 +1,162 @@
+//! Pausing and resuming accepting connections
+//!
+//! A daemon under maintenance or overloaded may want to stop taking on new clients for a
+//! while without shutting down. Pausing a server, through the `Pauser` returned by
+//! `Server::pauser`, stops it accepting connections until it is resumed. Connections already
+//! accepted keep being served, and clients connecting in the meantime wait in the listener's
+//! backlog, to be accepted on resuming, rather than being refused.
+
+// Std lib
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+
+/// A handle for pausing and resuming a running server, obtained from `Server::pauser` or
+/// `Serve::pauser`.
+///
+/// Workers started by `Server::run_workers_until` share their server's `Pauser`, so pausing
+/// it pauses all of them.
+///
+/// # Examples
+///
+/// ```rust
+/// use hyper::service::service_fn;
+/// use hyperlocal::server::Server;
+///
+/// # if let Err(err) = std::fs::remove_file("hyperlocal_test_pause_server.sock") {
+/// #   if err.kind() != std::io::ErrorKind::NotFound {
+/// #     panic!("{}", err)
+/// #   }
+/// # }
+/// #
+/// let server = Server::bind(
+///    "hyperlocal_test_pause_server.sock",
+///    || service_fn(|req| async move { Ok::<_, hyper::Error>(hyper::Response::new(req.into_body())) })
+/// )
+/// .unwrap();
+///
+/// let pauser = server.pauser();
+/// pauser.pause();
+/// // maintenance happens here
+/// pauser.resume();
+/// assert!(!pauser.is_paused());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Pauser {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    paused: AtomicBool,
+    // the servers, or workers of one, waiting to be resumed
+    waiting: Mutex<Vec<Waker>>,
+}
+
+impl Pauser {
+    pub(crate) fn new() -> Self {
+        Pauser::default()
+    }
+
+    /// Stop accepting connections until `resume` is called.
+    ///
+    /// Pausing a paused server does nothing.
+    pub fn pause(&self) {
+        self.inner.paused.store(true, Ordering::Release);
+    }
+
+    /// Start accepting connections again, beginning with those that queued up while paused.
+    ///
+    /// Resuming a server which isn't paused does nothing.
+    pub fn resume(&self) {
+        self.inner.paused.store(false, Ordering::Release);
+        let waiting = mem::take(&mut *self.waiting());
+        for waker in waiting {
+            waker.wake();
+        }
+    }
+
+    /// Return true if the server is paused.
+    pub fn is_paused(&self) -> bool {
+        self.inner.paused.load(Ordering::Acquire)
+    }
+
+    /// Return ready once the server isn't paused, waking the task when it is resumed.
+    pub(crate) fn poll_resumed(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if !self.is_paused() {
+            return Poll::Ready(());
+        }
+        let mut waiting = self.waiting();
+        // checked again under the lock, so that a concurrent resume can't miss this task
+        if !self.is_paused() {
+            return Poll::Ready(());
+        }
+        if !waiting.iter().any(|waker| waker.will_wake(cx.waker())) {
+            waiting.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+
+    fn waiting(&self) -> MutexGuard<'_, Vec<Waker>> {
+        self.inner.waiting.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::Server;
+    use crate::testing::TempSocket;
+    use futures::future;
+    use hyper::service::service_fn;
+    use hyper::{Body, Response};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixStream;
+
+    const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nhost: local\r\n\r\n";
+
+    // read a response, which ends in the body "ok"
+    async fn read_response(stream: &mut UnixStream) -> Vec<u8> {
+        let mut response = Vec::new();
+        while !response.ends_with(b"ok") {
+            let mut chunk = [0; 256];
+            let read = stream.read(&mut chunk).await.unwrap();
+            assert!(read > 0, "connection closed");
+            response.extend_from_slice(&chunk[..read]);
+        }
+        response
+    }
+
+    #[test]
+    fn paused_servers_serve_queued_connections_on_resuming() {
+        let socket = TempSocket::new().unwrap();
+        let server = Server::bind(socket.path(), || {
+            service_fn(|_| async { Ok::<_, hyper::Error>(Response::new(Body::from("ok"))) })
+        })
+        .unwrap();
+        let pauser = server.pauser();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            tokio::spawn(server.serve.serve_with_shutdown(future::pending::<()>()));
+            let mut open = UnixStream::connect(socket.path()).await.unwrap();
+            open.write_all(REQUEST).await.unwrap();
+            assert!(read_response(&mut open).await.starts_with(b"HTTP/1.1 200"));
+
+            pauser.pause();
+            let mut queued = UnixStream::connect(socket.path()).await.unwrap();
+            queued.write_all(REQUEST).await.unwrap();
+            let waited =
+                tokio::time::timeout(Duration::from_millis(100), read_response(&mut queued)).await;
+            assert!(waited.is_err(), "paused server accepted a connection");
+            // the connection accepted before pausing is still served
+            open.write_all(REQUEST).await.unwrap();
+            assert!(read_response(&mut open).await.starts_with(b"HTTP/1.1 200"));
+
+            pauser.resume();
+            assert!(read_response(&mut queued)
+                .await
+                .starts_with(b"HTTP/1.1 200"));
+        });
+    }
+}
@@ -0,0 +1,340 @@
+//! Exposing metrics to Prometheus over an admin socket
+//!
+//! `Exporter` renders a server's `metrics::Counters`, and, with the `client` feature, a
+//! client's `client::metrics::Latencies`, in Prometheus' text exposition format.
+//! `Server#metrics_socket` serves them at `GET /metrics` on a second socket, bound with its own
+//! `BindOptions`, so that a scraper can be granted access to the metrics without being granted
+//! access to the server's API.
+
+// Std lib
+use std::fmt::Write;
+use std::io;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+#[cfg(feature = "client")]
+use std::time::Duration;
+
+// Third party
+use futures::channel::oneshot;
+use futures::future::{self, FutureExt};
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+
+#[cfg(feature = "client")]
+use crate::client::metrics::{EndpointLatencies, Histogram, Latencies};
+use crate::endpoint::Endpoint;
+use crate::error::Error;
+use crate::server::bind::BindOptions;
+use crate::server::metrics::Counters;
+use crate::server::Server;
+
+/// The path metrics are served at.
+pub const METRICS: &str = "/metrics";
+
+/// The content type of the text exposition format.
+pub const CONTENT_TYPE_TEXT: &str = "text/plain; version=0.0.4";
+
+/// A set of metrics rendered in Prometheus' text exposition format.
+///
+/// # Examples
+///
+/// ```rust
+/// use std::sync::Arc;
+///
+/// use hyperlocal::server::{metrics::Counters, prometheus::Exporter};
+///
+/// let counters = Arc::new(Counters::default());
+/// let exporter = Exporter::new().counters(counters);
+/// assert!(exporter.render().contains("hyperlocal_connections_accepted_total 0\n"));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Exporter {
+    counters: Option<Arc<Counters>>,
+    #[cfg(feature = "client")]
+    latencies: Option<Arc<Latencies>>,
+}
+
+impl Exporter {
+    /// Create an exporter of no metrics.
+    pub fn new() -> Self {
+        Exporter::default()
+    }
+
+    /// Export a server's connection `counters`.
+    pub fn counters(mut self, counters: Arc<Counters>) -> Self {
+        self.counters = Some(counters);
+        self
+    }
+
+    /// Export a client's `latencies`, labelled with each endpoint.
+    #[cfg(feature = "client")]
+    pub fn latencies(mut self, latencies: Arc<Latencies>) -> Self {
+        self.latencies = Some(latencies);
+        self
+    }
+
+    /// Return the connection counters exported, if any.
+    pub(crate) fn exported_counters(&self) -> Option<&Arc<Counters>> {
+        self.counters.as_ref()
+    }
+
+    /// Render the metrics.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        if let Some(ref counters) = self.counters {
+            let mut metric = |name: &str, kind: &str, help: &str, value: usize| {
+                let _ = write!(
+                    out,
+                    "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n",
+                    name = name,
+                    kind = kind,
+                    help = help,
+                    value = value
+                );
+            };
+            metric(
+                "hyperlocal_connections_accepted_total",
+                "counter",
+                "Connections accepted.",
+                counters.accepted(),
+            );
+            metric(
+                "hyperlocal_connections_active",
+                "gauge",
+                "Connections currently open.",
+                counters.active(),
+            );
+            metric(
+                "hyperlocal_accept_errors_total",
+                "counter",
+                "Failed attempts to accept a connection.",
+                counters.accept_errors(),
+            );
+            metric(
+                "hyperlocal_connections_rejected_total",
+                "counter",
+                "Connections dropped by the accept filter.",
+                counters.rejected(),
+            );
+            metric(
+                "hyperlocal_requests_forbidden_total",
+                "counter",
+                "Requests refused by a guard.",
+                counters.forbidden(),
+            );
+        }
+        #[cfg(feature = "client")]
+        {
+            if let Some(ref latencies) = self.latencies {
+                render_latencies(&mut out, latencies);
+            }
+        }
+        out
+    }
+
+    /// Answer `req`, with the metrics for `GET /metrics` and `404 Not Found` otherwise.
+    pub fn respond<B>(&self, req: &Request<B>) -> Response<Body> {
+        let get = req.method() == Method::GET || req.method() == Method::HEAD;
+        if !get || req.uri().path() != METRICS {
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            return response;
+        }
+        let mut response = Response::new(Body::from(self.render()));
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static(CONTENT_TYPE_TEXT));
+        response
+    }
+}
+
+/// An admin socket serving an `Exporter`'s metrics from a thread of its own.
+///
+/// Dropping it stops serving, waits for in-flight scrapes and removes the socket file.
+pub(crate) struct MetricsSocket {
+    shutdown: Option<oneshot::Sender<()>>,
+    thread: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl MetricsSocket {
+    pub(crate) fn bind(
+        endpoint: &Endpoint,
+        options: &BindOptions,
+        exporter: Exporter,
+    ) -> Result<Self, Error> {
+        let server = Server::bind_with(endpoint, options, move || {
+            let exporter = exporter.clone();
+            service_fn(move |req: Request<Body>| {
+                future::ok::<_, hyper::Error>(exporter.respond(&req))
+            })
+        })?;
+        let (shutdown, stopped) = oneshot::channel::<()>();
+        let thread = thread::Builder::new()
+            .name("hyperlocal-metrics".into())
+            .spawn(move || server.run_until(stopped.map(|_| Ok(()))))
+            .map_err(|err| Error::bind(endpoint, err))?;
+        Ok(MetricsSocket {
+            shutdown: Some(shutdown),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for MetricsSocket {
+    fn drop(&mut self) {
+        drop(self.shutdown.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Picks one of an endpoint's histograms.
+#[cfg(feature = "client")]
+type HistogramOf = fn(&EndpointLatencies) -> &Histogram;
+
+/// Render each endpoint's latency histograms to `out`.
+#[cfg(feature = "client")]
+fn render_latencies(out: &mut String, latencies: &Latencies) {
+    let mut endpoints = latencies.endpoints();
+    endpoints.sort_by_key(|(endpoint, _)| endpoint.as_ref().map(ToString::to_string));
+    let histograms: [(&str, &str, HistogramOf); 3] = [
+        (
+            "hyperlocal_client_connect_seconds",
+            "Time taken to connect.",
+            EndpointLatencies::connect,
+        ),
+        (
+            "hyperlocal_client_first_byte_seconds",
+            "Time to the first byte of a response.",
+            EndpointLatencies::first_byte,
+        ),
+        (
+            "hyperlocal_client_request_seconds",
+            "Time to the last byte of a response.",
+            EndpointLatencies::total,
+        ),
+    ];
+    for (name, help, histogram_of) in histograms.iter() {
+        let _ = write!(
+            out,
+            "# HELP {name} {help}\n# TYPE {name} histogram\n",
+            name = name,
+            help = help
+        );
+        for (endpoint, latencies) in endpoints.iter() {
+            let endpoint = endpoint
+                .as_ref()
+                .map(ToString::to_string)
+                .unwrap_or_default();
+            let label = escape(&endpoint);
+            let histogram = histogram_of(latencies);
+            let buckets = histogram.buckets();
+            // the last bucket also counts everything longer, so is only accounted for in +Inf
+            let mut cumulative = 0;
+            for &(bound, count) in &buckets[..buckets.len() - 1] {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{{endpoint=\"{}\",le=\"{}\"}} {}",
+                    name,
+                    label,
+                    seconds(bound),
+                    cumulative
+                );
+            }
+            cumulative += buckets[buckets.len() - 1].1;
+            let _ = writeln!(
+                out,
+                "{}_bucket{{endpoint=\"{}\",le=\"+Inf\"}} {}",
+                name, label, cumulative
+            );
+            let _ = writeln!(
+                out,
+                "{}_sum{{endpoint=\"{}\"}} {}",
+                name,
+                label,
+                seconds(histogram.sum())
+            );
+            let _ = writeln!(
+                out,
+                "{}_count{{endpoint=\"{}\"}} {}",
+                name, label, cumulative
+            );
+        }
+    }
+}
+
+#[cfg(feature = "client")]
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs_f64()
+}
+
+/// Escape `value` for use as a label value.
+#[cfg(feature = "client")]
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::metrics::Metrics;
+
+    #[test]
+    fn exporters_render_counters() {
+        let counters = Arc::new(Counters::default());
+        counters.connection_accepted();
+        counters.connection_accepted();
+        counters.connection_closed();
+        let exporter = Exporter::new().counters(counters);
+        let rendered = exporter.render();
+        assert!(rendered.contains("# TYPE hyperlocal_connections_active gauge\n"));
+        assert!(rendered.contains("hyperlocal_connections_accepted_total 2\n"));
+        assert!(rendered.contains("hyperlocal_connections_active 1\n"));
+
+        let req = Request::get(METRICS).body(()).unwrap();
+        let response = exporter.respond(&req);
+        assert_eq!(response.headers()[CONTENT_TYPE], CONTENT_TYPE_TEXT);
+        let req = Request::get("/").body(()).unwrap();
+        assert_eq!(exporter.respond(&req).status(), StatusCode::NOT_FOUND);
+    }
+
+    #[cfg(feature = "client")]
+    #[test]
+    fn metrics_sockets_serve_metrics() {
+        use crate::testing::TempSocket;
+        use crate::{UnixConnector, Uri};
+
+        let main = TempSocket::new().unwrap();
+        let admin = TempSocket::new().unwrap();
+        let endpoint = Endpoint::Path(admin.path().to_owned());
+        let server = Server::bind(main.path(), || {
+            service_fn(|req: Request<Body>| async move {
+                Ok::<_, hyper::Error>(Response::new(req.into_body()))
+            })
+        })
+        .unwrap()
+        .metrics_socket(&endpoint, &BindOptions::new().mode(0o600), Exporter::new())
+        .unwrap();
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let body = runtime.block_on(async {
+            let client = hyper::Client::builder().build::<_, Body>(UnixConnector::new());
+            let response = client
+                .get(Uri::new(admin.path(), METRICS).into())
+                .await
+                .unwrap();
+            hyper::body::to_bytes(response.into_body()).await.unwrap()
+        });
+        assert!(std::str::from_utf8(&body)
+            .unwrap()
+            .contains("hyperlocal_connections_active 0\n"));
+        drop(server);
+        assert!(!admin.path().exists());
+    }
+}
@@ -0,0 +1,288 @@
+//! Forwarding requests to an upstream server
+//!
+//! `Proxy` serves the common pattern of exposing an internal HTTP service only through a
+//! unix domain socket: served with a `Server`, it forwards every request it receives to a
+//! TCP or unix domain socket upstream and streams the upstream's response back. Request and
+//! response bodies are streamed rather than buffered, and connections upgraded by the
+//! upstream, say to a WebSocket, are spliced through to the client.
+//!
+//! Hop-by-hop headers, such as `Connection` and `Transfer-Encoding`, only apply to a single
+//! connection and are not forwarded. Requests the upstream fails to answer are answered with
+//! `502 Bad Gateway`.
+
+// Std lib
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+// Third party
+use futures::future::{self, Ready};
+use hyper::client::connect::Connect;
+use hyper::client::HttpConnector;
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::http::uri::{Authority, Scheme};
+use hyper::service::Service;
+use hyper::upgrade::Upgraded;
+use hyper::{Body, Client, Request, Response, StatusCode, Uri as HyperUri};
+
+use super::websocket::has_token;
+use super::NewService;
+use crate::client::UnixConnector;
+use crate::Uri;
+
+/// Headers which only apply to a single connection, as listed in RFC 7230.
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Where a `Proxy` forwards requests to.
+#[derive(Debug)]
+enum Upstream {
+    Tcp(Scheme, Authority),
+    Unix(PathBuf),
+}
+
+impl Upstream {
+    /// Return the uri `uri` is forwarded to.
+    fn uri(&self, uri: &HyperUri) -> HyperUri {
+        let path = uri.path_and_query().map_or("/", |path| path.as_str());
+        match *self {
+            Upstream::Tcp(ref scheme, ref authority) => HyperUri::builder()
+                .scheme(scheme.clone())
+                .authority(authority.clone())
+                .path_and_query(path)
+                .build()
+                .expect("parts of valid uris form a valid uri"),
+            Upstream::Unix(ref socket) => Uri::new(socket, path).into(),
+        }
+    }
+}
+
+/// A `NewService` forwarding requests to an upstream server.
+///
+/// # Examples
+///
+/// ```rust
+/// use hyperlocal::server::{proxy::Proxy, Server};
+///
+/// # if let Err(err) = std::fs::remove_file("hyperlocal_test_proxy_server.sock") {
+/// #   if err.kind() != std::io::ErrorKind::NotFound {
+/// #     panic!("{}", err)
+/// #   }
+/// # }
+/// #
+/// let server = Server::bind(
+///    "hyperlocal_test_proxy_server.sock",
+///    Proxy::tcp("http://127.0.0.1:8080".parse().unwrap()).unwrap(),
+/// ).unwrap();
+/// ```
+pub struct Proxy<C> {
+    client: Client<C, Body>,
+    upstream: Arc<Upstream>,
+}
+
+impl Proxy<HttpConnector> {
+    /// Forward requests to the TCP upstream at `upstream`, an `http` uri with no path, such
+    /// as `http://127.0.0.1:8080`.
+    pub fn tcp(upstream: HyperUri) -> io::Result<Self> {
+        let parts = upstream.into_parts();
+        let path = parts
+            .path_and_query
+            .as_ref()
+            .map_or("/", |path| path.as_str());
+        let (scheme, authority) = match (parts.scheme, parts.authority) {
+            (Some(scheme), Some(authority)) if scheme == Scheme::HTTP && path == "/" => {
+                (scheme, authority)
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "upstream must be an http uri with an authority and no path",
+                ))
+            }
+        };
+        Ok(Proxy {
+            client: Client::builder().build(HttpConnector::new()),
+            upstream: Arc::new(Upstream::Tcp(scheme, authority)),
+        })
+    }
+}
+
+impl Proxy<UnixConnector> {
+    /// Forward requests to the server listening on the unix domain socket at `socket`.
+    pub fn unix<P>(socket: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        Proxy {
+            client: Client::builder().build(UnixConnector::new()),
+            upstream: Arc::new(Upstream::Unix(socket.as_ref().to_owned())),
+        }
+    }
+}
+
+impl<C: Clone> Clone for Proxy<C> {
+    fn clone(&self) -> Self {
+        Proxy {
+            client: self.client.clone(),
+            upstream: self.upstream.clone(),
+        }
+    }
+}
+
+impl<C> NewService for Proxy<C>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = hyper::Error;
+    type Service = ProxyService<C>;
+    type Future = Ready<Result<Self::Service, io::Error>>;
+    type InitError = io::Error;
+
+    fn new_service(&self) -> Self::Future {
+        future::ok(ProxyService {
+            client: self.client.clone(),
+            upstream: self.upstream.clone(),
+        })
+    }
+}
+
+/// A `Service` forwarding requests to an upstream server.
+pub struct ProxyService<C> {
+    client: Client<C, Body>,
+    upstream: Arc<Upstream>,
+}
+
+impl<C> Service<Request<Body>> for ProxyService<C>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    type Response = Response<Body>;
+    type Error = hyper::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<Body>, hyper::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        let upgrade = upgrade_protocol(req.headers());
+        remove_hop_by_hop(req.headers_mut());
+        *req.uri_mut() = self.upstream.uri(req.uri());
+        let on_upgrade = upgrade.map(|protocol| {
+            restore_upgrade(req.headers_mut(), protocol);
+            hyper::upgrade::on(&mut req)
+        });
+        let responding = self.client.request(req);
+
+        Box::pin(async move {
+            let mut response = match responding.await {
+                Ok(response) => response,
+                Err(err) => {
+                    let body = Body::from(format!("bad gateway: {}\n", err));
+                    let mut response = Response::new(body);
+                    *response.status_mut() = StatusCode::BAD_GATEWAY;
+                    return Ok(response);
+                }
+            };
+            let upgrade = upgrade_protocol(response.headers());
+            remove_hop_by_hop(response.headers_mut());
+            match (on_upgrade, upgrade) {
+                (Some(client), Some(protocol))
+                    if response.status() == StatusCode::SWITCHING_PROTOCOLS =>
+                {
+                    restore_upgrade(response.headers_mut(), protocol);
+                    let upstream = hyper::upgrade::on(&mut response);
+                    tokio::spawn(async move {
+                        if let (Ok(client), Ok(upstream)) = future::join(client, upstream).await {
+                            splice(client, upstream).await;
+                        }
+                    });
+                }
+                _ => (),
+            }
+            Ok(response)
+        })
+    }
+}
+
+/// Return the protocol `headers` ask to upgrade to, if any.
+fn upgrade_protocol(headers: &HeaderMap) -> Option<HeaderValue> {
+    if !has_token(headers, header::CONNECTION, "upgrade") {
+        return None;
+    }
+    headers.get(header::UPGRADE).cloned()
+}
+
+/// Remove the hop-by-hop headers from `headers`, along with any `Connection` names.
+pub(crate) fn remove_hop_by_hop(headers: &mut HeaderMap) {
+    let named: Vec<String> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+    for name in HOP_BY_HOP
+        .iter()
+        .cloned()
+        .chain(named.iter().map(String::as_str))
+    {
+        headers.remove(name);
+    }
+}
+
+fn restore_upgrade(headers: &mut HeaderMap, protocol: HeaderValue) {
+    headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+    headers.insert(header::UPGRADE, protocol);
+}
+
+/// Copy bytes both ways between two upgraded connections until both sides have finished.
+async fn splice(mut client: Upgraded, mut upstream: Upgraded) {
+    let _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hop_by_hop_headers_are_removed() {
+        let mut headers = HeaderMap::new();
+        headers.insert("connection", "keep-alive, x-trace".parse().unwrap());
+        headers.insert("keep-alive", "timeout=5".parse().unwrap());
+        headers.insert("x-trace", "1".parse().unwrap());
+        headers.insert("transfer-encoding", "chunked".parse().unwrap());
+        headers.insert("content-type", "application/json".parse().unwrap());
+        remove_hop_by_hop(&mut headers);
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers["content-type"], "application/json");
+    }
+
+    #[test]
+    fn upstreams_rewrite_uris() {
+        let uri = "/containers/json?all=1".parse().unwrap();
+        let tcp = Upstream::Tcp(Scheme::HTTP, "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(tcp.uri(&uri), "http://127.0.0.1:8080/containers/json?all=1");
+        let unix = Upstream::Unix("foo.sock".into());
+        assert_eq!(
+            unix.uri(&uri),
+            "unix://666f6f2e736f636b:0/containers/json?all=1"
+        );
+        assert!(Proxy::tcp("http://127.0.0.1:8080/api".parse().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,341 @@
+//! Per-user transfer accounting and quotas
+//!
+//! A system daemon shared by the users of a host is easily monopolized by one of them. An
+//! `Accounting` registered with a server, through `Server::accounting`, counts the bytes read
+//! from and written to every connection under the uid of its peer, across connections. Those
+//! counts can be reported as they are, or enforced by wrapping the server's services in a
+//! `Quota`, which answers requests from users over their quota with `429 Too Many Requests`.
+//!
+//! Counts only ever grow; call `Accounting#reset` to start a new accounting period. Peers
+//! whose credentials couldn't be queried aren't accounted for.
+
+// Std lib
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+
+// Third party
+use futures::future::{self, Either, Ready};
+use futures::ready;
+use hyper::service::Service;
+use hyper::{Body, Request, Response, StatusCode};
+use pin_project_lite::pin_project;
+
+use super::NewService;
+use crate::credentials::PeerCred;
+
+/// The bytes transferred by one user's connections.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Usage {
+    received: u64,
+    sent: u64,
+}
+
+impl Usage {
+    /// Return the number of bytes read from the user's connections.
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    /// Return the number of bytes written to the user's connections.
+    pub fn sent(&self) -> u64 {
+        self.sent
+    }
+
+    /// Return the number of bytes transferred either way.
+    pub fn total(&self) -> u64 {
+        self.received.saturating_add(self.sent)
+    }
+}
+
+/// Shared per-uid transfer counts, and the quotas they are held to.
+///
+/// # Examples
+///
+/// ```rust
+/// use hyper::service::service_fn;
+/// use hyperlocal::server::quota::{Accounting, Quota};
+/// use hyperlocal::server::Server;
+///
+/// # if let Err(err) = std::fs::remove_file("hyperlocal_test_quota_server.sock") {
+/// #   if err.kind() != std::io::ErrorKind::NotFound {
+/// #     panic!("{}", err)
+/// #   }
+/// # }
+/// #
+/// let accounting = Accounting::new();
+/// // 1GiB per user, until the next reset
+/// accounting.set_quota(Some(1 << 30));
+/// let server = Server::bind(
+///    "hyperlocal_test_quota_server.sock",
+///    Quota::new(
+///        accounting.clone(),
+///        || service_fn(|req| async move { Ok::<_, hyper::Error>(hyper::Response::new(req.into_body())) })
+///    )
+/// )
+/// .unwrap()
+/// .accounting(&accounting);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Accounting {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    users: Mutex<HashMap<u32, Arc<Counters>>>,
+    quotas: Mutex<Quotas>,
+}
+
+#[derive(Debug, Default)]
+struct Quotas {
+    default: Option<u64>,
+    users: HashMap<u32, Option<u64>>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    received: AtomicU64,
+    sent: AtomicU64,
+}
+
+impl Accounting {
+    /// Create an accounting with no counts and no quotas.
+    pub fn new() -> Self {
+        Accounting::default()
+    }
+
+    /// Hold every user without a quota of their own to `bytes` transferred, or to none.
+    pub fn set_quota(&self, bytes: Option<u64>) {
+        self.quotas().default = bytes;
+    }
+
+    /// Hold the user `uid` to `bytes` transferred, or to none, whatever the default.
+    pub fn set_user_quota(&self, uid: u32, bytes: Option<u64>) {
+        self.quotas().users.insert(uid, bytes);
+    }
+
+    /// Return the quota the user `uid` is held to, if any.
+    pub fn quota(&self, uid: u32) -> Option<u64> {
+        let quotas = self.quotas();
+        quotas.users.get(&uid).copied().unwrap_or(quotas.default)
+    }
+
+    /// Return the bytes transferred by the user `uid` since the last reset.
+    pub fn usage(&self, uid: u32) -> Usage {
+        self.users()
+            .get(&uid)
+            .map(|counters| counters.usage())
+            .unwrap_or_default()
+    }
+
+    /// Return the bytes transferred by every user who has connected since the last reset,
+    /// ordered by uid.
+    pub fn usages(&self) -> Vec<(u32, Usage)> {
+        let mut usages: Vec<_> = self
+            .users()
+            .iter()
+            .map(|(uid, counters)| (*uid, counters.usage()))
+            .collect();
+        usages.sort_by_key(|(uid, _)| *uid);
+        usages
+    }
+
+    /// Return true if the user `uid` has transferred at least their quota.
+    pub fn exceeded(&self, uid: u32) -> bool {
+        match self.quota(uid) {
+            Some(quota) => self.usage(uid).total() >= quota,
+            None => false,
+        }
+    }
+
+    /// Zero every user's counts, starting a new accounting period.
+    pub fn reset(&self) {
+        for counters in self.users().values() {
+            counters.received.store(0, Ordering::Relaxed);
+            counters.sent.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Return the account a connection from the user `uid` is counted in.
+    pub(crate) fn account(&self, uid: u32) -> Account {
+        let counters = self.users().entry(uid).or_default().clone();
+        Account { counters }
+    }
+
+    fn users(&self) -> MutexGuard<'_, HashMap<u32, Arc<Counters>>> {
+        self.inner.users.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn quotas(&self) -> MutexGuard<'_, Quotas> {
+        self.inner.quotas.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Counters {
+    fn usage(&self) -> Usage {
+        Usage {
+            received: self.received.load(Ordering::Relaxed),
+            sent: self.sent.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The counts a single connection's transfers are added to.
+pub(crate) struct Account {
+    counters: Arc<Counters>,
+}
+
+impl Account {
+    pub(crate) fn received(&self, bytes: usize) {
+        self.counters
+            .received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn sent(&self, bytes: usize) {
+        self.counters
+            .sent
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+/// A `NewService` turning away requests from users over their quota.
+///
+/// Requests carrying no `PeerCred` are always handed through.
+#[derive(Clone, Debug)]
+pub struct Quota<S> {
+    new_service: S,
+    accounting: Accounting,
+}
+
+impl<S> Quota<S> {
+    /// Wraps `new_service`, holding users to the quotas of `accounting`.
+    pub fn new(accounting: Accounting, new_service: S) -> Self {
+        Quota {
+            new_service,
+            accounting,
+        }
+    }
+}
+
+impl<S> NewService for Quota<S>
+where
+    S: NewService<ReqBody = Body, ResBody = Body>,
+{
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = S::Error;
+    type Service = QuotaService<S::Service>;
+    type Future = Metering<S::Future>;
+    type InitError = S::InitError;
+
+    fn new_service(&self) -> Self::Future {
+        self.new_service_for(None)
+    }
+
+    fn new_service_for(&self, peer_cred: Option<&PeerCred>) -> Self::Future {
+        Metering {
+            future: self.new_service.new_service_for(peer_cred),
+            accounting: Some(self.accounting.clone()),
+        }
+    }
+}
+
+pin_project! {
+    /// A future resolving to a `QuotaService` once the wrapped service has been created.
+    pub struct Metering<F> {
+        #[pin]
+        future: F,
+        accounting: Option<Accounting>,
+    }
+}
+
+impl<F, S, E> Future for Metering<F>
+where
+    F: Future<Output = Result<S, E>>,
+{
+    type Output = Result<QuotaService<S>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let inner = ready!(this.future.poll(cx))?;
+        Poll::Ready(Ok(QuotaService {
+            inner,
+            accounting: this.accounting.take().expect("polled after complete"),
+        }))
+    }
+}
+
+/// A `Service` answering requests from users over their quota with `429 Too Many Requests`.
+#[derive(Clone, Debug)]
+pub struct QuotaService<S> {
+    inner: S,
+    accounting: Accounting,
+}
+
+impl<S> Service<Request<Body>> for QuotaService<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = Either<Ready<Result<Response<Body>, S::Error>>, S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let exceeded = match req.extensions().get::<PeerCred>() {
+            Some(peer_cred) => self.accounting.exceeded(peer_cred.uid()),
+            None => false,
+        };
+        if !exceeded {
+            return Either::Right(self.inner.call(req));
+        }
+        let mut response = Response::new(Body::from("quota exceeded\n"));
+        *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+        Either::Left(future::ok(response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use hyper::service::service_fn;
+
+    #[test]
+    fn users_over_quota_are_turned_away() {
+        let accounting = Accounting::new();
+        accounting.set_quota(Some(100));
+        accounting.set_user_quota(0, None);
+        let quota = Quota::new(accounting.clone(), || {
+            service_fn(|_| async { Ok::<_, hyper::Error>(Response::new(Body::empty())) })
+        });
+        let mut service = block_on(quota.new_service()).unwrap();
+        let call = |service: &mut QuotaService<_>, uid| {
+            let mut req = Request::get("/").body(Body::empty()).unwrap();
+            req.extensions_mut().insert(PeerCred::new(uid, uid, None));
+            block_on(service.call(req)).unwrap().status()
+        };
+
+        let account = accounting.account(1000);
+        account.received(60);
+        assert_eq!(call(&mut service, 1000), StatusCode::OK);
+        account.sent(40);
+        assert_eq!(accounting.usage(1000).total(), 100);
+        assert_eq!(call(&mut service, 1000), StatusCode::TOO_MANY_REQUESTS);
+
+        accounting.account(0).sent(1000);
+        assert_eq!(call(&mut service, 0), StatusCode::OK);
+        assert_eq!(accounting.usages().len(), 2);
+
+        accounting.reset();
+        assert_eq!(call(&mut service, 1000), StatusCode::OK);
+    }
+}
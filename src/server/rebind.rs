@@ -0,0 +1,88 @@
+//! Moving a running server to a new socket path
+
+// Std lib
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::os::unix::net::UnixListener as StdUnixListener;
+use std::path::{Path, PathBuf};
+use std::process;
+
+// Third party
+use futures::channel::mpsc::UnboundedSender;
+
+/// Bind a listener to `path`, atomically replacing any socket file already there.
+///
+/// The listener is first bound to a temporary name next to `path` and then renamed over it,
+/// so clients never observe a moment where `path` doesn't exist. A server still listening on
+/// a replaced socket file keeps its listener, but can no longer be reached through `path`.
+///
+/// Note that the returned listener's local address reports the temporary name it was
+/// bound to.
+pub fn bind_replacing<P>(path: P) -> io::Result<StdUnixListener>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    let file_name = path.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a socket file path", path.display()),
+        )
+    })?;
+    let mut temp_name = OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(format!(".{}.tmp", process::id()));
+    let temp_path = path.with_file_name(temp_name);
+
+    // left behind by a previous process with our pid which didn't get to rename it
+    if let Err(err) = fs::remove_file(&temp_path) {
+        if err.kind() != io::ErrorKind::NotFound {
+            return Err(err);
+        }
+    }
+    let listener = StdUnixListener::bind(&temp_path)?;
+    if let Err(err) = fs::rename(&temp_path, path) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(err);
+    }
+    Ok(listener)
+}
+
+/// A freshly bound listener, and the path it was bound to.
+pub(crate) type Rebind = (StdUnixListener, PathBuf);
+
+/// A handle for moving a running server to a new socket path, obtained from
+/// `Server::rebinder` or `Serve::rebinder`.
+///
+/// Rebinding leaves connections already accepted untouched. Connections still waiting in
+/// the previous listener's backlog are accepted before it is closed, and the previous socket
+/// file is removed if the server moved to a different path.
+#[derive(Clone)]
+pub struct Rebinder {
+    tx: UnboundedSender<Rebind>,
+}
+
+impl Rebinder {
+    pub(crate) fn new(tx: UnboundedSender<Rebind>) -> Self {
+        Rebinder { tx }
+    }
+
+    /// Bind `path` using `bind_replacing` and have the server accept connections on it instead.
+    ///
+    /// `path` may be the server's current socket path, in which case the socket file is
+    /// swapped out from under clients without downtime.
+    pub fn rebind<P>(&self, path: P) -> io::Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let listener = bind_replacing(path)?;
+        self.tx
+            .unbounded_send((listener, path.to_path_buf()))
+            .map_err(|_| {
+                let _ = fs::remove_file(path);
+                io::Error::new(io::ErrorKind::NotConnected, "server is no longer running")
+            })
+    }
+}
@@ -0,0 +1,198 @@
+//! Relaying between two unix domain sockets
+//!
+//! `run` listens on one socket path and forwards every request to the server on another,
+//! optionally logging the traffic relayed. Moving a daemon's socket aside and relaying from
+//! its usual path is a quick way to watch what its clients are saying to it. The same is
+//! available from the command line, as `hyperlocal relay`.
+
+// Std lib
+use std::future::Future;
+use std::io::{self, Write};
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+// Third party
+use futures::ready;
+use hyper::service::Service;
+use hyper::{Body, HeaderMap, Request, Response};
+use pin_project_lite::pin_project;
+
+use super::log::AccessLog;
+use super::proxy::Proxy;
+use super::{shutdown, NewService, Server};
+use crate::credentials::PeerCred;
+
+/// How much of the traffic relayed to log, to standard error.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Log {
+    /// Log nothing.
+    Nothing,
+    /// Log an access log entry for each request.
+    Requests,
+    /// Log the head, line and headers, of each request and response, along with an access log
+    /// entry.
+    Headers,
+}
+
+/// Relay requests received on the socket at `listen` to the server on the socket at
+/// `upstream`, until the process receives `SIGINT` or `SIGTERM`.
+///
+/// The socket file at `listen` is created, and removed again before returning.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use hyperlocal::server::relay::{self, Log};
+///
+/// relay::run("/tmp/docker.sock", "/var/run/docker.sock", Log::Headers).unwrap();
+/// ```
+pub fn run<P, Q>(listen: P, upstream: Q, log: Log) -> io::Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let proxy = Proxy::unix(upstream);
+    match log {
+        Log::Nothing => Server::bind(listen, proxy)?.run_until(shutdown::signal()),
+        Log::Requests => Server::bind(listen, AccessLog::new(proxy))?.run_until(shutdown::signal()),
+        Log::Headers => {
+            Server::bind(listen, AccessLog::new(Dump::new(proxy)))?.run_until(shutdown::signal())
+        }
+    }
+}
+
+/// A `NewService` writing the heads of the requests and responses of the services it wraps
+/// to standard error.
+struct Dump<S> {
+    new_service: S,
+}
+
+impl<S> Dump<S> {
+    fn new(new_service: S) -> Self {
+        Dump { new_service }
+    }
+}
+
+impl<S> NewService for Dump<S>
+where
+    S: NewService<ReqBody = Body, ResBody = Body>,
+{
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = S::Error;
+    type Service = DumpService<S::Service>;
+    type Future = Dumping<S::Future>;
+    type InitError = S::InitError;
+
+    fn new_service(&self) -> Self::Future {
+        self.new_service_for(None)
+    }
+
+    fn new_service_for(&self, peer_cred: Option<&PeerCred>) -> Self::Future {
+        Dumping {
+            future: self.new_service.new_service_for(peer_cred),
+        }
+    }
+}
+
+pin_project! {
+    struct Dumping<F> {
+        #[pin]
+        future: F,
+    }
+}
+
+impl<F, S, E> Future for Dumping<F>
+where
+    F: Future<Output = Result<S, E>>,
+{
+    type Output = Result<DumpService<S>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let inner = ready!(self.project().future.poll(cx))?;
+        Poll::Ready(Ok(DumpService { inner }))
+    }
+}
+
+struct DumpService<S> {
+    inner: S,
+}
+
+impl<S> Service<Request<Body>> for DumpService<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = Dumped<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let pid = req
+            .extensions()
+            .get::<PeerCred>()
+            .and_then(|peer_cred| peer_cred.pid());
+        let prefix = match pid {
+            Some(pid) => format!("[{}] ", pid),
+            None => "[-] ".to_owned(),
+        };
+        let mut head = format!(
+            "{}> {} {} {:?}\n",
+            prefix,
+            req.method(),
+            req.uri(),
+            req.version()
+        );
+        push_headers(&mut head, &prefix, '>', req.headers());
+        let _ = io::stderr().write_all(head.as_bytes());
+        Dumped {
+            future: self.inner.call(req),
+            prefix,
+        }
+    }
+}
+
+pin_project! {
+    struct Dumped<F> {
+        #[pin]
+        future: F,
+        prefix: String,
+    }
+}
+
+impl<F, E> Future for Dumped<F>
+where
+    F: Future<Output = Result<Response<Body>, E>>,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let response = ready!(this.future.poll(cx))?;
+        let mut head = format!(
+            "{}< {:?} {}\n",
+            this.prefix,
+            response.version(),
+            response.status()
+        );
+        push_headers(&mut head, this.prefix, '<', response.headers());
+        let _ = io::stderr().write_all(head.as_bytes());
+        Poll::Ready(Ok(response))
+    }
+}
+
+fn push_headers(head: &mut String, prefix: &str, direction: char, headers: &HeaderMap) {
+    for (name, value) in headers {
+        head.push_str(&format!(
+            "{}{} {}: {}\n",
+            prefix,
+            direction,
+            name,
+            String::from_utf8_lossy(value.as_bytes())
+        ));
+    }
+}
@@ -0,0 +1,150 @@
+//! Swapping a server's services at runtime
+
+// Std lib
+use std::io;
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+// Third party
+use tokio::runtime::Builder;
+use tokio::signal::unix::{signal, SignalKind};
+
+use super::NewService;
+use crate::credentials::PeerCred;
+
+/// A `NewService` whose underlying `NewService` can be replaced while a server is running.
+///
+/// Replacing the underlying `NewService` only affects connections accepted afterwards.
+/// Connections already in flight carry on with the services they were created with, and
+/// the listener is left untouched.
+///
+/// `Reload` is cheaply cloneable, with every clone sharing the same underlying `NewService`,
+/// so a clone can be handed to `Server::bind` while another is kept around to reload with.
+///
+/// # Examples
+///
+/// ```rust
+/// use futures::future::{self, Ready};
+/// use hyper::service::Service;
+/// use hyper::{Body, Request, Response};
+/// use hyperlocal::server::{reload::Reload, Server};
+/// use std::task::{Context, Poll};
+///
+/// struct Greeter(String);
+///
+/// impl Service<Request<Body>> for Greeter {
+///     type Response = Response<Body>;
+///     type Error = hyper::Error;
+///     type Future = Ready<Result<Response<Body>, hyper::Error>>;
+///
+///     fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+///         Poll::Ready(Ok(()))
+///     }
+///
+///     fn call(&mut self, _: Request<Body>) -> Self::Future {
+///         future::ok(Response::new(Body::from(self.0.clone())))
+///     }
+/// }
+///
+/// fn greeting() -> String {
+///     // read from a configuration file, say
+///     "hello".to_string()
+/// }
+///
+/// fn serving(greeting: String) -> impl Fn() -> Greeter + Send + Sync {
+///     move || Greeter(greeting.clone())
+/// }
+///
+/// # if let Err(err) = std::fs::remove_file("hyperlocal_test_reload_server.sock") {
+/// #   if err.kind() != std::io::ErrorKind::NotFound {
+/// #     panic!("{}", err)
+/// #   }
+/// # }
+/// #
+/// let reload = Reload::new(serving(greeting()));
+/// reload.on_sighup(|| Some(serving(greeting()))).unwrap();
+///
+/// let server = Server::bind("hyperlocal_test_reload_server.sock", reload).unwrap();
+/// ```
+pub struct Reload<S> {
+    current: Arc<RwLock<S>>,
+}
+
+impl<S> Reload<S> {
+    /// Creates a new `Reload` initially delegating to `new_service`.
+    pub fn new(new_service: S) -> Self {
+        Reload {
+            current: Arc::new(RwLock::new(new_service)),
+        }
+    }
+
+    /// Replace the underlying `NewService` used for connections accepted from now on.
+    pub fn replace(&self, new_service: S) {
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = new_service;
+    }
+
+    /// Invoke `reload` each time the process receives `SIGHUP`, replacing the underlying
+    /// `NewService` with the one it returns.
+    ///
+    /// Returning `None` keeps the current `NewService`, which is useful when the new
+    /// configuration turns out to be invalid.
+    ///
+    /// The signal handler is installed before this method returns, so a `SIGHUP` received
+    /// afterwards no longer terminates the process. Signals are processed on a dedicated
+    /// background thread for the remaining lifetime of the process.
+    pub fn on_sighup<F>(&self, mut reload: F) -> io::Result<()>
+    where
+        F: FnMut() -> Option<S> + Send + 'static,
+        S: Send + Sync + 'static,
+    {
+        let runtime = Builder::new_current_thread().enable_io().build()?;
+        let mut hangups = {
+            let _entered = runtime.enter();
+            signal(SignalKind::hangup())?
+        };
+        let target = self.clone();
+        thread::Builder::new()
+            .name("hyperlocal-sighup".into())
+            .spawn(move || {
+                runtime.block_on(async {
+                    while hangups.recv().await.is_some() {
+                        if let Some(new_service) = reload() {
+                            target.replace(new_service);
+                        }
+                    }
+                })
+            })?;
+        Ok(())
+    }
+}
+
+impl<S> Clone for Reload<S> {
+    fn clone(&self) -> Self {
+        Reload {
+            current: self.current.clone(),
+        }
+    }
+}
+
+impl<S> NewService for Reload<S>
+where
+    S: NewService,
+{
+    type ReqBody = S::ReqBody;
+    type ResBody = S::ResBody;
+    type Error = S::Error;
+    type Service = S::Service;
+    type Future = S::Future;
+    type InitError = S::InitError;
+
+    fn new_service(&self) -> Self::Future {
+        self.new_service_for(None)
+    }
+
+    fn new_service_for(&self, peer_cred: Option<&PeerCred>) -> Self::Future {
+        self.current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .new_service_for(peer_cred)
+    }
+}
@@ -0,0 +1,264 @@
+//! Tagging requests with ids for correlating logs
+
+// Std lib
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+// Third party
+use futures::ready;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::service::Service;
+use hyper::{Request, Response};
+use pin_project_lite::pin_project;
+
+use super::NewService;
+use crate::credentials::PeerCred;
+
+/// The response header request ids are echoed in by default.
+pub const HEADER: &str = "x-request-id";
+
+/// The id of a request, found in its extensions.
+///
+/// Ids are made up of the pid of the peer which sent the request, when it is known, a
+/// connection number unique to the server and the number of the request on its connection,
+/// and are formatted as `{pid}-{connection}-{request}`, with `-` standing in for an unknown pid.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RequestId {
+    pid: Option<i32>,
+    connection: usize,
+    request: usize,
+}
+
+impl RequestId {
+    /// Return the pid of the peer which sent the request, if it could be queried.
+    pub fn pid(&self) -> Option<i32> {
+        self.pid
+    }
+
+    /// Return the number of the connection the request was sent on, starting from 1.
+    pub fn connection(&self) -> usize {
+        self.connection
+    }
+
+    /// Return the number of the request on its connection, starting from 1.
+    pub fn request(&self) -> usize {
+        self.request
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.pid {
+            Some(pid) => write!(f, "{}-", pid)?,
+            None => f.write_str("--")?,
+        }
+        write!(f, "{}-{}", self.connection, self.request)
+    }
+}
+
+/// A `NewService` assigning a `RequestId` to every request served by the services it wraps.
+///
+/// The id is inserted into the request's extensions, where layers it wraps, such as
+/// `log::AccessLog`, can pick it up, and echoed in a response header.
+///
+/// # Examples
+///
+/// ```rust
+/// use hyper::service::service_fn;
+/// use hyperlocal::server::{log::AccessLog, request_id::RequestIds, Server};
+///
+/// # if let Err(err) = std::fs::remove_file("hyperlocal_test_request_id_server.sock") {
+/// #   if err.kind() != std::io::ErrorKind::NotFound {
+/// #     panic!("{}", err)
+/// #   }
+/// # }
+/// #
+/// let server = Server::bind(
+///    "hyperlocal_test_request_id_server.sock",
+///    RequestIds::new(AccessLog::new(
+///        || service_fn(|req| async move { Ok::<_, hyper::Error>(hyper::Response::new(req.into_body())) })
+///    )),
+/// ).unwrap();
+/// ```
+pub struct RequestIds<S> {
+    new_service: S,
+    header: HeaderName,
+    connections: Arc<AtomicUsize>,
+}
+
+impl<S> RequestIds<S> {
+    /// Wraps `new_service`, echoing ids in the `x-request-id` response header.
+    pub fn new(new_service: S) -> Self {
+        RequestIds::with_header(new_service, HeaderName::from_static(HEADER))
+    }
+
+    /// Wraps `new_service`, echoing ids in the `header` response header.
+    pub fn with_header(new_service: S, header: HeaderName) -> Self {
+        RequestIds {
+            new_service,
+            header,
+            connections: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+impl<S> NewService for RequestIds<S>
+where
+    S: NewService,
+{
+    type ReqBody = S::ReqBody;
+    type ResBody = S::ResBody;
+    type Error = S::Error;
+    type Service = RequestIdService<S::Service>;
+    type Future = Identifying<S::Future>;
+    type InitError = S::InitError;
+
+    fn new_service(&self) -> Self::Future {
+        self.new_service_for(None)
+    }
+
+    fn new_service_for(&self, peer_cred: Option<&PeerCred>) -> Self::Future {
+        // servers create a service per connection
+        Identifying {
+            future: self.new_service.new_service_for(peer_cred),
+            header: Some(self.header.clone()),
+            connection: self.connections.fetch_add(1, Ordering::Relaxed) + 1,
+        }
+    }
+}
+
+pin_project! {
+    /// A future resolving to a `RequestIdService` once the wrapped service has been created.
+    pub struct Identifying<F> {
+        #[pin]
+        future: F,
+        header: Option<HeaderName>,
+        connection: usize,
+    }
+}
+
+impl<F, S, E> Future for Identifying<F>
+where
+    F: Future<Output = Result<S, E>>,
+{
+    type Output = Result<RequestIdService<S>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let inner = ready!(this.future.poll(cx))?;
+        Poll::Ready(Ok(RequestIdService {
+            inner,
+            header: this.header.take().expect("polled after complete"),
+            connection: *this.connection,
+            requests: 0,
+        }))
+    }
+}
+
+/// A `Service` assigning a `RequestId` to each request it serves.
+pub struct RequestIdService<S> {
+    inner: S,
+    header: HeaderName,
+    connection: usize,
+    requests: usize,
+}
+
+impl<S, B, R> Service<Request<B>> for RequestIdService<S>
+where
+    S: Service<Request<B>, Response = Response<R>>,
+{
+    type Response = Response<R>;
+    type Error = S::Error;
+    type Future = Identified<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<B>) -> Self::Future {
+        self.requests += 1;
+        let id = RequestId {
+            pid: req
+                .extensions()
+                .get::<PeerCred>()
+                .and_then(|peer_cred| peer_cred.pid()),
+            connection: self.connection,
+            request: self.requests,
+        };
+        req.extensions_mut().insert(id);
+        Identified {
+            future: self.inner.call(req),
+            header: Some((self.header.clone(), id)),
+        }
+    }
+}
+
+pin_project! {
+    /// A future echoing a request's id in its response.
+    pub struct Identified<F> {
+        #[pin]
+        future: F,
+        header: Option<(HeaderName, RequestId)>,
+    }
+}
+
+impl<F, B, E> Future for Identified<F>
+where
+    F: Future<Output = Result<Response<B>, E>>,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let mut response = ready!(this.future.poll(cx))?;
+        if let Some((header, id)) = this.header.take() {
+            let value =
+                HeaderValue::from_str(&id.to_string()).expect("ids are valid header values");
+            response.headers_mut().insert(header, value);
+        }
+        Poll::Ready(Ok(response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::future::{self, Ready};
+    use hyper::Body;
+
+    struct Echo;
+
+    impl Service<Request<Body>> for Echo {
+        type Response = Response<Body>;
+        type Error = hyper::Error;
+        type Future = Ready<Result<Response<Body>, hyper::Error>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: Request<Body>) -> Self::Future {
+            let id = req.extensions().get::<RequestId>().unwrap().to_string();
+            future::ok(Response::new(Body::from(id)))
+        }
+    }
+
+    #[test]
+    fn request_ids_count_connections_and_requests() {
+        let ids = RequestIds::new(|| Echo);
+        block_on(ids.new_service()).unwrap();
+        let mut service = block_on(ids.new_service()).unwrap();
+
+        let mut req = Request::new(Body::empty());
+        req.extensions_mut().insert(PeerCred::new(0, 0, Some(42)));
+        let response = block_on(service.call(req)).unwrap();
+        assert_eq!(response.headers()[HEADER], "42-2-1");
+        let response = block_on(service.call(Request::new(Body::empty()))).unwrap();
+        assert_eq!(response.headers()[HEADER], "--2-2");
+    }
+}
@@ -0,0 +1,223 @@
+//! Serving different services to different peers
+//!
+//! A daemon often offers several APIs over one socket: an admin API for root, a read-only
+//! API for members of some group, nothing at all for anyone else. `Select` picks which of
+//! two services serves a connection from its peer's credentials, so that each API can be
+//! written as a service of its own, knowing nothing about the others. Nesting `Select`s
+//! chooses among more than two.
+//!
+//! The choice is made once per connection, from the credentials queried on accepting it, and
+//! only the chosen service is made.
+
+// Std lib
+use std::error::Error as StdError;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+// Third party
+use futures::future::{Either, ErrInto, TryFutureExt};
+use futures::ready;
+use hyper::service::Service;
+use hyper::{Body, Request, Response};
+use pin_project_lite::pin_project;
+
+use super::guard::Require;
+use super::NewService;
+use crate::credentials::PeerCred;
+
+type BoxError = Box<dyn StdError + Send + Sync>;
+
+/// A `NewService` serving peers satisfying a requirement with one service, and every other
+/// peer with another.
+///
+/// Servers choose a connection's service when accepting it, by its peer's `PeerCred`, and
+/// make only that one, so every request on a connection goes to the same service. Making a
+/// service through `NewService::new_service`, with no credentials, chooses the one for peers
+/// whose credentials couldn't be queried.
+///
+/// # Examples
+///
+/// ```rust
+/// use hyper::service::service_fn;
+/// use hyper::{Body, Response, StatusCode};
+/// use hyperlocal::server::{guard::Require, select::Select, Server};
+///
+/// # if let Err(err) = std::fs::remove_file("hyperlocal_test_select_server.sock") {
+/// #   if err.kind() != std::io::ErrorKind::NotFound {
+/// #     panic!("{}", err)
+/// #   }
+/// # }
+/// #
+/// let operators = 27;
+/// let admin = || service_fn(|_| async { Ok::<_, hyper::Error>(Response::new(Body::from("admin"))) });
+/// let read_only =
+///     || service_fn(|_| async { Ok::<_, hyper::Error>(Response::new(Body::from("read-only"))) });
+/// let nothing = || {
+///     service_fn(|_| async {
+///         let mut response = Response::new(Body::empty());
+///         *response.status_mut() = StatusCode::FORBIDDEN;
+///         Ok::<_, hyper::Error>(response)
+///     })
+/// };
+/// let server = Server::bind(
+///    "hyperlocal_test_select_server.sock",
+///    Select::new(Require::Root, admin, Select::new(Require::Gid(operators), read_only, nothing)),
+/// )
+/// .unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct Select<A, B> {
+    require: Require,
+    matched: A,
+    otherwise: B,
+}
+
+impl<A, B> Select<A, B> {
+    /// Serve peers satisfying `require` with `matched`, and every other peer with
+    /// `otherwise`.
+    pub fn new(require: Require, matched: A, otherwise: B) -> Self {
+        Select {
+            require,
+            matched,
+            otherwise,
+        }
+    }
+}
+
+impl<A, B> NewService for Select<A, B>
+where
+    A: NewService<ReqBody = Body, ResBody = Body>,
+    B: NewService<ReqBody = Body, ResBody = Body>,
+{
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = BoxError;
+    type Service = SelectService<A::Service, B::Service>;
+    type Future = Selecting<A::Future, B::Future>;
+    type InitError = BoxError;
+
+    fn new_service(&self) -> Self::Future {
+        self.new_service_for(None)
+    }
+
+    fn new_service_for(&self, peer_cred: Option<&PeerCred>) -> Self::Future {
+        let future = if self.require.allows(peer_cred) {
+            Either::Left(self.matched.new_service_for(peer_cred))
+        } else {
+            Either::Right(self.otherwise.new_service_for(peer_cred))
+        };
+        Selecting { future }
+    }
+}
+
+pin_project! {
+    /// A future resolving to a `SelectService` once the chosen service has been made.
+    pub struct Selecting<FA, FB> {
+        #[pin]
+        future: Either<FA, FB>,
+    }
+}
+
+impl<FA, FB, A, B, EA, EB> Future for Selecting<FA, FB>
+where
+    FA: Future<Output = Result<A, EA>>,
+    FB: Future<Output = Result<B, EB>>,
+    EA: Into<BoxError>,
+    EB: Into<BoxError>,
+{
+    type Output = Result<SelectService<A, B>, BoxError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let inner = match self.project().future.as_pin_mut() {
+            Either::Left(matched) => Either::Left(ready!(matched.poll(cx)).map_err(Into::into)?),
+            Either::Right(otherwise) => {
+                Either::Right(ready!(otherwise.poll(cx)).map_err(Into::into)?)
+            }
+        };
+        Poll::Ready(Ok(SelectService { inner }))
+    }
+}
+
+/// A `Service` serving a connection with whichever of two services was chosen for its peer.
+#[derive(Clone, Debug)]
+pub struct SelectService<A, B> {
+    inner: Either<A, B>,
+}
+
+impl<A, B> Service<Request<Body>> for SelectService<A, B>
+where
+    A: Service<Request<Body>, Response = Response<Body>>,
+    A::Error: Into<BoxError>,
+    B: Service<Request<Body>, Response = Response<Body>>,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response<Body>;
+    type Error = BoxError;
+    type Future = Either<ErrInto<A::Future, BoxError>, ErrInto<B::Future, BoxError>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        match self.inner {
+            Either::Left(ref mut matched) => matched.poll_ready(cx).map_err(Into::into),
+            Either::Right(ref mut otherwise) => otherwise.poll_ready(cx).map_err(Into::into),
+        }
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        match self.inner {
+            Either::Left(ref mut matched) => Either::Left(matched.call(req).err_into()),
+            Either::Right(ref mut otherwise) => Either::Right(otherwise.call(req).err_into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use hyper::service::service_fn;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn select_serves_peers_by_their_credentials() {
+        let made = Arc::new(AtomicUsize::new(0));
+        let respond = |body: &'static str| {
+            let made = made.clone();
+            move || {
+                made.fetch_add(1, Ordering::SeqCst);
+                service_fn(move |_| async move {
+                    Ok::<_, hyper::Error>(Response::new(Body::from(body)))
+                })
+            }
+        };
+        let select = Select::new(
+            Require::Root,
+            respond("admin"),
+            Select::new(Require::Gid(100), respond("read-only"), respond("nothing")),
+        );
+        let call = |peer_cred: Option<PeerCred>| {
+            let mut service = block_on(select.new_service_for(peer_cred.as_ref())).unwrap();
+            block_on(futures::future::poll_fn(|cx| service.poll_ready(cx))).unwrap();
+            let response = block_on(service.call(Request::new(Body::empty()))).unwrap();
+            block_on(hyper::body::to_bytes(response.into_body())).unwrap()
+        };
+
+        assert_eq!(call(Some(PeerCred::new(0, 0, None))), "admin");
+        assert_eq!(call(Some(PeerCred::new(1000, 100, None))), "read-only");
+        assert_eq!(call(Some(PeerCred::new(1000, 1000, None))), "nothing");
+        assert_eq!(call(None), "nothing");
+        // only the chosen service is made for each connection
+        assert_eq!(made.load(Ordering::SeqCst), 4);
+        block_on(select.new_service()).unwrap();
+        assert_eq!(made.load(Ordering::SeqCst), 5);
+
+        // wrappers pass the peer's credentials on
+        let limited = crate::server::limit::Limit::new(64, select);
+        let root = PeerCred::new(0, 0, None);
+        let mut service = block_on(limited.new_service_for(Some(&root))).unwrap();
+        let response = block_on(service.call(Request::new(Body::empty()))).unwrap();
+        let body = block_on(hyper::body::to_bytes(response.into_body())).unwrap();
+        assert_eq!(body, "admin");
+    }
+}
@@ -0,0 +1,88 @@
+//! Creating a service for each connection a server accepts
+//!
+//! hyper's own servers are handed a service to make services for each connection. The
+//! servers here are handed a `NewService` instead, which every wrapper in this crate, such
+//! as `limit::Limit` or `log::AccessLog`, both takes and implements, so those wrappers
+//! stack in any order. Closures returning a `Service` are `NewService`s themselves.
+
+// Std lib
+use std::convert::Infallible;
+use std::error::Error as StdError;
+use std::future::Future;
+
+// Third party
+use futures::future::{self, Ready};
+use hyper::body::HttpBody;
+use hyper::service::Service;
+use hyper::{Body, Request, Response};
+
+use crate::credentials::PeerCred;
+
+/// A factory of the `Service` serving each connection.
+///
+/// # Examples
+///
+/// ```rust
+/// use hyper::service::service_fn;
+/// use hyper::{Body, Response};
+/// use hyperlocal::server::NewService;
+///
+/// let new_service = || {
+///     service_fn(|_| async { Ok::<_, hyper::Error>(Response::new(Body::from("hello"))) })
+/// };
+/// let service = futures::executor::block_on(new_service.new_service());
+/// assert!(service.is_ok());
+/// ```
+pub trait NewService {
+    /// The body of requests served.
+    type ReqBody;
+    /// The body of responses served.
+    type ResBody: HttpBody<Data: Send, Error: Into<Box<dyn StdError + Send + Sync>>>
+        + Send
+        + 'static;
+    /// The error a service fails a request with.
+    type Error: Into<Box<dyn StdError + Send + Sync>>;
+    /// The service made for each connection.
+    type Service: Service<
+        Request<Self::ReqBody>,
+        Response = Response<Self::ResBody>,
+        Error = Self::Error,
+    >;
+    /// The future resolving to a new service.
+    type Future: Future<Output = Result<Self::Service, Self::InitError>>;
+    /// The error making a new service fails with.
+    type InitError: Into<Box<dyn StdError + Send + Sync>> + Send + 'static;
+
+    /// Make the service for a new connection.
+    fn new_service(&self) -> Self::Future;
+
+    /// Make the service for a new connection from a peer with `peer_cred`, as servers do for
+    /// every connection they accept.
+    ///
+    /// This defaults to `new_service`, for services serving every peer alike. Wrappers pass
+    /// the credentials on to the `NewService` they wrap, so that one choosing its service by
+    /// peer, such as `select::Select`, sees them however deeply it is stacked.
+    fn new_service_for(&self, peer_cred: Option<&PeerCred>) -> Self::Future {
+        let _ = peer_cred;
+        self.new_service()
+    }
+}
+
+impl<F, S, B> NewService for F
+where
+    F: Fn() -> S,
+    S: Service<Request<Body>, Response = Response<B>>,
+    S::Error: Into<Box<dyn StdError + Send + Sync>>,
+    B: HttpBody<Data: Send, Error: Into<Box<dyn StdError + Send + Sync>>> + Send + 'static,
+{
+    type ReqBody = Body;
+    type ResBody = B;
+    type Error = S::Error;
+    type Service = S;
+    type Future = Ready<Result<S, Infallible>>;
+    type InitError = Infallible;
+
+    fn new_service(&self) -> Self::Future {
+        future::ready(Ok(self()))
+    }
+}
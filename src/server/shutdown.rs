@@ -0,0 +1,453 @@
+//! Graceful shutdown for unix domain socket servers
+
+// Std lib
+use std::error::Error as StdError;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+// Third party
+use futures::channel::{mpsc, oneshot};
+use futures::future::{self, Either, FutureExt, Shared};
+use futures::{pin_mut, ready, StreamExt};
+use hyper::body::HttpBody;
+use hyper::service::Service;
+use hyper::{Body, Request, Response};
+use tokio::signal::unix::{signal as unix_signal, SignalKind};
+use tokio::time::{self, Sleep};
+
+use super::panic::Isolated;
+use super::{Connecting, NewService, Serve};
+
+/// Returns a future which resolves once the process receives either `SIGINT` or `SIGTERM`.
+///
+/// This is intended to be handed to `Server::run_until` so that a daemon stopped by
+/// its supervisor, or interrupted with Ctrl-C from a terminal, drains its connections
+/// and removes its socket file before exiting.
+///
+/// The signal handlers are installed when the future is first polled, which must be from
+/// within a tokio runtime.
+pub async fn signal() -> io::Result<()> {
+    let mut interrupt = unix_signal(SignalKind::interrupt())?;
+    let mut terminate = unix_signal(SignalKind::terminate())?;
+    future::select(Box::pin(interrupt.recv()), Box::pin(terminate.recv())).await;
+    Ok(())
+}
+
+/// Why a `Triggers` future resolved.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    /// The process received `SIGINT` or `SIGTERM`.
+    Signal,
+    /// A channel was sent to, or its sender dropped.
+    Channel,
+    /// A deadline passed.
+    Deadline,
+    /// Some other future resolved.
+    Future,
+}
+
+/// A future resolving as soon as the first of several shutdown triggers fires.
+///
+/// A `Triggers` with no triggers never resolves. It resolves to an error if a trigger
+/// couldn't be set up, such as the handlers of a `signal` trigger failing to install,
+/// rather than to that trigger having fired.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use std::time::{Duration, Instant};
+///
+/// use futures::channel::oneshot;
+/// use hyperlocal::server::shutdown::Triggers;
+///
+/// let (stop, stopped) = oneshot::channel::<()>();
+/// let triggers = Triggers::new()
+///     .signal()
+///     .channel(stopped)
+///     .deadline(Instant::now() + Duration::from_secs(24 * 60 * 60));
+/// ```
+#[derive(Default)]
+pub struct Triggers {
+    triggers: Vec<Pin<Box<dyn Future<Output = io::Result<Trigger>> + Send>>>,
+}
+
+impl Triggers {
+    /// Create a set of no triggers.
+    pub fn new() -> Self {
+        Triggers::default()
+    }
+
+    /// Fire on `SIGINT` or `SIGTERM`, as `signal()` does, failing with its error if the
+    /// signal handlers can't be installed.
+    pub fn signal(self) -> Self {
+        self.push(async { signal().await.map(|()| Trigger::Signal) })
+    }
+
+    /// Fire once `receiver`'s sender sends, or is dropped.
+    pub fn channel(self, receiver: oneshot::Receiver<()>) -> Self {
+        self.push(async {
+            let _ = receiver.await;
+            Ok(Trigger::Channel)
+        })
+    }
+
+    /// Fire once `deadline` passes.
+    pub fn deadline(self, deadline: Instant) -> Self {
+        self.push(async move {
+            time::sleep_until(deadline.into()).await;
+            Ok(Trigger::Deadline)
+        })
+    }
+
+    /// Fire once `future` resolves.
+    pub fn future<F>(self, future: F) -> Self
+    where
+        F: Future + Send + 'static,
+    {
+        self.push(async {
+            future.await;
+            Ok(Trigger::Future)
+        })
+    }
+
+    fn push<F>(mut self, trigger: F) -> Self
+    where
+        F: Future<Output = io::Result<Trigger>> + Send + 'static,
+    {
+        self.triggers.push(Box::pin(trigger));
+        self
+    }
+}
+
+impl Future for Triggers {
+    type Output = io::Result<Trigger>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        for trigger in self.triggers.iter_mut() {
+            if let Poll::Ready(trigger) = trigger.as_mut().poll(cx) {
+                return Poll::Ready(trigger);
+            }
+        }
+        Poll::Pending
+    }
+}
+
+/// What became of the connections a server had open when it shut down.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Drained {
+    drained: usize,
+    aborted: usize,
+    duration: Duration,
+}
+
+impl Drained {
+    /// Return the number of connections which finished gracefully once shutdown began.
+    pub fn drained(&self) -> usize {
+        self.drained
+    }
+
+    /// Return the number of connections abandoned: those still being set up when shutdown
+    /// began, and those still open once the drain timeout passed.
+    pub fn aborted(&self) -> usize {
+        self.aborted
+    }
+
+    /// Return how long draining took, from shutdown beginning to the last connection ending.
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+}
+
+#[derive(Default)]
+struct Counts {
+    drained: AtomicUsize,
+    aborted: AtomicUsize,
+}
+
+/// A future driving a `Serve` until a signal future resolves, then draining in-flight
+/// connections and reporting what became of them.
+///
+/// Each accepted connection is spawned onto the current tokio runtime. Once `signal`
+/// completes, no new connections are accepted, every in-flight connection is asked to shut
+/// down gracefully, and this future resolves once all of them have finished, or once the
+/// drain timeout has passed and the rest have been dropped.
+pub struct Draining<S, F> {
+    running: Option<Running<S, F>>,
+    draining: mpsc::Receiver<()>,
+    counts: Arc<Counts>,
+    drain_timeout: Option<Duration>,
+    started: Option<Instant>,
+    timeout: Option<Pin<Box<Sleep>>>,
+    // dropping the sending half is what tells draining connections to give up
+    abort: Option<oneshot::Sender<()>>,
+}
+
+struct Running<S, F> {
+    serve: Serve<S>,
+    signal: Pin<Box<F>>,
+    // dropping the sending half is what tells watching connections to shut down
+    _trigger: oneshot::Sender<()>,
+    watch: Shared<oneshot::Receiver<()>>,
+    aborting: Shared<oneshot::Receiver<()>>,
+    counts: Arc<Counts>,
+    drained: mpsc::Sender<()>,
+}
+
+impl<S, F> Draining<S, F> {
+    pub(crate) fn new(serve: Serve<S>, signal: F) -> Self {
+        let (trigger, watch) = oneshot::channel();
+        let (abort, aborting) = oneshot::channel();
+        let (drained, draining) = mpsc::channel(0);
+        let counts = Arc::new(Counts::default());
+        Draining {
+            running: Some(Running {
+                serve,
+                signal: Box::pin(signal),
+                _trigger: trigger,
+                watch: watch.shared(),
+                aborting: aborting.shared(),
+                counts: counts.clone(),
+                drained,
+            }),
+            draining,
+            counts,
+            drain_timeout: None,
+            started: None,
+            timeout: None,
+            abort: Some(abort),
+        }
+    }
+
+    /// Drop connections still open `timeout` after shutdown begins, rather than waiting for
+    /// them indefinitely.
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = Some(timeout);
+        self
+    }
+}
+
+impl<S, F> Future for Draining<S, F>
+where
+    S: NewService<ReqBody = Body>,
+    S::Future: Send + 'static,
+    S::Service: Send + 'static,
+    <S::Service as Service<Request<Body>>>::Future: Send + 'static,
+    F: Future,
+{
+    type Output = io::Result<Drained>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(ref mut running) = self.running {
+            ready!(running.poll(cx))?;
+            // stop accepting and signal in-flight connections, then wait for them to finish
+            self.running = None;
+            self.started = Some(Instant::now());
+            self.timeout = self
+                .drain_timeout
+                .map(|timeout| Box::pin(time::sleep(timeout)));
+        }
+        if let Some(ref mut timeout) = self.timeout {
+            if timeout.as_mut().poll(cx).is_ready() {
+                self.timeout = None;
+                self.abort = None;
+            }
+        }
+        match self.draining.poll_next_unpin(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(_) => Poll::Ready(Ok(Drained {
+                drained: self.counts.drained.load(Ordering::SeqCst),
+                aborted: self.counts.aborted.load(Ordering::SeqCst),
+                duration: self
+                    .started
+                    .map(|started| started.elapsed())
+                    .unwrap_or_default(),
+            })),
+        }
+    }
+}
+
+/// A future driving a `Serve` until a signal future resolves, then draining in-flight connections.
+///
+/// This is a `Draining` without a drain timeout, resolving to nothing rather than to what
+/// became of the connections.
+pub struct Graceful<S, F> {
+    draining: Draining<S, F>,
+}
+
+impl<S, F> Graceful<S, F> {
+    pub(crate) fn new(serve: Serve<S>, signal: F) -> Self {
+        Graceful {
+            draining: Draining::new(serve, signal),
+        }
+    }
+}
+
+impl<S, F> Future for Graceful<S, F>
+where
+    S: NewService<ReqBody = Body>,
+    S::Future: Send + 'static,
+    S::Service: Send + 'static,
+    <S::Service as Service<Request<Body>>>::Future: Send + 'static,
+    F: Future,
+{
+    type Output = io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let drained = ready!(Pin::new(&mut self.draining).poll(cx));
+        Poll::Ready(drained.map(drop))
+    }
+}
+
+impl<S, F> Running<S, F>
+where
+    S: NewService<ReqBody = Body>,
+    S::Future: Send + 'static,
+    S::Service: Send + 'static,
+    <S::Service as Service<Request<Body>>>::Future: Send + 'static,
+    F: Future,
+{
+    /// Accept connections until either the signal or the incoming stream completes.
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            if self.signal.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(self.serve.poll_next_unpin(cx)) {
+                Some(connecting) => {
+                    let connecting = connecting?;
+                    let peer_cred = connecting.peer_cred;
+                    let serving = watching(
+                        connecting,
+                        self.watch.clone(),
+                        self.aborting.clone(),
+                        self.counts.clone(),
+                        self.drained.clone(),
+                    );
+                    tokio::spawn(Isolated::new(
+                        serving,
+                        peer_cred,
+                        self.serve.on_panic.clone(),
+                    ));
+                }
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+/// Serve a connection until it closes, shutting it down gracefully once the server starts
+/// draining, and dropping it if it is still open once the server gives up on it. Connections
+/// still being set up when the server starts draining are abandoned.
+async fn watching<F, S, E, B>(
+    connecting: Connecting<F>,
+    watch: Shared<oneshot::Receiver<()>>,
+    aborting: Shared<oneshot::Receiver<()>>,
+    counts: Arc<Counts>,
+    _drained: mpsc::Sender<()>,
+) where
+    F: Future<Output = Result<S, E>>,
+    S: Service<Request<Body>, Response = Response<B>> + Send + 'static,
+    S::Error: Into<Box<dyn StdError + Send + Sync>>,
+    S::Future: Send + 'static,
+    B: HttpBody<Data: Send, Error: Into<Box<dyn StdError + Send + Sync>>> + Send + 'static,
+{
+    pin_mut!(connecting);
+    let connection = match future::select(watch.clone(), connecting).await {
+        Either::Right((Ok(connection), _)) => connection,
+        Either::Right((Err(_), _)) => return,
+        Either::Left(_) => {
+            counts.aborted.fetch_add(1, Ordering::SeqCst);
+            return;
+        }
+    };
+    let connection = connection.with_upgrades();
+    pin_mut!(connection);
+    if let Either::Left(_) = future::select(watch, connection.as_mut()).await {
+        connection.as_mut().graceful_shutdown();
+        let count = match future::select(aborting, connection.as_mut()).await {
+            Either::Left(_) => &counts.aborted,
+            Either::Right(_) => &counts.drained,
+        };
+        count.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(all(test, feature = "client"))]
+mod tests {
+    use super::*;
+    use crate::server::Http;
+    use crate::testing;
+    use crate::PeerCred;
+    use futures::channel::oneshot;
+    use hyper::service::service_fn;
+    use hyper::Client;
+
+    #[test]
+    fn draining_reports_drained_and_aborted_connections() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let (connector, incoming) = testing::pair(PeerCred::new(0, 0, None)).unwrap();
+            let (started, mut starting) = mpsc::unbounded();
+            let serve = Http::new().serve_incoming(incoming, move || {
+                let started = started.clone();
+                service_fn(move |req: Request<Body>| {
+                    let _ = started.unbounded_send(());
+                    async move {
+                        if req.uri().path() == "/hang" {
+                            future::pending::<()>().await;
+                        }
+                        time::sleep(Duration::from_millis(20)).await;
+                        Ok::<_, hyper::Error>(Response::new(Body::empty()))
+                    }
+                })
+            });
+            let (stop, stopped) = oneshot::channel::<()>();
+            let draining = tokio::spawn(
+                serve
+                    .serve_with_shutdown(Triggers::new().channel(stopped))
+                    .drain_timeout(Duration::from_millis(200)),
+            );
+
+            // separate clients, so that each request has a connection of its own
+            for path in &["/slow", "/hang"] {
+                let client = Client::builder().build::<_, Body>(connector.clone());
+                let uri = format!("http://localhost{}", path).parse().unwrap();
+                tokio::spawn(async move { client.get(uri).await });
+            }
+            starting.next().await.unwrap();
+            starting.next().await.unwrap();
+            drop(stop);
+
+            let drained = draining.await.unwrap().unwrap();
+            assert_eq!(drained.drained(), 1);
+            assert_eq!(drained.aborted(), 1);
+            assert!(drained.duration() >= Duration::from_millis(200));
+        });
+    }
+
+    #[test]
+    fn triggers_fire_on_the_first_to_resolve() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let (_stop, stopped) = oneshot::channel::<()>();
+            let triggers = Triggers::new()
+                .channel(stopped)
+                .deadline(Instant::now() + Duration::from_millis(10));
+            assert_eq!(triggers.await.unwrap(), Trigger::Deadline);
+            assert_eq!(
+                Triggers::new().future(future::ready(())).await.unwrap(),
+                Trigger::Future
+            );
+            // a trigger failing to be set up fails the lot, rather than counting as fired
+            let failing = Triggers::new()
+                .deadline(Instant::now() + Duration::from_secs(60))
+                .push(future::ready(Err(io::Error::from(io::ErrorKind::Other))));
+            assert_eq!(failing.await.unwrap_err().kind(), io::ErrorKind::Other);
+        });
+    }
+}
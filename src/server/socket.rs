@@ -0,0 +1,506 @@
+//! The transport servers serve their connections over
+//!
+//! Unix domain sockets can carry open file descriptors alongside the bytes of a request, as
+//! `SCM_RIGHTS` ancillary data. Servers opted in with `Server::receive_fds` collect the
+//! descriptors sent by clients and hand them to the next request served on the connection,
+//! as a `ReceivedFds` extension. This enables protocols where a client passes the daemon a
+//! file or pty it has already opened.
+
+// Std lib
+use std::cmp;
+use std::fs::File;
+use std::io::{self, IoSlice};
+use std::mem;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::pin::Pin;
+use std::ptr;
+use std::slice;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+// Third party
+use futures::ready;
+use tokio::io::{AsyncRead, AsyncWrite, Interest, ReadBuf};
+use tokio::net::UnixStream;
+
+use super::quota::Account;
+use super::throttle::{Bucket, Throttle};
+use crate::credentials::PeerCred;
+use crate::tap::{Direction, Recorder, Tapper};
+
+/// The most descriptors accepted with a single read; any beyond are closed by the kernel.
+const MAX_FDS: usize = 32;
+
+/// Descriptors received on a connection, waiting to be handed to a request.
+pub(crate) type FdQueue = Arc<Mutex<Vec<OwnedFd>>>;
+
+/// The file descriptors a client sent along with a request, found in its extensions.
+///
+/// Descriptors are attributed to the first request served after they were received, which
+/// is the request they were sent with unless the client pipelines requests. They are closed
+/// when dropped, so handlers wanting to keep one should remove this extension from the
+/// request and take ownership of it.
+///
+/// # Examples
+///
+/// ```rust
+/// use std::fs::File;
+///
+/// use hyper::{Body, Request};
+/// use hyperlocal::server::socket::ReceivedFds;
+///
+/// fn files(req: &mut Request<Body>) -> Vec<File> {
+///     req.extensions_mut()
+///         .remove::<ReceivedFds>()
+///         .map(|fds| fds.into_inner().into_iter().map(File::from).collect())
+///         .unwrap_or_default()
+/// }
+/// ```
+#[derive(Debug)]
+pub struct ReceivedFds(Vec<OwnedFd>);
+
+impl ReceivedFds {
+    pub(crate) fn take(queue: &FdQueue) -> Option<Self> {
+        let mut queue = queue.lock().unwrap_or_else(|e| e.into_inner());
+        if queue.is_empty() {
+            None
+        } else {
+            Some(ReceivedFds(mem::take(&mut *queue)))
+        }
+    }
+
+    /// Return the descriptors, in the order they were received.
+    pub fn fds(&self) -> &[OwnedFd] {
+        &self.0
+    }
+
+    /// Take ownership of the descriptors.
+    pub fn into_inner(self) -> Vec<OwnedFd> {
+        self.0
+    }
+}
+
+/// A connected unix domain socket, as served by a `Serve`.
+pub struct Socket {
+    stream: UnixStream,
+    peer_cred: Option<PeerCred>,
+    fds: Option<FdQueue>,
+    tap: Option<Recorder>,
+    read_limit: Option<Bucket>,
+    write_limit: Option<Bucket>,
+    account: Option<Account>,
+}
+
+impl Socket {
+    pub(crate) fn new(stream: UnixStream) -> Self {
+        Socket {
+            stream,
+            peer_cred: None,
+            fds: None,
+            tap: None,
+            read_limit: None,
+            write_limit: None,
+            account: None,
+        }
+    }
+
+    /// Wrap `stream` so that reads also collect the descriptors sent over it into `queue`.
+    pub(crate) fn receiving_fds(stream: UnixStream, queue: FdQueue) -> Self {
+        Socket {
+            stream,
+            peer_cred: None,
+            fds: Some(queue),
+            tap: None,
+            read_limit: None,
+            write_limit: None,
+            account: None,
+        }
+    }
+
+    /// Report `peer_cred`, as queried when the socket was accepted, as its peer's credentials.
+    pub(crate) fn with_peer_cred(mut self, peer_cred: Option<PeerCred>) -> Self {
+        self.peer_cred = peer_cred;
+        self
+    }
+
+    /// Record what is read from and written to the socket with `tapper`.
+    pub(crate) fn tapped(mut self, tapper: &Tapper) -> Self {
+        self.tap = Some(tapper.recorder());
+        self
+    }
+
+    /// Read from and write to the socket no faster than `throttle` allows.
+    pub(crate) fn throttled(mut self, throttle: &Throttle) -> Self {
+        let (read_limit, write_limit) = throttle.buckets();
+        self.read_limit = read_limit;
+        self.write_limit = write_limit;
+        self
+    }
+
+    /// Count what is read from and written to the socket in `account`.
+    pub(crate) fn accounted(mut self, account: Account) -> Self {
+        self.account = Some(account);
+        self
+    }
+
+    /// Return the credentials of the peer, if they could be queried when it connected.
+    ///
+    /// This is the same `PeerCred` handed to each request served on the socket, for
+    /// connections served with `hyper::server::conn` directly, or recovered after an upgrade.
+    pub fn peer_cred(&self) -> Option<&PeerCred> {
+        self.peer_cred.as_ref()
+    }
+
+    /// Return the process id of the peer, on platforms reporting it.
+    pub fn peer_pid(&self) -> Option<i32> {
+        self.peer_cred.and_then(|peer_cred| peer_cred.pid())
+    }
+
+    /// Send up to `len` bytes of `file`, starting from `offset`, to the peer, returning how
+    /// many were sent before the file ended.
+    ///
+    /// On Linux the bytes go straight from the page cache to the socket with `sendfile(2)`;
+    /// elsewhere they are read and written in chunks. The file's own offset is left as it
+    /// was. As hyper frames the bodies of its responses itself, this is only for connections
+    /// taken over from hyper, such as those recovered with `hyper::upgrade::Upgraded::downcast`
+    /// after an upgrade.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use std::fs::File;
+    ///
+    /// use hyper::upgrade::Upgraded;
+    /// use hyperlocal::server::socket::Socket;
+    ///
+    /// async fn stream_log(upgraded: Upgraded, log: File) -> std::io::Result<u64> {
+    ///     let parts = upgraded
+    ///         .downcast::<Socket>()
+    ///         .map_err(|_| std::io::Error::other("not a hyperlocal connection"))?;
+    ///     let len = log.metadata()?.len();
+    ///     parts.io.send_file(&log, 0, len).await
+    /// }
+    /// ```
+    pub async fn send_file(&self, file: &File, offset: u64, len: u64) -> io::Result<u64> {
+        let mut sent = 0;
+        while sent < len {
+            self.stream.writable().await?;
+            let remaining = len - sent;
+            match self.stream.try_io(Interest::WRITABLE, || {
+                send_file(self.stream.as_raw_fd(), file, offset + sent, remaining)
+            }) {
+                Ok(0) => break,
+                Ok(written) => sent += written as u64,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(sent)
+    }
+}
+
+/// Send up to `len` bytes of `file` from `offset` to the socket `fd`, returning how many
+/// were sent.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn send_file(fd: RawFd, file: &File, offset: u64, len: u64) -> io::Result<usize> {
+    let mut offset = offset as libc::off_t;
+    // a single sendfile transfers at most a little under 2GiB anyway
+    let count = cmp::min(len, isize::MAX as u64) as usize;
+    let sent = unsafe { libc::sendfile(fd, file.as_raw_fd(), &mut offset, count) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(sent as usize)
+}
+
+/// Send up to `len` bytes of `file` from `offset` to the socket `fd`, returning how many
+/// were sent.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn send_file(fd: RawFd, file: &File, offset: u64, len: u64) -> io::Result<usize> {
+    let mut chunk = [0; 64 * 1024];
+    let count = cmp::min(len, chunk.len() as u64) as usize;
+    let read = file.read_at(&mut chunk[..count], offset)?;
+    if read == 0 {
+        return Ok(0);
+    }
+    let written = unsafe { libc::write(fd, chunk.as_ptr() as *const libc::c_void, read) };
+    if written < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(written as usize)
+}
+
+impl AsRawFd for Socket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+impl AsyncRead for Socket {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let allowed = match this.read_limit {
+            Some(ref mut limit) if buf.remaining() > 0 => {
+                Some(ready!(limit.poll_allow(cx, buf.remaining())))
+            }
+            _ => None,
+        };
+        match allowed {
+            Some(allowed) => {
+                let mut limited = buf.take(allowed);
+                ready!(poll_recv(
+                    &mut this.stream,
+                    this.fds.as_ref(),
+                    cx,
+                    &mut limited
+                ))?;
+                let read = limited.filled().len();
+                // the bytes read into `limited` were read into `buf`'s unfilled part
+                unsafe { buf.assume_init(read) };
+                buf.advance(read);
+                if let Some(ref mut limit) = this.read_limit {
+                    limit.consume(read);
+                }
+            }
+            None => ready!(poll_recv(&mut this.stream, this.fds.as_ref(), cx, buf))?,
+        }
+        if let Some(ref account) = this.account {
+            account.received(buf.filled().len() - filled);
+        }
+        if let Some(ref tap) = this.tap {
+            tap.record(Direction::Read, &buf.filled()[filled..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Read from `stream` into `buf`, collecting any descriptors received along the way into
+/// `queue`.
+fn poll_recv(
+    stream: &mut UnixStream,
+    queue: Option<&FdQueue>,
+    cx: &mut Context<'_>,
+    buf: &mut ReadBuf<'_>,
+) -> Poll<io::Result<()>> {
+    let queue = match queue {
+        None => return Pin::new(stream).poll_read(cx, buf),
+        Some(queue) => queue,
+    };
+    let fd = stream.as_raw_fd();
+    loop {
+        ready!(stream.poll_read_ready(cx))?;
+        let mut fds = Vec::new();
+        let unfilled = buf.initialize_unfilled();
+        match stream.try_io(Interest::READABLE, || recv_with_fds(fd, unfilled, &mut fds)) {
+            Ok(read) => {
+                if !fds.is_empty() {
+                    queue.lock().unwrap_or_else(|e| e.into_inner()).extend(fds);
+                }
+                buf.advance(read);
+                return Poll::Ready(Ok(()));
+            }
+            // readiness was cleared, so wait for the next event
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(err) => return Poll::Ready(Err(err)),
+        }
+    }
+}
+
+impl AsyncWrite for Socket {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let buf = match this.write_limit {
+            Some(ref mut limit) if !buf.is_empty() => {
+                &buf[..ready!(limit.poll_allow(cx, buf.len()))]
+            }
+            _ => buf,
+        };
+        let written = ready!(Pin::new(&mut this.stream).poll_write(cx, buf))?;
+        if let Some(ref mut limit) = this.write_limit {
+            limit.consume(written);
+        }
+        if let Some(ref account) = this.account {
+            account.sent(written);
+        }
+        if let Some(ref tap) = this.tap {
+            tap.record(Direction::Write, &buf[..written]);
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        if self.write_limit.is_some() {
+            // throttled writes go out one limited buffer at a time
+            let buf = bufs
+                .iter()
+                .find(|buf| !buf.is_empty())
+                .map_or(&[][..], |buf| &buf[..]);
+            return self.poll_write(cx, buf);
+        }
+        let written = ready!(Pin::new(&mut self.stream).poll_write_vectored(cx, bufs))?;
+        if let Some(ref account) = self.account {
+            account.sent(written);
+        }
+        if let Some(ref tap) = self.tap {
+            tap.record_vectored(bufs, written);
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.write_limit.is_none() && self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Read into `buf` from the socket `fd`, pushing any descriptors received along the way
+/// onto `fds`.
+fn recv_with_fds(fd: RawFd, buf: &mut [u8], fds: &mut Vec<OwnedFd>) -> io::Result<usize> {
+    let space = unsafe { libc::CMSG_SPACE((MAX_FDS * mem::size_of::<libc::c_int>()) as u32) };
+    // u64s keep the control buffer aligned for cmsghdr
+    let mut control = vec![0u64; (space as usize).div_ceil(mem::size_of::<u64>())];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let flags = libc::MSG_CMSG_CLOEXEC;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let flags = 0;
+    let read = unsafe { libc::recvmsg(fd, &mut msg, flags) };
+    if read < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        let header = unsafe { ptr::read_unaligned(cmsg) };
+        if header.cmsg_level == libc::SOL_SOCKET && header.cmsg_type == libc::SCM_RIGHTS {
+            let len = header.cmsg_len as usize - unsafe { libc::CMSG_LEN(0) } as usize;
+            let data = unsafe { slice::from_raw_parts(libc::CMSG_DATA(cmsg) as *const u8, len) };
+            for raw in data.chunks_exact(mem::size_of::<libc::c_int>()) {
+                let mut bytes = [0u8; 4];
+                bytes.copy_from_slice(raw);
+                let received = libc::c_int::from_ne_bytes(bytes);
+                #[cfg(not(any(target_os = "linux", target_os = "android")))]
+                unsafe {
+                    libc::fcntl(received, libc::F_SETFD, libc::FD_CLOEXEC);
+                }
+                fds.push(unsafe { OwnedFd::from_raw_fd(received) });
+            }
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+    }
+    Ok(read as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Seek;
+    use std::os::unix::net::UnixStream as StdUnixStream;
+
+    /// Send `data` over `fd` along with the descriptor `sent`.
+    fn send_with_fd(fd: RawFd, data: &[u8], sent: RawFd) {
+        let space = unsafe { libc::CMSG_SPACE(mem::size_of::<libc::c_int>() as u32) };
+        let mut control = vec![0u64; (space as usize).div_ceil(mem::size_of::<u64>())];
+        let mut iov = libc::iovec {
+            iov_base: data.as_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = space as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<libc::c_int>() as u32) as _;
+            ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut libc::c_int, sent);
+            assert_eq!(libc::sendmsg(fd, &msg, 0), data.len() as isize);
+        }
+    }
+
+    #[test]
+    fn sockets_report_peer_credentials() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let (server, _client) = UnixStream::pair().unwrap();
+            let peer_cred = PeerCred::of(&server).ok();
+            let socket = Socket::new(server).with_peer_cred(peer_cred);
+            assert_eq!(
+                socket.peer_cred().map(PeerCred::uid),
+                Some(unsafe { libc::getuid() })
+            );
+            assert_eq!(socket.peer_pid(), Some(std::process::id() as i32));
+        });
+    }
+
+    #[test]
+    fn send_file_sends_ranges() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let expected = std::fs::read("Cargo.toml").unwrap();
+        let file = File::open("Cargo.toml").unwrap();
+        let received = runtime.block_on(async {
+            let (server, mut client) = UnixStream::pair().unwrap();
+            let socket = Socket::new(server);
+            let len = expected.len() as u64;
+            assert_eq!(socket.send_file(&file, 4, len).await.unwrap(), len - 4);
+            drop(socket);
+            let mut received = Vec::new();
+            tokio::io::AsyncReadExt::read_to_end(&mut client, &mut received)
+                .await
+                .unwrap();
+            received
+        });
+        assert_eq!(received, expected[4..]);
+    }
+
+    #[test]
+    fn recv_with_fds_collects_descriptors() {
+        let (client, server) = StdUnixStream::pair().unwrap();
+        let mut file = File::open("Cargo.toml").unwrap();
+        send_with_fd(client.as_raw_fd(), b"GET", file.as_raw_fd());
+
+        let mut buf = [0u8; 16];
+        let mut fds = Vec::new();
+        let read = recv_with_fds(server.as_raw_fd(), &mut buf, &mut fds).unwrap();
+        assert_eq!(&buf[..read], b"GET");
+        assert_eq!(fds.len(), 1);
+
+        // the received descriptor shares its offset with the one sent
+        file.seek(io::SeekFrom::Start(7)).unwrap();
+        let mut received = File::from(fds.pop().unwrap());
+        assert_eq!(received.stream_position().unwrap(), 7);
+    }
+}
@@ -0,0 +1,160 @@
+//! Per-connection bandwidth limits
+//!
+//! Clients sharing a daemon's socket also share its IO. Without a limit, one client streaming
+//! a large body in or out, say an image being pushed, gets all of the bandwidth the daemon
+//! has while every other client waits. A `Throttle` registered through `Server::throttle`
+//! caps how fast each accepted connection is read from and written to, on its own.
+
+// Std lib
+use std::cmp;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+// Third party
+use tokio::time::{self, Instant, Sleep};
+
+/// Read and write rates applied to each connection a server accepts.
+///
+/// Rates are in bytes per second, and leave that direction unlimited when unset. Each
+/// connection may burst up to a second's worth of bytes after being idle.
+///
+/// # Examples
+///
+/// ```rust
+/// use hyperlocal::server::throttle::Throttle;
+///
+/// // 16MiB/s in, 64MiB/s out, per connection
+/// let throttle = Throttle::new().read(16 << 20).write(64 << 20);
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
+pub struct Throttle {
+    read: Option<u64>,
+    write: Option<u64>,
+}
+
+impl Throttle {
+    /// Create a throttle limiting neither direction.
+    pub fn new() -> Self {
+        Throttle::default()
+    }
+
+    /// Read at most `rate` bytes per second from each connection.
+    pub fn read(mut self, rate: u64) -> Self {
+        self.read = Some(rate);
+        self
+    }
+
+    /// Write at most `rate` bytes per second to each connection.
+    pub fn write(mut self, rate: u64) -> Self {
+        self.write = Some(rate);
+        self
+    }
+
+    /// Return the read rate, if one is set.
+    pub fn read_rate(&self) -> Option<u64> {
+        self.read
+    }
+
+    /// Return the write rate, if one is set.
+    pub fn write_rate(&self) -> Option<u64> {
+        self.write
+    }
+
+    /// Return the buckets limiting a single connection's reads and writes.
+    pub(crate) fn buckets(&self) -> (Option<Bucket>, Option<Bucket>) {
+        (self.read.map(Bucket::new), self.write.map(Bucket::new))
+    }
+}
+
+/// A token bucket holding up to a second's worth of bytes, refilled at its rate.
+pub(crate) struct Bucket {
+    rate: u64,
+    tokens: u64,
+    refilled: Instant,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        // a rate of zero would never refill; treat it as a byte per second
+        let rate = cmp::max(rate, 1);
+        Bucket {
+            rate,
+            tokens: rate,
+            refilled: Instant::now(),
+            sleep: None,
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let earned = (now - self.refilled).as_nanos() * u128::from(self.rate) / 1_000_000_000;
+        if earned > 0 {
+            self.tokens = cmp::min(self.rate, self.tokens.saturating_add(earned as u64));
+            self.refilled = now;
+        }
+    }
+
+    /// Return how many of the `want` bytes may be transferred now, waiting until enough may
+    /// be.
+    ///
+    /// Waits until `want` bytes, or a second's worth if that is less, are available, so that
+    /// slow rates are met with fewer, larger transfers rather than a byte at a time.
+    pub(crate) fn poll_allow(&mut self, cx: &mut Context<'_>, want: usize) -> Poll<usize> {
+        loop {
+            if let Some(ref mut sleep) = self.sleep {
+                if sleep.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                self.sleep = None;
+            }
+            self.refill();
+            let target = cmp::min(want as u64, self.rate);
+            if self.tokens >= target {
+                return Poll::Ready(target as usize);
+            }
+            let missing = target - self.tokens;
+            let wait = Duration::from_nanos(
+                (u128::from(missing) * 1_000_000_000 / u128::from(self.rate)) as u64 + 1,
+            );
+            self.sleep = Some(Box::pin(time::sleep(wait)));
+        }
+    }
+
+    /// Take `used` bytes, at most as many as `poll_allow` allowed, out of the bucket.
+    pub(crate) fn consume(&mut self, used: usize) {
+        self.tokens = self.tokens.saturating_sub(used as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::socket::Socket;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixStream;
+
+    #[test]
+    fn throttled_sockets_write_at_their_rate() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let (elapsed, received) = runtime.block_on(async {
+            let (server, mut client) = UnixStream::pair().unwrap();
+            let mut socket = Socket::new(server).throttled(&Throttle::new().write(20_000));
+            let started = std::time::Instant::now();
+            let writing = tokio::spawn(async move {
+                socket.write_all(&[7; 30_000]).await.unwrap();
+                started.elapsed()
+            });
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).await.unwrap();
+            (writing.await.unwrap(), received)
+        });
+        assert_eq!(received.len(), 30_000);
+        // the first 20k are a burst, the rest take half a second
+        assert!(elapsed >= Duration::from_millis(400), "{:?}", elapsed);
+    }
+}
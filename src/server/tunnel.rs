@@ -0,0 +1,201 @@
+//! Brokering `CONNECT` tunnels over a server's socket
+//!
+//! `Tunnel` accepts `CONNECT` requests in front of another service, answering them with
+//! `200 OK` and handing the upgraded connection to a callback once that response has been
+//! written. What the callback does with the stream is up to the application: typically it
+//! connects to the requested authority, say a container's TCP port, and copies bytes both
+//! ways until either side closes.
+
+// Std lib
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+// Third party
+use futures::future::{self, Either, Ready};
+use futures::ready;
+use hyper::http::request::Parts;
+use hyper::service::Service;
+use hyper::upgrade::Upgraded;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use pin_project_lite::pin_project;
+
+use super::NewService;
+use crate::credentials::PeerCred;
+
+/// A `NewService` accepting `CONNECT` requests, and handing every other request to the
+/// services it wraps.
+///
+/// The callback is given the head of the `CONNECT` request, whose `uri` holds the requested
+/// authority and whose extensions hold the peer's credentials, along with the upgraded
+/// stream. Its future is spawned onto the current tokio runtime. `CONNECT` requests without an
+/// authority are answered with `400 Bad Request`.
+///
+/// # Examples
+///
+/// ```rust
+/// use hyper::service::service_fn;
+/// use hyperlocal::server::{tunnel::Tunnel, Server};
+/// use tokio::net::TcpStream;
+///
+/// # if let Err(err) = std::fs::remove_file("hyperlocal_test_tunnel_server.sock") {
+/// #   if err.kind() != std::io::ErrorKind::NotFound {
+/// #     panic!("{}", err)
+/// #   }
+/// # }
+/// #
+/// let server = Server::bind(
+///    "hyperlocal_test_tunnel_server.sock",
+///    Tunnel::new(
+///        || service_fn(|req| async move { Ok::<_, hyper::Error>(hyper::Response::new(req.into_body())) }),
+///        |head: hyper::http::request::Parts, mut upgraded| async move {
+///            let authority = head.uri.authority().unwrap().to_string();
+///            if let Ok(mut tcp) = TcpStream::connect(authority).await {
+///                let _ = tokio::io::copy_bidirectional(&mut upgraded, &mut tcp).await;
+///            }
+///        }
+///    )
+/// ).unwrap();
+/// ```
+pub struct Tunnel<S, F> {
+    new_service: S,
+    on_connect: Arc<F>,
+}
+
+impl<S, F> Tunnel<S, F> {
+    /// Wraps `new_service`, handing tunnels to `on_connect`.
+    pub fn new(new_service: S, on_connect: F) -> Self {
+        Tunnel {
+            new_service,
+            on_connect: Arc::new(on_connect),
+        }
+    }
+}
+
+impl<S: Clone, F> Clone for Tunnel<S, F> {
+    fn clone(&self) -> Self {
+        Tunnel {
+            new_service: self.new_service.clone(),
+            on_connect: self.on_connect.clone(),
+        }
+    }
+}
+
+impl<S, F, R> NewService for Tunnel<S, F>
+where
+    S: NewService<ReqBody = Body, ResBody = Body>,
+    F: Fn(Parts, Upgraded) -> R + Send + Sync + 'static,
+    R: Future<Output = ()> + Send + 'static,
+{
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = S::Error;
+    type Service = TunnelService<S::Service, F>;
+    type Future = Tunneling<S::Future, F>;
+    type InitError = S::InitError;
+
+    fn new_service(&self) -> Self::Future {
+        self.new_service_for(None)
+    }
+
+    fn new_service_for(&self, peer_cred: Option<&PeerCred>) -> Self::Future {
+        Tunneling {
+            future: self.new_service.new_service_for(peer_cred),
+            on_connect: Some(self.on_connect.clone()),
+        }
+    }
+}
+
+pin_project! {
+    /// A future resolving to a `TunnelService` once the wrapped service has been created.
+    pub struct Tunneling<F, C> {
+        #[pin]
+        future: F,
+        on_connect: Option<Arc<C>>,
+    }
+}
+
+impl<F, C, S, E> Future for Tunneling<F, C>
+where
+    F: Future<Output = Result<S, E>>,
+{
+    type Output = Result<TunnelService<S, C>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let inner = ready!(this.future.poll(cx))?;
+        Poll::Ready(Ok(TunnelService {
+            inner,
+            on_connect: this.on_connect.take().expect("polled after complete"),
+        }))
+    }
+}
+
+/// A `Service` accepting `CONNECT` requests in front of another `Service`.
+pub struct TunnelService<S, F> {
+    inner: S,
+    on_connect: Arc<F>,
+}
+
+impl<S, F, R> Service<Request<Body>> for TunnelService<S, F>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+    F: Fn(Parts, Upgraded) -> R + Send + Sync + 'static,
+    R: Future<Output = ()> + Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = Either<Ready<Result<Response<Body>, S::Error>>, S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        if req.method() != Method::CONNECT {
+            return Either::Right(self.inner.call(req));
+        }
+        let mut response = Response::new(Body::empty());
+        if req.uri().authority().is_none() {
+            *response.status_mut() = StatusCode::BAD_REQUEST;
+            *response.body_mut() = Body::from("CONNECT requires an authority\n");
+            return Either::Left(future::ok(response));
+        }
+        let on_upgrade = hyper::upgrade::on(&mut req);
+        let (head, _) = req.into_parts();
+        let on_connect = self.on_connect.clone();
+        tokio::spawn(async move {
+            if let Ok(upgraded) = on_upgrade.await {
+                on_connect(head, upgraded).await;
+            }
+        });
+        Either::Left(future::ok(response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use hyper::service::service_fn;
+
+    #[test]
+    fn tunnel_rejects_connect_without_authority() {
+        let tunnel = Tunnel::new(
+            || service_fn(|_| async { Ok::<_, hyper::Error>(Response::new(Body::empty())) }),
+            |_, _| async {},
+        );
+        let mut service = block_on(tunnel.new_service()).unwrap();
+
+        let mut req = Request::new(Body::empty());
+        *req.method_mut() = Method::CONNECT;
+        *req.uri_mut() = "/containers".parse().unwrap();
+        let response = block_on(service.call(req)).unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let req = Request::new(Body::empty());
+        let response = block_on(service.call(req)).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
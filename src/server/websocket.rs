@@ -0,0 +1,189 @@
+//! Accepting WebSocket connections
+//!
+//! This module only performs the opening handshake described in
+//! [RFC 6455](https://tools.ietf.org/html/rfc6455#section-4.2). Once the `101 Switching
+//! Protocols` response has been sent, the `OnUpgrade` future resolves to the raw upgraded
+//! stream, which a WebSocket implementation such as `tokio-tungstenite` can then take over.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use hyper::service::service_fn;
+//! use hyper::{Body, Response, StatusCode};
+//! use hyperlocal::server::{websocket, Server};
+//!
+//! # if let Err(err) = std::fs::remove_file("hyperlocal_test_websocket_server.sock") {
+//! #   if err.kind() != std::io::ErrorKind::NotFound {
+//! #     panic!("{}", err)
+//! #   }
+//! # }
+//! #
+//! let server = Server::bind("hyperlocal_test_websocket_server.sock", || {
+//!     service_fn(|req| async move {
+//!         if !websocket::is_upgrade_request(&req) {
+//!             return Ok::<_, hyper::Error>(Response::new(Body::from("not a websocket")));
+//!         }
+//!         match websocket::upgrade(req) {
+//!             Ok((response, on_upgrade)) => {
+//!                 tokio::spawn(async move {
+//!                     if let Ok(_upgraded) = on_upgrade.await {
+//!                         // hand the stream to a websocket implementation
+//!                     }
+//!                 });
+//!                 Ok(response)
+//!             }
+//!             Err(err) => {
+//!                 let mut response = Response::new(Body::from(err.to_string()));
+//!                 *response.status_mut() = StatusCode::BAD_REQUEST;
+//!                 Ok(response)
+//!             }
+//!         }
+//!     })
+//! });
+//! ```
+
+// Std lib
+use std::error::Error as StdError;
+use std::fmt;
+
+// Third party
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::upgrade::OnUpgrade;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use sha1::Sha1;
+
+/// The GUID appended to a client's key to compute `Sec-WebSocket-Accept`.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The reason a request could not be upgraded to a WebSocket connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HandshakeError {
+    /// The request method was not `GET`.
+    Method,
+    /// The request did not ask to upgrade to `websocket`.
+    NotUpgrade,
+    /// The request asked for a WebSocket version other than 13.
+    Version,
+    /// The request had no valid `Sec-WebSocket-Key` header.
+    Key,
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            HandshakeError::Method => "websocket handshakes must use GET",
+            HandshakeError::NotUpgrade => "request is not a websocket upgrade",
+            HandshakeError::Version => "unsupported websocket version",
+            HandshakeError::Key => "missing or invalid Sec-WebSocket-Key",
+        })
+    }
+}
+
+impl StdError for HandshakeError {}
+
+/// Return true if `req` asks to upgrade its connection to a WebSocket.
+///
+/// This only inspects the `Connection` and `Upgrade` headers; `upgrade` validates the rest
+/// of the handshake.
+pub fn is_upgrade_request<B>(req: &Request<B>) -> bool {
+    let headers = req.headers();
+    has_token(headers, header::CONNECTION, "upgrade")
+        && has_token(headers, header::UPGRADE, "websocket")
+}
+
+/// Accept a WebSocket handshake.
+///
+/// On success, returns the `101 Switching Protocols` response the service should answer
+/// `req` with, and a future resolving to the upgraded stream once that response has been
+/// written. `Server` drives its connections with upgrades enabled; connections served
+/// through `Http` must be driven with hyper's `Connection::with_upgrades` instead.
+pub fn upgrade(req: Request<Body>) -> Result<(Response<Body>, OnUpgrade), HandshakeError> {
+    if req.method() != Method::GET {
+        return Err(HandshakeError::Method);
+    }
+    if !is_upgrade_request(&req) {
+        return Err(HandshakeError::NotUpgrade);
+    }
+    if req
+        .headers()
+        .get(header::SEC_WEBSOCKET_VERSION)
+        .map(|version| version != "13")
+        .unwrap_or(true)
+    {
+        return Err(HandshakeError::Version);
+    }
+    let accept = req
+        .headers()
+        .get(header::SEC_WEBSOCKET_KEY)
+        .filter(|key| key.len() == 24)
+        .map(|key| accept_key(key.as_bytes()))
+        .ok_or(HandshakeError::Key)?;
+
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+    {
+        let headers = response.headers_mut();
+        headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+        headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+        headers.insert(
+            header::SEC_WEBSOCKET_ACCEPT,
+            HeaderValue::from_str(&accept).expect("base64 is a valid header value"),
+        );
+    }
+    Ok((response, hyper::upgrade::on(req)))
+}
+
+/// Compute the `Sec-WebSocket-Accept` value answering a client's `Sec-WebSocket-Key`.
+fn accept_key(key: &[u8]) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key);
+    sha1.update(ACCEPT_GUID.as_bytes());
+    base64::encode(&sha1.digest().bytes())
+}
+
+/// Return true if any of the comma separated values of header `name` is `token`.
+pub(crate) fn has_token(headers: &HeaderMap, name: header::HeaderName, token: &str) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_key_matches_rfc() {
+        assert_eq!(
+            accept_key(b"dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn upgrade_validates_handshake() {
+        let req = Request::get("/events")
+            .header("connection", "keep-alive, Upgrade")
+            .header("upgrade", "websocket")
+            .header("sec-websocket-version", "13")
+            .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .body(Body::empty())
+            .unwrap();
+        let (response, _) = upgrade(req).unwrap();
+        assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(
+            response.headers()[header::SEC_WEBSOCKET_ACCEPT],
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+
+        let req = Request::get("/events")
+            .header("connection", "upgrade")
+            .header("upgrade", "websocket")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(upgrade(req).unwrap_err(), HandshakeError::Version);
+    }
+}
@@ -0,0 +1,136 @@
+//! Spreading a server over several threads
+//!
+//! A `Serve` accepts and serves all of its connections from a single task. For servers
+//! facing very high connection rates, `Server::run_workers_until` instead runs a number of
+//! worker threads, each with its own runtime and its own duplicate of the listener's file
+//! descriptor, accepting and serving connections independently of one another. The kernel
+//! hands each incoming connection to one of the workers waiting on the listener.
+
+// Std lib
+use std::future::Future;
+use std::io;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixListener as StdUnixListener;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+// Third party
+use futures::channel::{mpsc, oneshot};
+use futures::future::FutureExt;
+use futures::stream::FuturesUnordered;
+use hyper::service::Service;
+use hyper::{Body, Request};
+use tokio::runtime::{Builder, Runtime};
+
+use super::{Bound, Incoming, NewService, Serve};
+
+/// Serve connections accepted from `serve`'s listener on `workers` threads until `signal`
+/// resolves, then drain every worker.
+pub(crate) fn run<S, F, T>(serve: &Serve<S>, workers: usize, signal: F) -> io::Result<()>
+where
+    S: NewService<ReqBody = Body> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    S::Service: Send + 'static,
+    <S::Service as Service<Request<Body>>>::Future: Send + 'static,
+    F: Future<Output = io::Result<T>>,
+{
+    if workers == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "at least one worker is required",
+        ));
+    }
+    let fd = serve.incoming.fd.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "server has no listener to share with workers",
+        )
+    })?;
+
+    // dropping the sending half tells every worker to drain
+    let (trigger, stop) = oneshot::channel::<()>();
+    let stop = stop.shared();
+    let mut threads = Vec::with_capacity(workers);
+    for id in 0..workers {
+        let spawned = duplicate(fd)
+            .and_then(|listener| serve.worker(listener))
+            .and_then(|worker| {
+                let stop = stop.clone();
+                thread::Builder::new()
+                    .name(format!("hyperlocal-worker-{}", id))
+                    .spawn(move || runtime()?.block_on(worker.with_graceful_shutdown(stop)))
+            });
+        match spawned {
+            Ok(thread) => threads.push(thread),
+            Err(err) => {
+                drop(trigger);
+                return join(threads).and(Err(err));
+            }
+        }
+    }
+
+    // the signal may need a reactor, such as that of `shutdown::signal`
+    let waited = runtime().map(|runtime| runtime.block_on(signal));
+    drop(trigger);
+    join(threads).and(waited.and_then(|signalled| signalled.map(drop)))
+}
+
+/// Build the single-threaded runtime a worker, or the thread waiting on the signal, runs on.
+fn runtime() -> io::Result<Runtime> {
+    Builder::new_current_thread().enable_all().build()
+}
+
+impl<S> Serve<S>
+where
+    S: Clone,
+{
+    /// Return a `Serve` configured like this one, accepting on `listener`.
+    fn worker(&self, listener: StdUnixListener) -> io::Result<Serve<S>> {
+        let incoming = Incoming::from_std(listener)?;
+        // rebinding moves only the server handed out the Rebinder; workers keep their listener
+        let (rebind_tx, rebind_rx) = mpsc::unbounded();
+        Ok(Serve {
+            bound: Arc::new(Mutex::new(Bound::of(&incoming))),
+            incoming,
+            new_service: self.new_service.clone(),
+            protocol: self.protocol.clone(),
+            retiring: None,
+            rebind_tx,
+            rebind_rx,
+            pauser: self.pauser.clone(),
+            filter: self.filter.clone(),
+            filtering: FuturesUnordered::new(),
+            metrics: self.metrics.clone(),
+            receive_fds: self.receive_fds,
+            tap: self.tap.clone(),
+            on_accept_error: self.on_accept_error.clone(),
+            backoff: None,
+            on_panic: self.on_panic.clone(),
+            health: self.health.clone(),
+            connections: self.connections.clone(),
+            throttle: self.throttle,
+            accounting: self.accounting.clone(),
+        })
+    }
+}
+
+/// Open a new, close-on-exec, descriptor for the listener `fd`.
+fn duplicate(fd: RawFd) -> io::Result<StdUnixListener> {
+    let duplicate = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+    if duplicate < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { StdUnixListener::from_raw_fd(duplicate) })
+}
+
+/// Wait for every worker to finish, returning the first error any of them failed with.
+fn join(threads: Vec<JoinHandle<io::Result<()>>>) -> io::Result<()> {
+    let mut result = Ok(());
+    for thread in threads {
+        let joined = thread
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("worker thread panicked")));
+        result = result.and(joined);
+    }
+    result
+}
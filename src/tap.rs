@@ -0,0 +1,551 @@
+//! Capturing the bytes sent over connections, for debugging
+//!
+//! tcpdump can't observe traffic over unix domain sockets, so hyperlocal can tee it instead. A
+//! `Tapper` hands every chunk of bytes read from or written to the connections it taps to a
+//! `Sink`, along with the connection it travelled over, its direction and when it did. Clients
+//! tap their connections through a `TappedConnector`, servers through `Server#tap`.
+//!
+//! `Writer` dumps chunks to a file, or any other writer, in a format resembling `hexdump -C`:
+//!
+//! ```text
+//! 1728900000.123456 connection 1 write 16 bytes
+//! 00000000  47 45 54 20 2f 20 48 54  54 50 2f 31 2e 31 0d 0a  |GET / HTTP/1.1..|
+//! ```
+//!
+//! `channel` hands them to a stream instead, for inspection in tests.
+
+// Std lib
+use std::fmt;
+use std::fs::File;
+use std::io::{self, IoSlice, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Third party
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::ready;
+use pin_project_lite::pin_project;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// The direction a chunk travelled in, relative to the tapped end of a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Read from the connection.
+    Read,
+    /// Written to the connection.
+    Write,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Direction::Read => "read",
+            Direction::Write => "write",
+        })
+    }
+}
+
+/// A chunk of bytes read from or written to a tapped connection.
+#[derive(Clone, Copy, Debug)]
+pub struct Chunk<'a> {
+    connection: u64,
+    direction: Direction,
+    time: SystemTime,
+    bytes: &'a [u8],
+}
+
+impl<'a> Chunk<'a> {
+    /// Return the number of the connection the chunk travelled over, unique within the
+    /// process.
+    pub fn connection(&self) -> u64 {
+        self.connection
+    }
+
+    /// Return the direction the chunk travelled in.
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    /// Return when the chunk was read or written.
+    pub fn time(&self) -> SystemTime {
+        self.time
+    }
+
+    /// Return the bytes of the chunk.
+    pub fn bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    /// Copy the chunk, so that it can outlive the read or write it was captured from.
+    pub fn to_owned(&self) -> Captured {
+        Captured {
+            connection: self.connection,
+            direction: self.direction,
+            time: self.time,
+            bytes: self.bytes.to_vec(),
+        }
+    }
+}
+
+/// A chunk copied out of a tapped connection, as yielded by `channel`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Captured {
+    /// The number of the connection the chunk travelled over.
+    pub connection: u64,
+    /// The direction the chunk travelled in.
+    pub direction: Direction,
+    /// When the chunk was read or written.
+    pub time: SystemTime,
+    /// The bytes of the chunk.
+    pub bytes: Vec<u8>,
+}
+
+/// Where a `Tapper` sends the chunks it captures.
+pub trait Sink: Send + Sync {
+    /// Record a single chunk.
+    fn record(&self, chunk: &Chunk<'_>);
+}
+
+impl<F> Sink for F
+where
+    F: Fn(&Chunk<'_>) + Send + Sync,
+{
+    fn record(&self, chunk: &Chunk<'_>) {
+        (*self)(chunk)
+    }
+}
+
+/// A `Sink` dumping every chunk, in hex, to a writer.
+#[derive(Debug)]
+pub struct Writer<W> {
+    writer: Mutex<W>,
+}
+
+impl<W> Writer<W> {
+    /// Create a sink dumping chunks to `writer`.
+    pub fn new(writer: W) -> Self {
+        Writer {
+            writer: Mutex::new(writer),
+        }
+    }
+}
+
+impl Writer<File> {
+    /// Create a sink dumping chunks to the file at `path`, replacing any file already there.
+    pub fn create<P>(path: P) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        File::create(path).map(Writer::new)
+    }
+}
+
+impl<W> Sink for Writer<W>
+where
+    W: Write + Send,
+{
+    fn record(&self, chunk: &Chunk<'_>) {
+        let dump = hexdump(chunk);
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        // write each chunk whole, so that chunks from other connections can't interleave
+        let _ = writer
+            .write_all(dump.as_bytes())
+            .and_then(|_| writer.flush());
+    }
+}
+
+/// A `Sink` sending a copy of every chunk down a channel.
+#[derive(Clone, Debug)]
+pub struct Channel {
+    tx: UnboundedSender<Captured>,
+}
+
+impl Sink for Channel {
+    fn record(&self, chunk: &Chunk<'_>) {
+        let _ = self.tx.unbounded_send(chunk.to_owned());
+    }
+}
+
+/// Create a `Channel` sink, and the stream of the chunks sent to it.
+///
+/// # Examples
+///
+/// ```rust
+/// use futures::StreamExt;
+/// use hyperlocal::tap::{self, Direction, Tapper};
+/// use tokio::io::AsyncWriteExt;
+///
+/// # #[tokio::main]
+/// # async fn main() -> std::io::Result<()> {
+/// let (sink, mut chunks) = tap::channel();
+/// let (stream, _peer) = tokio::net::UnixStream::pair()?;
+/// let mut stream = Tapper::new(sink).wrap(stream);
+/// stream.write_all(b"ping").await?;
+///
+/// let chunk = chunks.next().await.unwrap();
+/// assert_eq!(chunk.direction, Direction::Write);
+/// assert_eq!(chunk.bytes, b"ping");
+/// # Ok(())
+/// # }
+/// ```
+pub fn channel() -> (Channel, UnboundedReceiver<Captured>) {
+    let (tx, rx) = mpsc::unbounded();
+    (Channel { tx }, rx)
+}
+
+/// Taps connections, sending what travels over them to a `Sink`.
+#[derive(Clone)]
+pub struct Tapper {
+    sink: Arc<dyn Sink>,
+}
+
+impl Tapper {
+    /// Create a tapper sending what it captures to `sink`.
+    pub fn new<K>(sink: K) -> Self
+    where
+        K: Sink + 'static,
+    {
+        Tapper {
+            sink: Arc::new(sink),
+        }
+    }
+
+    /// Tap `stream`, numbering it as a new connection.
+    pub fn wrap<S>(&self, stream: S) -> Tapped<S> {
+        Tapped {
+            stream,
+            recorder: self.recorder(),
+        }
+    }
+
+    pub(crate) fn recorder(&self) -> Recorder {
+        static CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+        Recorder {
+            sink: self.sink.clone(),
+            connection: CONNECTIONS.fetch_add(1, Ordering::Relaxed) + 1,
+        }
+    }
+}
+
+impl fmt::Debug for Tapper {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tapper").finish()
+    }
+}
+
+/// Records what travels over a single tapped connection.
+pub(crate) struct Recorder {
+    sink: Arc<dyn Sink>,
+    connection: u64,
+}
+
+impl Recorder {
+    pub(crate) fn record(&self, direction: Direction, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        self.sink.record(&Chunk {
+            connection: self.connection,
+            direction,
+            time: SystemTime::now(),
+            bytes,
+        });
+    }
+
+    /// Record the first `written` bytes of `bufs` as written.
+    pub(crate) fn record_vectored(&self, bufs: &[IoSlice<'_>], mut written: usize) {
+        for buf in bufs {
+            if written == 0 {
+                break;
+            }
+            let len = std::cmp::min(written, buf.len());
+            self.record(Direction::Write, &buf[..len]);
+            written -= len;
+        }
+    }
+}
+
+pin_project! {
+    /// A stream whose reads and writes are captured by a `Tapper`.
+    pub struct Tapped<S> {
+        #[pin]
+        stream: S,
+        recorder: Recorder,
+    }
+}
+
+impl<S> Tapped<S> {
+    /// Return the number of the tapped connection.
+    pub fn connection(&self) -> u64 {
+        self.recorder.connection
+    }
+
+    /// Return a reference to the wrapped stream.
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+}
+
+impl<S> AsyncRead for Tapped<S>
+where
+    S: AsyncRead,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let filled = buf.filled().len();
+        ready!(this.stream.poll_read(cx, buf))?;
+        this.recorder
+            .record(Direction::Read, &buf.filled()[filled..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for Tapped<S>
+where
+    S: AsyncWrite,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let written = ready!(this.stream.poll_write(cx, buf))?;
+        this.recorder.record(Direction::Write, &buf[..written]);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let written = ready!(this.stream.poll_write_vectored(cx, bufs))?;
+        this.recorder.record_vectored(bufs, written);
+        Poll::Ready(Ok(written))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_shutdown(cx)
+    }
+}
+
+impl<S> AsRawFd for Tapped<S>
+where
+    S: AsRawFd,
+{
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+#[cfg(feature = "client")]
+impl<S> hyper::client::connect::Connection for Tapped<S>
+where
+    S: hyper::client::connect::Connection,
+{
+    fn connected(&self) -> hyper::client::connect::Connected {
+        self.stream.connected()
+    }
+}
+
+/// A connector tapping the connections it makes.
+///
+/// # Examples
+///
+/// ```no_run
+/// use hyperlocal::tap::{self, TappedConnector, Tapper};
+/// use hyperlocal::UnixConnector;
+///
+/// # fn main() -> std::io::Result<()> {
+/// let tapper = Tapper::new(tap::Writer::create("/tmp/hyperlocal.tap")?);
+/// let client = hyper::Client::builder()
+///     .build::<_, hyper::Body>(TappedConnector::new(UnixConnector::new(), tapper));
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "client")]
+#[derive(Clone, Debug)]
+pub struct TappedConnector<C> {
+    inner: C,
+    tapper: Tapper,
+}
+
+#[cfg(feature = "client")]
+impl<C> TappedConnector<C> {
+    /// Wraps `inner`, tapping the connections it makes with `tapper`.
+    pub fn new(inner: C, tapper: Tapper) -> Self {
+        TappedConnector { inner, tapper }
+    }
+}
+
+#[cfg(feature = "client")]
+impl<C> hyper::service::Service<hyper::Uri> for TappedConnector<C>
+where
+    C: hyper::service::Service<hyper::Uri>,
+{
+    type Response = Tapped<C::Response>;
+    type Error = C::Error;
+    type Future = Tapping<C::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: hyper::Uri) -> Self::Future {
+        Tapping {
+            future: self.inner.call(uri),
+            tapper: self.tapper.clone(),
+        }
+    }
+}
+
+#[cfg(feature = "client")]
+pin_project! {
+    /// A future resolving to a `Tapped` stream once the wrapped connector has connected.
+    pub struct Tapping<F> {
+        #[pin]
+        future: F,
+        tapper: Tapper,
+    }
+}
+
+#[cfg(feature = "client")]
+impl<F, S, E> std::future::Future for Tapping<F>
+where
+    F: std::future::Future<Output = Result<S, E>>,
+{
+    type Output = Result<Tapped<S>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let stream = ready!(this.future.poll(cx))?;
+        Poll::Ready(Ok(this.tapper.wrap(stream)))
+    }
+}
+
+/// Format `chunk` as a header line followed by 16 bytes per line of hex and ascii.
+fn hexdump(chunk: &Chunk<'_>) -> String {
+    use std::fmt::Write;
+
+    let time = chunk.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut out = format!(
+        "{}.{:06} connection {} {} {} bytes\n",
+        time.as_secs(),
+        time.subsec_micros(),
+        chunk.connection,
+        chunk.direction,
+        chunk.bytes.len()
+    );
+    for (line, bytes) in chunk.bytes.chunks(16).enumerate() {
+        let _ = write!(out, "{:08x} ", line * 16);
+        for column in 0..16 {
+            if column == 8 {
+                out.push(' ');
+            }
+            match bytes.get(column) {
+                Some(byte) => {
+                    let _ = write!(out, " {:02x}", byte);
+                }
+                None => out.push_str("   "),
+            }
+        }
+        out.push_str("  |");
+        out.extend(bytes.iter().map(|&byte| {
+            if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            }
+        }));
+        out.push_str("|\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hexdumps_resemble_hexdump_c() {
+        let chunk = Chunk {
+            connection: 3,
+            direction: Direction::Write,
+            time: UNIX_EPOCH + std::time::Duration::from_micros(1_500_000),
+            bytes: b"GET / HTTP/1.1\r\nHost",
+        };
+        assert_eq!(
+            hexdump(&chunk),
+            "1.500000 connection 3 write 20 bytes\n\
+             00000000  47 45 54 20 2f 20 48 54  54 50 2f 31 2e 31 0d 0a  |GET / HTTP/1.1..|\n\
+             00000010  48 6f 73 74                                       |Host|\n"
+        );
+    }
+
+    #[cfg(all(feature = "client", feature = "server"))]
+    #[test]
+    fn servers_tap_connections() {
+        use futures::{FutureExt, StreamExt};
+        use hyper::service::service_fn;
+        use hyper::{Body, Response};
+
+        use crate::server::Server;
+        use crate::testing::TempSocket;
+        use crate::{UnixConnector, Uri};
+
+        let socket = TempSocket::new().unwrap();
+        let (sink, chunks) = channel();
+        let server = Server::bind(socket.path(), || {
+            service_fn(|_| async { Ok::<_, hyper::Error>(Response::new(Body::from("pong"))) })
+        })
+        .unwrap()
+        .tap(Tapper::new(sink));
+        let (shutdown, stopped) = futures::channel::oneshot::channel::<()>();
+        let serving = std::thread::spawn(move || server.run_until(stopped.map(|_| Ok(()))));
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let client = hyper::Client::builder().build::<_, Body>(UnixConnector::new());
+            let response = client
+                .get(Uri::new(socket.path(), "/ping").into())
+                .await
+                .unwrap();
+            hyper::body::to_bytes(response.into_body()).await.unwrap();
+        });
+        drop(runtime);
+        drop(shutdown);
+        serving.join().unwrap().unwrap();
+        let chunks: Vec<Captured> = futures::executor::block_on(chunks.collect());
+        let read: Vec<u8> = chunks
+            .iter()
+            .filter(|chunk| chunk.direction == Direction::Read)
+            .flat_map(|chunk| chunk.bytes.clone())
+            .collect();
+        let written: Vec<u8> = chunks
+            .iter()
+            .filter(|chunk| chunk.direction == Direction::Write)
+            .flat_map(|chunk| chunk.bytes.clone())
+            .collect();
+        assert!(read.starts_with(b"GET /ping HTTP/1.1\r\n"));
+        assert!(written.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(written.ends_with(b"pong"));
+    }
+}
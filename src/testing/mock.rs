@@ -0,0 +1,302 @@
+//! A mock server for testing clients against canned responses
+
+// Std lib
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+// Third party
+use futures::channel::oneshot;
+use futures::FutureExt;
+use hyper::body::Bytes;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::service::service_fn;
+use hyper::{Body, HeaderMap, Method, Request, Response, StatusCode, Uri};
+
+use super::TempSocket;
+use crate::server::Server;
+
+/// A server answering requests with the responses of `Mock`s registered with it, and
+/// recording every request it receives.
+///
+/// The server binds a `TempSocket` and serves it from a thread of its own until dropped.
+/// Requests matching no mock are answered with `404 Not Found`.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[cfg(feature = "client")]
+/// # #[tokio::main]
+/// # async fn main() {
+/// use hyper::{Body, Client, Method};
+/// use hyperlocal::testing::{Mock, MockServer};
+/// use hyperlocal::{UnixConnector, Uri};
+///
+/// let server = MockServer::start().unwrap();
+/// server.register(Mock::new(Method::GET, "/ping").body("pong"));
+///
+/// let client = Client::builder().build::<_, Body>(UnixConnector::new());
+/// let response = client.get(Uri::new(server.path(), "/ping").into()).await.unwrap();
+/// let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+/// assert_eq!(&body[..], b"pong");
+///
+/// let received = server.received();
+/// assert_eq!(received.len(), 1);
+/// assert_eq!(received[0].uri().path(), "/ping");
+/// # }
+/// # #[cfg(not(feature = "client"))]
+/// # fn main() {}
+/// ```
+pub struct MockServer {
+    socket: TempSocket,
+    state: Arc<Mutex<State>>,
+    shutdown: Option<oneshot::Sender<()>>,
+    thread: Option<JoinHandle<io::Result<()>>>,
+}
+
+#[derive(Default)]
+struct State {
+    mocks: Vec<Mock>,
+    received: Vec<ReceivedRequest>,
+}
+
+impl MockServer {
+    /// Bind a mock server to a fresh temporary socket and start serving it.
+    pub fn start() -> io::Result<Self> {
+        let socket = TempSocket::new()?;
+        let state = Arc::new(Mutex::new(State::default()));
+        let serving = state.clone();
+        let server = Server::bind(socket.path(), move || {
+            let state = serving.clone();
+            service_fn(move |req: Request<Body>| {
+                let state = state.clone();
+                async move {
+                    let (parts, body) = req.into_parts();
+                    let body = hyper::body::to_bytes(body).await?;
+                    let received = ReceivedRequest {
+                        method: parts.method,
+                        uri: parts.uri,
+                        headers: parts.headers,
+                        body,
+                    };
+                    let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+                    let response = state
+                        .mocks
+                        .iter()
+                        .find(|mock| mock.matches(&received))
+                        .map_or_else(not_found, Mock::respond);
+                    state.received.push(received);
+                    Ok::<_, hyper::Error>(response)
+                }
+            })
+        })?;
+        let (shutdown, stopped) = oneshot::channel::<()>();
+        let thread = thread::Builder::new()
+            .name("hyperlocal-mock".into())
+            .spawn(move || server.run_until(stopped.map(|_| Ok(()))))?;
+        Ok(MockServer {
+            socket,
+            state,
+            shutdown: Some(shutdown),
+            thread: Some(thread),
+        })
+    }
+
+    /// Return the path of the socket the server is bound to.
+    pub fn path(&self) -> &Path {
+        self.socket.path()
+    }
+
+    /// Answer requests matching `mock` with its response.
+    ///
+    /// Mocks are tried in the order they were registered, and the first matching a request
+    /// answers it.
+    pub fn register(&self, mock: Mock) {
+        self.lock().mocks.push(mock);
+    }
+
+    /// Forget every registered mock and recorded request.
+    pub fn reset(&self) {
+        let mut state = self.lock();
+        state.mocks.clear();
+        state.received.clear();
+    }
+
+    /// Return the requests received so far, in the order they were received.
+    pub fn received(&self) -> Vec<ReceivedRequest> {
+        self.lock().received.clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl fmt::Debug for MockServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MockServer")
+            .field("path", &self.path())
+            .finish()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        drop(self.shutdown.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// A canned response, and the requests it answers.
+#[derive(Clone, Debug)]
+pub struct Mock {
+    method: Method,
+    path: String,
+    headers: HeaderMap,
+    status: StatusCode,
+    response_headers: HeaderMap,
+    body: Bytes,
+}
+
+impl Mock {
+    /// Answer requests with `method` for `path` with an empty `200 OK`.
+    pub fn new<P>(method: Method, path: P) -> Self
+    where
+        P: Into<String>,
+    {
+        Mock {
+            method,
+            path: path.into(),
+            headers: HeaderMap::new(),
+            status: StatusCode::OK,
+            response_headers: HeaderMap::new(),
+            body: Bytes::new(),
+        }
+    }
+
+    /// Only answer requests with the header `name` set to `value`.
+    pub fn header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.append(name, value);
+        self
+    }
+
+    /// Respond with `status`.
+    pub fn status(mut self, status: StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// Respond with the header `name` set to `value`.
+    pub fn response_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.response_headers.append(name, value);
+        self
+    }
+
+    /// Respond with `body`.
+    pub fn body<B>(mut self, body: B) -> Self
+    where
+        B: Into<Vec<u8>>,
+    {
+        self.body = Bytes::from(body.into());
+        self
+    }
+
+    fn matches(&self, req: &ReceivedRequest) -> bool {
+        req.method() == self.method
+            && req.uri().path() == self.path
+            && self
+                .headers
+                .iter()
+                .all(|(name, value)| req.headers().get_all(name).iter().any(|v| v == value))
+    }
+
+    fn respond(&self) -> Response<Body> {
+        let mut response = Response::new(Body::from(self.body.clone()));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.response_headers.clone();
+        response
+    }
+}
+
+fn not_found() -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::NOT_FOUND;
+    response
+}
+
+/// A request received by a `MockServer`.
+#[derive(Clone, Debug)]
+pub struct ReceivedRequest {
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl ReceivedRequest {
+    /// Return the method of the request.
+    pub fn method(&self) -> &Method {
+        &self.method
+    }
+
+    /// Return the uri of the request.
+    pub fn uri(&self) -> &Uri {
+        &self.uri
+    }
+
+    /// Return the headers of the request.
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    /// Return the body of the request.
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+}
+
+#[cfg(all(test, feature = "client"))]
+mod tests {
+    use super::*;
+    use crate::{UnixConnector, Uri as UnixUri};
+    use hyper::header::CONTENT_TYPE;
+
+    #[test]
+    fn mock_servers_match_and_record_requests() {
+        let server = MockServer::start().unwrap();
+        server.register(
+            Mock::new(Method::POST, "/v1/items")
+                .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
+                .status(StatusCode::CREATED)
+                .body("{\"id\":1}"),
+        );
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let statuses = runtime.block_on(async {
+            let client = hyper::Client::builder().build::<_, Body>(UnixConnector::new());
+            let mut statuses = Vec::new();
+            for content_type in &["application/json", "text/plain"] {
+                let req = Request::post(hyper::Uri::from(UnixUri::new(server.path(), "/v1/items")))
+                    .header(CONTENT_TYPE, *content_type)
+                    .body(Body::from("{}"))
+                    .unwrap();
+                statuses.push(client.request(req).await.unwrap().status());
+            }
+            statuses
+        });
+        assert_eq!(statuses, [StatusCode::CREATED, StatusCode::NOT_FOUND]);
+
+        let received = server.received();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].method(), Method::POST);
+        assert_eq!(received[0].body(), b"{}");
+        assert_eq!(received[1].headers()[CONTENT_TYPE], "text/plain");
+
+        let path = server.path().to_owned();
+        drop(server);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,290 @@
+//! An in-memory transport for exercising clients and servers together in tests
+//!
+//! `pair` returns a client connector and a server `Incoming` joined by a channel. Every
+//! connection the client makes is a fresh socket pair whose server end is handed straight
+//! to the `Incoming`, so no socket file is created and nothing touches the filesystem.
+//! Accepted connections report the peer credentials given to the connector rather than
+//! those of the current process, which lets tests exercise credential checks.
+//!
+//! Tests which do need a real socket file can use `TempSocket` for a path nothing else will
+//! collide with, and tests of clients can use `MockServer` for a server answering with canned
+//! responses.
+//!
+//! # Examples
+//!
+//! ```rust
+//! # #[cfg(all(feature = "client", feature = "server"))]
+//! # #[tokio::main]
+//! # async fn main() {
+//! use futures::future;
+//! use hyper::service::service_fn;
+//! use hyper::{Body, Client, Request, Response};
+//! use hyperlocal::server::Http;
+//! use hyperlocal::{testing, PeerCred};
+//!
+//! let (connector, incoming) = testing::pair(PeerCred::new(1000, 100, None)).unwrap();
+//! let serve = Http::new().serve_incoming(incoming, || {
+//!     service_fn(|req: Request<Body>| async move {
+//!         let uid = req.extensions().get::<PeerCred>().unwrap().uid();
+//!         Ok::<_, hyper::Error>(Response::new(Body::from(uid.to_string())))
+//!     })
+//! });
+//! tokio::spawn(serve.with_graceful_shutdown(future::pending::<()>()));
+//!
+//! let client = Client::builder().build::<_, Body>(connector);
+//! let response = client.get("http://localhost/".parse().unwrap()).await.unwrap();
+//! let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+//! assert_eq!(&body[..], b"1000");
+//! # }
+//! # #[cfg(not(all(feature = "client", feature = "server")))]
+//! # fn main() {}
+//! ```
+
+// Std lib
+use std::env;
+use std::fs::{self, DirBuilder};
+use std::io;
+use std::os::unix::fs::DirBuilderExt;
+#[cfg(all(feature = "client", feature = "server"))]
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(all(feature = "client", feature = "server"))]
+use std::task::{Context, Poll};
+
+// Third party
+#[cfg(all(feature = "client", feature = "server"))]
+use futures::channel::mpsc::{self, UnboundedSender};
+#[cfg(all(feature = "client", feature = "server"))]
+use futures::future::{self, Ready};
+#[cfg(all(feature = "client", feature = "server"))]
+use hyper::service::Service;
+#[cfg(all(feature = "client", feature = "server"))]
+use hyper::Uri;
+#[cfg(all(feature = "client", feature = "server"))]
+use tokio::net::UnixStream;
+
+#[cfg(all(feature = "client", feature = "server"))]
+use crate::client::UnixStream as ClientStream;
+#[cfg(all(feature = "client", feature = "server"))]
+use crate::credentials::PeerCred;
+use crate::paths;
+#[cfg(all(feature = "client", feature = "server"))]
+use crate::server::Incoming;
+
+#[cfg(feature = "server")]
+mod mock;
+
+#[cfg(feature = "server")]
+pub use self::mock::{Mock, MockServer, ReceivedRequest};
+
+/// Create a connector and an `Incoming` joined in memory.
+///
+/// Connections accepted from the `Incoming` report `peer_cred` as their peer's credentials.
+/// The `Incoming` ends once the connector and all of its clones have been dropped.
+#[cfg(all(feature = "client", feature = "server"))]
+pub fn pair(peer_cred: PeerCred) -> io::Result<(DuplexConnector, Incoming)> {
+    let (tx, rx) = mpsc::unbounded();
+    // socket pairs are unnamed, so any one of them has the address every other would
+    let local_addr = StdUnixStream::pair()?.0.local_addr()?;
+    Ok((
+        DuplexConnector { tx, peer_cred },
+        Incoming::duplex(rx, local_addr),
+    ))
+}
+
+/// A hyper client connector creating in-memory connections to the `Incoming` returned
+/// alongside it by `pair`.
+///
+/// The destination of requests is ignored, so any uri may be used with it.
+#[cfg(all(feature = "client", feature = "server"))]
+#[derive(Clone)]
+pub struct DuplexConnector {
+    tx: UnboundedSender<(UnixStream, PeerCred)>,
+    peer_cred: PeerCred,
+}
+
+#[cfg(all(feature = "client", feature = "server"))]
+impl DuplexConnector {
+    /// Return a connector whose connections report `peer_cred` to the server instead.
+    pub fn with_peer_cred(&self, peer_cred: PeerCred) -> Self {
+        DuplexConnector {
+            tx: self.tx.clone(),
+            peer_cred,
+        }
+    }
+
+    /// Return the peer credentials this connector's connections report to the server.
+    pub fn peer_cred(&self) -> PeerCred {
+        self.peer_cred
+    }
+}
+
+#[cfg(all(feature = "client", feature = "server"))]
+impl Service<Uri> for DuplexConnector {
+    type Response = ClientStream;
+    type Error = io::Error;
+    type Future = Ready<io::Result<ClientStream>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _: Uri) -> Self::Future {
+        future::ready(self.connect())
+    }
+}
+
+#[cfg(all(feature = "client", feature = "server"))]
+impl DuplexConnector {
+    fn connect(&self) -> io::Result<ClientStream> {
+        let (client, server) = UnixStream::pair()?;
+        self.tx
+            .unbounded_send((server, self.peer_cred))
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    "server is no longer running",
+                )
+            })?;
+        Ok(ClientStream::new(client, None))
+    }
+}
+
+/// A socket path inside a freshly created, private temporary directory.
+///
+/// The directory is unique to each `TempSocket`, so tests using one can run in parallel
+/// without racing to bind the same path. Nothing is bound to the path; it is left for a
+/// server to bind. On drop, the directory is removed along with the socket file and
+/// anything else put next to it, such as a `lock::BindLock` sidecar file.
+///
+/// Directories are created in the system's temporary directory, unless the socket path would
+/// then be too long to bind, as with the long `TMPDIR`s of macOS, in which case `/tmp` is used
+/// instead.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[cfg(feature = "server")]
+/// # fn main() {
+/// use hyper::service::service_fn;
+/// use hyperlocal::server::Server;
+/// use hyperlocal::testing::TempSocket;
+///
+/// let socket = TempSocket::new().unwrap();
+/// let server = Server::bind(
+///    &socket,
+///    || service_fn(|req| async { Ok::<_, hyper::Error>(hyper::Response::new(req.into_body())) })
+/// ).unwrap();
+/// # }
+/// # #[cfg(not(feature = "server"))]
+/// # fn main() {}
+/// ```
+#[derive(Debug)]
+pub struct TempSocket {
+    dir: PathBuf,
+    path: PathBuf,
+}
+
+impl TempSocket {
+    /// Create a temporary directory holding a socket path named `hyperlocal.sock`.
+    pub fn new() -> io::Result<Self> {
+        TempSocket::with_name("hyperlocal.sock")
+    }
+
+    /// Create a temporary directory holding a socket path named `name`.
+    ///
+    /// Fails with an error of kind `InvalidInput` if `name` is too long for the socket path to
+    /// be bound even in `/tmp`.
+    pub fn with_name<P>(name: P) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        TempSocket::in_dir(env::temp_dir(), name)
+    }
+
+    /// Create a directory inside `root` holding a socket path named `name`.
+    ///
+    /// Falls back to `/tmp` if the socket path would be too long to bind inside `root`.
+    pub fn in_dir<R, P>(root: R, name: P) -> io::Result<Self>
+    where
+        R: AsRef<Path>,
+        P: AsRef<Path>,
+    {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+        let name = name.as_ref();
+        let mut builder = DirBuilder::new();
+        builder.mode(0o700);
+        loop {
+            let dir_name = format!(
+                "hyperlocal-{}-{}",
+                process::id(),
+                COUNT.fetch_add(1, Ordering::Relaxed)
+            );
+            let mut dir = root.as_ref().join(&dir_name);
+            if dir.join(name).as_os_str().len() > paths::max_socket_path_len() {
+                dir = Path::new("/tmp").join(&dir_name);
+            }
+            let path = dir.join(name);
+            if path.as_os_str().len() > paths::max_socket_path_len() {
+                return Err(paths::too_long(&path));
+            }
+            match builder.create(&dir) {
+                Ok(()) => return Ok(TempSocket { dir, path }),
+                // left behind by a previous process with our pid
+                Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Return the socket path.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for TempSocket {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn temp_sockets_clean_up_on_drop() {
+        let first = TempSocket::new().unwrap();
+        let second = TempSocket::new().unwrap();
+        assert_ne!(first.path(), second.path());
+
+        let _listener = UnixListener::bind(&first).unwrap();
+        let dir = first.path().parent().unwrap().to_path_buf();
+        assert!(first.path().exists());
+        drop(first);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn temp_sockets_fit_in_a_sockaddr_un() {
+        let root = env::temp_dir().join("a".repeat(paths::max_socket_path_len()));
+        let socket = TempSocket::in_dir(&root, "hyperlocal.sock").unwrap();
+        assert!(socket.path().starts_with("/tmp"));
+        let _listener = UnixListener::bind(&socket).unwrap();
+
+        let name = "a".repeat(paths::max_socket_path_len());
+        let err = TempSocket::with_name(name).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
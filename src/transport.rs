@@ -0,0 +1,267 @@
+//! Connecting, binding and accepting on runtimes other than tokio
+//!
+//! hyperlocal's uri, credential and policy layers only need three things from a runtime:
+//! connecting to a socket path, binding one and accepting connections on it. `Transport` is
+//! those three things, and `connect`, `bind` and `accept` build the rest on top of any
+//! `Transport`, so that a `hyperlocal::Uri` is resolved, a long socket path shortened and a
+//! peer's `PeerCred` checked against a `CredPolicy` the same way whichever runtime drives the
+//! socket. `Tokio` is the transport hyperlocal's own connectors and servers use; `AsyncStd`
+//! and `Smol` adapt async-std's and smol's sockets, behind the `async-std` and `smol`
+//! features.
+//!
+//! ```rust
+//! # #[cfg(feature = "smol")]
+//! # fn main() -> Result<(), hyperlocal::Error> {
+//! use hyperlocal::transport::{self, Smol};
+//!
+//! # let path = std::env::temp_dir().join(format!("hyperlocal-transport-{}.sock", std::process::id()));
+//! let listener = transport::bind::<Smol, _>(&path)?;
+//! # drop(listener);
+//! # std::fs::remove_file(&path).unwrap();
+//! # Ok(())
+//! # }
+//! # #[cfg(not(feature = "smol"))]
+//! # fn main() {}
+//! ```
+
+// Std lib
+use std::future::Future;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::pin::Pin;
+
+// Third party
+use hyper::Uri as HyperUri;
+use tokio::net::{UnixListener, UnixStream};
+
+use super::{Uri, TLS_SCHEME, UNIX_SCHEME};
+use crate::credentials::{CredPolicy, PeerCred};
+use crate::error::Error;
+use crate::paths;
+
+/// A future resolving to a stream connected by a `Transport`.
+pub type Connecting<S> = Pin<Box<dyn Future<Output = io::Result<S>> + Send>>;
+
+/// A future resolving to the next stream accepted by a `Transport`'s listener.
+pub type Accepting<'a, S> = Pin<Box<dyn Future<Output = io::Result<S>> + Send + 'a>>;
+
+/// The unix domain sockets of an async runtime.
+pub trait Transport {
+    /// A connected stream.
+    type Stream: AsRawFd + Send + 'static;
+    /// A bound listener.
+    type Listener: AsRawFd + Send + Sync;
+
+    /// Connect to the socket at `path`, which is short enough to fit a `sockaddr_un`.
+    fn connect(path: &Path) -> Connecting<Self::Stream>;
+
+    /// Bind a listener to the socket at `path`.
+    fn bind(path: &Path) -> io::Result<Self::Listener>;
+
+    /// Accept the next connection on `listener`.
+    fn accept(listener: &Self::Listener) -> Accepting<'_, Self::Stream>;
+}
+
+/// The `Transport` of tokio's unix domain sockets.
+///
+/// Binding and accepting must happen from within a tokio runtime.
+#[derive(Clone, Copy, Debug)]
+pub struct Tokio;
+
+impl Transport for Tokio {
+    type Stream = UnixStream;
+    type Listener = UnixListener;
+
+    fn connect(path: &Path) -> Connecting<UnixStream> {
+        let path = path.to_owned();
+        Box::pin(async move { UnixStream::connect(path).await })
+    }
+
+    fn bind(path: &Path) -> io::Result<UnixListener> {
+        UnixListener::bind(path)
+    }
+
+    fn accept(listener: &UnixListener) -> Accepting<'_, UnixStream> {
+        Box::pin(async move { listener.accept().await.map(|(stream, _)| stream) })
+    }
+}
+
+/// The `Transport` of async-std's unix domain sockets.
+#[cfg(feature = "async-std")]
+#[derive(Clone, Copy, Debug)]
+pub struct AsyncStd;
+
+#[cfg(feature = "async-std")]
+impl Transport for AsyncStd {
+    type Stream = async_std::os::unix::net::UnixStream;
+    type Listener = async_std::os::unix::net::UnixListener;
+
+    fn connect(path: &Path) -> Connecting<Self::Stream> {
+        let path = path.to_owned();
+        Box::pin(async move { Self::Stream::connect(path).await })
+    }
+
+    fn bind(path: &Path) -> io::Result<Self::Listener> {
+        // binding std's listener keeps this synchronous, as `Transport::bind` is
+        std::os::unix::net::UnixListener::bind(path).map(Self::Listener::from)
+    }
+
+    fn accept(listener: &Self::Listener) -> Accepting<'_, Self::Stream> {
+        Box::pin(async move { listener.accept().await.map(|(stream, _)| stream) })
+    }
+}
+
+/// The `Transport` of smol's unix domain sockets, std's sockets registered with its reactor
+/// as `Async<UnixStream>` and `Async<UnixListener>`.
+#[cfg(feature = "smol")]
+#[derive(Clone, Copy, Debug)]
+pub struct Smol;
+
+#[cfg(feature = "smol")]
+impl Transport for Smol {
+    type Stream = smol::Async<std::os::unix::net::UnixStream>;
+    type Listener = smol::Async<std::os::unix::net::UnixListener>;
+
+    fn connect(path: &Path) -> Connecting<Self::Stream> {
+        let path = path.to_owned();
+        Box::pin(async move { Self::Stream::connect(path).await })
+    }
+
+    fn bind(path: &Path) -> io::Result<Self::Listener> {
+        Self::Listener::bind(path)
+    }
+
+    fn accept(listener: &Self::Listener) -> Accepting<'_, Self::Stream> {
+        Box::pin(async move { listener.accept().await.map(|(stream, _)| stream) })
+    }
+}
+
+/// Connect over `T` to the server listening on the socket `uri` names, as built by
+/// `hyperlocal::Uri`.
+///
+/// Both `unix` and `https+unix` uris are connected to; speaking TLS over the latter is left to
+/// the caller. Socket paths too long for a `sockaddr_un` are shortened as `paths` describes.
+pub async fn connect<T>(uri: &HyperUri) -> Result<T::Stream, Error>
+where
+    T: Transport,
+{
+    let scheme = uri.scheme_str().unwrap_or("");
+    if scheme != UNIX_SCHEME && scheme != TLS_SCHEME {
+        return Err(Error::Uri(uri.to_string()));
+    }
+    let path = match Uri::socket_path(uri) {
+        Some(path) => path,
+        None => return Err(Error::Uri(uri.to_string())),
+    };
+    // the shortened path only needs to live until the connection is made
+    let short = paths::shorten(Path::new(&path)).map_err(|err| Error::connect(&path, err))?;
+    T::connect(short.path())
+        .await
+        .map_err(|err| Error::connect(&path, err))
+}
+
+/// Bind a `T` listener to the socket at `path`.
+pub fn bind<T, P>(path: P) -> Result<T::Listener, Error>
+where
+    T: Transport,
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    T::bind(path).map_err(|err| Error::bind(path.display(), err))
+}
+
+/// Accept the next connection on `listener` from a peer `policy` allows, along with the
+/// peer's credentials if they could be queried.
+///
+/// Connections from any other peer are closed as soon as they are accepted.
+pub async fn accept<T>(
+    listener: &T::Listener,
+    policy: &CredPolicy,
+) -> io::Result<(T::Stream, Option<PeerCred>)>
+where
+    T: Transport,
+{
+    loop {
+        let stream = T::accept(listener).await?;
+        let peer_cred = PeerCred::of(&stream).ok();
+        if policy.allows(peer_cred.as_ref()) {
+            return Ok((stream, peer_cred));
+        }
+    }
+}
+
+/// Return whether unix domain sockets can be created at all.
+///
+/// Sandboxes, seccomp filters and some container runtimes deny `AF_UNIX` sockets outright, so
+/// that every connect or bind fails. Probing once at startup lets an application choose
+/// another way of reaching its peer up front instead.
+pub fn af_unix_supported() -> bool {
+    let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_STREAM, 0) };
+    if fd < 0 {
+        return false;
+    }
+    unsafe {
+        libc::close(fd);
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempSocket;
+
+    #[test]
+    fn tokio_transport_connects_and_accepts() {
+        let socket = TempSocket::new().unwrap();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let listener = bind::<Tokio, _>(socket.path()).unwrap();
+            let uri: HyperUri = Uri::new(socket.path(), "/").into();
+            let policy = CredPolicy::same_uid();
+            let (connected, accepted) =
+                futures::join!(connect::<Tokio>(&uri), accept::<Tokio>(&listener, &policy));
+            connected.unwrap();
+            let (_, peer_cred) = accepted.unwrap();
+            assert_eq!(peer_cred.unwrap().uid(), unsafe { libc::getuid() });
+
+            let uri = "http://localhost/".parse().unwrap();
+            match connect::<Tokio>(&uri).await {
+                Err(Error::Uri(_)) => (),
+                other => panic!("expected a uri error, got {:?}", other.map(drop)),
+            }
+        });
+    }
+
+    #[cfg(any(feature = "async-std", feature = "smol"))]
+    async fn connect_and_accept<T: Transport>(path: &Path) {
+        let listener = bind::<T, _>(path).unwrap();
+        let uri: HyperUri = Uri::new(path, "/").into();
+        let policy = CredPolicy::same_uid();
+        let (connected, accepted) =
+            futures::join!(connect::<T>(&uri), accept::<T>(&listener, &policy));
+        connected.unwrap();
+        let (_, peer_cred) = accepted.unwrap();
+        assert_eq!(peer_cred.unwrap().uid(), unsafe { libc::getuid() });
+    }
+
+    #[cfg(feature = "async-std")]
+    #[test]
+    fn async_std_transport_connects_and_accepts() {
+        let socket = TempSocket::new().unwrap();
+        async_std::task::block_on(connect_and_accept::<AsyncStd>(socket.path()));
+    }
+
+    #[cfg(feature = "smol")]
+    #[test]
+    fn smol_transport_connects_and_accepts() {
+        let socket = TempSocket::new().unwrap();
+        smol::block_on(connect_and_accept::<Smol>(socket.path()));
+    }
+
+    #[test]
+    fn af_unix_sockets_are_supported() {
+        assert!(af_unix_supported());
+    }
+}